@@ -1,17 +1,96 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
+use std::{
+    net::{SocketAddrV4, SocketAddrV6},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use tracing::{debug, info};
+use parking_lot::Mutex;
+use tracing::{debug, info, warn};
 
-use crate::app::{checking::PING_MAX_RETRY, socks5::SocksServer, AppContext, InnerProto};
+use crate::{
+    app::{checking::PING_MAX_RETRY, socks5::SocksServer, AppContext, InnerProto},
+    cli::{CheckMethod, CliArgs},
+};
 
 use super::ping::Pingable;
 
-#[derive(Debug, Default)]
+/// Upstream sending more malformed SOCKSv5 UDP replies than this within
+/// the window goes trouble.
+const MALFORMED_REPLIES_THRESHOLD: usize = 50;
+const MALFORMED_REPLIES_WINDOW: Duration = Duration::from_secs(10);
+/// Slow upstream recovers once its average RTT drops below this fraction
+/// of `max_rtt`.
+const MAX_RTT_HYSTERESIS: f32 = 0.8;
+
+#[derive(Debug)]
 pub(crate) struct Health {
     in_trouble: AtomicBool,
+    /// Average RTT exceeds `max_rtt`, kept apart from `in_trouble` as
+    /// traffic doesn't recover it.
+    too_slow: AtomicBool,
+    malformed_replies: MalformedReplies,
+    /// Last time `is_healthy()` flipped, or when created
+    changed_at: Mutex<Instant>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            in_trouble: Default::default(),
+            too_slow: Default::default(),
+            malformed_replies: Default::default(),
+            changed_at: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Health {
+    pub(crate) fn carry_over_from(&self, old: &Health) {
+        for (flag, old_flag) in [
+            (&self.in_trouble, &old.in_trouble),
+            (&self.too_slow, &old.too_slow),
+        ] {
+            flag.store(old_flag.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        *self.changed_at.lock() = *old.changed_at.lock();
+    }
+}
+
+#[derive(Debug)]
+struct MalformedReplies {
+    total: AtomicUsize,
+    /// Start & count of current window
+    window: Mutex<(Instant, usize)>,
+}
+
+impl Default for MalformedReplies {
+    fn default() -> Self {
+        Self {
+            total: Default::default(),
+            window: Mutex::new((Instant::now(), 0)),
+        }
+    }
+}
+
+impl MalformedReplies {
+    /// Return true if exceed the threshold within current window.
+    fn record(&self) -> bool {
+        self.total.fetch_add(1, Ordering::Relaxed);
+        let mut window = self.window.lock();
+        if window.0.elapsed() >= MALFORMED_REPLIES_WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        window.1 += 1;
+        window.1 >= MALFORMED_REPLIES_THRESHOLD
+    }
+
+    fn is_flooding(&self) -> bool {
+        let window = self.window.lock();
+        window.0.elapsed() < MALFORMED_REPLIES_WINDOW && window.1 >= MALFORMED_REPLIES_THRESHOLD
+    }
 }
 
 pub(crate) trait Healthy {
@@ -21,10 +100,12 @@ pub(crate) trait Healthy {
 
 impl Healthy for SocksServer {
     fn is_healthy(&self) -> bool {
-        !self.status.health.in_trouble.load(Ordering::Relaxed)
+        let health = &self.status.health;
+        !health.in_trouble.load(Ordering::Relaxed) && !health.too_slow.load(Ordering::Relaxed)
     }
 
     fn set_troubleness(&self, trouble: bool) {
+        let was_healthy = self.is_healthy();
         let old = self
             .status
             .health
@@ -35,29 +116,199 @@ impl Healthy for SocksServer {
             (true, false) => info!("Upstream [{}] goes out of trouble", self.name),
             _ => (),
         };
+        self.record_health_change(was_healthy);
     }
 }
 
 impl SocksServer {
+    fn record_health_change(&self, was_healthy: bool) {
+        if self.is_healthy() != was_healthy {
+            *self.status.health.changed_at.lock() = Instant::now();
+        }
+    }
+
+    /// Whether the server has ever been proved working by a probe. Servers
+    /// start healthy, so this tells a cold one from a verified one.
+    pub(crate) fn ever_ok(&self) -> bool {
+        self.status.ping_stats.ever_reachable()
+    }
+
+    /// Since when the server has been continuously healthy, `None` if not.
+    pub(crate) fn healthy_since(&self) -> Option<Instant> {
+        let changed_at = *self.status.health.changed_at.lock();
+        self.is_healthy().then_some(changed_at)
+    }
+
+    /// Since when the server has been continuously unhealthy, `None` if not.
+    pub(crate) fn troubled_since(&self) -> Option<Instant> {
+        let changed_at = *self.status.health.changed_at.lock();
+        (!self.is_healthy()).then_some(changed_at)
+    }
+
+    /// Count a reply failed to decode, mark the server trouble if there are
+    /// too many of them recently, as a misbehaving relay is unusable.
+    pub(crate) fn record_malformed_reply(&self) {
+        let health = &self.status.health;
+        if health.malformed_replies.record() && self.is_healthy() {
+            warn!(
+                "Upstream [{}] sent {}+ malformed replies within {:?}",
+                self.name, MALFORMED_REPLIES_THRESHOLD, MALFORMED_REPLIES_WINDOW
+            );
+            self.set_troubleness(true);
+        }
+    }
+
+    pub(crate) fn malformed_replies(&self) -> usize {
+        self.status
+            .health
+            .malformed_replies
+            .total
+            .load(Ordering::Relaxed)
+    }
+
+    /// Update `too_slow` per average RTT, with hysteresis.
+    pub(super) fn check_rtt(&self, max_rtt: Option<Duration>) {
+        let was_healthy = self.is_healthy();
+        let too_slow = &self.status.health.too_slow;
+        let delay = self.status.pings.lock().average_delay();
+        let slow = match (max_rtt, delay) {
+            (Some(max), Some(delay)) if too_slow.load(Ordering::Relaxed) => {
+                delay >= max.mul_f32(MAX_RTT_HYSTERESIS)
+            }
+            (Some(max), Some(delay)) => delay > max,
+            _ => false,
+        };
+        match (too_slow.swap(slow, Ordering::Relaxed), slow) {
+            (false, true) => info!(
+                "Upstream [{}] too slow ({:#.0?} > {:#.0?})",
+                self.name,
+                delay.unwrap_or_default(),
+                max_rtt.unwrap_or_default()
+            ),
+            (true, false) => info!("Upstream [{}] no longer too slow", self.name),
+            _ => (),
+        }
+        self.record_health_change(was_healthy);
+    }
+
+    /// Traffic doesn't mean recovery if it's garbage.
+    pub(super) fn is_flooded_by_malformed(&self) -> bool {
+        self.status.health.malformed_replies.is_flooding()
+    }
+
+    /// IPv4 & IPv6 targets of availability check, with the upstream's own
+    /// DNS servers (if any) in place of the global ones.
+    pub(super) fn check_targets(&self, args: &CliArgs) -> (SocketAddrV4, SocketAddrV6) {
+        let (target4, target6) = args.check_targets();
+        match args.check_method {
+            CheckMethod::Dns => (
+                self.check_dns.v4.unwrap_or(target4),
+                self.check_dns.v6.unwrap_or(target6),
+            ),
+            CheckMethod::Quic => (target4, target6),
+        }
+    }
+
     pub(super) async fn check_troubleness(self: &Arc<Self>, context: &AppContext) -> bool {
         debug!("Checking [{}]", self.name);
-        let dns4 = context.cli_args.check_dns_server_v4.into();
-        let dns6 = context.cli_args.check_dns_server_v6.into();
+        let (target4, target6) = self.check_targets(context.cli_args);
+        let (target4, target6) = (target4.into(), target6.into());
         let result = match self.inner_proto.get() {
             InnerProto::Unspecified => {
                 tokio::select! {
-                    r = self.ping_with_dns_query(dns4, PING_MAX_RETRY) => r,
-                    r = self.ping_with_dns_query(dns6, PING_MAX_RETRY) => r,
+                    r = self.ping(context, target4, PING_MAX_RETRY) => r,
+                    r = self.ping(context, target6, PING_MAX_RETRY) => r,
                 }
             }
             InnerProto::IPv4 | InnerProto::Inet => {
-                self.ping_with_dns_query(dns4, PING_MAX_RETRY).await
+                self.ping(context, target4, PING_MAX_RETRY).await
             }
-            InnerProto::IPv6 => self.ping_with_dns_query(dns6, PING_MAX_RETRY).await,
+            InnerProto::IPv6 => self.ping(context, target6, PING_MAX_RETRY).await,
         };
-        match result {
-            Err(_) | Ok(None) => true,
-            Ok(Some(_)) => false,
-        }
+        self.status.ping_stats.record(result);
+        result.delay().is_none()
     }
 }
+
+#[test]
+fn test_malformed_replies() {
+    let server = SocksServer::new(([127, 0, 0, 1], 1080).into(), "s".into(), InnerProto::Inet);
+    (1..MALFORMED_REPLIES_THRESHOLD).for_each(|_| server.record_malformed_reply());
+    assert!(server.is_healthy());
+    assert!(!server.is_flooded_by_malformed());
+    server.record_malformed_reply();
+    assert!(!server.is_healthy());
+    assert!(server.is_flooded_by_malformed());
+    assert_eq!(server.malformed_replies(), MALFORMED_REPLIES_THRESHOLD);
+}
+
+#[test]
+fn test_max_rtt_hysteresis() {
+    let server = SocksServer::new(([127, 0, 0, 1], 1080).into(), "s".into(), InnerProto::Inet);
+    let max_rtt = Some(Duration::from_millis(100));
+    let set_delay = |ms| {
+        let mut pings = server.status.pings.lock();
+        (0..100).for_each(|_| pings.add_measurement(Some(Duration::from_millis(ms).into())));
+    };
+    set_delay(150);
+    server.check_rtt(max_rtt);
+    assert!(!server.is_healthy());
+    set_delay(90);
+    server.check_rtt(max_rtt);
+    assert!(!server.is_healthy());
+    set_delay(50);
+    server.check_rtt(max_rtt);
+    assert!(server.is_healthy());
+    set_delay(150);
+    server.check_rtt(None);
+    assert!(server.is_healthy());
+}
+
+#[test]
+fn test_check_targets() {
+    use crate::app::socks5::CheckDnsServers;
+    use clap::Parser;
+
+    let args = CliArgs::parse_from(["quproxy", "-p", "0"]);
+    let v4 = "192.0.2.53:53".parse().unwrap();
+    let server = SocksServer::new(([127, 0, 0, 1], 1080).into(), "s".into(), InnerProto::Inet)
+        .with_check_dns(CheckDnsServers {
+            v4: Some(v4),
+            v6: None,
+        });
+    assert_eq!(server.check_targets(&args), (v4, args.check_dns_server_v6));
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "--check-method", "quic"]);
+    assert_eq!(server.check_targets(&args), args.check_targets());
+}
+
+#[test]
+fn test_health_since() {
+    let server = SocksServer::new(([127, 0, 0, 1], 1080).into(), "s".into(), InnerProto::Inet);
+    let created = server.healthy_since().unwrap();
+    assert!(server.troubled_since().is_none());
+    server.set_troubleness(true);
+    let troubled = server.troubled_since().unwrap();
+    assert!(troubled >= created);
+    assert!(server.healthy_since().is_none());
+    // Not a transition
+    server.set_troubleness(true);
+    assert_eq!(server.troubled_since(), Some(troubled));
+    server.set_troubleness(false);
+    assert!(server.healthy_since().unwrap() >= troubled);
+}
+
+#[test]
+fn test_ever_ok() {
+    use super::PingResult;
+
+    let server = SocksServer::new(([127, 0, 0, 1], 1080).into(), "s".into(), InnerProto::Inet);
+    assert!(server.is_healthy() && !server.ever_ok());
+    server.status.ping_stats.record(PingResult::NoReply);
+    assert!(!server.ever_ok());
+    server
+        .status
+        .ping_stats
+        .record(PingResult::Reachable(Duration::from_millis(10)));
+    server.set_troubleness(true);
+    assert!(server.ever_ok());
+}
@@ -1,4 +1,7 @@
-use std::{collections::VecDeque, time::Instant};
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
 
 use crate::app::socks5::{SocksServer, Traffic};
 
@@ -13,14 +16,14 @@ pub(crate) struct Meter {
 
 #[derive(Debug, Clone, Copy)]
 struct Sample {
-    _time: Instant,
+    time: Instant,
     traffic: Traffic,
 }
 
 impl From<Traffic> for Sample {
     fn from(traffic: Traffic) -> Self {
         Self {
-            _time: Instant::now(),
+            time: Instant::now(),
             traffic,
         }
     }
@@ -42,12 +45,50 @@ impl Meter {
         self.samples.push_back(traffic.into());
     }
 
+    /// Return bytes per second of `bytes` between the last two samples.
+    fn rate(&self, bytes: fn(&Traffic) -> u64) -> Option<u64> {
+        let mut iter = self.samples.iter().rev();
+        let (b, a) = (iter.next()?, iter.next()?);
+        let secs = b.time.duration_since(a.time).as_secs_f64();
+        if secs > 0.0 {
+            Some(((bytes(&b.traffic) - bytes(&a.traffic)) as f64 / secs) as u64)
+        } else {
+            None
+        }
+    }
+
+    /// Return RX bytes per second between the last two samples.
+    pub(crate) fn rx_rate(&self) -> Option<u64> {
+        self.rate(|t| t.rx_bytes)
+    }
+
+    /// Return TX bytes per second between the last two samples.
+    pub(crate) fn tx_rate(&self) -> Option<u64> {
+        self.rate(|t| t.tx_bytes)
+    }
+
+    /// Return true if any RX traffic within the sampling window.
+    pub(super) fn has_rx(&self) -> bool {
+        match (self.samples.front(), self.samples.back()) {
+            (Some(a), Some(b)) => b.traffic.rx_bytes > a.traffic.rx_bytes,
+            _ => false,
+        }
+    }
+
     /// Return true if there is TX traffic but no RX traffic, excpet all TX
-    /// occur only in the latter half samples.
-    pub(super) fn tx_only(&self) -> bool {
+    /// occur only in the latter half samples or within `grace` before the
+    /// last sample.
+    pub(super) fn tx_only(&self, grace: Duration) -> bool {
         let x = self.samples.front().copied();
-        let a = self.samples.get(MAX_SAMPLES / 2).copied();
         let b = self.samples.get(MAX_SAMPLES - 1).copied();
+        let a = b.and_then(|b| {
+            self.samples
+                .iter()
+                .take(MAX_SAMPLES / 2 + 1)
+                .rev()
+                .find(|a| b.time.duration_since(a.time) >= grace)
+                .copied()
+        });
         if let (Some(x), Some(a), Some(b)) = (x, a, b) {
             let head = a.traffic - x.traffic;
             let total = b.traffic - x.traffic;
@@ -67,7 +108,7 @@ impl Sampling for SocksServer {
         let mut meter = self.status.meter.lock();
         let sample = self.status.usage.traffic.get();
         meter.add_sample(sample);
-        if sample.rx_bytes > 0 {
+        if sample.rx_bytes > 0 && !self.is_flooded_by_malformed() {
             // Fast recovery from trouble
             self.set_troubleness(false);
         }
@@ -2,10 +2,12 @@ mod health;
 mod meter;
 mod ping;
 mod service;
+mod state;
 
 pub(crate) use health::{Health, Healthy};
 pub(crate) use meter::Meter;
-pub(crate) use ping::PingHistory;
-pub(crate) use service::CheckingService;
+pub(crate) use ping::{PingExemplar, PingHistory, PingResult, PingStats, ScoreParams};
+pub(crate) use service::{sort_servers, CheckingService};
+pub(crate) use state::{restore_ping_histories, save_ping_histories};
 
 const PING_MAX_RETRY: usize = 8;
@@ -5,23 +5,36 @@ use std::{
     io,
     net::{SocketAddr, SocketAddrV4, SocketAddrV6},
     num::NonZeroU8,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
 use async_trait::async_trait;
-use bytes::{BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::StreamExt;
 use hex_literal::hex;
+use serde::{Deserialize, Serialize};
 use tokio::time::{interval_at, timeout};
-use tracing::{debug, instrument, trace, warn};
+use tracing::{debug, field, instrument, trace, warn, Span};
 
-use crate::app::{net::MsgArrayWriteBuffer, socks5::SocksServer, InnerProto};
+use crate::{
+    app::{
+        net::MsgArrayWriteBuffer,
+        quic::MIN_INITIAL_PACKET_SIZE_BYTES,
+        socks5::{InnerProtoProbe, SocksServer},
+        AppContext, InnerProto,
+    },
+    cli::{CheckMethod, CliArgs, DelayEstimator, DnsPadding},
+};
 
 const DELAY_POWER: f32 = 0.75;
 const DELAY_MAX_HISTORY: usize = 100;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
 pub(crate) struct Delay(NonZeroU8);
 
 impl From<Duration> for Delay {
@@ -43,15 +56,71 @@ impl Delay {
     }
 }
 
-#[derive(Debug, Clone)]
+/// How to turn ping history into a score, see `PingHistory::score_with()`.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ScoreParams {
+    pub(crate) estimator: DelayEstimator,
+    pub(crate) ewma_alpha: f32,
+    pub(crate) jitter_weight: f32,
+}
+
+impl Default for ScoreParams {
+    fn default() -> Self {
+        Self {
+            estimator: DelayEstimator::Mean,
+            ewma_alpha: 0.2,
+            jitter_weight: 0.0,
+        }
+    }
+}
+
+impl ScoreParams {
+    pub(crate) fn from_args(args: &CliArgs) -> Self {
+        Self {
+            estimator: args.delay_estimator,
+            ewma_alpha: args.ewma_alpha,
+            jitter_weight: args.jitter_weight,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(from = "VecDeque<Option<Delay>>", into = "VecDeque<Option<Delay>>")]
 pub(crate) struct PingHistory {
     pings: VecDeque<Option<Delay>>,
+    /// Alpha of the running `ewma`, see `set_ewma_alpha()`
+    ewma_alpha: f32,
+    /// Running EWMA of delays in ms, kept by `add_measurement()`
+    ewma: Option<f32>,
+}
+
+impl From<VecDeque<Option<Delay>>> for PingHistory {
+    fn from(mut pings: VecDeque<Option<Delay>>) -> Self {
+        if pings.len() > DELAY_MAX_HISTORY {
+            pings.drain(..pings.len() - DELAY_MAX_HISTORY);
+        }
+        let ewma_alpha = ScoreParams::default().ewma_alpha;
+        let ewma = ewma_millis(&pings, ewma_alpha);
+        Self {
+            pings,
+            ewma_alpha,
+            ewma,
+        }
+    }
+}
+
+impl From<PingHistory> for VecDeque<Option<Delay>> {
+    fn from(history: PingHistory) -> Self {
+        history.pings
+    }
 }
 
 impl Default for PingHistory {
     fn default() -> Self {
         Self {
             pings: VecDeque::with_capacity(DELAY_MAX_HISTORY),
+            ewma_alpha: ScoreParams::default().ewma_alpha,
+            ewma: None,
         }
     }
 }
@@ -78,6 +147,22 @@ impl PingHistory {
             self.pings.pop_front();
         }
         self.pings.push_back(delay);
+        if let Some(delay) = delay {
+            let x = delay.as_millis() as f32;
+            let alpha = self.ewma_alpha;
+            self.ewma = Some(match self.ewma {
+                Some(avg) => alpha * x + (1.0 - alpha) * avg,
+                None => x,
+            });
+        }
+    }
+
+    /// Keep the running EWMA with `alpha`, recomputed from history if changed.
+    pub(super) fn set_ewma_alpha(&mut self, alpha: f32) {
+        if alpha != self.ewma_alpha {
+            self.ewma_alpha = alpha;
+            self.ewma = ewma_millis(&self.pings, alpha);
+        }
     }
 
     pub(crate) fn loss_percent(&self) -> u8 {
@@ -101,6 +186,20 @@ impl PingHistory {
         }
     }
 
+    /// Exponentially weighted moving average of delays, `alpha` is the
+    /// weight of the latest reply. Lost pings are skipped.
+    ///
+    /// O(1) with the alpha set by `set_ewma_alpha()`, otherwise computed
+    /// over the whole history.
+    pub(crate) fn ewma_delay(&self, alpha: f32) -> Option<Duration> {
+        let millis = if alpha == self.ewma_alpha {
+            self.ewma?
+        } else {
+            ewma_millis(&self.pings, alpha)?
+        };
+        Some(Duration::from_secs_f32(millis / 1000.0))
+    }
+
     /// Assume exponential distribution for round-trip time (RTT):
     /// RTT = base + D, where D ~ Exp(λ), base is a constant over observation.
     ///
@@ -108,13 +207,7 @@ impl PingHistory {
     /// distribution fitted by observed pings.  
     pub(crate) fn quantile_delay(&self, quantile: f32) -> Option<Duration> {
         assert!(quantile > 0f32 && quantile < 1f32);
-        let pings: Vec<_> = self
-            .pings
-            .iter()
-            .copied()
-            .flatten()
-            .map(|t| t.as_millis() as f32)
-            .collect();
+        let pings = self.delays_millis();
         if pings.len() < 3 {
             return None;
         }
@@ -130,9 +223,43 @@ impl PingHistory {
         Some(Duration::from_secs_f32((base + millis) / 1000.0))
     }
 
+    /// Standard deviation of RTT, `None` if less than 3 replies.
+    pub(crate) fn jitter(&self) -> Option<Duration> {
+        let pings = self.delays_millis();
+        if pings.len() < 3 {
+            return None;
+        }
+        let var = variance(&pings, mean(&pings));
+        Some(Duration::from_secs_f32(var.sqrt() / 1000.0))
+    }
+
+    fn delays_millis(&self) -> Vec<f32> {
+        self.pings
+            .iter()
+            .copied()
+            .flatten()
+            .map(|t| t.as_millis() as f32)
+            .collect()
+    }
+
     pub(super) fn score(&self) -> i16 {
-        if let Some(delay) = self.average_delay() {
-            let delay_ms = delay.as_millis().clamp(10, 2000) as f32;
+        self.score_with(&Default::default())
+    }
+
+    /// Score with delay from `params.estimator`, and jitter (in ms) times
+    /// `params.jitter_weight` added to the delay.
+    pub(crate) fn score_with(&self, params: &ScoreParams) -> i16 {
+        let delay = match params.estimator {
+            DelayEstimator::Mean => self.average_delay(),
+            DelayEstimator::Ewma => self.ewma_delay(params.ewma_alpha),
+        };
+        if let Some(delay) = delay {
+            let jitter_ms = match self.jitter() {
+                Some(jitter) if params.jitter_weight != 0.0 => jitter.as_secs_f32() * 1000.0,
+                _ => 0.0,
+            };
+            let delay_ms =
+                delay.as_millis().clamp(10, 2000) as f32 + jitter_ms * params.jitter_weight;
             let loss_rate = self.loss_percent().clamp(0, 99) as f32 / 100.0;
             let score = (delay_ms + loss_rate * 1000.0) / (1.0 - loss_rate).powf(2.0);
             score.clamp(i16::MIN as f32, i16::MAX as f32).round() as i16
@@ -155,15 +282,174 @@ fn variance(xs: &[f32], xbar: f32) -> f32 {
     sum / (xs.len() as f32 - 1.0)
 }
 
+/// EWMA of delays (in ms) in `pings`, lost ones skipped.
+fn ewma_millis(pings: &VecDeque<Option<Delay>>, alpha: f32) -> Option<f32> {
+    pings
+        .iter()
+        .copied()
+        .flatten()
+        .map(|t| t.as_millis() as f32)
+        .reduce(|avg, x| alpha * x + (1.0 - alpha) * avg)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PingResult {
+    Reachable(Duration),
+    NoReply,
+    BindError(io::ErrorKind),
+    SendError(io::ErrorKind),
+    RecvError(io::ErrorKind),
+}
+
+impl Display for PingResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PingResult::Reachable(delay) => write!(f, "reachable ({:#.1?})", delay),
+            PingResult::NoReply => write!(f, "no reply"),
+            PingResult::BindError(kind) => write!(f, "bind error ({})", kind),
+            PingResult::SendError(kind) => write!(f, "send error ({})", kind),
+            PingResult::RecvError(kind) => write!(f, "recv error ({})", kind),
+        }
+    }
+}
+
+impl PingResult {
+    pub(crate) fn delay(&self) -> Option<Duration> {
+        match self {
+            PingResult::Reachable(delay) => Some(*delay),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn is_error(&self) -> bool {
+        !matches!(self, PingResult::Reachable(_) | PingResult::NoReply)
+    }
+}
+
+/// The latest successful probe, exported as an OpenMetrics exemplar.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PingExemplar {
+    /// Random ID recorded as `probe_id` on the span of the probe, so its
+    /// logs can be found
+    pub(crate) probe_id: u64,
+    pub(crate) delay: Duration,
+    pub(crate) at: SystemTime,
+}
+
+/// Per-server counters of ping results.
+#[derive(Debug, Default)]
+pub(crate) struct PingStats {
+    reachable: AtomicUsize,
+    no_reply: AtomicUsize,
+    bind_error: AtomicUsize,
+    send_error: AtomicUsize,
+    recv_error: AtomicUsize,
+    /// No-reply results since the last reachable one
+    consecutive_no_reply: AtomicUsize,
+}
+
+impl PingStats {
+    pub(crate) fn record(&self, result: PingResult) {
+        match result {
+            PingResult::Reachable(_) => self.consecutive_no_reply.store(0, Ordering::Relaxed),
+            PingResult::NoReply => {
+                self.consecutive_no_reply.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => (),
+        }
+        let counter = match result {
+            PingResult::Reachable(_) => &self.reachable,
+            PingResult::NoReply => &self.no_reply,
+            PingResult::BindError(_) => &self.bind_error,
+            PingResult::SendError(_) => &self.send_error,
+            PingResult::RecvError(_) => &self.recv_error,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn carry_over_from(&self, old: &PingStats) {
+        for (counter, old_counter) in [
+            (&self.reachable, &old.reachable),
+            (&self.no_reply, &old.no_reply),
+            (&self.bind_error, &old.bind_error),
+            (&self.send_error, &old.send_error),
+            (&self.recv_error, &old.recv_error),
+            (&self.consecutive_no_reply, &old.consecutive_no_reply),
+        ] {
+            counter.store(old_counter.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+    }
+
+    /// Whether any probe has succeeded, to tell never-probed (or
+    /// never-reachable) servers from those went down later.
+    pub(crate) fn ever_reachable(&self) -> bool {
+        self.reachable.load(Ordering::Relaxed) > 0
+    }
+
+    pub(crate) fn consecutive_no_reply(&self) -> usize {
+        self.consecutive_no_reply.load(Ordering::Relaxed)
+    }
+
+    /// Counts of each kind of result, labeled in snake case.
+    pub(crate) fn counts(&self) -> [(&'static str, usize); 5] {
+        [
+            ("reachable", &self.reachable),
+            ("no_reply", &self.no_reply),
+            ("bind_error", &self.bind_error),
+            ("send_error", &self.send_error),
+            ("recv_error", &self.recv_error),
+        ]
+        .map(|(name, n)| (name, n.load(Ordering::Relaxed)))
+    }
+}
+
+impl Display for PingStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "ok {}, no-reply {}, bind-err {}, send-err {}, recv-err {}",
+            self.reachable.load(Ordering::Relaxed),
+            self.no_reply.load(Ordering::Relaxed),
+            self.bind_error.load(Ordering::Relaxed),
+            self.send_error.load(Ordering::Relaxed),
+            self.recv_error.load(Ordering::Relaxed),
+        )
+    }
+}
+
 #[async_trait]
 pub(super) trait Pingable {
+    /// Ping with the configured check method (`--check-method`).
+    async fn ping(&self, context: &AppContext, target: SocketAddr, count: usize) -> PingResult;
+
     async fn ping_with_dns_query(
         &self,
+        context: &AppContext,
         dns_addr: SocketAddr,
         count: usize,
-    ) -> io::Result<Option<Duration>>;
+    ) -> PingResult;
+
+    async fn ping_with_quic_initial(
+        &self,
+        context: &AppContext,
+        quic_addr: SocketAddr,
+        count: usize,
+    ) -> PingResult;
 
-    async fn probe_inner_proto(&self, dns4: SocketAddrV4, dns6: SocketAddrV6) -> InnerProto;
+    async fn probe_inner_proto(
+        &self,
+        context: &AppContext,
+        target4: SocketAddrV4,
+        target6: SocketAddrV6,
+    ) -> InnerProtoProbe;
+}
+
+/// A kind of request-reply packet sent to the remote via SOCKS server.
+trait Probe: Send + Sync {
+    fn new_id(&self) -> u64;
+    fn build(&self, id: u64) -> Bytes;
+    /// Return ID of the probe that `pkt` replies to.
+    fn parse_reply(&self, pkt: &[u8]) -> Option<u64>;
 }
 
 const DNS_QUERY: &[u8] = &hex!(
@@ -183,59 +469,199 @@ const DNS_QUERY: &[u8] = &hex!(
 
 const DNS_QUERY_SIZE: usize = 500;
 
-#[async_trait]
-impl Pingable for Arc<SocksServer> {
-    #[instrument(skip_all, fields(server=self.name, dns=?dns_addr))]
-    async fn ping_with_dns_query(
-        &self,
-        dns_addr: SocketAddr,
+/// EDNS option codes for padding
+const EDNS_OPTION_PADDING: u16 = 12;
+const EDNS_OPTION_LOCAL: u16 = 65001;
+
+struct DnsProbe {
+    padding: DnsPadding,
+}
+
+impl Probe for DnsProbe {
+    fn new_id(&self) -> u64 {
+        rand::random::<u16>().into()
+    }
+
+    fn build(&self, tid: u64) -> Bytes {
+        // Construct DNS query
+        let mut query = BytesMut::with_capacity(DNS_QUERY_SIZE);
+        query.put_u16(tid as u16);
+        query.put_slice(DNS_QUERY);
+        let code = match self.padding {
+            DnsPadding::Experimental => EDNS_OPTION_LOCAL,
+            DnsPadding::Standard => EDNS_OPTION_PADDING,
+            DnsPadding::None => {
+                query.put_u16(0); // RDATA length
+                return query.freeze();
+            }
+        };
+        // Fill query to match DNS_QUERY_SIZE size
+        let rdata_len: u16 = (DNS_QUERY_SIZE - query.len() - 2).try_into().unwrap();
+        query.put_u16(rdata_len); // RDATA length
+        query.put_u16(code); // Option code
+        query.put_u16(rdata_len - 4); // Option length
+
+        // RFC 7830 asks for zeros
+        let fill = match self.padding {
+            DnsPadding::Standard => 0,
+            _ => rand::random(),
+        };
+        query.put_bytes(fill, (rdata_len - 4) as usize);
+        assert!(query.len() == DNS_QUERY_SIZE);
+        query.freeze()
+    }
+
+    fn parse_reply(&self, pkt: &[u8]) -> Option<u64> {
+        if pkt.len() < 12 {
+            debug!("DNS reply too short ({} bytes)", pkt.len());
+            return None;
+        }
+        if pkt.len() < 400 {
+            warn!("Suspicious DNS reply: {} < 400 bytes", pkt.len())
+        }
+        let tid = (pkt[0] as u16) << 8 | (pkt[1] as u16);
+        Some(tid.into())
+    }
+}
+
+/// Reserved version for forcing version negotiation (RFC 9000, 15)
+const QUIC_VERSION_NEGOTIATION_PROBE: u32 = 0x1a2a_3a4a;
+
+/// Send a long header packet with unsupported version, server should reply
+/// with Version Negotiation which echo our connection IDs back.
+struct QuicProbe;
+
+impl Probe for QuicProbe {
+    fn new_id(&self) -> u64 {
+        rand::random()
+    }
+
+    fn build(&self, scid: u64) -> Bytes {
+        let mut pkt = BytesMut::with_capacity(MIN_INITIAL_PACKET_SIZE_BYTES);
+        pkt.put_u8(0xc0 | (rand::random::<u8>() & 0x3f)); // Long header
+        pkt.put_u32(QUIC_VERSION_NEGOTIATION_PROBE);
+        pkt.put_u8(8); // DCID
+        pkt.put_u64(rand::random());
+        pkt.put_u8(8); // SCID
+        pkt.put_u64(scid);
+        // Servers ignore initial packets smaller than 1200 bytes
+        pkt.put_bytes(0, MIN_INITIAL_PACKET_SIZE_BYTES - pkt.len());
+        pkt.freeze()
+    }
+
+    fn parse_reply(&self, mut pkt: &[u8]) -> Option<u64> {
+        if pkt.len() < 1 + 4 + 1 + 8 || pkt[0] & 0x80 == 0 {
+            debug!("Not a QUIC long header packet ({} bytes)", pkt.len());
+            return None;
+        }
+        let version = u32::from_be_bytes(pkt[1..5].try_into().unwrap());
+        if version != 0 {
+            trace!("Non-version-negotiation reply (version {:#x})", version);
+        }
+        // Any reply echoing our SCID counts
+        pkt.advance(5);
+        if pkt.get_u8() != 8 {
+            debug!("Unexpected DCID length on QUIC reply");
+            return None;
+        }
+        Some(pkt.get_u64())
+    }
+}
+
+/// Interval between probes & time to wait after the last one, from ping
+/// history and `--first-reply-grace`. Both `wait_last` and the time taken
+/// to send all probes are clamped to `max_wait` if given.
+fn probe_waits(
+    pings: &PingHistory,
+    count: usize,
+    first_reply_grace: Option<Duration>,
+    max_wait: Option<Duration>,
+) -> (Duration, Duration) {
+    let (mut wait_send, mut wait_last) =
+        match (pings.quantile_delay(0.8), pings.quantile_delay(0.95)) {
+            (Some(a), Some(b)) => (a, cmp::max(b, Duration::from_millis(500))),
+            _ => (Duration::from_millis(200), Duration::from_millis(2000)),
+        };
+    // Each probe use a fresh session, so the reply is always a first one
+    if let Some(grace) = first_reply_grace {
+        wait_last = cmp::max(wait_last, grace);
+    }
+    if let Some(max_wait) = max_wait {
+        wait_last = cmp::min(wait_last, max_wait);
+        if count > 1 {
+            wait_send = cmp::min(wait_send, max_wait / (count as u32 - 1));
+        }
+    }
+    (wait_send, wait_last)
+}
+
+impl SocksServer {
+    async fn ping_failed(
+        self: &Arc<Self>,
+        context: &AppContext,
+        target: SocketAddr,
+        count: usize,
+    ) -> bool {
+        self.ping(context, target, count).await.is_error()
+    }
+
+    #[instrument(
+        skip_all,
+        fields(server=self.name, group=self.group, target=?target, probe_id=field::Empty)
+    )]
+    async fn ping_with<P: Probe>(
+        self: &Arc<Self>,
+        context: &AppContext,
+        probe: P,
+        target: SocketAddr,
         count: usize,
-    ) -> io::Result<Option<Duration>> {
-        // Generate unique transcation IDs
-        let tids: Vec<_> = {
-            let mut set: HashSet<u16> = HashSet::with_capacity(count);
+    ) -> PingResult {
+        // Tags logs of this probe, see `PingExemplar`
+        let probe_id: u64 = rand::random();
+        Span::current().record("probe_id", format!("{:016x}", probe_id).as_str());
+        // Generate unique probe IDs
+        let ids: Vec<_> = {
+            let mut set: HashSet<u64> = HashSet::with_capacity(count);
             while set.len() < count {
-                set.insert(rand::random());
+                set.insert(probe.new_id());
             }
             set.into_iter().collect()
         };
 
-        let (wait_send, wait_last) = {
-            let pings = self.status.pings.lock();
-            match (pings.quantile_delay(0.8), pings.quantile_delay(0.95)) {
-                (Some(a), Some(b)) => (a, cmp::max(b, Duration::from_millis(500))),
-                _ => (Duration::from_millis(200), Duration::from_millis(2000)),
-            }
-        };
+        let (wait_send, wait_last) = probe_waits(
+            &self.status.pings.lock(),
+            count,
+            context.cli_args.first_reply_grace,
+            context.cli_args.max_probe_wait,
+        );
         trace!("wait_send {:#.1?}, wait_last {:#.1?}", wait_send, wait_last);
 
-        let session: Arc<_> = self.bind(dns_addr.into()).await?.into();
+        let args = context.cli_args;
+        let session = self.bind_probe(
+            target.into(),
+            args.socks_udp_connected,
+            args.probe_fwmark,
+            args.probe_dscp,
+        );
+        let session: Arc<_> = match session.await {
+            Ok(session) => session.into(),
+            Err(err) => return PingResult::BindError(err.kind()),
+        };
 
-        // Send queries
-        let tid_send = tids.clone();
+        // Send probes
+        let id_send = ids.clone();
         let session_clone = session.clone();
+        let probe = &probe;
         let mut send_inverval = interval_at(Instant::now().into(), wait_send);
         let task_send = async move {
             let mut buf = MsgArrayWriteBuffer::with_capacity(1);
-            for tid in tid_send {
+            for id in id_send {
                 send_inverval.tick().await;
-                // Construct DNS query
-                let mut query = BytesMut::with_capacity(DNS_QUERY_SIZE);
-                query.put_u16(tid);
-                query.put_slice(DNS_QUERY);
-                // Fill query to match DNS_QUERY_SIZE size
-                let rdata_len: u16 = (DNS_QUERY_SIZE - query.len() - 2).try_into().unwrap();
-                query.put_u16(rdata_len); // RDATA length
-                query.put_u16(65001); // Option code: local/experimental use
-                query.put_u16(rdata_len - 4); // Option length
-                query.put_bytes(rand::random(), (rdata_len - 4) as usize);
-                assert!(query.len() == DNS_QUERY_SIZE);
-                trace!("Send DNS query: {:?}", query);
-                session_clone
-                    .send_to_remote(&[query.freeze()], &mut buf)
-                    .await?;
+                let pkt = probe.build(id);
+                trace!("Send probe: {:?}", pkt);
+                session_clone.send_to_remote(&[pkt], &mut buf).await?;
             }
-            Ok(())
+            Ok::<_, io::Error>(())
         };
 
         // Receive replies
@@ -249,20 +675,17 @@ impl Pingable for Arc<SocksServer> {
                         Err(err) => break Err(err),
                     };
                     for pkt in pkts.iter() {
-                        if pkt.len() < 12 {
-                            debug!("DNS reply too short ({} bytes)", pkt.len());
-                            continue;
-                        }
-                        if pkt.len() < 400 {
-                            warn!("Suspicious DNS reply: {} < 400 bytes", pkt.len())
-                        }
-                        trace!("Recevied DNS reply: {:?}", &pkt);
-                        let tid = (pkt[0] as u16) << 8 | (pkt[1] as u16);
-                        if let Some(n) = tids.iter().position(|t| t == &tid) {
-                            let delay = t0.elapsed() - wait_send * (n as u32);
+                        trace!("Recevied reply: {:?}", &pkt);
+                        let id = match probe.parse_reply(pkt) {
+                            Some(id) => id,
+                            None => continue,
+                        };
+                        if let Some(n) = ids.iter().position(|t| t == &id) {
+                            // Sending may start slightly before `t0`
+                            let delay = t0.elapsed().saturating_sub(wait_send * (n as u32));
                             return Ok((n, delay));
                         } else {
-                            debug!("Unknown transcation ID ({})", tid);
+                            debug!("Unknown probe ID ({})", id);
                             continue;
                         }
                     }
@@ -272,9 +695,9 @@ impl Pingable for Arc<SocksServer> {
         };
 
         let (loss, delay) = tokio::select! {
-            Err(err) = task_send => return Err(err),
+            Err(err) = task_send => return PingResult::SendError(err.kind()),
             result = task_recv => match result {
-                Ok(Err(err)) => return Err(err),
+                Ok(Err(err)) => return PingResult::RecvError(err.kind()),
                 Ok(Ok((loss, delay))) => {
                     trace!("[{}] Ping: {:#.1?}, lost {}", self.name, delay, loss);
                     (loss, Some(delay))
@@ -285,14 +708,62 @@ impl Pingable for Arc<SocksServer> {
                 }
             },
         };
+        if let Some(delay) = delay {
+            debug!("[{}] Probe replied in {:#.1?}", self.name, delay);
+            *self.status.last_ping_exemplar.lock() = Some(PingExemplar {
+                probe_id,
+                delay,
+                at: SystemTime::now(),
+            });
+        }
         let mut pings = self.status.pings.lock();
+        pings.set_ewma_alpha(context.cli_args.ewma_alpha);
         (0..loss).for_each(|_| pings.add_measurement(None));
         pings.add_measurement(delay.map(Delay::from));
-        Ok(delay)
+        match delay {
+            Some(delay) => PingResult::Reachable(delay),
+            None => PingResult::NoReply,
+        }
+    }
+}
+
+#[async_trait]
+impl Pingable for Arc<SocksServer> {
+    async fn ping(&self, context: &AppContext, target: SocketAddr, count: usize) -> PingResult {
+        match context.cli_args.check_method {
+            CheckMethod::Dns => self.ping_with_dns_query(context, target, count).await,
+            CheckMethod::Quic => self.ping_with_quic_initial(context, target, count).await,
+        }
     }
 
-    #[instrument(skip_all, fields(server=self.name))]
-    async fn probe_inner_proto(&self, dns4: SocketAddrV4, dns6: SocketAddrV6) -> InnerProto {
+    async fn ping_with_dns_query(
+        &self,
+        context: &AppContext,
+        dns_addr: SocketAddr,
+        count: usize,
+    ) -> PingResult {
+        let probe = DnsProbe {
+            padding: context.cli_args.dns_padding,
+        };
+        self.ping_with(context, probe, dns_addr, count).await
+    }
+
+    async fn ping_with_quic_initial(
+        &self,
+        context: &AppContext,
+        quic_addr: SocketAddr,
+        count: usize,
+    ) -> PingResult {
+        self.ping_with(context, QuicProbe, quic_addr, count).await
+    }
+
+    #[instrument(skip_all, fields(server=self.name, group=self.group))]
+    async fn probe_inner_proto(
+        &self,
+        context: &AppContext,
+        target4: SocketAddrV4,
+        target6: SocketAddrV6,
+    ) -> InnerProtoProbe {
         // False rate = p^N * (1-p)^N, where p = (packet loss rate)^R
         // Fail rate = TODO
         const N: usize = 3; // Max false rate (when p = 0.5) is 0.5^(3 * 2) = 1.6%
@@ -303,8 +774,8 @@ impl Pingable for Arc<SocksServer> {
         for _ in 0..N {
             test_cnt += 1;
             tokio::select! {
-                Ok(_) = self.ping_with_dns_query(dns4.into(), R) => v4_ok_cnt += 1,
-                Ok(_) = self.ping_with_dns_query(dns6.into(), R) => v6_ok_cnt += 1,
+                false = self.ping_failed(context, target4.into(), R) => v4_ok_cnt += 1,
+                false = self.ping_failed(context, target6.into(), R) => v6_ok_cnt += 1,
                 else => (),
             }
             if v4_ok_cnt > 0 && v6_ok_cnt > 0 {
@@ -315,11 +786,125 @@ impl Pingable for Arc<SocksServer> {
             "v4 {}/{}, v6 {}/{}",
             v4_ok_cnt, test_cnt, v6_ok_cnt, test_cnt
         );
-        match (v4_ok_cnt, v6_ok_cnt) {
+        let proto = match (v4_ok_cnt, v6_ok_cnt) {
             (N, 0) => InnerProto::IPv4,
             (0, N) => InnerProto::IPv6,
             (0, 0) => InnerProto::Unspecified,
             (_, _) => InnerProto::Inet,
+        };
+        InnerProtoProbe {
+            proto,
+            v4_ok: v4_ok_cnt,
+            v6_ok: v6_ok_cnt,
+            tests: test_cnt,
+            probed_at: Instant::now(),
         }
     }
 }
+
+#[test]
+fn test_quic_probe_version_negotiation() {
+    let probe = QuicProbe;
+    let pkt = probe.build(0x0102030405060708);
+    assert_eq!(pkt.len(), MIN_INITIAL_PACKET_SIZE_BYTES);
+    // Version negotiation with DCID/SCID swapped
+    let mut reply = BytesMut::new();
+    reply.put_u8(0x80);
+    reply.put_u32(0);
+    reply.put_slice(&pkt[14..23]); // SCID of probe
+    reply.put_slice(&pkt[5..14]); // DCID of probe
+    reply.put_u32(1);
+    assert_eq!(probe.parse_reply(&reply), Some(0x0102030405060708));
+    assert_eq!(probe.parse_reply(&reply[..10]), None);
+}
+
+#[test]
+fn test_dns_probe_padding() {
+    let build = |padding| DnsProbe { padding }.build(0x1234);
+    let query = build(DnsPadding::Standard);
+    assert_eq!(query.len(), DNS_QUERY_SIZE);
+    // OPT RDATA: length, option code & length, then zeros
+    let opt = &query[2 + DNS_QUERY.len()..];
+    assert_eq!(&opt[..6], [0x01, 0xcd, 0x00, 0x0c, 0x01, 0xc9]);
+    assert!(opt[6..].iter().all(|b| *b == 0));
+    let query = build(DnsPadding::Experimental);
+    assert_eq!(query.len(), DNS_QUERY_SIZE);
+    assert_eq!(&query[2 + DNS_QUERY.len() + 2..][..2], [0xfd, 0xe9]);
+    let query = build(DnsPadding::None);
+    assert_eq!(query.len(), 2 + DNS_QUERY.len() + 2);
+    assert_eq!(&query[..2], [0x12, 0x34]);
+}
+
+#[test]
+fn test_ping_history_jitter() {
+    let mut stable = PingHistory::default();
+    let mut jittery = PingHistory::default();
+    for i in 0..10 {
+        stable.add_measurement(Some(Duration::from_millis(100).into()));
+        let t = if i % 2 == 0 { 50 } else { 150 };
+        jittery.add_measurement(Some(Duration::from_millis(t).into()));
+    }
+    assert!(stable.jitter().unwrap() < Duration::from_millis(1));
+    assert!(jittery.jitter().unwrap() > Duration::from_millis(30));
+    // Jitter matters only if weighted
+    let weighted = ScoreParams {
+        jitter_weight: 1.0,
+        ..Default::default()
+    };
+    assert_eq!(stable.score(), stable.score_with(&weighted));
+    assert!(jittery.score_with(&weighted) > jittery.score());
+}
+
+#[test]
+fn test_ping_history_ewma() {
+    let mut history = PingHistory::default();
+    assert_eq!(None, history.ewma_delay(0.5));
+    for _ in 0..10 {
+        history.add_measurement(Some(Duration::from_millis(100).into()));
+    }
+    history.add_measurement(None);
+    history.add_measurement(Some(Duration::from_millis(300).into()));
+    let ewma = history.ewma_delay(0.5).unwrap();
+    assert!(ewma.as_millis().abs_diff(200) < 2);
+    // Reacts faster than mean on latency shifts
+    assert!(ewma > history.average_delay().unwrap());
+    let ewma = ScoreParams {
+        estimator: DelayEstimator::Ewma,
+        ewma_alpha: 0.5,
+        ..Default::default()
+    };
+    assert!(history.score_with(&ewma) > history.score());
+
+    // Running one agrees with the one over history
+    history.set_ewma_alpha(0.5);
+    history.add_measurement(Some(Duration::from_millis(100).into()));
+    assert_eq!(history.ewma, ewma_millis(&history.pings, 0.5));
+    assert!(history.ewma_delay(0.5).unwrap().as_millis().abs_diff(150) < 2);
+
+    use clap::Parser;
+    for alpha in ["0", "1.5", "NaN"] {
+        let args = ["quproxy", "-p", "0", "--ewma-alpha", alpha];
+        assert!(CliArgs::try_parse_from(args).is_err());
+    }
+    assert!(CliArgs::try_parse_from(["quproxy", "-p", "0", "--ewma-alpha", "1"]).is_ok());
+}
+
+#[test]
+fn test_probe_waits_clamped() {
+    let mut history = PingHistory::default();
+    for i in 0..10 {
+        let t = if i % 2 == 0 { 300 } else { 1500 };
+        history.add_measurement(Some(Duration::from_millis(t).into()));
+    }
+    let ms = Duration::from_millis;
+    let (wait_send, wait_last) = probe_waits(&history, 3, None, None);
+    assert!(wait_send > ms(200) && wait_last > ms(400));
+    assert_eq!(
+        probe_waits(&history, 3, Some(ms(5000)), Some(ms(400))),
+        (ms(200), ms(400))
+    );
+    // Nothing to clamp
+    let empty = PingHistory::default();
+    let waits = probe_waits(&empty, 1, None, Some(ms(4000)));
+    assert_eq!(waits, (ms(200), ms(2000)));
+}
@@ -2,12 +2,15 @@ use derivative::Derivative;
 use futures::stream::{FuturesUnordered, StreamExt};
 use std::{fmt::Debug, future, sync::Arc, time::Duration};
 use tokio::time::{interval_at, Instant, MissedTickBehavior};
-use tracing::{debug, info, instrument, trace};
+use tracing::{debug, info, instrument, trace, warn};
 
-use crate::app::{
-    checking::{ping::Pingable, Healthy, PING_MAX_RETRY},
-    socks5::{InnerProto, SocksServer},
-    AppContext,
+use crate::{
+    app::{
+        checking::{ping::Pingable, Healthy, PingResult, ScoreParams, PING_MAX_RETRY},
+        socks5::{InnerProto, SocksServer},
+        AppContext,
+    },
+    cli::TieBreak,
 };
 
 use super::meter::Sampling;
@@ -32,8 +35,14 @@ impl CheckingService {
     pub(crate) async fn launch(self) -> ! {
         debug!("Checking service started");
         let task_ping = async {
-            let mut interval_ping =
-                interval_at(Instant::now(), self.context.cli_args.check_interval);
+            let period = self.context.cli_args.check_interval;
+            // Skip the first round if `ping_all_once()` just did it
+            let start = if self.context.is_first_probe_done() {
+                Instant::now() + period
+            } else {
+                Instant::now()
+            };
+            let mut interval_ping = interval_at(start, period);
             loop {
                 interval_ping.tick().await;
                 self.ping_all().await;
@@ -58,39 +67,66 @@ impl CheckingService {
         tokio::join!(task_ping, task_meter, task_health).0
     }
 
+    /// Ping all servers once, give up after `timeout`.
+    pub(crate) async fn ping_all_once(&self, timeout: Duration) {
+        if tokio::time::timeout(timeout, self.ping_all())
+            .await
+            .is_err()
+        {
+            warn!("Initial probing not finished within {:?}", timeout);
+        }
+    }
+
     #[instrument(skip_all)]
     async fn ping_all(&self) {
         trace!("Ping all servers");
-        let dns4 = self.context.cli_args.check_dns_server_v4;
-        let dns6 = self.context.cli_args.check_dns_server_v6;
         let servers = self.context.socks5_servers();
         let best_server = servers.first().cloned();
-        let checkings: FuturesUnordered<_> = self
-            .context
-            .socks5_servers()
+        let ctx = &self.context;
+        // Replies seen recently prove it works, save the probe
+        let (busy, idle): (Vec<_>, Vec<_>) = servers.into_iter().partition(|server| {
+            ctx.cli_args.passive_check
+                && server.inner_proto.get() != InnerProto::Unspecified
+                && server.status.meter.lock().has_rx()
+        });
+        busy.iter()
+            .for_each(|server| trace!("Skip probing busy [{}]", server.name));
+        let checkings: FuturesUnordered<_> = idle
             .into_iter()
             .map(|server| {
                 Box::pin(async move {
+                    let (target4, target6) = server.check_targets(ctx.cli_args);
                     let result = match server.inner_proto.get() {
-                        InnerProto::IPv4 => {
-                            server
-                                .ping_with_dns_query(dns4.into(), PING_MAX_RETRY)
-                                .await
-                        }
+                        InnerProto::IPv4 => server.ping(ctx, target4.into(), PING_MAX_RETRY).await,
                         InnerProto::IPv6 | InnerProto::Inet => {
-                            server
-                                .ping_with_dns_query(dns6.into(), PING_MAX_RETRY)
-                                .await
+                            server.ping(ctx, target6.into(), PING_MAX_RETRY).await
                         }
                         InnerProto::Unspecified => {
                             let result = tokio::select! {
-                                r = server.ping_with_dns_query(dns4.into(), PING_MAX_RETRY) => r,
-                                r = server.ping_with_dns_query(dns6.into(), PING_MAX_RETRY) => r,
+                                r = server.ping(ctx, target4.into(), PING_MAX_RETRY) => r,
+                                r = server.ping(ctx, target6.into(), PING_MAX_RETRY) => r,
                             };
-                            if matches!(result, Ok(Some(_))) {
-                                let proto = server.probe_inner_proto(dns4, dns6).await;
-                                server.inner_proto.set(proto);
-                                info!("Set [{}] inner protocal: {:?}", server.name, proto);
+                            if result.delay().is_some() {
+                                let was_unknown = server.is_proto_unknown();
+                                let probe = server.probe_inner_proto(ctx, target4, target6).await;
+                                server.set_inner_proto_probe(probe);
+                                if probe.proto != InnerProto::Unspecified {
+                                    info!(
+                                        "Set [{}] inner protocal: {:?} (v4 {}/{}, v6 {}/{})",
+                                        server.name,
+                                        probe.proto,
+                                        probe.v4_ok,
+                                        probe.tests,
+                                        probe.v6_ok,
+                                        probe.tests
+                                    );
+                                } else if !was_unknown {
+                                    warn!(
+                                        "[{}] passed check but failed on both IPv4 & IPv6 in \
+                                         probing inner protocol, use with caution",
+                                        server.name
+                                    );
+                                }
                             }
                             result
                         }
@@ -100,30 +136,50 @@ impl CheckingService {
             })
             .collect();
         let (sum, ok) = checkings
-            .inspect(|(server, result)| match result {
-                Err(err) => {
-                    info!("Failed to ping upstream [{}]: {}", server.name, err);
-                    server.set_troubleness(true);
-                }
-                Ok(None) => {
-                    debug!("Upstream [{}] is unreachable", server.name);
-                    server.set_troubleness(true);
+            .inspect(|(server, result)| {
+                server.status.ping_stats.record(*result);
+                server.check_rtt(server.max_rtt.or(ctx.cli_args.max_rtt));
+                match result {
+                    PingResult::Reachable(_) => (),
+                    PingResult::NoReply => {
+                        let timeouts = server.status.ping_stats.consecutive_no_reply();
+                        let loss = server.status.pings.lock().loss_percent();
+                        if is_down_on_timeout(
+                            timeouts,
+                            loss,
+                            ctx.cli_args.down_after_timeouts,
+                            ctx.cli_args.down_loss_percent,
+                        ) {
+                            debug!(
+                                "Upstream [{}] is unreachable ({})",
+                                server.name, server.status.ping_stats
+                            );
+                            server.set_troubleness(true);
+                        } else {
+                            debug!(
+                                "Upstream [{}] timed out {} times, loss {}%, keep it",
+                                server.name, timeouts, loss
+                            );
+                        }
+                    }
+                    err => {
+                        info!("Failed to ping upstream [{}]: {}", server.name, err);
+                        server.set_troubleness(true);
+                    }
                 }
-                Ok(Some(_)) => (),
             })
             .fold((0usize, 0usize), |(sum, ok), (_, result)| {
-                future::ready((
-                    sum + 1,
-                    ok + if result.ok().flatten().is_some() {
-                        1
-                    } else {
-                        0
-                    },
-                ))
+                future::ready((sum + 1, ok + result.delay().map_or(0, |_| 1)))
             })
             .await;
-        debug!("All pinged, {}/{} up", ok, sum);
+        debug!(
+            "All pinged, {}/{} up, {} skipped as busy",
+            ok,
+            sum,
+            busy.len()
+        );
         let new_best_server = self.resort_servers();
+        self.context.set_first_probe_done();
         if best_server != new_best_server {
             if let Some(server) = new_best_server {
                 info!(
@@ -145,15 +201,18 @@ impl CheckingService {
 
     #[instrument(skip_all)]
     async fn health_check_all(&self) {
+        let grace = self.context.cli_args.first_reply_grace.unwrap_or_default();
         let checking: FuturesUnordered<_> = self
             .context
             .socks5_servers()
             .into_iter()
-            .filter(|proxy| proxy.is_healthy() && proxy.status.meter.lock().tx_only())
+            .filter(|proxy| proxy.is_healthy() && proxy.status.meter.lock().tx_only(grace))
             .map(|proxy| {
                 let ctx = self.context.clone();
                 async move {
-                    if proxy.check_troubleness(&ctx).await && proxy.status.meter.lock().tx_only() {
+                    if proxy.check_troubleness(&ctx).await
+                        && proxy.status.meter.lock().tx_only(grace)
+                    {
                         proxy.set_troubleness(true);
                     }
                 }
@@ -163,12 +222,89 @@ impl CheckingService {
     }
 
     fn resort_servers(&self) -> Option<Arc<SocksServer>> {
+        let params = ScoreParams::from_args(self.context.cli_args);
+        let tie_break = self.context.cli_args.tie_break;
         self.context.update_socks5_servers(|servers| {
-            servers.sort_by_key(|h| {
-                let health = h.status.pings.lock();
-                health.score()
-            });
+            sort_servers(servers, &params, tie_break);
             servers.first().cloned()
         })
     }
 }
+
+/// Whether to mark a server down after a timed-out probe, given its count of
+/// consecutive timeouts and the loss rate over its ping history.
+fn is_down_on_timeout(
+    timeouts: usize,
+    loss_percent: u8,
+    down_after_timeouts: usize,
+    down_loss_percent: Option<u8>,
+) -> bool {
+    timeouts >= down_after_timeouts || down_loss_percent.is_some_and(|p| loss_percent >= p)
+}
+
+/// Sort servers by their ping score, the best first, ties broken by
+/// `tie_break`. The sort is stable, so rotating ties by one each time
+/// cycles them through.
+pub(crate) fn sort_servers(
+    servers: &mut [Arc<SocksServer>],
+    params: &ScoreParams,
+    tie_break: TieBreak,
+) {
+    let score = |h: &Arc<SocksServer>| h.status.pings.lock().score_with(params);
+    match tie_break {
+        TieBreak::None => servers.sort_by_cached_key(score),
+        TieBreak::Sessions => {
+            servers.sort_by_cached_key(|h| (score(h), h.status.usage.active_sessions()))
+        }
+        TieBreak::Rotate => {
+            servers.sort_by_cached_key(score);
+            let scores: Vec<_> = servers.iter().map(score).collect();
+            let mut start = 0;
+            while start < servers.len() {
+                let len = scores[start..]
+                    .iter()
+                    .take_while(|&&s| s == scores[start])
+                    .count();
+                servers[start..start + len].rotate_left(1);
+                start += len;
+            }
+        }
+    }
+}
+
+#[test]
+fn test_is_down_on_timeout() {
+    // Default: down on the first timeout
+    assert!(is_down_on_timeout(1, 0, 1, None));
+    assert!(!is_down_on_timeout(2, 10, 3, None));
+    assert!(is_down_on_timeout(3, 10, 3, None));
+    assert!(!is_down_on_timeout(1, 10, 3, Some(20)));
+    assert!(is_down_on_timeout(1, 20, 3, Some(20)));
+}
+
+#[test]
+fn test_sort_servers_rotate_ties() {
+    let servers: Vec<_> = ["a", "b", "c", "d"]
+        .into_iter()
+        .map(|name| {
+            let addr = ([127, 0, 0, 1], 1080).into();
+            Arc::new(SocksServer::new(addr, name.into(), InnerProto::Inet))
+        })
+        .collect();
+    servers[3]
+        .status
+        .pings
+        .lock()
+        .add_measurement(Some(Duration::from_millis(1).into()));
+    let names = |servers: &[Arc<SocksServer>]| -> String {
+        servers.iter().map(|s| s.name.as_str()).collect()
+    };
+    let params = ScoreParams::default();
+    let mut sorted = servers.clone();
+    sort_servers(&mut sorted, &params, TieBreak::None);
+    assert_eq!(names(&sorted), "dabc");
+    sort_servers(&mut sorted, &params, TieBreak::Rotate);
+    assert_eq!(names(&sorted), "dbca");
+    sort_servers(&mut sorted, &params, TieBreak::Rotate);
+    assert_eq!(names(&sorted), "dcab");
+}
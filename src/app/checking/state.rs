@@ -0,0 +1,172 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::app::socks5::SocksServer;
+
+use super::PingHistory;
+
+/// State older than this is considered stale and ignored.
+const MAX_STATE_AGE: Duration = Duration::from_secs(3600);
+/// State saved later than now by more than this is ignored, as its age
+/// can't be told.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+/// Ping histories of servers keyed by name, persisted across restarts.
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    /// Unix timestamp in seconds
+    saved_at: u64,
+    ping_histories: HashMap<String, PingHistory>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Write ping histories of `servers` to `path`, replacing it atomically.
+pub(crate) fn save_ping_histories(servers: &[Arc<SocksServer>], path: &Path) -> io::Result<()> {
+    let state = State {
+        saved_at: unix_now(),
+        ping_histories: servers
+            .iter()
+            .map(|server| (server.name.clone(), server.status.pings.lock().clone()))
+            .collect(),
+    };
+    // Unique among instances sharing the path, to never write into the same
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(
+        ".{}.{:x}.tmp",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let tmp_path = path.with_file_name(tmp_name);
+    let write = || -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)?;
+        let mut writer = io::BufWriter::new(file);
+        serde_json::to_writer(&mut writer, &state)?;
+        writer.flush()?;
+        // Or a crash right after rename may leave an empty file behind
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, path)
+    };
+    if let Err(err) = write() {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    info!(
+        "Saved ping histories of {} servers to {}",
+        servers.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Load ping histories from `path` into `servers` with the same name,
+/// unless the file is missing, malformed, stale or from the future. Return
+/// those left, e.g. of referred servers not negotiated yet.
+pub(crate) fn restore_ping_histories(
+    servers: &[Arc<SocksServer>],
+    path: &Path,
+) -> HashMap<String, PingHistory> {
+    let state: State = match File::open(path) {
+        Ok(file) => match serde_json::from_reader(io::BufReader::new(file)) {
+            Ok(state) => state,
+            Err(err) => {
+                warn!("Ignore malformed state file {}: {}", path.display(), err);
+                return Default::default();
+            }
+        },
+        Err(err) => {
+            debug!("No state loaded from {}: {}", path.display(), err);
+            return Default::default();
+        }
+    };
+    let now = unix_now();
+    if state.saved_at > now + MAX_CLOCK_SKEW.as_secs() {
+        warn!(
+            "Ignore state file {} saved {}s in the future",
+            path.display(),
+            state.saved_at - now
+        );
+        return Default::default();
+    }
+    let age = now.saturating_sub(state.saved_at);
+    if age > MAX_STATE_AGE.as_secs() {
+        info!("Ignore stale state file {} ({}s old)", path.display(), age);
+        return Default::default();
+    }
+    let mut histories = state.ping_histories;
+    let restored = servers
+        .iter()
+        .filter_map(|server| {
+            let history = histories.remove(&server.name)?;
+            *server.status.pings.lock() = history;
+            Some(())
+        })
+        .count();
+    info!(
+        "Restored ping histories of {} servers from {}",
+        restored,
+        path.display()
+    );
+    histories
+}
+
+#[test]
+fn test_ping_histories_roundtrip() {
+    use crate::app::InnerProto;
+
+    let path = std::env::temp_dir().join(format!("quproxy-state-{}.json", std::process::id()));
+    let new_server = || {
+        let addr = ([127, 0, 0, 1], 1080).into();
+        Arc::new(SocksServer::new(addr, "s".into(), InnerProto::Inet))
+    };
+    let server = new_server();
+    server
+        .status
+        .pings
+        .lock()
+        .add_measurement(Some(Duration::from_millis(100).into()));
+    save_ping_histories(std::slice::from_ref(&server), &path).unwrap();
+    // Replaced, with no temp file left behind
+    save_ping_histories(&[server], &path).unwrap();
+    let name = path.file_name().unwrap().to_str().unwrap();
+    let leftover = fs::read_dir(path.parent().unwrap())
+        .unwrap()
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .any(|file| file.starts_with(name) && file.ends_with(".tmp"));
+    assert!(!leftover);
+
+    let server = new_server();
+    let left = restore_ping_histories(&[], &path);
+    assert!(left.contains_key("s"));
+    restore_ping_histories(std::slice::from_ref(&server), &path);
+    let delay = server.status.pings.lock().average_delay().unwrap();
+    assert!(delay.as_millis().abs_diff(100) < 10);
+
+    // Saved in the future
+    let state = State {
+        saved_at: unix_now() + 3600,
+        ping_histories: left,
+    };
+    fs::write(&path, serde_json::to_vec(&state).unwrap()).unwrap();
+    let server = new_server();
+    assert!(restore_ping_histories(std::slice::from_ref(&server), &path).is_empty());
+    fs::remove_file(&path).unwrap();
+    assert!(server.status.pings.lock().average_delay().is_none());
+}
@@ -1,12 +1,35 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
 
+use bytesize::ByteSize;
 use derivative::Derivative;
 use lru_time_cache::LruCache;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use tokio::sync::Semaphore;
 use tracing::{info, warn};
 
-use super::socks5::{SocksServer, SocksServerReferrer};
-use crate::cli::{CliArgs, ConfigFile, Upstream, UpstreamProtocol};
+use super::{
+    checking::{
+        restore_ping_histories, save_ping_histories, sort_servers, Healthy, PingHistory,
+        ScoreParams,
+    },
+    dns::DnsCache,
+    limit::RateLimits,
+    socks5::{
+        selection_policy, CheckDnsServers, Credentials, ReassemblyMem, SelectionPolicy,
+        SocksServer, SocksServerReferrer,
+    },
+    stats::Stats,
+    types::canonicalize_socket_addr,
+};
+use crate::cli::{CliArgs, ConfigFile, Upstream, UpstreamAddr, UpstreamProtocol};
 
 #[derive(Derivative)]
 #[derivative(Debug, Clone(bound = ""))]
@@ -14,6 +37,50 @@ pub(crate) struct AppContext {
     pub(crate) cli_args: &'static CliArgs,
     socks5_servers: Arc<RwLock<Vec<Arc<SocksServer>>>>,
     socks5_referrers: Arc<RwLock<Vec<Arc<SocksServerReferrer>>>>,
+    #[derivative(Debug = "ignore")]
+    pub(crate) dns_cache: Arc<DnsCache>,
+    pub(crate) stats: Arc<Stats>,
+    /// Permits for reply-forwarding tasks, see `--max-reply-tasks`
+    pub(crate) reply_tasks: Arc<Semaphore>,
+    /// Bytes held by fragment reassembly, see `--max-reassembly-mem`
+    pub(crate) reassembly_mem: Arc<ReassemblyMem>,
+    sni_pins: Arc<RwLock<HashMap<String, String>>>,
+    sni_duplicate: Arc<RwLock<HashSet<String>>>,
+    first_probe_done: Arc<AtomicBool>,
+    /// Restored from `--state-file` for referred servers, taken on their
+    /// first negotiation
+    saved_referred_pings: Arc<Mutex<HashMap<String, PingHistory>>>,
+    /// Addresses of quproxy itself & its upstreams, see `is_self_addr()`
+    self_addrs: Arc<RwLock<HashSet<SocketAddr>>>,
+    /// IPs of local interfaces, which wildcard listen addresses stand for
+    local_ips: Arc<RwLock<HashSet<IpAddr>>>,
+    /// Policy of `--select-mode`
+    #[derivative(Debug = "ignore")]
+    pub(crate) selection_policy: Arc<dyn SelectionPolicy>,
+}
+
+/// Listen address plus UDP addresses of all upstreams.
+fn collect_self_addrs(args: &CliArgs, servers: &[Arc<SocksServer>]) -> HashSet<SocketAddr> {
+    std::iter::once(SocketAddr::new(args.host, args.port))
+        .chain(servers.iter().map(|server| server.udp_addr))
+        .collect()
+}
+
+/// IPs of local interfaces, loopback ones included.
+fn collect_local_ips() -> HashSet<IpAddr> {
+    let mut ips: HashSet<IpAddr> = [Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()].into();
+    match nix::ifaddrs::getifaddrs() {
+        Ok(ifaddrs) => ips.extend(ifaddrs.filter_map(|ifaddr| {
+            let addr = ifaddr.address?;
+            let ip: IpAddr = match addr.as_sockaddr_in() {
+                Some(v4) => Ipv4Addr::from(v4.ip()).into(),
+                None => addr.as_sockaddr_in6()?.ip().into(),
+            };
+            Some(ip)
+        })),
+        Err(err) => warn!("Failed to list local addresses: {}", err),
+    }
+    ips
 }
 
 fn filter_duplicated_socket_addrs(addrs: &Vec<SocketAddr>) -> HashSet<SocketAddr> {
@@ -26,57 +93,260 @@ fn filter_duplicated_socket_addrs(addrs: &Vec<SocketAddr>) -> HashSet<SocketAddr
     set
 }
 
-impl AppContext {
-    pub(crate) fn from_cli_args(args: CliArgs) -> Self {
-        let mut socks5_servers: Vec<Arc<_>> = filter_duplicated_socket_addrs(&args.socks5_udp)
-            .into_iter()
-            .map(|addr| Arc::new(addr.into()))
-            .collect();
-        let mut socks5_referrers: Vec<Arc<_>> = filter_duplicated_socket_addrs(&args.socks5_tcp)
-            .into_iter()
-            .map(|addr| Arc::new(addr.into()))
-            .collect();
+/// Fail if `bind_from` can't send to `addr`.
+fn check_bind_from(name: &str, bind_from: Option<IpAddr>, addr: SocketAddr) -> io::Result<()> {
+    if matches!(bind_from, Some(ip) if ip.is_ipv4() != addr.is_ipv4()) {
+        io_error!(
+            InvalidInput,
+            format!("{}: bind_from in a different family", name)
+        );
+    }
+    Ok(())
+}
 
-        // TODO: check duplicated socket address & name
-        // TODO: retain order
-        if let Some(path) = &args.list {
-            let cfg = ConfigFile::from_path(path).expect("Error on read upstream list file");
-            for (
-                name,
-                Upstream {
-                    protocol,
-                    address,
-                    enabled,
-                    inner_proto,
-                },
-            ) in cfg.upstreams
-            {
-                if !enabled {
-                    continue;
+/// First address of "host:port", `None` if it fails to resolve.
+fn resolve_first(host: &str) -> Option<SocketAddr> {
+    host.to_socket_addrs().ok()?.next()
+}
+
+/// Upstreams from both command line and the list file.
+struct Upstreams {
+    servers: Vec<Arc<SocksServer>>,
+    referrers: Vec<Arc<SocksServerReferrer>>,
+    pins: HashMap<String, String>,
+    duplicate: HashSet<String>,
+}
+
+fn load_upstreams(args: &CliArgs) -> io::Result<Upstreams> {
+    let mut servers: Vec<Arc<_>> = filter_duplicated_socket_addrs(&args.socks5_udp)
+        .into_iter()
+        .map(|addr| Arc::new(addr.into()))
+        .collect();
+    let mut referrers: Vec<Arc<_>> = filter_duplicated_socket_addrs(&args.socks5_tcp)
+        .into_iter()
+        .map(|addr| Arc::new(addr.into()))
+        .collect();
+
+    // TODO: check duplicated socket address & name
+    // TODO: retain order
+    let mut pins = HashMap::new();
+    let mut duplicate = HashSet::new();
+    if let Some(path) = &args.list {
+        let mut cfg = ConfigFile::from_path(path)?;
+        pins = std::mem::take(&mut cfg.pins);
+        duplicate = std::mem::take(&mut cfg.duplicate);
+        for (
+            name,
+            Upstream {
+                protocol,
+                address,
+                enabled,
+                inner_proto,
+                tx_limit,
+                rx_limit,
+                max_rtt,
+                group,
+                check_dns_v4,
+                check_dns_v6,
+                bind_from,
+                username,
+                password,
+                weight,
+            },
+        ) in cfg.upstreams
+        {
+            if !enabled {
+                continue;
+            }
+            let auth = match (username, password) {
+                (None, None) => None,
+                // No handshake to authenticate in, as with an old config
+                (Some(_), _) | (_, Some(_)) if protocol == UpstreamProtocol::Socks5Udp => {
+                    warn!(
+                        "Ignore username & password of socks5_udp upstream [{}]",
+                        name
+                    );
+                    None
+                }
+                (Some(username), Some(password))
+                    if (1..=255).contains(&username.len())
+                        && (1..=255).contains(&password.len()) =>
+                {
+                    Some(Credentials { username, password })
                 }
-                match protocol {
-                    UpstreamProtocol::Socks5Udp => {
-                        socks5_servers.push(SocksServer::new(address, name, inner_proto).into())
+                _ => io_error!(
+                    InvalidInput,
+                    format!(
+                        "{}: username & password must be both set, 1 to 255 bytes",
+                        name
+                    )
+                ),
+            };
+            let limits = RateLimits {
+                tx: tx_limit,
+                rx: rx_limit,
+            };
+            let check_dns = CheckDnsServers {
+                v4: check_dns_v4,
+                v6: check_dns_v6,
+            };
+            match protocol {
+                UpstreamProtocol::Socks5Udp => {
+                    let address = match address {
+                        UpstreamAddr::Ip(addr) => addr,
+                        UpstreamAddr::Host(host) => match resolve_first(&host) {
+                            Some(addr) => addr,
+                            None => {
+                                warn!("Skip upstream [{}], {} resolved to nothing", name, host);
+                                continue;
+                            }
+                        },
+                    };
+                    check_bind_from(&name, bind_from, address)?;
+                    servers.push(
+                        SocksServer::new(address, name, inner_proto)
+                            .with_rate_limits(limits)
+                            .with_max_rtt(max_rtt)
+                            .with_group(group)
+                            .with_check_dns(check_dns)
+                            .with_bind_from(bind_from)
+                            .with_weight(weight)
+                            .into(),
+                    )
+                }
+                UpstreamProtocol::Socks5Tcp => {
+                    // Hostnames are resolved on every negotiation instead
+                    if let UpstreamAddr::Ip(addr) = &address {
+                        check_bind_from(&name, bind_from, *addr)?;
                     }
-                    UpstreamProtocol::Socks5Tcp => socks5_referrers
-                        .push(SocksServerReferrer::new(address, name, inner_proto).into()),
+                    referrers.push(
+                        SocksServerReferrer::new(address, name, inner_proto)
+                            .with_rate_limits(limits)
+                            .with_max_rtt(max_rtt)
+                            .with_group(group)
+                            .with_check_dns(check_dns)
+                            .with_bind_from(bind_from)
+                            .with_weight(weight)
+                            .with_auth(auth)
+                            .into(),
+                    )
                 }
             }
         }
+    }
+    info!(
+        "Configured SOCKSv5 servers: {}",
+        servers.len() + referrers.len()
+    );
+    if servers.is_empty() && referrers.is_empty() {
+        warn!("No proxy server configured");
+    }
+    Ok(Upstreams {
+        servers,
+        referrers,
+        pins,
+        duplicate,
+    })
+}
 
-        info!(
-            "Configured SOCKSv5 servers: {}",
-            socks5_servers.len() + socks5_referrers.len()
-        );
-        if socks5_servers.is_empty() && socks5_referrers.is_empty() {
-            warn!("No proxy server configured");
+impl AppContext {
+    pub(crate) fn from_cli_args(args: CliArgs) -> io::Result<Self> {
+        let Upstreams {
+            mut servers,
+            referrers,
+            pins,
+            duplicate,
+        } = load_upstreams(&args)?;
+        let mut saved_referred_pings = HashMap::new();
+        if let Some(path) = &args.state_file {
+            saved_referred_pings = restore_ping_histories(&servers, path);
+            saved_referred_pings.retain(|name, _| referrers.iter().any(|r| r.name == *name));
+            sort_servers(&mut servers, &ScoreParams::from_args(&args), args.tie_break);
         }
-        Self {
+        Ok(Self {
+            dns_cache: DnsCache::new(args.local_dns_cache_ttl).into(),
+            stats: Default::default(),
+            reply_tasks: Semaphore::new(args.max_reply_tasks).into(),
+            reassembly_mem: ReassemblyMem::new(args.max_reassembly_mem).into(),
+            sni_pins: RwLock::new(pins).into(),
+            sni_duplicate: RwLock::new(duplicate).into(),
+            // Nothing to wait for if checking is disabled
+            first_probe_done: AtomicBool::new(args.no_check).into(),
+            saved_referred_pings: Mutex::new(saved_referred_pings).into(),
+            self_addrs: RwLock::new(collect_self_addrs(&args, &servers)).into(),
+            local_ips: RwLock::new(collect_local_ips()).into(),
+            selection_policy: selection_policy(&args),
             cli_args: Box::leak(args.into()),
-            socks5_servers: RwLock::new(socks5_servers).into(),
-            socks5_referrers: RwLock::new(socks5_referrers).into(),
+            socks5_servers: RwLock::new(servers).into(),
+            socks5_referrers: RwLock::new(referrers).into(),
+        })
+    }
+
+    /// Save ping histories to `--state-file`, if given.
+    pub(crate) fn save_state(&self) {
+        if let Some(path) = &self.cli_args.state_file {
+            if let Err(err) = save_ping_histories(&self.socks5_servers(), path) {
+                warn!("Failed to save state to {}: {}", path.display(), err);
+            }
         }
     }
+
+    /// Reload upstreams, pins & duplicate from the list file. On any
+    /// error, the current ones are kept untouched. Unchanged servers are
+    /// kept along with their status. Removed servers are drained first,
+    /// and dropped by the forward service once their conns are migrated.
+    /// Reconfigured servers are replaced by new ones taking over their
+    /// health, the old ones drained like removed. Reconfigured referrers
+    /// are renegotiated by the refer service.
+    pub(crate) fn reload_upstreams(&self) -> io::Result<()> {
+        let Upstreams {
+            servers,
+            referrers,
+            pins,
+            duplicate,
+        } = load_upstreams(self.cli_args)?;
+        *self.sni_pins.write() = pins;
+        *self.sni_duplicate.write() = duplicate;
+        let mut old_referrers = self.socks5_referrers.write();
+        self.update_socks5_servers(|current| {
+            // Servers referred by TCP referrers are managed by refer service
+            for server in current.iter() {
+                let keep =
+                    servers.contains(server) || old_referrers.iter().any(|r| r.name == server.name);
+                if !keep && server.removed_at().is_none() {
+                    info!("Draining removed upstream [{}]", server.name);
+                    server.set_removed();
+                }
+            }
+            let mut added = Vec::new();
+            for server in servers {
+                // Re-added while being drained, start over with a new one
+                match current
+                    .iter()
+                    .find(|p| **p == server && p.removed_at().is_none())
+                {
+                    Some(old) if old.same_config(&server) => continue,
+                    Some(old) => {
+                        info!("Reconfigure upstream [{}]", server.name);
+                        server.carry_over_from(old);
+                        old.set_removed();
+                    }
+                    None => info!("Add upstream [{}]", server.name),
+                }
+                added.push(server);
+            }
+            current.extend(added);
+        });
+        *old_referrers = referrers;
+        Ok(())
+    }
+}
+
+/// Yield "a.b.c", "b.c", "c" for "a.b.c".
+fn domain_and_parents(name: &str) -> impl Iterator<Item = &str> {
+    std::iter::successors(Some(name), |domain| {
+        domain.split_once('.').map(|(_, parent)| parent)
+    })
 }
 
 impl AppContext {
@@ -94,15 +364,291 @@ impl AppContext {
         self.socks5_servers.read().clone()
     }
 
+    pub(crate) fn find_socks5_server<P>(&self, predicate: P) -> Option<Arc<SocksServer>>
+    where
+        P: FnMut(&&Arc<SocksServer>) -> bool,
+    {
+        self.socks5_servers.read().iter().find(predicate).cloned()
+    }
+
+    /// Ping history of referred server `name` saved by a previous run, if
+    /// not taken yet.
+    pub(crate) fn take_saved_referred_pings(&self, name: &str) -> Option<PingHistory> {
+        self.saved_referred_pings.lock().remove(name)
+    }
+
+    /// Whether the first round of `ping_all` has been completed.
+    pub(crate) fn is_first_probe_done(&self) -> bool {
+        self.first_probe_done.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_first_probe_done(&self) {
+        if !self.first_probe_done.swap(true, Ordering::Relaxed) {
+            info!("First probe cycle completed");
+        }
+    }
+
+    /// Return name of the upstream pinned for domain `name` or its parents.
+    pub(crate) fn pinned_server_name(&self, name: &str) -> Option<String> {
+        let pins = self.sni_pins.read();
+        if pins.is_empty() {
+            return None;
+        }
+        domain_and_parents(name)
+            .find_map(|domain| pins.get(domain))
+            .cloned()
+    }
+
+    /// Whether conns to domain `name` should be duplicated over two
+    /// upstreams.
+    pub(crate) fn is_duplicated(&self, name: &str) -> bool {
+        let duplicate = self.sni_duplicate.read();
+        !duplicate.is_empty() && domain_and_parents(name).any(|domain| duplicate.contains(domain))
+    }
+
     pub(crate) fn socks5_referrers(&self) -> Vec<Arc<SocksServerReferrer>> {
         self.socks5_referrers.read().clone()
     }
 
+    /// Write a one-line summary of upstreams & traffic, for a quick glance
+    /// on SIGUSR2. Servers are kept sorted by score, so the best is the
+    /// first healthy one.
+    pub(crate) fn write_summary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let servers = self.socks5_servers.read();
+        let (mut healthy, mut sessions, mut tx, mut rx) = (0, 0, 0, 0);
+        for server in servers.iter() {
+            healthy += server.is_healthy() as usize;
+            sessions += server.status.usage.active_sessions();
+            let meter = server.status.meter.lock();
+            tx += meter.tx_rate().unwrap_or_default();
+            rx += meter.rx_rate().unwrap_or_default();
+        }
+        write!(
+            out,
+            "{} servers ({} healthy), {} active sessions, TX {}/s RX {}/s, best=",
+            servers.len(),
+            healthy,
+            sessions,
+            ByteSize(tx),
+            ByteSize(rx),
+        )?;
+        match servers.iter().find(|p| p.is_healthy() && !p.is_draining()) {
+            Some(best) => writeln!(out, "{} {}", best.name, best.status.pings.lock()),
+            None => writeln!(out, "none"),
+        }
+    }
+
     pub(crate) fn update_socks5_servers<F, R>(&self, func: F) -> R
     where
         F: FnOnce(&mut Vec<Arc<SocksServer>>) -> R,
     {
         let mut servers = self.socks5_servers.write();
-        func(&mut servers)
+        let ret = func(&mut servers);
+        *self.self_addrs.write() = collect_self_addrs(self.cli_args, &servers);
+        *self.local_ips.write() = collect_local_ips();
+        ret
     }
+
+    /// Whether `addr` is quproxy's own listen address or one of upstreams,
+    /// which means a routing loop if it's taken as a remote. An unspecified
+    /// IP matches loopback & local interface addresses on the same port.
+    pub(crate) fn is_self_addr(&self, addr: SocketAddr) -> bool {
+        let addr = canonicalize_socket_addr(addr);
+        let addrs = self.self_addrs.read();
+        if addrs.contains(&addr) {
+            return true;
+        }
+        let any = |ip: IpAddr| addrs.contains(&SocketAddr::new(ip, addr.port()));
+        let wildcard = any(Ipv6Addr::UNSPECIFIED.into())
+            || (addr.is_ipv4() && any(Ipv4Addr::UNSPECIFIED.into()));
+        wildcard && (addr.ip().is_loopback() || self.local_ips.read().contains(&addr.ip()))
+    }
+}
+
+#[test]
+fn test_domain_and_parents() {
+    let domains: Vec<_> = domain_and_parents("a.example.com").collect();
+    assert_eq!(domains, ["a.example.com", "example.com", "com"]);
+}
+
+#[test]
+fn test_reload_drains_removed() {
+    use clap::Parser;
+
+    let path = std::env::temp_dir().join(format!("quproxy-list-{}.toml", std::process::id()));
+    let write_list = |names: &[&str]| {
+        let list: String = names
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("[upstreams.{}]\naddr = \"127.0.0.1:{}\"\n", name, 1080 + i))
+            .collect();
+        std::fs::write(&path, list).unwrap();
+    };
+    write_list(&["a", "b"]);
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "-l", path.to_str().unwrap()]);
+    let context = AppContext::from_cli_args(args).unwrap();
+
+    write_list(&["a"]);
+    context.reload_upstreams().unwrap();
+    let removed = context.find_socks5_server(|p| p.name == "b").unwrap();
+    assert!(removed.is_draining() && removed.removed_at().is_some());
+    assert_eq!(context.socks5_servers().len(), 2);
+
+    write_list(&["a", "b"]);
+    context.reload_upstreams().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let servers: Vec<_> = context
+        .socks5_servers()
+        .into_iter()
+        .filter(|p| p.name == "b")
+        .collect();
+    assert_eq!(servers.len(), 2);
+    assert!(servers.iter().any(|p| !p.is_draining()));
+}
+
+#[test]
+fn test_reload_reconfigured() {
+    use clap::Parser;
+
+    let path = std::env::temp_dir().join(format!("quproxy-conf-{}.toml", std::process::id()));
+    let write_list = |tx_limit: u64, pin: &str| {
+        let list = format!(
+            "[upstreams.a]\naddr = \"127.0.0.1:1080\"\ntx_limit = {}\n\
+             [upstreams.b]\naddr = \"127.0.0.1:1081\"\n\
+             [pins]\n\"example.com\" = \"{}\"\n",
+            tx_limit, pin
+        );
+        std::fs::write(&path, list).unwrap();
+    };
+    write_list(1000, "a");
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "-l", path.to_str().unwrap()]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let old = context.find_socks5_server(|p| p.name == "a").unwrap();
+    old.set_troubleness(true);
+    assert_eq!(context.pinned_server_name("www.example.com").unwrap(), "a");
+
+    write_list(2000, "b");
+    context.reload_upstreams().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(old.removed_at().is_some());
+    let new = context
+        .find_socks5_server(|p| p.name == "a" && p.removed_at().is_none())
+        .unwrap();
+    assert!(new.take_tx(2000) && !new.take_tx(1));
+    assert!(!new.is_healthy());
+    // Unchanged one kept as is
+    assert_eq!(context.socks5_servers().len(), 3);
+    assert_eq!(context.pinned_server_name("www.example.com").unwrap(), "b");
+}
+
+#[test]
+fn test_write_summary() {
+    use clap::Parser;
+
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "-u", "127.0.0.1:1080"]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let mut out = Vec::new();
+    context.write_summary(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "1 servers (1 healthy), 0 active sessions, TX 0 B/s RX 0 B/s, \
+         best=127.0.0.1:1080 [unknown (32767)]\n"
+    );
+}
+
+#[test]
+fn test_is_self_addr() {
+    use clap::Parser;
+
+    let args = CliArgs::parse_from(["quproxy", "-p", "4433", "-u", "127.0.0.1:1080"]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    assert!(context.is_self_addr("127.0.0.1:1080".parse().unwrap()));
+    // Wildcard listen address covers local ones only
+    assert!(context.is_self_addr("127.0.0.2:4433".parse().unwrap()));
+    assert!(context.is_self_addr("[::1]:4433".parse().unwrap()));
+    assert!(context.is_self_addr("[::ffff:127.0.0.1]:4433".parse().unwrap()));
+    assert!(!context.is_self_addr("192.0.2.1:4433".parse().unwrap()));
+    assert!(!context.is_self_addr("[2001:db8::1]:4433".parse().unwrap()));
+    assert!(!context.is_self_addr("192.0.2.1:443".parse().unwrap()));
+    assert!(!context.is_self_addr("127.0.0.2:1080".parse().unwrap()));
+}
+
+#[test]
+fn test_upstream_addr_typo() {
+    use crate::cli::ConfigFile;
+
+    let parse = |addr: &str| {
+        toml::de::from_str::<ConfigFile>(&format!("[upstreams.a]\naddr = \"{}\"\n", addr))
+    };
+    for addr in [
+        "127.0.0.1:1080",
+        "[::1]:1080",
+        "localhost:1080",
+        "a1.example:1080",
+    ] {
+        assert!(parse(addr).is_ok(), "{}", addr);
+    }
+    for addr in [
+        "127.0.0.256:1080",
+        "127.0.0:1080",
+        "[::1:1080",
+        "::1:1080",
+        "localhost",
+    ] {
+        assert!(parse(addr).is_err(), "{}", addr);
+    }
+}
+
+#[test]
+fn test_upstream_hostnames() {
+    use clap::Parser;
+
+    let path = std::env::temp_dir().join(format!("quproxy-hosts-{}.toml", std::process::id()));
+    let list = r#"
+        [upstreams.by-ip]
+        addr = "127.0.0.1:1080"
+        [upstreams.by-name]
+        addr = "localhost:1081"
+        [upstreams.unresolved]
+        addr = "upstream.invalid:1082"
+        [upstreams.referrer]
+        proto = "socks5_tcp"
+        addr = "upstream.invalid:1083"
+    "#;
+    std::fs::write(&path, list).unwrap();
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "-l", path.to_str().unwrap()]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let by_name = context.find_socks5_server(|p| p.name == "by-name").unwrap();
+    assert!(by_name.udp_addr.ip().is_loopback());
+    assert_eq!(by_name.udp_addr.port(), 1081);
+    assert!(context
+        .find_socks5_server(|p| p.name == "unresolved")
+        .is_none());
+    assert_eq!(context.socks5_servers().len(), 2);
+    // Left to be resolved on negotiation
+    let referrers = context.socks5_referrers.read();
+    assert_eq!(
+        referrers[0].tcp_addr,
+        UpstreamAddr::Host("upstream.invalid:1083".into())
+    );
+}
+
+#[test]
+fn test_udp_upstream_credentials_ignored() {
+    use clap::Parser;
+
+    let path = std::env::temp_dir().join(format!("quproxy-auth-{}.toml", std::process::id()));
+    let list = r#"
+        [upstreams.udp]
+        addr = "127.0.0.1:1080"
+        username = "user"
+        password = "secret"
+    "#;
+    std::fs::write(&path, list).unwrap();
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "-l", path.to_str().unwrap()]);
+    let context = AppContext::from_cli_args(args);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(context.unwrap().socks5_servers().len(), 1);
 }
@@ -0,0 +1,109 @@
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use lru_time_cache::LruCache;
+use parking_lot::Mutex;
+use tokio::net::lookup_host;
+use tracing::{debug, trace};
+
+const DNS_CACHE_CAPACITY: usize = 1024;
+/// Min interval between lookups of the same name, so failing names are not
+/// looked up again on every new conn
+const LOOKUP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Resolve domain names with system resolver, results are cached for a
+/// fixed TTL since `getaddrinfo()` don't tell the TTL of records.
+pub(crate) struct DnsCache {
+    cache: Mutex<LruCache<String, Arc<[IpAddr]>>>,
+    /// Names looked up (or being looked up) recently
+    lookups: Mutex<LruCache<String, ()>>,
+}
+
+impl DnsCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            cache: LruCache::with_expiry_duration_and_capacity(ttl, DNS_CACHE_CAPACITY).into(),
+            lookups: LruCache::with_expiry_duration_and_capacity(
+                LOOKUP_INTERVAL,
+                DNS_CACHE_CAPACITY,
+            )
+            .into(),
+        }
+    }
+
+    /// Cached address of `name`, prefer one in the same family as `like`,
+    /// and take the port number of `like`. Never wait: on cache miss, start
+    /// resolving `name` in background for later conns and return `None`.
+    pub(crate) fn cached_like(
+        self: &Arc<Self>,
+        name: &str,
+        like: SocketAddr,
+    ) -> Option<SocketAddr> {
+        if let Some(ips) = self.cache.lock().get(name) {
+            return Some(pick_like(ips, like));
+        }
+        let mut lookups = self.lookups.lock();
+        if lookups.get(name).is_none() {
+            lookups.insert(name.to_string(), ());
+            let cache = self.clone();
+            let name = name.to_string();
+            tokio::spawn(async move {
+                if let Err(err) = cache.resolve(&name).await {
+                    debug!("Failed to resolve {}: {}", name, err);
+                }
+            });
+        }
+        None
+    }
+
+    async fn resolve(&self, name: &str) -> io::Result<Arc<[IpAddr]>> {
+        if let Some(ips) = self.cache.lock().get(name) {
+            trace!("DNS cache hit: {} => {:?}", name, ips);
+            return Ok(ips.clone());
+        }
+        let ips: Arc<[_]> = lookup_host((name, 0)).await?.map(|a| a.ip()).collect();
+        if ips.is_empty() {
+            io_error!(NotFound, "Domain name resolved to nothing");
+        }
+        debug!("Resolved {} => {:?}", name, ips);
+        self.cache.lock().insert(name.to_string(), ips.clone());
+        Ok(ips)
+    }
+}
+
+/// One of non-empty `ips` in the same family as `like` if any, with the
+/// port number of `like`.
+fn pick_like(ips: &[IpAddr], like: SocketAddr) -> SocketAddr {
+    let ip = ips
+        .iter()
+        .find(|ip| ip.is_ipv4() == like.is_ipv4())
+        .unwrap_or(&ips[0]);
+    (*ip, like.port()).into()
+}
+
+#[tokio::test]
+async fn test_dns_cache_in_background() {
+    let cache = Arc::new(DnsCache::new(Duration::from_secs(60)));
+    let like: SocketAddr = ([127, 0, 0, 2], 443).into();
+    assert_eq!(cache.cached_like("localhost", like), None);
+    // Looked up once, not again on each miss
+    assert_eq!(cache.cached_like("localhost", like), None);
+    assert_eq!(cache.lookups.lock().len(), 1);
+    let resolved = async {
+        loop {
+            match cache.cached_like("localhost", like) {
+                Some(addr) => break addr,
+                None => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    };
+    let addr = tokio::time::timeout(Duration::from_secs(5), resolved)
+        .await
+        .unwrap();
+    assert!(addr.ip().is_loopback());
+    assert_eq!(addr.port(), 443);
+}
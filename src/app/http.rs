@@ -0,0 +1,671 @@
+use std::{
+    io::{self, Write},
+    net::SocketAddr,
+    sync::atomic::Ordering,
+    time::Duration,
+};
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use ring::constant_time;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot},
+    time::timeout,
+};
+use tracing::{debug, info, instrument, warn};
+
+use super::{
+    checking::Healthy,
+    metrics,
+    socks5::{InnerProtoProbe, SocksServer, Traffic},
+    stats::SIZE_BUCKETS,
+    types::canonicalize_socket_addr,
+    AdminQuery, AppContext, InnerProto,
+};
+
+const MAX_REQUEST_SIZE: usize = 4096;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Smaller bodies are not worth compressing
+const COMPRESS_MIN_SIZE: usize = 1024;
+
+/// Minimal HTTP/1.x listener for health checks & admin API, one request
+/// per connection.
+pub(crate) struct HttpService {
+    context: AppContext,
+    listener: TcpListener,
+    admin: mpsc::Sender<AdminQuery>,
+}
+
+#[derive(Debug)]
+struct Request {
+    method: String,
+    path: String,
+    /// Query string without `?`, not percent-decoded
+    query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    content_encoding: Option<&'static str>,
+    body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    /// Pick one from `Accept-Encoding`, prefer gzip, skip those with q=0.
+    fn negotiate(accept: &str) -> Option<Self> {
+        let accepted: Vec<_> = accept
+            .split(',')
+            .filter_map(|item| {
+                let mut params = item.split(';').map(str::trim);
+                let coding = params.next()?;
+                let rejected = params
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .any(|q| q.parse::<f32>() == Ok(0.0));
+                (!rejected).then_some(coding)
+            })
+            .collect();
+        let has = |name: &str| accepted.iter().any(|c| c.eq_ignore_ascii_case(name));
+        if has("gzip") {
+            Some(Self::Gzip)
+        } else if has("deflate") {
+            Some(Self::Deflate)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    fn encode(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Self::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+impl Response {
+    fn text<B: Into<Vec<u8>>>(status: u16, body: B) -> Self {
+        Self {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            content_encoding: None,
+            body: body.into(),
+        }
+    }
+
+    fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            content_encoding: None,
+            body: serde_json::to_vec(value).unwrap(),
+        }
+    }
+
+    /// Compress the body if it's large enough and client accepts it.
+    fn compress(mut self, accept_encoding: Option<&str>) -> Self {
+        let encoding = match accept_encoding.and_then(Encoding::negotiate) {
+            Some(encoding) if self.body.len() >= COMPRESS_MIN_SIZE => encoding,
+            _ => return self,
+        };
+        match encoding.encode(&self.body) {
+            Ok(body) => {
+                self.body = body;
+                self.content_encoding = Some(encoding.name());
+            }
+            Err(err) => warn!("Failed to compress response: {}", err),
+        }
+        self
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            409 => "Conflict",
+            405 => "Method Not Allowed",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+
+    async fn write_to(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let encoding = match self.content_encoding {
+            Some(encoding) => format!(
+                "Content-Encoding: {}\r\nVary: Accept-Encoding\r\n",
+                encoding
+            ),
+            None => String::new(),
+        };
+        let head = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            self.reason(),
+            self.content_type,
+            encoding,
+            self.body.len(),
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(&self.body).await?;
+        stream.shutdown().await
+    }
+}
+
+impl HttpService {
+    pub(crate) async fn bind(
+        context: &AppContext,
+        port: u16,
+        admin: mpsc::Sender<AdminQuery>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind((context.cli_args.host, port)).await?;
+        info!("HTTP service listen on {}", listener.local_addr()?);
+        Ok(Self {
+            context: context.clone(),
+            listener,
+            admin,
+        })
+    }
+
+    #[instrument(skip_all)]
+    pub(crate) async fn launch(self) {
+        loop {
+            let (mut stream, peer) = match self.listener.accept().await {
+                Ok(v) => v,
+                Err(err) => {
+                    warn!("Error on accepting HTTP connection: {}", err);
+                    continue;
+                }
+            };
+            let context = self.context.clone();
+            let admin = self.admin.clone();
+            tokio::spawn(async move {
+                let response = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+                    Ok(Ok(Some(request))) => route(&context, &admin, &request, peer).await,
+                    Ok(Ok(None)) => Response::text(400, "Bad request\n"),
+                    Ok(Err(err)) => {
+                        debug!("Failed to read request from {}: {}", peer, err);
+                        return;
+                    }
+                    Err(_) => {
+                        debug!("Timeout on reading request from {}", peer);
+                        return;
+                    }
+                };
+                if let Err(err) = response.write_to(&mut stream).await {
+                    debug!("Failed to write response to {}: {}", peer, err);
+                }
+            });
+        }
+    }
+}
+
+/// Read & parse request head, return `None` if malformed or oversized.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>> {
+    let mut buf = Vec::with_capacity(512);
+    let mut chunk = [0u8; 512];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 || buf.len() + len > MAX_REQUEST_SIZE {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..len]);
+    }
+    Ok(parse_request(&buf))
+}
+
+fn parse_request(buf: &[u8]) -> Option<Request> {
+    let head = std::str::from_utf8(buf).ok()?;
+    let mut lines = head.lines();
+    let mut line = lines.next()?.split_whitespace();
+    let method = line.next()?.to_string();
+    let target = line.next()?;
+    if !line.next()?.starts_with("HTTP/1.") {
+        return None;
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let headers = lines
+        .take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    Some(Request {
+        method,
+        path: path.to_string(),
+        query: query.to_string(),
+        headers,
+    })
+}
+
+async fn route(
+    context: &AppContext,
+    admin: &mpsc::Sender<AdminQuery>,
+    request: &Request,
+    peer: SocketAddr,
+) -> Response {
+    debug!("{} {}", request.method, request.path);
+    let segments: Vec<_> = request.path.split('/').skip(1).collect();
+    let admin_only = matches!(
+        (request.method.as_str(), segments.as_slice()),
+        ("POST", ["servers", _, "drain" | "undrain"]) | ("GET", ["diagnostics"])
+    );
+    if admin_only {
+        if let Err(response) = authorize(context, request, peer) {
+            return response;
+        }
+    }
+    match (request.method.as_str(), segments.as_slice()) {
+        // Being able to respond means the tproxy socket was bound
+        ("GET", ["healthz"]) => Response::text(200, "OK\n"),
+        ("GET", ["ready"]) => ready(context),
+        ("GET", ["metrics"]) => {
+            metrics(context, request).compress(request.header("Accept-Encoding"))
+        }
+        ("GET", ["servers"]) => servers(context),
+        ("POST", ["servers", name, "drain"]) => drain(admin, name).await,
+        ("POST", ["servers", name, "undrain"]) => undrain(context, name),
+        ("GET", ["explain"]) => explain(admin, request).await,
+        ("GET", ["diagnostics"]) => diagnostics(context, admin)
+            .await
+            .compress(request.header("Accept-Encoding")),
+        (_, ["healthz" | "ready" | "metrics" | "servers" | "explain" | "diagnostics"])
+        | (_, ["servers", _, "drain" | "undrain"]) => Response::text(405, "Method not allowed\n"),
+        _ => Response::text(404, "Not found\n"),
+    }
+}
+
+/// Check `--admin-token` if set, or that `peer` is on loopback.
+fn authorize(context: &AppContext, request: &Request, peer: SocketAddr) -> Result<(), Response> {
+    match &context.cli_args.admin_token {
+        Some(token) => {
+            let given = request
+                .header("Authorization")
+                .and_then(|value| value.strip_prefix("Bearer "))
+                .unwrap_or_default();
+            match constant_time::verify_slices_are_equal(given.as_bytes(), token.as_bytes()) {
+                Ok(()) => Ok(()),
+                Err(_) => Err(Response::text(401, "Unauthorized\n")),
+            }
+        }
+        None if canonicalize_socket_addr(peer).ip().is_loopback() => Ok(()),
+        None => Err(Response::text(403, "Admin API is for loopback only\n")),
+    }
+}
+
+fn metrics(context: &AppContext, request: &Request) -> Response {
+    let openmetrics = context.cli_args.metrics_exemplars
+        && request
+            .header("Accept")
+            .is_some_and(|accept| accept.contains("application/openmetrics-text"));
+    let content_type = if openmetrics {
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    } else {
+        "text/plain; version=0.0.4; charset=utf-8"
+    };
+    Response {
+        content_type,
+        ..Response::text(200, metrics::render(context, openmetrics))
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ServerReport {
+    name: String,
+    group: Option<String>,
+    healthy: bool,
+    /// Whether any probe has succeeded, false for never-probed servers
+    ever_ok: bool,
+    /// Seconds since the server went healthy, `None` if it isn't
+    healthy_for_secs: Option<u64>,
+    /// Seconds since the server went unhealthy, `None` if it isn't
+    troubled_for_secs: Option<u64>,
+    draining: bool,
+    weight: u32,
+    inner_proto: InnerProto,
+    /// Why `inner_proto` was decided, `None` if configured or not probed
+    inner_proto_probe: Option<InnerProtoProbe>,
+    /// Probed but neither protocol worked, deprioritized on selection
+    proto_unknown: bool,
+    /// New conns this server couldn't take due to `inner_proto`
+    proto_mismatches: usize,
+    reply_addr_mismatches: usize,
+    /// Batches dropped for exceeding `tx_limit`
+    tx_limited: usize,
+    /// Datagram counts by size, bucketed at 512/1200/1500/2047 bytes
+    tx_sizes: [usize; SIZE_BUCKETS.len() + 1],
+    rx_sizes: [usize; SIZE_BUCKETS.len() + 1],
+}
+
+impl From<&SocksServer> for ServerReport {
+    fn from(server: &SocksServer) -> Self {
+        Self {
+            name: server.name.clone(),
+            group: server.group.clone(),
+            healthy: server.is_healthy(),
+            ever_ok: server.ever_ok(),
+            healthy_for_secs: server.healthy_since().map(|t| t.elapsed().as_secs()),
+            troubled_for_secs: server.troubled_since().map(|t| t.elapsed().as_secs()),
+            draining: server.is_draining(),
+            weight: server.weight,
+            inner_proto: server.inner_proto.get(),
+            inner_proto_probe: server.inner_proto_probe(),
+            proto_unknown: server.is_proto_unknown(),
+            proto_mismatches: server.proto_mismatches(),
+            reply_addr_mismatches: server.reply_addr_mismatches(),
+            tx_limited: server.tx_limited(),
+            tx_sizes: server.status.usage.tx_sizes.counts(),
+            rx_sizes: server.status.usage.rx_sizes.counts(),
+        }
+    }
+}
+
+fn servers(context: &AppContext) -> Response {
+    let reports: Vec<_> = context
+        .socks5_servers()
+        .iter()
+        .map(|server| ServerReport::from(server.as_ref()))
+        .collect();
+    Response::json(200, &reports)
+}
+
+/// Everything for a bug report, see `GET /diagnostics`.
+#[derive(Debug, Serialize)]
+struct Diagnostics {
+    version: &'static str,
+    /// Command line options with defaults filled, secrets redacted
+    config: String,
+    /// `None` if the forward service didn't answer
+    conns: Option<usize>,
+    tproxy_senders: usize,
+    reply_tasks: usize,
+    egress_limited: usize,
+    egress_shaped: usize,
+    /// Route lookups per forwarded batch, via existing sessions vs.
+    /// selecting upstreams anew
+    sticky_hits: usize,
+    sticky_misses: usize,
+    migrations: usize,
+    first_probe_done: bool,
+    servers: Vec<ServerDiagnostics>,
+}
+
+#[derive(Debug, Serialize)]
+struct ServerDiagnostics {
+    #[serde(flatten)]
+    report: ServerReport,
+    address: SocketAddr,
+    /// Average delay, loss & score
+    pings: String,
+    traffic: Traffic,
+    active_sessions: usize,
+    total_sessions: usize,
+}
+
+/// `GET /diagnostics`
+async fn diagnostics(context: &AppContext, admin: &mpsc::Sender<AdminQuery>) -> Response {
+    let (reply, rx) = oneshot::channel();
+    let conns = match admin.send(AdminQuery::ConnCount { reply }).await {
+        Ok(()) => rx.await.ok(),
+        Err(_) => None,
+    };
+    let args = context.cli_args;
+    let servers = context
+        .socks5_servers()
+        .iter()
+        .map(|server| {
+            let usage = &server.status.usage;
+            ServerDiagnostics {
+                report: server.as_ref().into(),
+                address: server.udp_addr,
+                pings: server.status.pings.lock().to_string(),
+                traffic: usage.traffic.get(),
+                active_sessions: usage.active_sessions(),
+                total_sessions: usage.total_sessions(),
+            }
+        })
+        .collect();
+    let diagnostics = Diagnostics {
+        version: env!("CARGO_PKG_VERSION"),
+        config: format!("{:?}", args),
+        conns,
+        tproxy_senders: context.stats.tproxy_senders.load(Ordering::Relaxed),
+        reply_tasks: args.max_reply_tasks - context.reply_tasks.available_permits(),
+        egress_limited: context.stats.egress_limited.load(Ordering::Relaxed),
+        egress_shaped: context.stats.egress_shaped.load(Ordering::Relaxed),
+        sticky_hits: context.stats.sticky_hits.load(Ordering::Relaxed),
+        sticky_misses: context.stats.sticky_misses.load(Ordering::Relaxed),
+        migrations: context.stats.migrations.load(Ordering::Relaxed),
+        first_probe_done: context.is_first_probe_done(),
+        servers,
+    };
+    Response::json(200, &diagnostics)
+}
+
+async fn drain(admin: &mpsc::Sender<AdminQuery>, name: &str) -> Response {
+    let (reply, rx) = oneshot::channel();
+    let query = AdminQuery::Drain {
+        server: name.to_string(),
+        reply,
+    };
+    if admin.send(query).await.is_err() {
+        return Response::text(503, "Forward service unavailable\n");
+    }
+    match rx.await {
+        Ok(Some(report)) => Response::json(200, &report),
+        Ok(None) => Response::text(404, "No such server\n"),
+        Err(_) => Response::text(503, "Forward service unavailable\n"),
+    }
+}
+
+/// `POST /servers/{name}/undrain`, let it take new conns again.
+fn undrain(context: &AppContext, name: &str) -> Response {
+    let server = match context.find_socks5_server(|p| p.name == name) {
+        Some(server) => server,
+        None => return Response::text(404, "No such server\n"),
+    };
+    if !server.undrain() {
+        return Response::text(409, "Removed on reload\n");
+    }
+    info!("Undrain upstream [{}]", server.name);
+    Response::json(200, &ServerReport::from(server.as_ref()))
+}
+
+/// `GET /explain?remote=<addr>[&sni=<name>][&client=<addr>]`
+async fn explain(admin: &mpsc::Sender<AdminQuery>, request: &Request) -> Response {
+    let addr = |name| request.param(name).map(str::parse::<SocketAddr>);
+    let remote = match addr("remote") {
+        Some(Ok(remote)) => remote.into(),
+        _ => return Response::text(400, "Invalid or missing remote address\n"),
+    };
+    let client = match addr("client") {
+        Some(Ok(client)) => Some(client.into()),
+        Some(Err(_)) => return Response::text(400, "Invalid client address\n"),
+        None => None,
+    };
+    let (reply, rx) = oneshot::channel();
+    let query = AdminQuery::Explain {
+        client,
+        remote,
+        sni: request.param("sni").map(String::from),
+        reply,
+    };
+    if admin.send(query).await.is_err() {
+        return Response::text(503, "Forward service unavailable\n");
+    }
+    match rx.await {
+        Ok(explanation) => Response::json(200, &explanation),
+        Err(_) => Response::text(503, "Forward service unavailable\n"),
+    }
+}
+
+fn ready(context: &AppContext) -> Response {
+    if !context.is_first_probe_done() {
+        Response::text(503, "Waiting for first probe cycle\n")
+    } else if context
+        .find_socks5_server(|s| {
+            // Servers start healthy, count only probed ones unless unchecked
+            let probed = s.ever_ok() || context.cli_args.no_check;
+            s.is_healthy() && !s.is_draining() && probed
+        })
+        .is_none()
+    {
+        Response::text(503, "No usable upstream\n")
+    } else {
+        Response::text(200, "OK\n")
+    }
+}
+
+#[test]
+fn test_parse_request() {
+    let req = parse_request(b"GET /ready?verbose HTTP/1.1\r\nHost: x\r\n\r\n").unwrap();
+    assert_eq!(req.method, "GET");
+    assert_eq!(req.path, "/ready");
+    assert_eq!(req.header("host"), Some("x"));
+    assert_eq!(req.param("verbose"), None);
+    let req = parse_request(b"GET /explain?remote=1.2.3.4:443&sni=a HTTP/1.1\r\n\r\n").unwrap();
+    assert_eq!(req.path, "/explain");
+    assert_eq!(req.param("remote"), Some("1.2.3.4:443"));
+    assert_eq!(req.param("sni"), Some("a"));
+    assert_eq!(req.param("client"), None);
+    assert!(parse_request(b"GET /ready\r\n\r\n").is_none());
+    assert!(parse_request(b"\xff\r\n\r\n").is_none());
+}
+
+#[test]
+fn test_compress_response() {
+    use std::io::Read;
+
+    assert_eq!(Encoding::negotiate("gzip, deflate"), Some(Encoding::Gzip));
+    assert_eq!(
+        Encoding::negotiate("gzip;q=0, deflate"),
+        Some(Encoding::Deflate)
+    );
+    assert_eq!(Encoding::negotiate("br, identity"), None);
+
+    let body = "quproxy_upstream_up{upstream=\"a\"} 1\n".repeat(100);
+    let small = Response::text(200, "OK\n").compress(Some("gzip"));
+    assert_eq!(small.content_encoding, None);
+    let plain = Response::text(200, body.clone()).compress(None);
+    assert_eq!(plain.content_encoding, None);
+    let gzip = Response::text(200, body.clone()).compress(Some("gzip"));
+    assert_eq!(gzip.content_encoding, Some("gzip"));
+    assert!(gzip.body.len() < body.len());
+    let mut decoded = String::new();
+    flate2::read::GzDecoder::new(&gzip.body[..])
+        .read_to_string(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, body);
+}
+
+#[tokio::test]
+async fn test_admin_auth() {
+    use clap::Parser;
+
+    let undrain = |auth: &str| {
+        let raw = format!(
+            "POST /servers/127.0.0.1:1080/undrain HTTP/1.1\r\n{}\r\n",
+            auth
+        );
+        parse_request(raw.as_bytes()).unwrap()
+    };
+    let local: SocketAddr = "127.0.0.1:9000".parse().unwrap();
+    let remote: SocketAddr = "[::ffff:192.0.2.1]:9000".parse().unwrap();
+    let (admin, _admin_rx) = mpsc::channel(1);
+
+    let args = crate::cli::CliArgs::parse_from(["quproxy", "-p", "0", "-u", "127.0.0.1:1080"]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let server = context.socks5_servers().pop().unwrap();
+    server.set_draining();
+    assert_eq!(
+        route(&context, &admin, &undrain(""), remote).await.status,
+        403
+    );
+    assert!(server.is_draining());
+    assert_eq!(
+        route(&context, &admin, &undrain(""), local).await.status,
+        200
+    );
+    assert!(!server.is_draining());
+    server.set_removed();
+    assert_eq!(
+        route(&context, &admin, &undrain(""), local).await.status,
+        409
+    );
+
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        "127.0.0.1:1080",
+        "--admin-token",
+        "s3cret",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let server = context.socks5_servers().pop().unwrap();
+    server.set_draining();
+    for auth in [
+        "",
+        "Authorization: Bearer s3cre\r\n",
+        "Authorization: s3cret\r\n",
+    ] {
+        assert_eq!(
+            route(&context, &admin, &undrain(auth), local).await.status,
+            401
+        );
+    }
+    assert!(server.is_draining());
+    let auth = "Authorization: Bearer s3cret\r\n";
+    assert_eq!(
+        route(&context, &admin, &undrain(auth), remote).await.status,
+        200
+    );
+    assert!(!server.is_draining());
+}
@@ -0,0 +1,293 @@
+use std::{
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use lru_time_cache::LruCache;
+use parking_lot::Mutex;
+
+use super::{types::ClientAddr, AppContext};
+
+/// Per-upstream rate limits from config file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RateLimits {
+    /// Bytes per second sending to upstream
+    pub(crate) tx: Option<u64>,
+    /// Bytes per second receiving from upstream
+    pub(crate) rx: Option<u64>,
+}
+
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    /// Bucket filled at `rate` tokens per second, hold at most one second of
+    /// tokens.
+    pub(crate) fn new(rate: u64) -> Self {
+        let rate = rate as f64;
+        Self {
+            rate,
+            burst: rate,
+            state: Mutex::new((rate, Instant::now())),
+        }
+    }
+
+    fn refill(&self) -> parking_lot::MutexGuard<'_, (f64, Instant)> {
+        let mut state = self.state.lock();
+        let (tokens, last) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * self.rate).min(self.burst);
+        *last = now;
+        state
+    }
+
+    /// Take `n` tokens if available, otherwise return the time to wait.
+    pub(crate) fn try_take(&self, n: usize) -> Result<(), Duration> {
+        let mut state = self.refill();
+        let tokens = &mut state.0;
+        let n = n as f64;
+        if *tokens >= n {
+            *tokens -= n;
+            Ok(())
+        } else if n > self.burst {
+            // Never be satisfied
+            Err(Duration::MAX)
+        } else {
+            Err(Duration::from_secs_f64((n - *tokens) / self.rate))
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PacketBuckets {
+    pps: Option<TokenBucket>,
+    bps: Option<TokenBucket>,
+}
+
+impl PacketBuckets {
+    fn new(pps: Option<u64>, bps: Option<u64>) -> Self {
+        Self {
+            pps: pps.map(TokenBucket::new),
+            bps: bps.map(TokenBucket::new),
+        }
+    }
+
+    /// Take a packet of `len` bytes only if both buckets have room for it,
+    /// leaving at least `reserve` packets in `pps`.
+    fn try_take(&self, len: usize, reserve: f64) -> bool {
+        let mut pps = self.pps.as_ref().map(TokenBucket::refill);
+        let mut bps = self.bps.as_ref().map(TokenBucket::refill);
+        let pps_ok = pps.as_ref().is_none_or(|state| state.0 - 1.0 >= reserve);
+        let bps_ok = bps.as_ref().is_none_or(|state| state.0 >= len as f64);
+        if !(pps_ok && bps_ok) {
+            return false;
+        }
+        if let Some(state) = &mut pps {
+            state.0 -= 1.0;
+        }
+        if let Some(state) = &mut bps {
+            state.0 -= len as f64;
+        }
+        true
+    }
+}
+
+/// How often the number of active clients is recounted for fair shares
+const ACTIVE_RECOUNT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A client's share of the global packet rate.
+struct FairShare {
+    tokens: f64,
+    last: Instant,
+}
+
+/// Global packet rate split evenly among client IPs seen within the session
+/// timeout, see `--egress-fair-share`.
+struct FairShares {
+    rate: f64,
+    clients: LruCache<IpAddr, FairShare>,
+    /// Number of active clients & when it was counted
+    active: (usize, Instant),
+}
+
+impl FairShares {
+    /// Take a packet from `client`'s share, return false if it's used up.
+    fn try_take(&mut self, client: IpAddr) -> bool {
+        let now = Instant::now();
+        if !self.clients.contains_key(&client) {
+            // Starts full, capped below
+            let share = FairShare {
+                tokens: f64::MAX,
+                last: now,
+            };
+            self.clients.insert(client, share);
+            self.active = (self.clients.len(), now);
+        } else if now.duration_since(self.active.1) >= ACTIVE_RECOUNT_INTERVAL {
+            self.active = (self.clients.len(), now);
+        }
+        let rate = self.rate / self.active.0.max(1) as f64;
+        let share = match self.clients.get_mut(&client) {
+            Some(share) => share,
+            None => return false,
+        };
+        share.tokens =
+            (share.tokens + now.duration_since(share.last).as_secs_f64() * rate).min(rate);
+        share.last = now;
+        if share.tokens >= 1.0 {
+            share.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Why a packet is refused by `EgressLimiter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EgressDrop {
+    /// Over a global or per-client cap
+    Limited,
+    /// Over its fair share while the global cap is nearly reached
+    Shaped,
+}
+
+/// Global and per-client caps on packets forwarded to upstreams, as a
+/// defense-in-depth against amplification abuse.
+pub(crate) struct EgressLimiter {
+    global: PacketBuckets,
+    client_limits: (Option<u64>, Option<u64>),
+    clients: Option<LruCache<IpAddr, PacketBuckets>>,
+    fair_shares: Option<FairShares>,
+}
+
+impl EgressLimiter {
+    pub(crate) fn new(context: &AppContext) -> Self {
+        let args = context.cli_args;
+        let client_limits = (args.max_client_egress_pps, args.max_client_egress_bps);
+        let clients = match client_limits {
+            (None, None) => None,
+            _ => Some(context.new_lru_cache_for_sessions()),
+        };
+        let fair_shares = match args.max_egress_pps {
+            Some(rate) if args.egress_fair_share => Some(FairShares {
+                rate: rate as f64,
+                clients: context.new_lru_cache_for_sessions(),
+                active: (0, Instant::now()),
+            }),
+            _ => None,
+        };
+        Self {
+            global: PacketBuckets::new(args.max_egress_pps, args.max_egress_bps),
+            client_limits,
+            clients,
+            fair_shares,
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.global.pps.is_some() || self.global.bps.is_some() || self.clients.is_some()
+    }
+
+    /// Check if a packet of `len` bytes from `client` is allowed.
+    /// Clients are told apart by IP only, so rotating source ports doesn't
+    /// get more of the caps.
+    pub(crate) fn allow(&mut self, client: ClientAddr, len: usize) -> Result<(), EgressDrop> {
+        let ip = client.0.ip();
+        if let Some(clients) = &mut self.clients {
+            let (pps, bps) = self.client_limits;
+            let buckets = clients
+                .entry(ip)
+                .or_insert_with(|| PacketBuckets::new(pps, bps));
+            if !buckets.try_take(len, 0.0) {
+                return Err(EgressDrop::Limited);
+            }
+        }
+        let over_share = self
+            .fair_shares
+            .as_mut()
+            .is_some_and(|shares| !shares.try_take(ip));
+        match &self.fair_shares {
+            // Beyond its share, a client only gets the spare half
+            Some(shares) if over_share => {
+                if !self.global.try_take(len, shares.rate / 2.0) {
+                    return Err(EgressDrop::Shaped);
+                }
+            }
+            _ => {
+                if !self.global.try_take(len, 0.0) {
+                    return Err(EgressDrop::Limited);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let bucket = TokenBucket::new(1000);
+    assert!(bucket.try_take(600).is_ok());
+    let wait = bucket.try_take(600).unwrap_err();
+    assert!(wait > Duration::from_millis(150) && wait <= Duration::from_millis(200));
+    assert_eq!(bucket.try_take(2000), Err(Duration::MAX));
+}
+
+#[test]
+fn test_egress_fair_share() {
+    use clap::Parser;
+
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "--max-egress-pps",
+        "100",
+        "--egress-fair-share",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let mut limiter = EgressLimiter::new(&context);
+    let light = ClientAddr(([10, 0, 0, 1], 1).into());
+    let heavy = ClientAddr(([10, 0, 0, 2], 1).into());
+    assert!(limiter.allow(light, 1200).is_ok());
+    // Half of the rate, the rest is kept for the other client
+    let allowed = (0..200)
+        .filter(|_| limiter.allow(heavy, 1200).is_ok())
+        .count();
+    assert_eq!(allowed, 50);
+    assert_eq!(limiter.allow(heavy, 1200), Err(EgressDrop::Shaped));
+    // Another port of the same IP shares the same share
+    let heavy_alt = ClientAddr(([10, 0, 0, 2], 2).into());
+    assert_eq!(limiter.allow(heavy_alt, 1200), Err(EgressDrop::Shaped));
+    assert!(limiter.allow(light, 1200).is_ok());
+}
+
+#[test]
+fn test_egress_pps_bps() {
+    use clap::Parser;
+
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "--max-egress-pps",
+        "10",
+        "--max-egress-bps",
+        "5000",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let mut limiter = EgressLimiter::new(&context);
+    let client = ClientAddr(([10, 0, 0, 1], 1).into());
+    assert!(limiter.allow(client, 4000).is_ok());
+    // Refused by bps, must not take any pps
+    for _ in 0..20 {
+        assert_eq!(limiter.allow(client, 2000), Err(EgressDrop::Limited));
+    }
+    let allowed = (0..20)
+        .filter(|_| limiter.allow(client, 10).is_ok())
+        .count();
+    assert_eq!(allowed, 9);
+}
@@ -0,0 +1,503 @@
+use std::{fmt::Write, sync::atomic::Ordering, time::UNIX_EPOCH};
+
+use super::{
+    checking::{Healthy, PingExemplar},
+    quic::NameLookup,
+    stats::{SizeHistogram, SIZE_BUCKETS},
+    AppContext,
+};
+
+/// A metric with all its samples, each one is (labels, value, exemplar).
+struct Family {
+    name: &'static str,
+    kind: &'static str,
+    help: &'static str,
+    samples: Vec<(String, f64, Option<String>)>,
+}
+
+impl Family {
+    fn new(name: &'static str, kind: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            kind,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn add<V: Into<f64>>(&mut self, labels: String, value: V) {
+        self.samples.push((labels, value.into(), None));
+    }
+
+    /// Add a sample with an exemplar (`{labels} value timestamp`), which is
+    /// written out in OpenMetrics format only.
+    fn add_with_exemplar<V: Into<f64>>(&mut self, labels: String, value: V, exemplar: String) {
+        self.samples.push((labels, value.into(), Some(exemplar)));
+    }
+
+    /// Add `_bucket`, `_sum` & `_count` samples of a histogram, `labels`
+    /// is without braces and may be empty.
+    fn add_histogram(&mut self, labels: &str, histogram: &SizeHistogram) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut count = 0;
+        let bounds = SIZE_BUCKETS.iter().map(|b| b.to_string());
+        for (le, n) in bounds.chain(["+Inf".into()]).zip(histogram.counts()) {
+            count += n;
+            let labels = format!("_bucket{{{}{}le=\"{}\"}}", labels, sep, le);
+            self.add(labels, count as f64);
+        }
+        self.add(format!("_sum{{{}}}", labels), histogram.sum() as f64);
+        self.add(format!("_count{{{}}}", labels), count as f64);
+    }
+
+    fn write_to(&self, out: &mut String, openmetrics: bool) {
+        // OpenMetrics names counter families without the `_total` suffix
+        let family = match self.name.strip_suffix("_total") {
+            Some(name) if openmetrics && self.kind == "counter" => name,
+            _ => self.name,
+        };
+        writeln!(out, "# HELP quproxy_{} {}", family, self.help).unwrap();
+        writeln!(out, "# TYPE quproxy_{} {}", family, self.kind).unwrap();
+        for (labels, value, exemplar) in &self.samples {
+            write!(out, "quproxy_{}{} {}", self.name, labels, value).unwrap();
+            match exemplar {
+                Some(exemplar) if openmetrics => writeln!(out, " # {}", exemplar).unwrap(),
+                _ => writeln!(out).unwrap(),
+            }
+        }
+    }
+}
+
+fn format_exemplar(exemplar: &PingExemplar) -> String {
+    let timestamp = exemplar
+        .at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+    format!(
+        "{{probe_id=\"{:016x}\"}} {} {:.3}",
+        exemplar.probe_id,
+        exemplar.delay.as_secs_f64(),
+        timestamp
+    )
+}
+
+/// Render metrics in Prometheus text exposition format, or in OpenMetrics
+/// format with exemplars linking ping counts to logs of the latest probe.
+pub(crate) fn render(context: &AppContext, openmetrics: bool) -> String {
+    let mut up = Family::new("upstream_up", "gauge", "Whether upstream is healthy");
+    let mut sessions = Family::new("upstream_sessions", "gauge", "Active SOCKSv5 sessions");
+    let mut sessions_total = Family::new(
+        "upstream_sessions_total",
+        "counter",
+        "SOCKSv5 sessions opened",
+    );
+    let mut tx_bytes = Family::new("upstream_tx_bytes_total", "counter", "Bytes sent");
+    let mut rx_bytes = Family::new("upstream_rx_bytes_total", "counter", "Bytes received");
+    let mut ping = Family::new("upstream_ping_seconds", "gauge", "Average ping delay");
+    let mut loss = Family::new("upstream_ping_loss_ratio", "gauge", "Ping loss ratio");
+    let mut jitter = Family::new("upstream_ping_jitter_seconds", "gauge", "Ping jitter");
+    let mut pings = Family::new("upstream_pings_total", "counter", "Pings by result");
+    let mut mismatches = Family::new(
+        "upstream_proto_mismatches_total",
+        "counter",
+        "New conns skipped for inner protocol",
+    );
+    let mut addr_mismatches = Family::new(
+        "upstream_reply_addr_mismatches_total",
+        "counter",
+        "SOCKSv5 UDP replies from other than the target, with --validate-socks-reply-addr",
+    );
+    let mut tx_limited = Family::new(
+        "upstream_tx_limited_total",
+        "counter",
+        "Batches dropped for exceeding tx_limit",
+    );
+    let mut malformed = Family::new(
+        "upstream_malformed_replies_total",
+        "counter",
+        "Undecodable SOCKSv5 UDP replies",
+    );
+    let mut sizes = Family::new(
+        "upstream_datagram_size_bytes",
+        "histogram",
+        "Sizes of datagrams via upstream",
+    );
+
+    for server in context.socks5_servers() {
+        let labels = format!("{{upstream=\"{}\"}}", escape(&server.name));
+        let usage = &server.status.usage;
+        let traffic = usage.traffic.get();
+        up.add(labels.clone(), server.is_healthy() as u8);
+        malformed.add(labels.clone(), server.malformed_replies() as f64);
+        mismatches.add(labels.clone(), server.proto_mismatches() as f64);
+        addr_mismatches.add(labels.clone(), server.reply_addr_mismatches() as f64);
+        tx_limited.add(labels.clone(), server.tx_limited() as f64);
+        sessions.add(labels.clone(), usage.active_sessions() as f64);
+        sessions_total.add(labels.clone(), usage.total_sessions() as f64);
+        tx_bytes.add(labels.clone(), traffic.tx_bytes as f64);
+        rx_bytes.add(labels.clone(), traffic.rx_bytes as f64);
+        for (direction, histogram) in [("tx", &usage.tx_sizes), ("rx", &usage.rx_sizes)] {
+            let labels = format!(
+                "upstream=\"{}\",direction=\"{}\"",
+                escape(&server.name),
+                direction
+            );
+            sizes.add_histogram(&labels, histogram);
+        }
+        let history = server.status.pings.lock().clone();
+        if let Some(delay) = history.average_delay() {
+            ping.add(labels.clone(), delay.as_secs_f64());
+            loss.add(labels.clone(), history.loss_percent() as f64 / 100.0);
+        }
+        if let Some(delay) = history.jitter() {
+            jitter.add(labels, delay.as_secs_f64());
+        }
+        for (result, n) in server.status.ping_stats.counts() {
+            let labels = format!(
+                "{{upstream=\"{}\",result=\"{}\"}}",
+                escape(&server.name),
+                result
+            );
+            let exemplar = *server.status.last_ping_exemplar.lock();
+            match exemplar {
+                Some(exemplar) if result == "reachable" => {
+                    pings.add_with_exemplar(labels, n as f64, format_exemplar(&exemplar))
+                }
+                _ => pings.add(labels, n as f64),
+            }
+        }
+    }
+
+    let stats = &context.stats;
+    let mut empty = Family::new(
+        "empty_datagrams_total",
+        "counter",
+        "Zero-length datagrams dropped",
+    );
+    empty.add(
+        String::new(),
+        stats.empty_datagrams.load(Ordering::Relaxed) as f64,
+    );
+    let mut limited = Family::new(
+        "egress_limited_total",
+        "counter",
+        "Packets dropped by egress limits",
+    );
+    limited.add(
+        String::new(),
+        stats.egress_limited.load(Ordering::Relaxed) as f64,
+    );
+    let mut shaped = Family::new(
+        "egress_shaped_total",
+        "counter",
+        "Packets dropped for exceeding their client's fair share",
+    );
+    shaped.add(
+        String::new(),
+        stats.egress_shaped.load(Ordering::Relaxed) as f64,
+    );
+
+    let mut resets = Family::new(
+        "reset_teardowns_total",
+        "counter",
+        "Conns closed on suspected stateless resets",
+    );
+    resets.add(
+        String::new(),
+        stats.reset_teardowns.load(Ordering::Relaxed) as f64,
+    );
+    let mut evictions = Family::new(
+        "lifetime_evictions_total",
+        "counter",
+        "Conns evicted for exceeding max lifetime",
+    );
+    evictions.add(
+        String::new(),
+        stats.lifetime_evictions.load(Ordering::Relaxed) as f64,
+    );
+
+    let mut exhausted = Family::new(
+        "reply_tasks_exhausted_total",
+        "counter",
+        "New conns refused for too many reply tasks",
+    );
+    exhausted.add(
+        String::new(),
+        stats.reply_tasks_exhausted.load(Ordering::Relaxed) as f64,
+    );
+    let mut tasks = Family::new("reply_tasks", "gauge", "Live reply-forwarding tasks");
+    tasks.add(
+        String::new(),
+        (context.cli_args.max_reply_tasks - context.reply_tasks.available_permits()) as f64,
+    );
+
+    let mut malformed_proxy = Family::new(
+        "proxy_protocol_malformed_total",
+        "counter",
+        "Datagrams dropped for malformed PROXY protocol header",
+    );
+    malformed_proxy.add(
+        String::new(),
+        stats.proxy_protocol_malformed.load(Ordering::Relaxed) as f64,
+    );
+    let mut reassembly_bytes = Family::new(
+        "reassembly_bytes",
+        "gauge",
+        "Bytes held for reassembling SOCKSv5 UDP fragments",
+    );
+    reassembly_bytes.add(String::new(), context.reassembly_mem.used() as f64);
+    let mut reassembly_dropped = Family::new(
+        "reassembly_mem_dropped_total",
+        "counter",
+        "SOCKSv5 UDP fragments dropped for --max-reassembly-mem",
+    );
+    reassembly_dropped.add(String::new(), context.reassembly_mem.dropped() as f64);
+
+    let mut untrusted_proxy = Family::new(
+        "proxy_protocol_untrusted_total",
+        "counter",
+        "Datagrams dropped for coming from peers not in --proxy-protocol-from",
+    );
+    untrusted_proxy.add(
+        String::new(),
+        stats.proxy_protocol_untrusted.load(Ordering::Relaxed) as f64,
+    );
+
+    let mut initial_resends = Family::new(
+        "initial_resends_total",
+        "counter",
+        "First Initial packets re-sent for no reply",
+    );
+    initial_resends.add(
+        String::new(),
+        stats.initial_resends.load(Ordering::Relaxed) as f64,
+    );
+    let mut decode_overflows = Family::new(
+        "decode_overflows_total",
+        "counter",
+        "Datagrams of new conns dropped for full decode queue",
+    );
+    decode_overflows.add(
+        String::new(),
+        stats.decode_overflows.load(Ordering::Relaxed) as f64,
+    );
+    let mut decode_pending_drops = Family::new(
+        "decode_pending_drops_total",
+        "counter",
+        "Batches of conns being decoded dropped for too many pending",
+    );
+    decode_pending_drops.add(
+        String::new(),
+        stats.decode_pending_drops.load(Ordering::Relaxed) as f64,
+    );
+    let mut self_destined = Family::new(
+        "self_destined_total",
+        "counter",
+        "Datagrams dropped for being destined to quproxy itself",
+    );
+    self_destined.add(
+        String::new(),
+        stats.self_destined.load(Ordering::Relaxed) as f64,
+    );
+    let mut version_negotiations = Family::new(
+        "version_negotiations_total",
+        "counter",
+        "Version Negotiation packets replied by remotes",
+    );
+    version_negotiations.add(
+        String::new(),
+        stats.version_negotiations.load(Ordering::Relaxed) as f64,
+    );
+    let mut forged_version_negotiations = Family::new(
+        "forged_version_negotiations_total",
+        "counter",
+        "Version Negotiation packets sent to clients for --force-quic-v1",
+    );
+    forged_version_negotiations.add(
+        String::new(),
+        stats.forged_version_negotiations.load(Ordering::Relaxed) as f64,
+    );
+    let mut alpn_dropped = Family::new(
+        "alpn_dropped_total",
+        "counter",
+        "New conns dropped by --allow-alpn & --deny-alpn",
+    );
+    alpn_dropped.add(
+        String::new(),
+        stats.alpn_dropped.load(Ordering::Relaxed) as f64,
+    );
+    let mut client_port_follows = Family::new(
+        "client_port_follows_total",
+        "counter",
+        "Conns followed to a new client port by QUIC connection ID",
+    );
+    client_port_follows.add(
+        String::new(),
+        stats.client_port_follows.load(Ordering::Relaxed) as f64,
+    );
+    let mut loop_suspects = Family::new(
+        "loop_suspects_total",
+        "counter",
+        "Windows of traffic suggesting a routing loop or a scan",
+    );
+    loop_suspects.add(
+        String::new(),
+        stats.loop_suspects.load(Ordering::Relaxed) as f64,
+    );
+    let mut oversize_replies = Family::new(
+        "oversize_replies_total",
+        "counter",
+        "Replies dropped for exceeding --client-mtu",
+    );
+    oversize_replies.add(
+        String::new(),
+        stats.oversize_replies.load(Ordering::Relaxed) as f64,
+    );
+    let mut routing = Family::new(
+        "route_decisions_total",
+        "counter",
+        "Route lookups per forwarded batch, sticky or selected anew",
+    );
+    for (decision, counter) in [
+        ("sticky", &stats.sticky_hits),
+        ("selected", &stats.sticky_misses),
+        ("migrated", &stats.migrations),
+    ] {
+        routing.add(
+            format!("{{decision=\"{}\"}}", decision),
+            counter.load(Ordering::Relaxed) as f64,
+        );
+    }
+
+    let mut new_conns = Family::new(
+        "new_conns_total",
+        "counter",
+        "New conns by outcome of server name lookup",
+    );
+    for lookup in NameLookup::ALL {
+        new_conns.add(
+            format!("{{name_lookup=\"{}\"}}", lookup.label()),
+            stats.name_lookups[lookup as usize].load(Ordering::Relaxed) as f64,
+        );
+    }
+
+    let mut senders = Family::new(
+        "tproxy_senders",
+        "gauge",
+        "Live sockets for replying to clients",
+    );
+    senders.add(
+        String::new(),
+        stats.tproxy_senders.load(Ordering::Relaxed) as f64,
+    );
+
+    let mut global_sizes = Family::new(
+        "datagram_size_bytes",
+        "histogram",
+        "Sizes of datagrams forwarded",
+    );
+    for (direction, histogram) in [("tx", &stats.tx_sizes), ("rx", &stats.rx_sizes)] {
+        global_sizes.add_histogram(&format!("direction=\"{}\"", direction), histogram);
+    }
+
+    let mut out = String::new();
+    [
+        up,
+        sessions,
+        sessions_total,
+        tx_bytes,
+        rx_bytes,
+        sizes,
+        ping,
+        loss,
+        jitter,
+        pings,
+        malformed,
+        mismatches,
+        addr_mismatches,
+        tx_limited,
+        empty,
+        limited,
+        shaped,
+        resets,
+        evictions,
+        exhausted,
+        tasks,
+        malformed_proxy,
+        untrusted_proxy,
+        reassembly_bytes,
+        reassembly_dropped,
+        initial_resends,
+        decode_overflows,
+        decode_pending_drops,
+        self_destined,
+        version_negotiations,
+        forged_version_negotiations,
+        alpn_dropped,
+        client_port_follows,
+        loop_suspects,
+        oversize_replies,
+        routing,
+        new_conns,
+        senders,
+        global_sizes,
+    ]
+    .iter()
+    .for_each(|family| family.write_to(&mut out, openmetrics));
+    if openmetrics {
+        out.push_str("# EOF\n");
+    }
+    out
+}
+
+/// Escape label value per the exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[test]
+fn test_write_exemplar() {
+    use std::time::Duration;
+
+    let mut family = Family::new("pings_total", "counter", "Pings");
+    let exemplar = PingExemplar {
+        probe_id: 42,
+        delay: Duration::from_millis(125),
+        at: UNIX_EPOCH + Duration::from_secs(1000),
+    };
+    family.add_with_exemplar("{result=\"ok\"}".into(), 3, format_exemplar(&exemplar));
+
+    let mut text = String::new();
+    family.write_to(&mut text, false);
+    assert!(text.contains("# TYPE quproxy_pings_total counter\n"));
+    assert!(text.ends_with("quproxy_pings_total{result=\"ok\"} 3\n"));
+
+    let mut text = String::new();
+    family.write_to(&mut text, true);
+    assert!(text.contains("# TYPE quproxy_pings counter\n"));
+    assert!(text.ends_with(
+        "quproxy_pings_total{result=\"ok\"} 3 # {probe_id=\"000000000000002a\"} 0.125 1000.000\n"
+    ));
+}
+
+#[test]
+fn test_write_histogram() {
+    let histogram = SizeHistogram::default();
+    histogram.record(1200);
+    histogram.record(1500);
+    histogram.record(2048);
+    let mut family = Family::new("datagram_size_bytes", "histogram", "Sizes");
+    family.add_histogram("direction=\"tx\"", &histogram);
+
+    let mut text = String::new();
+    family.write_to(&mut text, false);
+    assert!(text.contains("quproxy_datagram_size_bytes_bucket{direction=\"tx\",le=\"512\"} 0\n"));
+    assert!(text.contains("quproxy_datagram_size_bytes_bucket{direction=\"tx\",le=\"1500\"} 2\n"));
+    assert!(text.contains("quproxy_datagram_size_bytes_bucket{direction=\"tx\",le=\"+Inf\"} 3\n"));
+    assert!(text.contains("quproxy_datagram_size_bytes_sum{direction=\"tx\"} 4748\n"));
+    assert!(text.ends_with("quproxy_datagram_size_bytes_count{direction=\"tx\"} 3\n"));
+}
@@ -9,15 +9,23 @@ macro_rules! io_error {
 
 mod checking;
 mod context;
+mod dns;
+mod http;
+mod limit;
+mod metrics;
 mod net;
 mod quic;
 mod socks5;
+mod stats;
 mod status;
 mod tproxy;
 pub(crate) mod types;
 
 pub(crate) use checking::CheckingService;
 pub(crate) use context::AppContext;
-pub(crate) use socks5::{InnerProto, SocksForwardService, SocksReferService};
+pub(crate) use http::HttpService;
+pub(crate) use net::{check_kernel_support, enter_netns};
+pub(crate) use quic::bench_decode;
+pub(crate) use socks5::{AdminQuery, InnerProto, SocksForwardService, SocksReferService};
 pub(crate) use status::ServerStatus;
 pub(crate) use tproxy::TProxyReceiver;
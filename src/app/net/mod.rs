@@ -1,6 +1,10 @@
+mod netns;
+mod preflight;
 mod socket;
 
 pub(crate) const UDP_MAX_SIZE: usize = 2048;
 pub(crate) const UDP_BATCH_SIZE: usize = 16;
 
-pub(crate) use socket::{AsyncUdpSocket, MsgArrayReadBuffer, MsgArrayWriteBuffer};
+pub(crate) use netns::{enter_netns, in_netns};
+pub(crate) use preflight::check_kernel_support;
+pub(crate) use socket::{AsyncUdpSocket, EgressOpts, MsgArrayReadBuffer, MsgArrayWriteBuffer};
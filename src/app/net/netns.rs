@@ -0,0 +1,98 @@
+use std::{
+    fs::File,
+    io,
+    os::unix::prelude::AsRawFd,
+    path::PathBuf,
+    sync::{mpsc, OnceLock},
+    thread,
+};
+
+use nix::{errno::Errno, sched::CloneFlags};
+use tokio::sync::oneshot;
+use tracing::info;
+
+/// Where `ip netns add` puts named namespaces
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A thread that has entered the namespace, as `setns()` applies to the
+/// calling thread only, and runtime threads must stay where they are.
+static WORKER: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+/// Resolve `--netns` to a path, which is either a name or a path.
+fn netns_path(netns: &str) -> PathBuf {
+    if netns.contains('/') {
+        netns.into()
+    } else {
+        [NETNS_RUN_DIR, netns].iter().collect()
+    }
+}
+
+/// Start the worker in namespace `netns` (name or path), sockets created
+/// by `in_netns()` after that are in the namespace. CAP_SYS_ADMIN required.
+pub(crate) fn enter_netns(netns: &str) -> io::Result<()> {
+    let path = netns_path(netns);
+    let file = File::open(&path)
+        .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+    let (job_tx, job_rx) = mpsc::channel::<Job>();
+    let (ready_tx, ready_rx) = mpsc::channel();
+    thread::Builder::new().name("netns".into()).spawn(move || {
+        let result = nix::sched::setns(file.as_raw_fd(), CloneFlags::CLONE_NEWNET);
+        let entered = result.is_ok();
+        ready_tx.send(result).unwrap();
+        if entered {
+            job_rx.into_iter().for_each(|job| job());
+        }
+    })?;
+    match ready_rx.recv().unwrap() {
+        Ok(()) => (),
+        Err(Errno::EPERM) => io_error!(
+            PermissionDenied,
+            format!(
+                "Not permitted to enter {}, CAP_SYS_ADMIN required (e.g. run with \
+                 `setcap cap_net_admin,cap_sys_admin+ep` or AmbientCapabilities=)",
+                path.display()
+            )
+        ),
+        Err(err) => return Err(err.into()),
+    }
+    if WORKER.set(job_tx).is_err() {
+        io_error!(AlreadyExists, "Network namespace already entered");
+    }
+    info!("Egress sockets in network namespace {}", path.display());
+    Ok(())
+}
+
+/// Run `f` in the namespace entered by `enter_netns()`, or in place if
+/// none. Sockets keep their namespace once created.
+pub(crate) async fn in_netns<T, F>(f: F) -> io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> io::Result<T> + Send + 'static,
+{
+    let worker = match WORKER.get() {
+        Some(worker) => worker,
+        None => return f(),
+    };
+    let (tx, rx) = oneshot::channel();
+    let job = Box::new(move || {
+        let _ = tx.send(f());
+    });
+    if worker.send(job).is_err() {
+        io_error!("Network namespace worker exited");
+    }
+    match rx.await {
+        Ok(result) => result,
+        Err(_) => io_error!("Network namespace job dropped"),
+    }
+}
+
+#[test]
+fn test_netns_path() {
+    assert_eq!(PathBuf::from("/var/run/netns/vpn"), netns_path("vpn"));
+    assert_eq!(
+        PathBuf::from("/proc/1/ns/net"),
+        netns_path("/proc/1/ns/net")
+    );
+}
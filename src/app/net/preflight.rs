@@ -0,0 +1,103 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    os::unix::prelude::AsRawFd,
+    ptr,
+};
+
+use nix::errno::Errno;
+use socket2::Socket;
+use tracing::{debug, error, info, warn};
+
+use super::socket::{new_socket, SocketExt};
+
+/// Result of probing one kernel feature on a throwaway socket.
+#[derive(Debug)]
+struct Probe {
+    feature: &'static str,
+    result: io::Result<()>,
+}
+
+/// Probe kernel features needed by TProxy & batch I/O, on sockets of the
+/// same family as `addr`, and log what's missing.
+pub(crate) fn check_kernel_support(addr: &SocketAddr) {
+    let probes = probe_all(addr);
+    for probe in &probes {
+        match &probe.result {
+            Ok(()) => debug!("Kernel support: {} OK", probe.feature),
+            Err(err) if err.kind() == ErrorKind::PermissionDenied => warn!(
+                "Kernel support: {} not permitted ({}), CAP_NET_ADMIN required",
+                probe.feature, err
+            ),
+            Err(err) => warn!("Kernel support: {} missing ({})", probe.feature, err),
+        }
+    }
+    let missing: Vec<_> = probes
+        .iter()
+        .filter(|p| p.result.is_err())
+        .map(|p| p.feature)
+        .collect();
+    if missing.is_empty() {
+        info!("Kernel features for TProxy are present");
+    } else {
+        error!(
+            "Missing kernel features: {}; TProxy may not work",
+            missing.join(", ")
+        );
+    }
+    // TPROXY rules & routing of local table aren't visible to sockets
+    debug!("TPROXY iptables/nftables rules and policy routing are not checked");
+}
+
+fn probe_all(addr: &SocketAddr) -> Vec<Probe> {
+    let probe = |feature, f: fn(&Socket, &SocketAddr) -> io::Result<()>| Probe {
+        feature,
+        result: new_socket(addr).and_then(|sock| f(&sock, addr)),
+    };
+    vec![
+        probe("recvmmsg", probe_recvmmsg),
+        probe("IP_TRANSPARENT", |sock, _| sock.set_ip_transparent(true)),
+        probe("IP_RECVORIGDSTADDR", |sock, _| {
+            sock.set_ip_recv_orig_dst_addr(true)
+        }),
+        probe("IP_PKTINFO", |sock, _| sock.set_ip_recv_pktinfo(true)),
+        probe("non-local bind", probe_nonlocal_bind),
+    ]
+}
+
+/// Nothing to read on a fresh non-blocking socket, so it should fail with
+/// `EAGAIN`, not `ENOSYS`.
+fn probe_recvmmsg(sock: &Socket, _: &SocketAddr) -> io::Result<()> {
+    let mut buf = [0u8; 1];
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut _,
+        iov_len: buf.len(),
+    };
+    let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+    msg.msg_hdr.msg_iov = &mut iov;
+    msg.msg_hdr.msg_iovlen = 1;
+    let ret = unsafe { libc::recvmmsg(sock.as_raw_fd(), &mut msg, 1, 0, ptr::null_mut()) };
+    match Errno::result(ret) {
+        Ok(_) | Err(Errno::EAGAIN) => Ok(()),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// Senders bind on remote addresses, which needs IP_TRANSPARENT to work.
+fn probe_nonlocal_bind(sock: &Socket, addr: &SocketAddr) -> io::Result<()> {
+    // TEST-NET-1 & documentation prefix, should never be local
+    let ip: IpAddr = match addr {
+        SocketAddr::V4(_) => Ipv4Addr::new(192, 0, 2, 1).into(),
+        SocketAddr::V6(_) => Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+    };
+    sock.set_ip_transparent(true)?;
+    sock.bind(&SocketAddr::new(ip, 0).into())
+}
+
+#[test]
+fn test_probe_recvmmsg() {
+    let addr = ([127, 0, 0, 1], 0).into();
+    let probes = probe_all(&addr);
+    assert_eq!(probes.len(), 5);
+    assert!(probes[0].result.is_ok());
+}
@@ -3,25 +3,42 @@ use std::{
     io::{self, ErrorKind},
     marker::PhantomPinned,
     mem,
-    net::SocketAddr,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     os::unix::prelude::AsRawFd,
     pin::Pin,
     ptr,
+    sync::atomic::{AtomicUsize, Ordering},
     task::{Context, Poll},
 };
 
 use bytes::Bytes;
 use futures::ready;
-use libc::{setsockopt, IPPROTO_IP, IPPROTO_IPV6, IPV6_RECVORIGDSTADDR, IP_RECVORIGDSTADDR};
+use libc::{
+    setsockopt, IPPROTO_IP, IPPROTO_IPV6, IPV6_RECVORIGDSTADDR, IPV6_RECVPKTINFO, IPV6_TCLASS,
+    IP_PKTINFO, IP_RECVORIGDSTADDR, IP_TOS, SOL_SOCKET, SO_MARK,
+};
 use nix::errno::Errno;
 use socket2::{Domain, Protocol, SockAddr, Socket, Type};
 use tokio::{io::unix::AsyncFd, net::UdpSocket};
 use tracing::warn;
 
+use super::netns::in_netns;
+
 pub(crate) struct AsyncUdpSocket {
     inner: AsyncFd<Socket>,
 }
 
+/// Options of sockets toward upstreams.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct EgressOpts {
+    /// Local address to send from, picked by the kernel if `None`
+    pub(crate) bind_from: Option<IpAddr>,
+    /// SO_MARK for policy routing & firewall rules, CAP_NET_ADMIN required
+    pub(crate) fwmark: Option<u32>,
+    /// DSCP (0 to 63) in IP header
+    pub(crate) dscp: Option<u8>,
+}
+
 impl AsyncUdpSocket {
     fn bind(sock: Socket, addr: &SocketAddr) -> io::Result<Self> {
         sock.bind(&(*addr).into())?;
@@ -30,28 +47,61 @@ impl AsyncUdpSocket {
         })
     }
 
-    pub(crate) fn connect(addr: &SocketAddr) -> io::Result<Self> {
-        let sock = new_socket(addr)?;
+    pub(crate) async fn connect(addr: &SocketAddr, opts: &EgressOpts) -> io::Result<Self> {
+        let sock = new_egress_socket(addr, opts).await?;
+        if let Some(ip) = opts.bind_from {
+            sock.bind(&SocketAddr::new(ip, 0).into())?;
+        }
         sock.connect(&(*addr).into())?;
         Ok(Self {
             inner: AsyncFd::new(sock)?,
         })
     }
 
+    /// Bind on an ephemeral port of `bind_from`, or any address of the same
+    /// family as `peer`, but not connect to it, so that replies from other
+    /// addresses are received.
+    pub(crate) async fn unconnected(peer: &SocketAddr, opts: &EgressOpts) -> io::Result<Self> {
+        let ip = opts.bind_from.unwrap_or_else(|| unspecified(peer));
+        AsyncUdpSocket::bind(new_egress_socket(peer, opts).await?, &(ip, 0).into())
+    }
+
+    /// Bind on an ephemeral port of any address of the same family as
+    /// `peer`, in the original namespace, for replies to clients.
+    pub(crate) fn bind_any(peer: &SocketAddr) -> io::Result<Self> {
+        AsyncUdpSocket::bind(new_socket(peer)?, &(unspecified(peer), 0).into())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        let addr = self.inner.get_ref().local_addr()?;
+        addr.as_socket()
+            .ok_or_else(|| io::Error::other("Not an IP socket"))
+    }
+
     pub(crate) fn bind_tproxy(addr: &SocketAddr) -> io::Result<Self> {
         let sock = new_socket(addr)?;
         // Set IP_TRANSPARENT for TPROXY, CAP_NET_ADMIN required.
         sock.set_ip_transparent(true)?;
         sock.set_ip_recv_orig_dst_addr(true)?;
+        sock.set_ip_recv_pktinfo(true)?;
         AsyncUdpSocket::bind(sock, addr)
     }
 
+    /// Bind on a non-local `addr` in the original namespace, for replies to
+    /// clients as from their remotes.
     pub(crate) fn bind_nonlocal(addr: &SocketAddr) -> io::Result<Self> {
         let sock = new_socket(addr)?;
         sock.set_ip_transparent(true)?;
         AsyncUdpSocket::bind(sock, addr)
     }
 
+    /// Bind on a local address, for tests without CAP_NET_ADMIN.
+    #[cfg(test)]
+    pub(crate) fn bind_local(addr: &SocketAddr) -> io::Result<Self> {
+        AsyncUdpSocket::bind(new_socket(addr)?, addr)
+    }
+
     pub(crate) async fn batch_send<const N: usize>(
         &self,
         buf: &mut MsgArrayWriteBuffer<N>,
@@ -106,7 +156,7 @@ impl TryFrom<UdpSocket> for AsyncUdpSocket {
     }
 }
 
-fn new_socket(addr: &SocketAddr) -> io::Result<Socket> {
+pub(super) fn new_socket(addr: &SocketAddr) -> io::Result<Socket> {
     let domain = Domain::for_address(*addr);
     let sock = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
     sock.set_nonblocking(true)?;
@@ -114,6 +164,26 @@ fn new_socket(addr: &SocketAddr) -> io::Result<Socket> {
     Ok(sock)
 }
 
+fn unspecified(peer: &SocketAddr) -> IpAddr {
+    match peer {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    }
+}
+
+/// Create socket toward upstreams in the namespace of `--netns`, if given.
+async fn new_egress_socket(addr: &SocketAddr, opts: &EgressOpts) -> io::Result<Socket> {
+    let addr = *addr;
+    let sock = in_netns(move || new_socket(&addr)).await?;
+    if let Some(mark) = opts.fwmark {
+        sock.set_fwmark(mark)?;
+    }
+    if let Some(dscp) = opts.dscp {
+        sock.set_dscp(dscp)?;
+    }
+    Ok(sock)
+}
+
 struct WriteMsg<const N: usize> {
     addr: Option<SockAddr>,
     iovecs: [libc::iovec; N],
@@ -200,7 +270,14 @@ unsafe impl<const N: usize> Send for MsgArrayWriteBuffer<N> {}
 // Safety: no interior mutability
 unsafe impl<const N: usize> Sync for MsgArrayWriteBuffer<N> {}
 
-const MSG_CTRL_BUF_SIZE: usize = 128;
+/// Size of control data buffer per message. It must be large enough to
+/// hold all enabled cmsgs (e.g. both IPv4 & IPv6 `RECVORIGDSTADDR` and
+/// `PKTINFO` on a dual-stack socket), otherwise they are truncated
+/// (`MSG_CTRUNC`).
+const MSG_CTRL_BUF_SIZE: usize = 256;
+
+static TRUNCATED_DATAGRAMS: AtomicUsize = AtomicUsize::new(0);
+static TRUNCATED_CTRL_DATA: AtomicUsize = AtomicUsize::new(0);
 
 pub(crate) struct MsgArrayReadBuffer<const N: usize, const M: usize> {
     msg_cnt: usize,
@@ -221,12 +298,20 @@ unsafe impl<const N: usize, const M: usize> Sync for MsgArrayReadBuffer<N, M> {}
 pub(crate) struct Message<'a> {
     pub(crate) src_addr: Option<SocketAddr>,
     pub(crate) dst_addr: Option<SocketAddr>,
+    /// Index of ingress interface, from `IP_PKTINFO`/`IPV6_PKTINFO`
+    pub(crate) ifindex: Option<u32>,
+    /// Local address the packet arrived on, from `IP_PKTINFO` (`ipi_spec_dst`)
+    /// or `IPV6_PKTINFO` (`ipi6_addr`)
+    pub(crate) local_addr: Option<IpAddr>,
     pub(crate) buf: &'a [u8],
 }
 
 impl fmt::Display for Message<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "Message ({}B", self.buf.len())?;
+        if let (Some(ifindex), Some(local)) = (self.ifindex, self.local_addr) {
+            write!(f, " if#{}/{}", ifindex, local)?;
+        }
         match (self.src_addr, self.dst_addr) {
             (Some(src), Some(dst)) => write!(f, " {} => {})", src, dst),
             (Some(src), None) => write!(f, " {} => ?)", src),
@@ -290,18 +375,26 @@ impl<const N: usize, const M: usize> MsgArrayReadBuffer<N, M> {
         let msghdr = self.msgs[idx].msg_hdr;
         let src_addr = unsafe { SockAddr::new(self.addrs[idx], msghdr.msg_namelen) };
         let dst_addr = parse_dest_addr_from_cmsg(&msghdr).ok();
+        let pktinfo = parse_pktinfo_from_cmsg(&msghdr);
         if msghdr.msg_flags & libc::MSG_TRUNC != 0 {
-            warn!("MSG_TRUNC: datagram has been truncted");
+            let n = TRUNCATED_DATAGRAMS.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("MSG_TRUNC: datagram has been truncted ({} in total)", n);
         }
         if msghdr.msg_flags & libc::MSG_CTRUNC != 0 {
+            // Original dest addr may lost, breaks TProxy
+            let n = TRUNCATED_CTRL_DATA.fetch_add(1, Ordering::Relaxed) + 1;
             warn!(
-                "MSG_CTRUNC: control data has been truncted, len = {}",
-                msghdr.msg_controllen
+                "MSG_CTRUNC: control data has been truncted, len = {}, dest addr {} ({} in total)",
+                msghdr.msg_controllen,
+                if dst_addr.is_some() { "kept" } else { "lost" },
+                n,
             );
         }
         Message {
             src_addr: src_addr.as_socket(),
             dst_addr: dst_addr.and_then(|d| d.as_socket()),
+            ifindex: pktinfo.map(|(ifindex, _)| ifindex),
+            local_addr: pktinfo.map(|(_, addr)| addr),
             buf: &self.bufs[idx][..self.msgs[idx].msg_len as usize],
         }
     }
@@ -381,8 +474,40 @@ fn parse_dest_addr_from_cmsg(msghdr: &libc::msghdr) -> io::Result<SockAddr> {
     ))
 }
 
+/// Return (ifindex, local addr) from `IP_PKTINFO` or `IPV6_PKTINFO` cmsg.
+fn parse_pktinfo_from_cmsg(msghdr: &libc::msghdr) -> Option<(u32, IpAddr)> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msghdr) };
+    while !cmsg.is_null() {
+        // Safety: cmsg_len checked by CMSG_NXTHDR, read_unaligned for data
+        match unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) } {
+            (IPPROTO_IP, IP_PKTINFO) => {
+                let info: libc::in_pktinfo =
+                    unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _) };
+                let addr = Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr));
+                return Some((info.ipi_ifindex as u32, addr.into()));
+            }
+            (IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info: libc::in6_pktinfo =
+                    unsafe { ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const _) };
+                let addr = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                let addr = match addr.to_ipv4_mapped() {
+                    Some(addr) => addr.into(),
+                    None => addr.into(),
+                };
+                return Some((info.ipi6_ifindex, addr));
+            }
+            _ => (),
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(msghdr, cmsg) };
+    }
+    None
+}
+
 pub trait SocketExt {
     fn set_ip_recv_orig_dst_addr(&self, enable: bool) -> io::Result<()>;
+    fn set_ip_recv_pktinfo(&self, enable: bool) -> io::Result<()>;
+    fn set_fwmark(&self, mark: u32) -> io::Result<()>;
+    fn set_dscp(&self, dscp: u8) -> io::Result<()>;
 }
 
 impl SocketExt for Socket {
@@ -393,6 +518,27 @@ impl SocketExt for Socket {
         }
         Ok(())
     }
+
+    fn set_ip_recv_pktinfo(&self, enable: bool) -> io::Result<()> {
+        setsockopt_bool(self, IPPROTO_IP, IP_PKTINFO, enable)?;
+        if matches!(self.domain()?, Domain::IPV6) {
+            setsockopt_bool(self, IPPROTO_IPV6, IPV6_RECVPKTINFO, enable)?;
+        }
+        Ok(())
+    }
+
+    fn set_fwmark(&self, mark: u32) -> io::Result<()> {
+        setsockopt_int(self, SOL_SOCKET, SO_MARK, mark as libc::c_int)
+    }
+
+    fn set_dscp(&self, dscp: u8) -> io::Result<()> {
+        // DSCP is the upper 6 bits of TOS / traffic class, ECN is left 0
+        let tos = (dscp << 2) as libc::c_int;
+        match self.domain()? {
+            Domain::IPV6 => setsockopt_int(self, IPPROTO_IPV6, IPV6_TCLASS, tos),
+            _ => setsockopt_int(self, IPPROTO_IP, IP_TOS, tos),
+        }
+    }
 }
 
 fn setsockopt_bool<T: AsRawFd>(
@@ -401,7 +547,15 @@ fn setsockopt_bool<T: AsRawFd>(
     name: libc::c_int,
     val: bool,
 ) -> io::Result<()> {
-    let val: libc::c_int = if val { 1 } else { 0 };
+    setsockopt_int(sock, level, name, if val { 1 } else { 0 })
+}
+
+fn setsockopt_int<T: AsRawFd>(
+    sock: &T,
+    level: libc::c_int,
+    name: libc::c_int,
+    val: libc::c_int,
+) -> io::Result<()> {
     let ret = unsafe {
         setsockopt(
             sock.as_raw_fd(),
@@ -414,3 +568,33 @@ fn setsockopt_bool<T: AsRawFd>(
     Errno::result(ret)?;
     Ok(())
 }
+
+#[tokio::test]
+async fn test_egress_dscp() {
+    let opts = EgressOpts {
+        dscp: Some(46),
+        ..Default::default()
+    };
+    for addr in ["127.0.0.1:1", "[::1]:1"] {
+        let sock = new_egress_socket(&addr.parse().unwrap(), &opts)
+            .await
+            .unwrap();
+        let (level, name) = match sock.domain().unwrap() {
+            Domain::IPV6 => (IPPROTO_IPV6, IPV6_TCLASS),
+            _ => (IPPROTO_IP, IP_TOS),
+        };
+        let mut val: libc::c_int = 0;
+        let mut len = mem::size_of_val(&val) as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                sock.as_raw_fd(),
+                level,
+                name,
+                &mut val as *mut _ as *mut _,
+                &mut len,
+            )
+        };
+        Errno::result(ret).unwrap();
+        assert_eq!(val, 46 << 2);
+    }
+}
@@ -0,0 +1,35 @@
+use std::time::Instant;
+
+use bytes::Bytes;
+
+use super::packet::{self, SAMPLE_INITIAL_PACKET};
+
+/// Repeatedly decode a sample initial packet and extract its SNI, print out
+/// the timing. Dominated by AEAD, useful for comparing CPUs w/ & w/o AES-NI.
+pub(crate) fn bench_decode(iterations: u32) {
+    let pkt = Bytes::from_static(SAMPLE_INITIAL_PACKET);
+    let mut samples = Vec::with_capacity(iterations as usize);
+    let t0 = Instant::now();
+    for _ in 0..iterations {
+        let t = Instant::now();
+        let name = packet::get_server_name(pkt.clone());
+        samples.push(t.elapsed());
+        assert!(name.is_some(), "failed to decode sample packet");
+    }
+    let total = t0.elapsed();
+    samples.sort_unstable();
+    let per_decode = total / iterations;
+    println!(
+        "{} decodes in {:#.1?}: {:.0} decodes/sec, {} ns/decode",
+        iterations,
+        total,
+        iterations as f64 / total.as_secs_f64(),
+        per_decode.as_nanos(),
+    );
+    println!(
+        "min {} ns, median {} ns, max {} ns",
+        samples[0].as_nanos(),
+        samples[samples.len() / 2].as_nanos(),
+        samples[samples.len() - 1].as_nanos(),
+    );
+}
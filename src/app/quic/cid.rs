@@ -0,0 +1,97 @@
+use std::{collections::HashMap, sync::Arc};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+
+use super::packet::long_header_scid;
+use crate::app::types::{ClientAddr, RemoteAddr};
+
+/// Connection IDs chosen by remotes, learned from their long-header
+/// replies, mapped to the client of the conn. Clients put them as DCID of
+/// their short-header packets, which lets a conn be found again after its
+/// client moved to another port, see `--follow-client-port`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct ConnIdIndex {
+    inner: Arc<Mutex<ConnIds>>,
+}
+
+#[derive(Debug, Default)]
+struct ConnIds {
+    clients: HashMap<(RemoteAddr, Bytes), ClientAddr>,
+    /// Bit N set if any CID of length N is known, to limit lookups
+    lens: u32,
+}
+
+impl ConnIdIndex {
+    /// Learn CIDs of `remote` from its replies to `client`.
+    pub(super) fn observe(&self, pkts: &[Bytes], client: ClientAddr, remote: RemoteAddr) {
+        for cid in pkts.iter().filter_map(long_header_scid) {
+            self.insert(remote, cid, client);
+        }
+    }
+
+    pub(crate) fn insert(&self, remote: RemoteAddr, cid: Bytes, client: ClientAddr) {
+        let mut ids = self.inner.lock();
+        ids.lens |= 1 << cid.len();
+        ids.clients.insert((remote, cid), client);
+    }
+
+    /// Client & the CID of the conn that the short-header `pkt` to
+    /// `remote` belongs to, if it's known.
+    pub(crate) fn find(&self, remote: RemoteAddr, pkt: &Bytes) -> Option<(ClientAddr, Bytes)> {
+        if pkt.is_empty() || pkt[0] & 0xc0 != 0x40 {
+            return None;
+        }
+        let ids = self.inner.lock();
+        (1..pkt.len().min(21))
+            .filter(|len| ids.lens & (1 << len) != 0)
+            .find_map(|len| {
+                let cid = pkt.slice(1..1 + len);
+                let client = ids.clients.get(&(remote, cid.clone()))?;
+                Some((*client, cid))
+            })
+    }
+
+    /// Forget CIDs of conns that `is_alive` returns false for.
+    pub(crate) fn retain<F>(&self, mut is_alive: F)
+    where
+        F: FnMut(ClientAddr, RemoteAddr) -> bool,
+    {
+        let mut ids = self.inner.lock();
+        ids.clients
+            .retain(|(remote, _), client| is_alive(*client, *remote));
+        ids.lens = ids
+            .clients
+            .keys()
+            .fold(0, |lens, (_, cid)| lens | 1 << cid.len());
+    }
+}
+
+#[test]
+fn test_conn_id_index() {
+    use bytes::BufMut;
+
+    let index = ConnIdIndex::default();
+    let remote = RemoteAddr(([192, 0, 2, 1], 443).into());
+    let client = ClientAddr(([10, 0, 0, 1], 50000).into());
+    // Handshake packet from remote, DCID = 01, SCID = 0a0b0c0d
+    let mut reply = vec![0xe0, 0, 0, 0, 1, 1, 0x01, 4, 0x0a, 0x0b, 0x0c, 0x0d];
+    reply.put_bytes(0, 32);
+    index.observe(&[Bytes::from(reply)], client, remote);
+
+    let mut pkt = vec![0x40, 0x0a, 0x0b, 0x0c, 0x0d];
+    pkt.put_bytes(0, 32);
+    let pkt = Bytes::from(pkt);
+    let (found, cid) = index.find(remote, &pkt).unwrap();
+    assert_eq!(found, client);
+    assert_eq!(&cid[..], [0x0a, 0x0b, 0x0c, 0x0d]);
+    let other = RemoteAddr(([192, 0, 2, 2], 443).into());
+    assert!(index.find(other, &pkt).is_none());
+    // Long headers aren't looked up
+    let mut initial = pkt.to_vec();
+    initial[0] = 0xc0;
+    assert!(index.find(remote, &initial.into()).is_none());
+
+    index.retain(|c, _| c != client);
+    assert!(index.find(remote, &pkt).is_none());
+}
@@ -1,30 +1,204 @@
-use std::{fmt, io, sync::Arc};
+use std::{
+    collections::{hash_map::DefaultHasher, VecDeque},
+    fmt,
+    hash::{Hash, Hasher},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
-use futures::StreamExt;
-use tracing::{info, trace};
+use futures::{stream, StreamExt};
+use parking_lot::Mutex;
+use tokio::sync::OwnedSemaphorePermit;
+use tracing::{debug, info, info_span, trace, Instrument};
 
 use crate::app::{
     net::{MsgArrayWriteBuffer, UDP_BATCH_SIZE},
-    socks5::SocksSession,
-    tproxy::TProxySender,
-    types::{ClientAddr, RemoteAddr},
+    socks5::{SocksSession, Traffic},
+    stats::Stats,
+    tproxy::{ReplyBatcher, TProxySender},
+    types::{canonicalize_socket_addr, ClientAddr, RemoteAddr},
+};
+
+use super::{
+    cid::ConnIdIndex,
+    packet::{
+        is_likely_stateless_reset, negotiated_versions, peek_initial_scid, InitialPacket,
+        ParseError,
+    },
 };
 
-use super::packet;
+/// Where a reply task sends replies to. The sender must be the one of the
+/// conn's remote, replies are validated against the session's target
+/// beforehand, see `--validate-socks-reply-addr`.
+#[derive(Clone)]
+struct ReplySink {
+    sender: Arc<TProxySender>,
+    stats: Arc<Stats>,
+    batcher: Option<ReplyBatcher>,
+    /// Largest UDP payload to send, see `with_client_mtu()`
+    max_payload: Option<usize>,
+}
 
 pub(crate) struct QuicConn {
     pub(crate) remote: RemoteAddr,
     pub(crate) remote_name: Option<String>,
+    /// Why `remote_name` is found or not
+    pub(crate) name_lookup: NameLookup,
+    /// ALPN protocols offered by the client, empty if unknown
+    pub(crate) alpn: Vec<String>,
     pub(crate) client: ClientAddr,
+    pub(crate) created_at: Instant,
+    /// SCID of client's initial packet, its length is needed to parse
+    /// short headers from server, which aren't self-describing.
+    pub(crate) scid: Option<Bytes>,
     proxy: Option<Arc<SocksSession>>,
+    /// Second session sending the same packets, see `duplicate` in config
+    duplicate: Option<Arc<SocksSession>>,
+    /// Traffic of sessions replaced before, for accounting on close
+    past_traffic: Traffic,
+    /// Name of the server before `clear_proxy()`, until connected again
+    migrated_from: Option<String>,
+    resets: Arc<ResetTracker>,
+    /// Deadline for the first reply of each new session
+    first_reply_timeout: Option<Duration>,
+    /// Set if the current session missed `first_reply_timeout`
+    missed_first_reply: Arc<AtomicBool>,
+    /// First Initial packet & when to re-send it if no reply, taken by the
+    /// first session
+    initial_resend: Option<(Duration, Bytes)>,
+    /// Path MTU toward the client, larger replies are dropped
+    client_mtu: Option<u16>,
+    /// Where to record CIDs of the remote, see `with_conn_id_index()`
+    conn_ids: Option<ConnIdIndex>,
+}
+
+/// Outcome of looking up server name from the first packet of a conn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NameLookup {
+    /// Neither `--remote-dns` nor `--local-dns` is on
+    Skipped,
+    Found,
+    TooShort,
+    UnsupportedVersion,
+    NotInitial,
+    /// A valid Initial packet without SNI extension
+    NoSni,
+}
+
+impl NameLookup {
+    pub(crate) const ALL: [Self; 6] = [
+        Self::Skipped,
+        Self::Found,
+        Self::TooShort,
+        Self::UnsupportedVersion,
+        Self::NotInitial,
+        Self::NoSni,
+    ];
+
+    /// Short name for metric labels.
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            Self::Skipped => "skipped",
+            Self::Found => "found",
+            Self::TooShort => "too_short",
+            Self::UnsupportedVersion => "unsupported_version",
+            Self::NotInitial => "not_initial",
+            Self::NoSni => "no_sni",
+        }
+    }
+
+    /// Whether a name is wanted but not found, so the conn is routed by IP.
+    pub(crate) fn is_missed(&self) -> bool {
+        !matches!(self, Self::Skipped | Self::Found)
+    }
+}
+
+impl From<&ParseError> for NameLookup {
+    fn from(err: &ParseError) -> Self {
+        match err {
+            ParseError::NoEnoughData => Self::TooShort,
+            ParseError::UnsupportedVersion => Self::UnsupportedVersion,
+            ParseError::NotValidQuicPacket | ParseError::NotInitialPacket => Self::NotInitial,
+        }
+    }
+}
+
+impl fmt::Display for NameLookup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Skipped => "skipped",
+            Self::Found => "found",
+            Self::TooShort => "too short for Initial",
+            Self::UnsupportedVersion => "unsupported QUIC version",
+            Self::NotInitial => "not a QUIC Initial",
+            Self::NoSni => "no SNI",
+        })
+    }
+}
+
+/// Max number of reply hashes waiting for their copies
+const DEDUP_WINDOW: usize = 256;
+
+/// Recent replies of duplicated conns, to drop the later copies.
+#[derive(Debug, Default)]
+struct ReplyDedup {
+    pending: VecDeque<u64>,
+}
+
+impl ReplyDedup {
+    /// Return true if `pkt` is the first copy.
+    fn is_first(&mut self, pkt: &[u8]) -> bool {
+        let mut hasher = DefaultHasher::new();
+        pkt.hash(&mut hasher);
+        let hash = hasher.finish();
+        if let Some(idx) = self.pending.iter().position(|h| *h == hash) {
+            // Each one has exactly two copies
+            self.pending.remove(idx);
+            return false;
+        }
+        if self.pending.len() >= DEDUP_WINDOW {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(hash);
+        true
+    }
+}
+
+/// Suspected stateless resets in packets from remote.
+#[derive(Debug, Default)]
+struct ResetTracker {
+    suspected: AtomicUsize,
+    /// Whether the latest packet from remote looks like a reset
+    last_suspected: AtomicBool,
+}
+
+impl ResetTracker {
+    fn observe(&self, pkts: &[Bytes], client_scid: &[u8]) {
+        for pkt in pkts {
+            let reset = is_likely_stateless_reset(pkt, client_scid);
+            if reset {
+                self.suspected.fetch_add(1, Ordering::Relaxed);
+            }
+            self.last_suspected.store(reset, Ordering::Relaxed);
+        }
+    }
 }
 
 impl fmt::Display for QuicConn {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "QuicConn ({} => ", self.client.0)?;
         if let Some(proxy) = &self.proxy {
-            write!(f, "{} => ", proxy.server.name)?;
+            write!(f, "{}", proxy.server.name)?;
+            if let Some(duplicate) = &self.duplicate {
+                write!(f, "+{}", duplicate.server.name)?;
+            }
+            write!(f, " => ")?;
         }
         match &self.remote_name {
             Some(name) => write!(f, "{}/{})", name, self.remote.0),
@@ -35,53 +209,396 @@ impl fmt::Display for QuicConn {
 
 impl Drop for QuicConn {
     fn drop(&mut self) {
-        trace!("Close {}", self);
-        if let Some(session) = &self.proxy {
-            assert_eq!(1, Arc::strong_count(session));
-            assert_eq!(1, Arc::weak_count(session));
+        debug!(
+            "Close {}, {:#.0?}, {}, {} suspected resets{}",
+            self,
+            self.created_at.elapsed(),
+            self.traffic(),
+            self.resets.suspected.load(Ordering::Relaxed),
+            match self.proxy.as_ref().and_then(|p| p.resolved_addr()) {
+                Some(addr) => format!(", resolved to {}", addr),
+                None => String::new(),
+            }
+        );
+        // Reply tasks only hold weak refs, upgraded temporarily on polling,
+        // so sessions get dropped here or right after that polling, which
+        // then ends the tasks.
+        for session in self.proxy.iter().chain(&self.duplicate) {
+            debug_assert!(Arc::weak_count(session) <= 1);
+        }
+    }
+}
+
+/// What's learned from the first packet of a conn, may be worked out off
+/// the forwarding task, see `DecodePool`.
+#[derive(Debug)]
+pub(crate) struct FirstPacket {
+    remote_name: Option<String>,
+    name_lookup: NameLookup,
+    alpn: Vec<String>,
+    scid: Option<Bytes>,
+}
+
+impl FirstPacket {
+    /// Decrypt `pkt` for server name & ALPN only if `decode_initial`, SCID
+    /// is taken from the unprotected header anyway. `tolerate_greased_bit`
+    /// accepts Initial packets with the fixed bit cleared.
+    pub(crate) fn inspect(pkt: &Bytes, decode_initial: bool, tolerate_greased_bit: bool) -> Self {
+        let init = decode_initial.then(|| InitialPacket::decode(pkt.clone(), tolerate_greased_bit));
+        let hello = match &init {
+            Some(Ok(init)) => init.client_hello().unwrap_or_default(),
+            _ => Default::default(),
+        };
+        let remote_name = hello.server_name;
+        let name_lookup = match &init {
+            None => NameLookup::Skipped,
+            Some(Err(err)) => err.into(),
+            Some(Ok(_)) if remote_name.is_some() => NameLookup::Found,
+            Some(Ok(_)) => NameLookup::NoSni,
+        };
+        let scid = match init {
+            Some(Ok(init)) => Some(init.scid),
+            _ => peek_initial_scid(pkt, tolerate_greased_bit),
+        };
+        Self {
+            remote_name,
+            name_lookup,
+            alpn: hello.alpn,
+            scid,
         }
     }
 }
 
 impl QuicConn {
-    pub(crate) fn new(remote: RemoteAddr, client: ClientAddr, pkt: Option<Bytes>) -> Self {
+    /// See `FirstPacket::inspect()` for the arguments.
+    pub(crate) fn new(
+        remote: RemoteAddr,
+        client: ClientAddr,
+        first_pkt: &Bytes,
+        decode_initial: bool,
+        tolerate_greased_bit: bool,
+    ) -> Self {
+        let first = FirstPacket::inspect(first_pkt, decode_initial, tolerate_greased_bit);
+        Self::with_first_packet(remote, client, first)
+    }
+
+    pub(crate) fn with_first_packet(
+        remote: RemoteAddr,
+        client: ClientAddr,
+        first: FirstPacket,
+    ) -> Self {
         Self {
             remote,
             client,
-            remote_name: pkt.and_then(packet::get_server_name),
+            remote_name: first.remote_name,
+            name_lookup: first.name_lookup,
+            alpn: first.alpn,
+            created_at: Instant::now(),
+            scid: first.scid,
             proxy: None,
+            duplicate: None,
+            past_traffic: Default::default(),
+            migrated_from: None,
+            resets: Default::default(),
+            first_reply_timeout: None,
+            missed_first_reply: Default::default(),
+            initial_resend: None,
+            client_mtu: None,
+            conn_ids: None,
+        }
+    }
+
+    /// Keep `first_pkt` to re-send it once via the first session if no
+    /// reply within `after`. Ignored if it isn't an Initial packet.
+    pub(crate) fn with_initial_resend(
+        mut self,
+        after: Option<Duration>,
+        first_pkt: &Bytes,
+    ) -> Self {
+        if self.scid.is_some() {
+            self.initial_resend = after.map(|after| (after, first_pkt.clone()));
         }
+        self
+    }
+
+    /// Warn if no reply comes via a new session within `timeout`, see
+    /// `missed_first_reply()`.
+    pub(crate) fn with_first_reply_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.first_reply_timeout = timeout;
+        self
     }
 
-    pub(crate) fn set_proxy(&mut self, proxy: SocksSession, sender: Arc<TProxySender>) {
+    /// Drop replies that don't fit in `mtu` with IP & UDP headers, rather
+    /// than let them be fragmented on the way to the client.
+    pub(crate) fn with_client_mtu(mut self, mtu: Option<u16>) -> Self {
+        self.client_mtu = mtu;
+        self
+    }
+
+    /// Record CIDs the remote chooses into `index`, so that the conn can be
+    /// found by them if the client moves to another port.
+    pub(crate) fn with_conn_id_index(mut self, index: Option<&ConnIdIndex>) -> Self {
+        self.conn_ids = index.cloned();
+        self
+    }
+
+    /// Start forwarding replies of `proxy`, and of `duplicate` if given,
+    /// with later copies of the same reply dropped. Each forwarding task
+    /// holds its permit of `--max-reply-tasks`, taken before binding the
+    /// session. Sizes of replies are recorded into `stats`. Replies are
+    /// sent via `batcher` if given, or directly if it has exited.
+    pub(crate) fn set_proxy(
+        &mut self,
+        proxy: SocksSession,
+        permit: OwnedSemaphorePermit,
+        duplicate: Option<(SocksSession, OwnedSemaphorePermit)>,
+        sender: Arc<TProxySender>,
+        stats: &Arc<Stats>,
+        batcher: Option<&ReplyBatcher>,
+    ) {
+        debug_assert_eq!(sender.remote(), Some(self.remote));
+        let dedup = duplicate
+            .is_some()
+            .then(|| Arc::new(Mutex::new(ReplyDedup::default())));
         let proxy = Arc::new(proxy);
-        let mut incoming = Box::pin(proxy.incoming());
+        self.missed_first_reply = Default::default();
+        let watchdog = self
+            .first_reply_timeout
+            .map(|timeout| (timeout, self.missed_first_reply.clone()));
+        let sink = ReplySink {
+            sender,
+            stats: stats.clone(),
+            batcher: batcher.cloned(),
+            max_payload: self
+                .client_mtu
+                .map(|mtu| max_udp_payload(mtu, &self.client)),
+        };
+        let resend = self.initial_resend.take();
+        let first_wait = FirstWait { watchdog, resend };
+        self.spawn_forwarding(&proxy, sink.clone(), dedup.clone(), first_wait, permit);
         self.proxy = Some(proxy);
+        self.duplicate = duplicate.map(|(duplicate, permit)| {
+            let duplicate = Arc::new(duplicate);
+            let first_wait = FirstWait::default();
+            self.spawn_forwarding(&duplicate, sink, dedup, first_wait, permit);
+            duplicate
+        });
+        for session in self.proxy.iter().chain(&self.duplicate) {
+            debug_assert_eq!(1, Arc::weak_count(session));
+        }
+    }
+
+    fn spawn_forwarding(
+        &self,
+        proxy: &Arc<SocksSession>,
+        sink: ReplySink,
+        dedup: Option<Arc<Mutex<ReplyDedup>>>,
+        first_wait: FirstWait,
+        permit: OwnedSemaphorePermit,
+    ) {
+        let mut incoming = Box::pin(proxy.incoming());
+        let target = match &self.remote_name {
+            Some(name) => format!("{}/{}", name, self.remote.0),
+            None => self.remote.0.to_string(),
+        };
         let client = self.client;
         let remote = self.remote;
+        let scid = self.scid.clone();
+        let resets = self.resets.clone();
+        let conn_ids = self.conn_ids.clone();
+        let span = info_span!(
+            "reply",
+            server = proxy.server.name,
+            group = proxy.server.group
+        );
 
-        tokio::spawn(async move {
+        let task = async move {
             trace!("Start forwarding {:?} => {:?}", remote, client);
             let mut buf = MsgArrayWriteBuffer::<1>::with_capacity(UDP_BATCH_SIZE / 2);
+            let started = Instant::now();
+            let mut first = None;
+            if let Some((after, pkt)) = first_wait.resend {
+                match tokio::time::timeout(after, incoming.next()).await {
+                    Ok(Some(pkts)) => first = Some(pkts),
+                    Ok(None) => return,
+                    Err(_) => resend_initial(incoming.session(), pkt, &sink.stats).await,
+                }
+            }
+            // Set while the first reply is overdue
+            let mut missed = None;
+            if let (None, Some((timeout, flag))) = (&first, first_wait.watchdog) {
+                let timeout = timeout.saturating_sub(started.elapsed());
+                match tokio::time::timeout(timeout, incoming.next()).await {
+                    Ok(Some(pkts)) => first = Some(pkts),
+                    Ok(None) => return,
+                    Err(_) => {
+                        debug!(
+                            "No reply for {:?} => {} within {:?}",
+                            client.0, target, timeout
+                        );
+                        flag.store(true, Ordering::Relaxed);
+                        missed = Some(flag);
+                    }
+                }
+            }
+            let mut incoming = stream::iter(first).chain(incoming);
             while let Some(pkts) = incoming.next().await {
-                match forward_packets(pkts, client, &sender, &mut buf).await {
+                if let (Ok(_), Some(flag)) = (&pkts, missed.take()) {
+                    debug!(
+                        "Late reply for {:?} => {} after {:?}",
+                        client.0,
+                        target,
+                        started.elapsed()
+                    );
+                    flag.store(false, Ordering::Relaxed);
+                }
+                let pkts = match (pkts, &dedup) {
+                    (Ok(pkts), Some(dedup)) => {
+                        let mut dedup = dedup.lock();
+                        Ok(pkts
+                            .iter()
+                            .filter(|pkt| dedup.is_first(pkt))
+                            .cloned()
+                            .collect())
+                    }
+                    (pkts, _) => pkts,
+                };
+                if let Ok(pkts) = &pkts {
+                    pkts.iter()
+                        .for_each(|pkt| sink.stats.rx_sizes.record(pkt.len()));
+                    if let Some(scid) = &scid {
+                        resets.observe(pkts, scid);
+                    }
+                    if let Some(conn_ids) = &conn_ids {
+                        conn_ids.observe(pkts, client, remote);
+                    }
+                    for versions in pkts.iter().filter_map(negotiated_versions) {
+                        sink.stats
+                            .version_negotiations
+                            .fetch_add(1, Ordering::Relaxed);
+                        info!(
+                            "Version negotiation for {:?} => {}, remote offers {:08x?}",
+                            client.0, target, versions
+                        );
+                    }
+                }
+                let pkts = pkts.map(|pkts| clamp_to_mtu(pkts, sink.max_payload, &sink.stats));
+                let pkts = match (pkts, &sink.batcher) {
+                    (Ok(pkts), Some(batcher)) if !pkts.is_empty() => {
+                        match batcher.queue(&sink.sender, client, pkts).await {
+                            Ok(()) => continue,
+                            Err(pkts) => Ok(pkts),
+                        }
+                    }
+                    (pkts, _) => pkts,
+                };
+                match forward_packets(pkts, client, &sink.sender, &mut buf).await {
                     Err(err) => info!("Forwarding to client error: {}", err),
                     Ok((n, len)) => trace!("{:?} => {:?}: {} pkts {}B", remote, client, n, len),
                 }
             }
             trace!("Stop forwarding");
-        });
-        assert_eq!(1, Arc::strong_count(self.proxy.as_ref().unwrap()));
-        assert_eq!(1, Arc::weak_count(self.proxy.as_ref().unwrap()));
+            drop(permit);
+        };
+        tokio::spawn(task.instrument(span));
     }
 
     pub(crate) fn clear_proxy(&mut self) {
-        self.proxy.take();
+        if let Some(proxy) = &self.proxy {
+            self.migrated_from = Some(proxy.server.name.clone());
+        }
+        for session in self.proxy.take().iter().chain(&self.duplicate.take()) {
+            self.past_traffic += session.traffic();
+        }
+    }
+
+    /// Upstream traffic of this conn over all its sessions, duplicated
+    /// ones included.
+    pub(crate) fn traffic(&self) -> Traffic {
+        let mut traffic = self.past_traffic;
+        for session in self.proxy.iter().chain(&self.duplicate) {
+            traffic += session.traffic();
+        }
+        traffic
+    }
+
+    /// Take the name of the server it was on before reconnecting, `None`
+    /// if it's the first connect or already taken.
+    pub(crate) fn take_migrated_from(&mut self) -> Option<String> {
+        self.migrated_from.take()
     }
 
     pub(crate) fn proxy(&self) -> Option<&SocksSession> {
         self.proxy.as_ref().map(|p| p.as_ref())
     }
+
+    pub(crate) fn duplicate_proxy(&self) -> Option<&SocksSession> {
+        self.duplicate.as_ref().map(|p| p.as_ref())
+    }
+
+    /// Whether no reply came via the current session within the first
+    /// reply timeout.
+    pub(crate) fn missed_first_reply(&self) -> bool {
+        self.missed_first_reply.load(Ordering::Relaxed)
+    }
+
+    /// Whether the latest packet from remote looks like a stateless reset,
+    /// i.e. the remote may have forgotten this conn.
+    pub(crate) fn is_likely_reset(&self) -> bool {
+        self.resets.last_suspected.load(Ordering::Relaxed)
+    }
+}
+
+/// What to do while waiting for the first reply of a session.
+#[derive(Default)]
+struct FirstWait {
+    /// Timeout & the flag to set if exceeded, see `missed_first_reply()`
+    watchdog: Option<(Duration, Arc<AtomicBool>)>,
+    /// See `with_initial_resend()`
+    resend: Option<(Duration, Bytes)>,
+}
+
+async fn resend_initial(session: Option<Arc<SocksSession>>, pkt: Bytes, stats: &Stats) {
+    let session = match session {
+        Some(session) => session,
+        None => return,
+    };
+    if !session.within_tx_limit(std::slice::from_ref(&pkt)) {
+        return;
+    }
+    debug!("No reply yet, re-send Initial via {}", session);
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    match session.send_to_remote(&[pkt], &mut buf).await {
+        Ok(()) => {
+            stats.initial_resends.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(err) => debug!("Failed to re-send Initial: {}", err),
+    }
+}
+
+fn max_udp_payload(mtu: u16, client: &ClientAddr) -> usize {
+    let ip_header = match canonicalize_socket_addr(client.0) {
+        SocketAddr::V4(_) => 20,
+        SocketAddr::V6(_) => 40,
+    };
+    (mtu as usize).saturating_sub(ip_header + 8)
+}
+
+/// Drop (& count) replies larger than `max_payload`, if set.
+fn clamp_to_mtu(pkts: Box<[Bytes]>, max_payload: Option<usize>, stats: &Stats) -> Box<[Bytes]> {
+    let max_payload = match max_payload {
+        Some(max) if pkts.iter().any(|pkt| pkt.len() > max) => max,
+        _ => return pkts,
+    };
+    let (fit, oversize): (Vec<_>, Vec<_>) = pkts
+        .iter()
+        .cloned()
+        .partition(|pkt| pkt.len() <= max_payload);
+    stats
+        .oversize_replies
+        .fetch_add(oversize.len(), Ordering::Relaxed);
+    trace!("Drop {} replies over {}B", oversize.len(), max_payload);
+    fit.into()
 }
 
 async fn forward_packets(
@@ -104,3 +621,190 @@ async fn forward_packets(
     }
     Ok((total_n, total_len))
 }
+
+#[test]
+fn test_reply_dedup() {
+    let mut dedup = ReplyDedup::default();
+    assert!(dedup.is_first(b"a"));
+    assert!(dedup.is_first(b"b"));
+    assert!(!dedup.is_first(b"a"));
+    // Pending one is removed once its copy arrived
+    assert!(dedup.is_first(b"a"));
+    (0..DEDUP_WINDOW).for_each(|i| assert!(dedup.is_first(&i.to_be_bytes())));
+    assert!(dedup.is_first(b"b"));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_drop_conn_ends_reply_task() {
+    use crate::app::{socks5::SocksServer, tproxy::TProxySenderCache, InnerProto};
+    use std::time::Duration;
+
+    let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = Arc::new(SocksServer::new(
+        relay.local_addr().unwrap(),
+        "relay".into(),
+        InnerProto::Inet,
+    ));
+    let remote = RemoteAddr(([127, 0, 0, 1], 0).into());
+    let client = ClientAddr(([127, 0, 0, 1], 1).into());
+    let session = server.bind(remote.0.into(), true).await.unwrap();
+    let sender = TProxySenderCache::new_local(1)
+        .get_or_create(remote)
+        .unwrap();
+
+    let mut conn = QuicConn::new(remote, client, &Bytes::new(), false, false);
+    let reply_tasks = Arc::new(tokio::sync::Semaphore::new(1));
+    let permit = reply_tasks.clone().try_acquire_owned().unwrap();
+    conn.set_proxy(
+        session,
+        permit,
+        None,
+        sender.clone(),
+        &Default::default(),
+        None,
+    );
+    assert_eq!(reply_tasks.available_permits(), 0);
+    // Let the task start waiting on replies
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(Arc::strong_count(&sender), 2);
+
+    // Task holds the sender until it ends
+    drop(conn);
+    let ended = async {
+        while Arc::strong_count(&sender) > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(1), ended)
+        .await
+        .expect("Reply task outlives its conn");
+    assert_eq!(server.status.usage.active_sessions(), 0);
+    assert_eq!(reply_tasks.available_permits(), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_first_reply_watchdog() {
+    use crate::app::{socks5::SocksServer, tproxy::TProxySenderCache, InnerProto};
+
+    // Relay replies late
+    let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = Arc::new(SocksServer::new(
+        relay.local_addr().unwrap(),
+        "relay".into(),
+        InnerProto::Inet,
+    ));
+    let remote = RemoteAddr(([127, 0, 0, 1], 0).into());
+    let client = ClientAddr(([127, 0, 0, 1], 1).into());
+    let session = server.bind(remote.0.into(), true).await.unwrap();
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    session
+        .send_to_remote(&[Bytes::from_static(b"hello")], &mut buf)
+        .await
+        .unwrap();
+    let mut pkt = [0u8; 64];
+    let (len, from) = relay.recv_from(&mut pkt).await.unwrap();
+    let sender = TProxySenderCache::new_local(1)
+        .get_or_create(remote)
+        .unwrap();
+
+    let mut conn = QuicConn::new(remote, client, &Bytes::new(), false, false)
+        .with_first_reply_timeout(Some(Duration::from_millis(30)));
+    let reply_tasks = Arc::new(tokio::sync::Semaphore::new(1));
+    let permit = reply_tasks.clone().try_acquire_owned().unwrap();
+    conn.set_proxy(session, permit, None, sender, &Default::default(), None);
+    assert!(!conn.missed_first_reply());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(conn.missed_first_reply());
+
+    relay
+        .send_to(&[&pkt[..len - 5], b"world"].concat(), from)
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!conn.missed_first_reply());
+}
+
+#[test]
+fn test_name_lookup() {
+    use super::packet::SAMPLE_INITIAL_PACKET;
+
+    let remote = RemoteAddr(([127, 0, 0, 1], 443).into());
+    let client = ClientAddr(([127, 0, 0, 1], 1).into());
+    let lookup = |pkt: Vec<u8>, decode| {
+        QuicConn::new(remote, client, &pkt.into(), decode, false).name_lookup
+    };
+    let sample = SAMPLE_INITIAL_PACKET.to_vec();
+    assert_eq!(lookup(sample.clone(), false), NameLookup::Skipped);
+    assert_eq!(lookup(sample.clone(), true), NameLookup::Found);
+    assert_eq!(lookup(sample[..1000].to_vec(), true), NameLookup::TooShort);
+    assert_eq!(
+        lookup(vec![0x40; 1200], true),
+        NameLookup::UnsupportedVersion
+    );
+    let mut short_header = sample;
+    short_header[0] = 0x40;
+    assert_eq!(lookup(short_header, true), NameLookup::NotInitial);
+    // Counters are indexed by discriminant
+    for (i, lookup) in NameLookup::ALL.iter().enumerate() {
+        assert_eq!(*lookup as usize, i);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_initial_resend() {
+    use super::packet::SAMPLE_INITIAL_PACKET;
+    use crate::app::{socks5::SocksServer, tproxy::TProxySenderCache, InnerProto};
+
+    // Relay never replies, only receives
+    let relay = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = Arc::new(SocksServer::new(
+        relay.local_addr().unwrap(),
+        "relay".into(),
+        InnerProto::Inet,
+    ));
+    let remote = RemoteAddr(([127, 0, 0, 1], 0).into());
+    let client = ClientAddr(([127, 0, 0, 1], 1).into());
+    let session = server.bind(remote.0.into(), true).await.unwrap();
+    let sender = TProxySenderCache::new_local(1)
+        .get_or_create(remote)
+        .unwrap();
+
+    let first_pkt = Bytes::from_static(SAMPLE_INITIAL_PACKET);
+    let mut conn = QuicConn::new(remote, client, &first_pkt, false, false)
+        .with_initial_resend(Some(Duration::from_millis(10)), &first_pkt);
+    let reply_tasks = Arc::new(tokio::sync::Semaphore::new(1));
+    let stats: Arc<Stats> = Default::default();
+    let permit = reply_tasks.clone().try_acquire_owned().unwrap();
+    conn.set_proxy(session, permit, None, sender, &stats, None);
+    let mut buf = vec![0; 2048];
+    let recv = relay.recv(&mut buf);
+    let len = tokio::time::timeout(Duration::from_secs(5), recv)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(buf[..len].ends_with(SAMPLE_INITIAL_PACKET));
+    assert_eq!(stats.initial_resends.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn test_clamp_to_mtu() {
+    let client4 = ClientAddr(([127, 0, 0, 1], 1).into());
+    let client6 = ClientAddr("[::1]:1".parse().unwrap());
+    let mapped = ClientAddr("[::ffff:127.0.0.1]:1".parse().unwrap());
+    assert_eq!(max_udp_payload(1280, &client4), 1252);
+    assert_eq!(max_udp_payload(1280, &client6), 1232);
+    assert_eq!(max_udp_payload(1280, &mapped), 1252);
+
+    let stats = Stats::default();
+    let pkts: Box<[Bytes]> = [vec![0; 1200], vec![0; 1300], vec![0; 1252]]
+        .into_iter()
+        .map(Bytes::from)
+        .collect();
+    assert_eq!(clamp_to_mtu(pkts.clone(), None, &stats).len(), 3);
+    let fit = clamp_to_mtu(pkts, Some(1252), &stats);
+    assert_eq!(fit.iter().map(Bytes::len).collect::<Vec<_>>(), [1200, 1252]);
+    assert_eq!(stats.oversize_replies.load(Ordering::Relaxed), 1);
+}
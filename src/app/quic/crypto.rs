@@ -4,63 +4,105 @@ use ring::{
         quic::{HeaderProtectionKey, AES_128},
         LessSafeKey, Nonce, UnboundKey, AES_128_GCM, NONCE_LEN,
     },
+    error::Unspecified,
     hkdf::{KeyType, Prk, Salt, HKDF_SHA256},
 };
 
-const INITIAL_SALT: &[u8] = &hex!("38762cf7f55934b34d179ae6a4c80cadccbb7f0a");
+/// QUIC version 2, RFC 9369
+pub(super) const QUIC_V2: u32 = 0x6b3343cf;
+
 const LABEL_CLIENT_IN: &[u8] = &hex!("00200f746c73313320636c69656e7420696e00");
-const LABEL_QUIC_HP: &[u8] = &hex!("00100d746c733133207175696320687000");
-const LABEL_QUIC_KEY: &[u8] = &hex!("00100e746c7331332071756963206b657900");
-const LABEL_QUIC_IV: &[u8] = &hex!("000c0d746c733133207175696320697600");
 
-pub(super) struct InitialSecret([u8; 32]);
+/// Initial salt, HKDF labels & Initial packet type of a QUIC version.
+pub(super) struct VersionParams {
+    salt: &'static [u8],
+    label_key: &'static [u8],
+    label_iv: &'static [u8],
+    label_hp: &'static [u8],
+    /// Long packet type of Initial, remapped by v2
+    pub(super) initial_type: u8,
+}
+
+const V1: VersionParams = VersionParams {
+    salt: &hex!("38762cf7f55934b34d179ae6a4c80cadccbb7f0a"),
+    label_key: &hex!("00100e746c7331332071756963206b657900"),
+    label_iv: &hex!("000c0d746c733133207175696320697600"),
+    label_hp: &hex!("00100d746c733133207175696320687000"),
+    initial_type: 0b00,
+};
+
+const V2: VersionParams = VersionParams {
+    salt: &hex!("0dede3def700a6db819381be6e269dcbf9bd2ed9"),
+    label_key: &hex!("001010746c73313320717569637632206b657900"),
+    label_iv: &hex!("000c0f746c7331332071756963763220697600"),
+    label_hp: &hex!("00100f746c7331332071756963763220687000"),
+    initial_type: 0b01,
+};
+
+/// Parameters for deriving initial secrets of QUIC `version`, `None` if
+/// the version is unsupported.
+pub(super) fn version_params(version: u32) -> Option<&'static VersionParams> {
+    match version {
+        1 => Some(&V1),
+        QUIC_V2 => Some(&V2),
+        _ => None,
+    }
+}
+
+pub(super) struct InitialSecret {
+    secret: [u8; 32],
+    params: &'static VersionParams,
+}
 
 impl InitialSecret {
-    pub(super) fn new(dcid: &[u8]) -> Self {
-        let init_key = Salt::new(HKDF_SHA256, INITIAL_SALT).extract(dcid);
-        let client_in = init_key
-            .expand(&[LABEL_CLIENT_IN], HKDF_SHA256)
-            .expect("len too large");
-        let mut key = [0u8; 32];
-        client_in.fill(&mut key).unwrap();
-        Self(key)
+    pub(super) fn new(params: &'static VersionParams, dcid: &[u8]) -> Result<Self, Unspecified> {
+        let init_key = Salt::new(HKDF_SHA256, params.salt).extract(dcid);
+        let client_in = init_key.expand(&[LABEL_CLIENT_IN], HKDF_SHA256)?;
+        let mut secret = [0u8; 32];
+        client_in.fill(&mut secret)?;
+        Ok(Self { secret, params })
     }
 
-    fn compute_iv(&self) -> [u8; NONCE_LEN] {
-        let prk = Prk::new_less_safe(HKDF_SHA256, &self.0);
-        let okm = prk.expand(&[LABEL_QUIC_IV], Iv).unwrap();
+    fn compute_iv(&self) -> Result<[u8; NONCE_LEN], Unspecified> {
+        let prk = Prk::new_less_safe(HKDF_SHA256, &self.secret);
+        let info = [self.params.label_iv];
+        let okm = prk.expand(&info, Iv)?;
         let mut iv = [0u8; NONCE_LEN];
-        okm.fill(&mut iv).unwrap();
-        iv
+        okm.fill(&mut iv)?;
+        Ok(iv)
     }
 
-    pub(super) fn nonce(&self, pkt_no: u64) -> Nonce {
-        let mut iv = self.compute_iv();
+    pub(super) fn nonce(&self, pkt_no: u64) -> Result<Nonce, Unspecified> {
+        let mut iv = self.compute_iv()?;
         iv.iter_mut()
             .rev()
             .zip(pkt_no.to_be_bytes().iter().rev())
             .for_each(|(k, n)| *k ^= n);
-        Nonce::assume_unique_for_key(iv)
+        Ok(Nonce::assume_unique_for_key(iv))
     }
 }
 
-impl From<&InitialSecret> for HeaderProtectionKey {
-    fn from(init: &InitialSecret) -> Self {
-        Prk::new_less_safe(HKDF_SHA256, &init.0)
-            .expand(&[LABEL_QUIC_HP], &AES_128)
-            .expect("len too large")
-            .into()
+impl TryFrom<&InitialSecret> for HeaderProtectionKey {
+    type Error = Unspecified;
+
+    fn try_from(init: &InitialSecret) -> Result<Self, Self::Error> {
+        Ok(Prk::new_less_safe(HKDF_SHA256, &init.secret)
+            .expand(&[init.params.label_hp], &AES_128)?
+            .into())
     }
 }
 
-impl From<&InitialSecret> for LessSafeKey {
-    fn from(InitialSecret(init): &InitialSecret) -> Self {
-        let prk = Prk::new_less_safe(HKDF_SHA256, init);
-        let okm = prk.expand(&[LABEL_QUIC_KEY], &AES_128_GCM).unwrap();
+impl TryFrom<&InitialSecret> for LessSafeKey {
+    type Error = Unspecified;
+
+    fn try_from(init: &InitialSecret) -> Result<Self, Self::Error> {
+        let prk = Prk::new_less_safe(HKDF_SHA256, &init.secret);
+        let info = [init.params.label_key];
+        let okm = prk.expand(&info, &AES_128_GCM)?;
         let mut key = [0u8; 16];
-        okm.fill(&mut key).unwrap();
-        let key = UnboundKey::new(&AES_128_GCM, &key).unwrap();
-        LessSafeKey::new(key)
+        okm.fill(&mut key)?;
+        let key = UnboundKey::new(&AES_128_GCM, &key)?;
+        Ok(LessSafeKey::new(key))
     }
 }
 
@@ -77,14 +119,17 @@ fn test_initial_keys() {
     let dcid = hex!("8394c8f03e515708");
     let sample = hex!("d1b1c98dd7689fb8ec11d242b123dc9b");
     // Header protection
-    let init = InitialSecret::new(&dcid);
-    let key: HeaderProtectionKey = (&init).into();
+    let init = InitialSecret::new(&V1, &dcid).unwrap();
+    let key: HeaderProtectionKey = (&init).try_into().unwrap();
     assert_eq!(key.new_mask(&sample).unwrap(), hex!("437b9aec36"));
     // Payload: IV
-    assert_eq!(&init.compute_iv(), &hex!("fa044b2f42a3fd3b46fb255c"));
+    assert_eq!(
+        &init.compute_iv().unwrap(),
+        &hex!("fa044b2f42a3fd3b46fb255c")
+    );
     // Payload: Key
-    let init = InitialSecret::new(&dcid);
-    let key: LessSafeKey = (&init).into();
+    let init = InitialSecret::new(&V1, &dcid).unwrap();
+    let key: LessSafeKey = (&init).try_into().unwrap();
     // Payload: encryption
     let header = &hex!("c300000001088394c8f03e5157080000449e00000002");
     let frame = &hex!("""
@@ -100,10 +145,28 @@ fn test_initial_keys() {
     let mut payload: bytes::BytesMut = frame.as_ref().into();
     payload.resize(1162, 0);
     let aad = ring::aead::Aad::from(header);
-    let nonce = init.nonce(2);
+    let nonce = init.nonce(2).unwrap();
     let tag = key
         .seal_in_place_separate_tag(nonce, aad, &mut payload)
         .unwrap();
     assert_eq!(&payload[..8], hex!("d1b1c98dd7689fb8"));
     assert_eq!(tag.as_ref(), hex!("e221af44860018ab0856972e194cd934"));
 }
+
+#[test]
+fn test_initial_keys_v2() {
+    // RFC 9369, Appendix A.1
+    let dcid = hex!("8394c8f03e515708");
+    let init = InitialSecret::new(version_params(QUIC_V2).unwrap(), &dcid).unwrap();
+    assert_eq!(
+        init.secret,
+        hex!("14ec9d6eb9fd7af83bf5a668bc17a7e283766aade7ecd0891f70f9ff7f4bf47b")
+    );
+    assert_eq!(
+        &init.compute_iv().unwrap(),
+        &hex!("91f73e2351d8fa91660e909f")
+    );
+    let key: HeaderProtectionKey = (&init).try_into().unwrap();
+    let sample = hex!("ffe67b6abcdb4298b485dd04de806071");
+    assert_eq!(key.new_mask(&sample).unwrap(), hex!("94a0c95e80"));
+}
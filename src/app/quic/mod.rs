@@ -1,7 +1,15 @@
+mod bench;
+mod cid;
 mod conn;
 mod crypto;
 mod packet;
+mod pool;
 mod tls;
 
-pub(super) use conn::QuicConn;
-pub(super) use packet::MIN_INITIAL_PACKET_SIZE_BYTES;
+pub(crate) use bench::bench_decode;
+pub(crate) use cid::ConnIdIndex;
+pub(super) use conn::{FirstPacket, NameLookup, QuicConn};
+#[cfg(test)]
+pub(super) use packet::SAMPLE_INITIAL_PACKET;
+pub(super) use packet::{forge_version_negotiation, MIN_INITIAL_PACKET_SIZE_BYTES};
+pub(super) use pool::{DecodePool, Decoded};
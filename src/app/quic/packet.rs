@@ -1,19 +1,24 @@
 use std::{cmp, io};
 
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use ring::{
     aead::{quic::HeaderProtectionKey, Aad, LessSafeKey},
     error::Unspecified,
 };
 use tracing::info;
 
-use super::{crypto::InitialSecret, tls};
+use super::{
+    crypto::{version_params, InitialSecret, QUIC_V2},
+    tls::{self, ClientHello},
+};
 
 pub(crate) const MIN_INITIAL_PACKET_SIZE_BYTES: usize = 1200;
 
 #[derive(Debug)]
 pub(super) enum ParseError {
     NotValidQuicPacket,
+    /// No initial salt known for the version, not worth any crypto work
+    UnsupportedVersion,
     NotInitialPacket,
     NoEnoughData,
 }
@@ -31,17 +36,33 @@ impl From<Unspecified> for ParseError {
 }
 
 pub(crate) fn get_server_name(pkt: Bytes) -> Option<String> {
-    let init = InitialPacket::decode(pkt).ok()?;
-    let crypto_msg = init.crypto_message().ok()?;
-    tls::get_server_name_from_client_hello(crypto_msg)
+    InitialPacket::decode(pkt, false)
+        .ok()?
+        .client_hello()?
+        .server_name
+}
+
+/// Whether `flags` is the first byte of a long-header Initial packet,
+/// whose type is `initial_type` in its version. The fixed bit (0x40) is
+/// ignored if `tolerate_greased_bit`, RFC 9287.
+fn is_initial_flags(flags: u8, initial_type: u8, tolerate_greased_bit: bool) -> bool {
+    let type_bits = initial_type << 4;
+    if tolerate_greased_bit {
+        flags & 0xb0 == 0x80 | type_bits
+    } else {
+        flags & 0xf0 == 0xc0 | type_bits
+    }
 }
 
 pub(super) struct InitialPacket {
+    /// Client-chosen source connection ID, which the server uses as DCID
+    /// in its short-header packets of the same flow.
+    pub(super) scid: Bytes,
     payload: Bytes,
 }
 
 impl InitialPacket {
-    pub(super) fn decode(pkt: Bytes) -> Result<Self, ParseError> {
+    pub(super) fn decode(pkt: Bytes, tolerate_greased_bit: bool) -> Result<Self, ParseError> {
         let mut buf = pkt.clone();
         if pkt.len() < MIN_INITIAL_PACKET_SIZE_BYTES {
             return Err(ParseError::NoEnoughData);
@@ -49,25 +70,23 @@ impl InitialPacket {
         let flags = buf[0];
         let version = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
         buf.advance(1 + 4);
-        if version != 1 {
-            return Err(ParseError::NotValidQuicPacket);
-        }
-        if flags & 0xf0 != 0xc0 {
+        let params = version_params(version).ok_or(ParseError::UnsupportedVersion)?;
+        if !is_initial_flags(flags, params.initial_type, tolerate_greased_bit) {
             return Err(ParseError::NotInitialPacket);
         }
 
         // Decode unprotected header
         let dcid = decode_conn_id(&mut buf)?;
-        let _scid = decode_conn_id(&mut buf)?;
+        let scid = decode_conn_id(&mut buf)?;
         let token = {
-            let len = decode_var_int(&mut buf) as usize;
+            let len = decode_var_int(&mut buf)? as usize;
             if len > buf.remaining() {
                 return Err(ParseError::NoEnoughData);
             }
             buf.slice(0..len)
         };
         buf.advance(token.len());
-        let payload_len = decode_var_int(&mut buf) as usize;
+        let payload_len = decode_var_int(&mut buf)? as usize;
         if buf.remaining() < payload_len {
             return Err(ParseError::NoEnoughData);
         }
@@ -75,14 +94,15 @@ impl InitialPacket {
         let mut pkt: BytesMut = pkt.slice(..pn_offset + payload_len).as_ref().into();
 
         // Decode protected header
-        let init_secret = InitialSecret::new(&dcid);
-        let header_key: HeaderProtectionKey = (&init_secret).into();
+        let init_secret = InitialSecret::new(params, &dcid)?;
+        let header_key: HeaderProtectionKey = (&init_secret).try_into()?;
         let mask = {
             let len = header_key.algorithm().sample_len();
-            if buf.remaining() < 4 + len {
+            // Sample must lie within the payload, RFC 9001 5.4.2
+            if payload_len < 4 + len {
                 return Err(ParseError::NoEnoughData);
             }
-            header_key.new_mask(&buf[4..4 + len]).unwrap()
+            header_key.new_mask(&buf[4..4 + len])?
         };
         drop(buf);
         pkt[0] ^= mask[0] & 0x0f;
@@ -99,18 +119,26 @@ impl InitialPacket {
         let mut payload = pkt;
 
         // Decode protected payload
-        let key: LessSafeKey = (&init_secret).into();
-        key.open_in_place(
-            init_secret.nonce(pkt_no as u64),
-            Aad::from(header),
-            &mut payload,
-        )?;
-        payload.truncate(payload.len() - 16);
+        let key: LessSafeKey = (&init_secret).try_into()?;
+        let len = key
+            .open_in_place(
+                init_secret.nonce(pkt_no as u64)?,
+                Aad::from(header),
+                &mut payload,
+            )?
+            .len();
+        payload.truncate(len);
         Ok(Self {
+            scid,
             payload: payload.freeze(),
         })
     }
 
+    pub(super) fn client_hello(&self) -> Option<ClientHello> {
+        let crypto_msg = self.crypto_message().ok()?;
+        tls::parse_client_hello(crypto_msg)
+    }
+
     fn crypto_message(&self) -> Result<Bytes, ParseError> {
         let mut buf = self.payload.clone();
         // Use `msg` for avoid copy, fallback to `msg_buf` if CRYPTO frames
@@ -128,9 +156,12 @@ impl InitialPacket {
                 0x02 | 0x03 => return Err(ParseError::NotInitialPacket),
                 // CRYPTO
                 0x06 => {
-                    let pos = decode_var_int(&mut buf) as usize;
-                    let len = decode_var_int(&mut buf) as usize;
-                    if pos + len > self.payload.len() {
+                    let pos = decode_var_int(&mut buf)? as usize;
+                    let len = decode_var_int(&mut buf)? as usize;
+                    if len > buf.remaining() {
+                        return Err(ParseError::NoEnoughData);
+                    }
+                    if pos.saturating_add(len) > self.payload.len() {
                         // Prevent allocate lots of memory
                         return Err(ParseError::NotValidQuicPacket);
                     }
@@ -175,9 +206,99 @@ impl InitialPacket {
     }
 }
 
+/// Get SCID from the unprotected header of an initial packet, without
+/// decrypting it.
+pub(super) fn peek_initial_scid(pkt: &Bytes, tolerate_greased_bit: bool) -> Option<Bytes> {
+    let mut buf = pkt.clone();
+    if buf.len() < 5 {
+        return None;
+    }
+    let params = version_params(u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]))?;
+    if !is_initial_flags(buf[0], params.initial_type, tolerate_greased_bit) {
+        return None;
+    }
+    buf.advance(1 + 4);
+    decode_conn_id(&mut buf).ok()?;
+    decode_conn_id(&mut buf).ok()
+}
+
+/// Get SCID from the long header of a packet other than Version
+/// Negotiation, `None` if it's empty or a short header.
+pub(super) fn long_header_scid(pkt: &Bytes) -> Option<Bytes> {
+    let mut buf = pkt.clone();
+    if buf.len() < 5 || buf[0] & 0x80 == 0 || buf[1..5] == [0, 0, 0, 0] {
+        return None;
+    }
+    buf.advance(1 + 4);
+    decode_conn_id(&mut buf).ok()?;
+    decode_conn_id(&mut buf)
+        .ok()
+        .filter(|scid| !scid.is_empty())
+}
+
+/// Heuristic check on server-to-client packets for stateless reset, RFC
+/// 9000 10.3. A reset looks like a short-header packet of at least 21
+/// bytes, but its "DCID" is random instead of client's SCID. False
+/// positives are possible if the server switched to another CID issued by
+/// the client.
+pub(super) fn is_likely_stateless_reset(pkt: &[u8], client_scid: &[u8]) -> bool {
+    // Zero-length CID gives nothing to compare with
+    !client_scid.is_empty()
+        && pkt.len() >= 21
+        && pkt[0] & 0xc0 == 0x40
+        && pkt[1..1 + client_scid.len()] != *client_scid
+}
+
+/// Versions offered by a Version Negotiation packet from server, RFC 9000
+/// 17.2.1, i.e. long header with version 0. `None` if it isn't one.
+pub(super) fn negotiated_versions(pkt: &Bytes) -> Option<Vec<u32>> {
+    let mut buf = pkt.clone();
+    if buf.len() < 5 || buf[0] & 0x80 == 0 || buf[1..5] != [0, 0, 0, 0] {
+        return None;
+    }
+    buf.advance(1 + 4);
+    decode_conn_id(&mut buf).ok()?;
+    decode_conn_id(&mut buf).ok()?;
+    Some(
+        buf.chunks_exact(4)
+            .map(|v| u32::from_be_bytes([v[0], v[1], v[2], v[3]]))
+            .collect(),
+    )
+}
+
+/// Version Negotiation packet offering QUIC v1 & v2, in response to a client's
+/// packet of unsupported versions that may open a new conn, RFC 9000 6.1.
+/// `None` if it's v1 or v2, a short header, or too short for an Initial.
+pub(crate) fn forge_version_negotiation(pkt: &Bytes) -> Option<Bytes> {
+    let mut buf = pkt.clone();
+    if buf.len() < MIN_INITIAL_PACKET_SIZE_BYTES || buf[0] & 0x80 == 0 {
+        return None;
+    }
+    let version = u32::from_be_bytes([buf[1], buf[2], buf[3], buf[4]]);
+    if version == 0 || version_params(version).is_some() {
+        return None;
+    }
+    buf.advance(1 + 4);
+    let dcid = decode_conn_id(&mut buf).ok()?;
+    let scid = decode_conn_id(&mut buf).ok()?;
+    let mut reply = BytesMut::with_capacity(1 + 4 + 1 + scid.len() + 1 + dcid.len() + 8);
+    reply.put_u8(0xc0);
+    reply.put_u32(0);
+    // Conn IDs swapped, as it's from the server
+    reply.put_u8(scid.len() as u8);
+    reply.put_slice(&scid);
+    reply.put_u8(dcid.len() as u8);
+    reply.put_slice(&dcid);
+    reply.put_u32(1);
+    reply.put_u32(QUIC_V2);
+    Some(reply.freeze())
+}
+
 fn decode_conn_id(buf: &mut Bytes) -> Result<Bytes, ParseError> {
-    let len = buf[0] as usize;
-    buf.advance(1);
+    if !buf.has_remaining() {
+        return Err(ParseError::NoEnoughData);
+    }
+    let len = buf.get_u8() as usize;
     if len > 20 {
         // RFC 9000, 17.2: Connection ID MUST NOT exceed 20 bytes
         return Err(ParseError::NotValidQuicPacket);
@@ -190,65 +311,75 @@ fn decode_conn_id(buf: &mut Bytes) -> Result<Bytes, ParseError> {
     Ok(id)
 }
 
-fn decode_var_int(buf: &mut Bytes) -> u64 {
+fn decode_var_int(buf: &mut Bytes) -> Result<u64, ParseError> {
+    if !buf.has_remaining() {
+        return Err(ParseError::NoEnoughData);
+    }
     let len = 2u8.pow((buf[0] >> 6) as u32) as usize;
+    if len > buf.remaining() {
+        return Err(ParseError::NoEnoughData);
+    }
     let mut n = (buf[0] & 0b0011_1111) as u64;
     for i in 1..len {
         n = (n << 8) | buf[i] as u64;
     }
     buf.advance(len);
-    n
+    Ok(n)
 }
 
 #[test]
 fn test_decode_var_int() {
-    let mut buf = Bytes::copy_from_slice(&[0, 0x40, 0x47]);
-    assert_eq!(decode_var_int(&mut buf), 0);
-    assert_eq!(decode_var_int(&mut buf), 71);
+    let mut buf = Bytes::copy_from_slice(&[0, 0x40, 0x47, 0x80, 0x01]);
+    assert_eq!(decode_var_int(&mut buf).unwrap(), 0);
+    assert_eq!(decode_var_int(&mut buf).unwrap(), 71);
+    assert!(decode_var_int(&mut buf).is_err());
 }
 
+/// Client initial packet from RFC 9001, Appendix A.2
+pub(crate) const SAMPLE_INITIAL_PACKET: &[u8] = &hex_literal::hex!("""
+    c000000001088394c8f03e5157080000 449e7b9aec34d1b1c98dd7689fb8ec11
+    d242b123dc9bd8bab936b47d92ec356c 0bab7df5976d27cd449f63300099f399
+    1c260ec4c60d17b31f8429157bb35a12 82a643a8d2262cad67500cadb8e7378c
+    8eb7539ec4d4905fed1bee1fc8aafba1 7c750e2c7ace01e6005f80fcb7df6212
+    30c83711b39343fa028cea7f7fb5ff89 eac2308249a02252155e2347b63d58c5
+    457afd84d05dfffdb20392844ae81215 4682e9cf012f9021a6f0be17ddd0c208
+    4dce25ff9b06cde535d0f920a2db1bf3 62c23e596d11a4f5a6cf3948838a3aec
+    4e15daf8500a6ef69ec4e3feb6b1d98e 610ac8b7ec3faf6ad760b7bad1db4ba3
+    485e8a94dc250ae3fdb41ed15fb6a8e5 eba0fc3dd60bc8e30c5c4287e53805db
+    059ae0648db2f64264ed5e39be2e20d8 2df566da8dd5998ccabdae053060ae6c
+    7b4378e846d29f37ed7b4ea9ec5d82e7 961b7f25a9323851f681d582363aa5f8
+    9937f5a67258bf63ad6f1a0b1d96dbd4 faddfcefc5266ba6611722395c906556
+    be52afe3f565636ad1b17d508b73d874 3eeb524be22b3dcbc2c7468d54119c74
+    68449a13d8e3b95811a198f3491de3e7 fe942b330407abf82a4ed7c1b311663a
+    c69890f4157015853d91e923037c227a 33cdd5ec281ca3f79c44546b9d90ca00
+    f064c99e3dd97911d39fe9c5d0b23a22 9a234cb36186c4819e8b9c5927726632
+    291d6a418211cc2962e20fe47feb3edf 330f2c603a9d48c0fcb5699dbfe58964
+    25c5bac4aee82e57a85aaf4e2513e4f0 5796b07ba2ee47d80506f8d2c25e50fd
+    14de71e6c418559302f939b0e1abd576 f279c4b2e0feb85c1f28ff18f58891ff
+    ef132eef2fa09346aee33c28eb130ff2 8f5b766953334113211996d20011a198
+    e3fc433f9f2541010ae17c1bf202580f 6047472fb36857fe843b19f5984009dd
+    c324044e847a4f4a0ab34f719595de37 252d6235365e9b84392b061085349d73
+    203a4a13e96f5432ec0fd4a1ee65accd d5e3904df54c1da510b0ff20dcc0c77f
+    cb2c0e0eb605cb0504db87632cf3d8b4 dae6e705769d1de354270123cb11450e
+    fc60ac47683d7b8d0f811365565fd98c 4c8eb936bcab8d069fc33bd801b03ade
+    a2e1fbc5aa463d08ca19896d2bf59a07 1b851e6c239052172f296bfb5e724047
+    90a2181014f3b94a4e97d117b4381303 68cc39dbb2d198065ae3986547926cd2
+    162f40a29f0c3c8745c0f50fba3852e5 66d44575c29d39a03f0cda721984b6f4
+    40591f355e12d439ff150aab7613499d bd49adabc8676eef023b15b65bfc5ca0
+    6948109f23f350db82123535eb8a7433 bdabcb909271a6ecbcb58b936a88cd4e
+    8f2e6ff5800175f113253d8fa9ca8885 c2f552e657dc603f252e1a8e308f76f0
+    be79e2fb8f5d5fbbe2e30ecadd220723 c8c0aea8078cdfcb3868263ff8f09400
+    54da48781893a7e49ad5aff4af300cd8 04a6b6279ab3ff3afb64491c85194aab
+    760d58a606654f9f4400e8b38591356f bf6425aca26dc85244259ff2b19c41b9
+    f96f3ca9ec1dde434da7d2d392b905dd f3d1f9af93d1af5950bd493f5aa731b4
+    056df31bd267b6b90a079831aaf579be 0a39013137aac6d404f518cfd4684064
+    7e78bfe706ca4cf5e9c5453e9f7cfd2b 8b4c8d169a44e55c88d4a9a7f9474241
+    e221af44860018ab0856972e194cd934
+""");
+
 #[test]
 fn test_decode_packet() {
-    let pkt = &hex_literal::hex!("""
-        c000000001088394c8f03e5157080000 449e7b9aec34d1b1c98dd7689fb8ec11
-        d242b123dc9bd8bab936b47d92ec356c 0bab7df5976d27cd449f63300099f399
-        1c260ec4c60d17b31f8429157bb35a12 82a643a8d2262cad67500cadb8e7378c
-        8eb7539ec4d4905fed1bee1fc8aafba1 7c750e2c7ace01e6005f80fcb7df6212
-        30c83711b39343fa028cea7f7fb5ff89 eac2308249a02252155e2347b63d58c5
-        457afd84d05dfffdb20392844ae81215 4682e9cf012f9021a6f0be17ddd0c208
-        4dce25ff9b06cde535d0f920a2db1bf3 62c23e596d11a4f5a6cf3948838a3aec
-        4e15daf8500a6ef69ec4e3feb6b1d98e 610ac8b7ec3faf6ad760b7bad1db4ba3
-        485e8a94dc250ae3fdb41ed15fb6a8e5 eba0fc3dd60bc8e30c5c4287e53805db
-        059ae0648db2f64264ed5e39be2e20d8 2df566da8dd5998ccabdae053060ae6c
-        7b4378e846d29f37ed7b4ea9ec5d82e7 961b7f25a9323851f681d582363aa5f8
-        9937f5a67258bf63ad6f1a0b1d96dbd4 faddfcefc5266ba6611722395c906556
-        be52afe3f565636ad1b17d508b73d874 3eeb524be22b3dcbc2c7468d54119c74
-        68449a13d8e3b95811a198f3491de3e7 fe942b330407abf82a4ed7c1b311663a
-        c69890f4157015853d91e923037c227a 33cdd5ec281ca3f79c44546b9d90ca00
-        f064c99e3dd97911d39fe9c5d0b23a22 9a234cb36186c4819e8b9c5927726632
-        291d6a418211cc2962e20fe47feb3edf 330f2c603a9d48c0fcb5699dbfe58964
-        25c5bac4aee82e57a85aaf4e2513e4f0 5796b07ba2ee47d80506f8d2c25e50fd
-        14de71e6c418559302f939b0e1abd576 f279c4b2e0feb85c1f28ff18f58891ff
-        ef132eef2fa09346aee33c28eb130ff2 8f5b766953334113211996d20011a198
-        e3fc433f9f2541010ae17c1bf202580f 6047472fb36857fe843b19f5984009dd
-        c324044e847a4f4a0ab34f719595de37 252d6235365e9b84392b061085349d73
-        203a4a13e96f5432ec0fd4a1ee65accd d5e3904df54c1da510b0ff20dcc0c77f
-        cb2c0e0eb605cb0504db87632cf3d8b4 dae6e705769d1de354270123cb11450e
-        fc60ac47683d7b8d0f811365565fd98c 4c8eb936bcab8d069fc33bd801b03ade
-        a2e1fbc5aa463d08ca19896d2bf59a07 1b851e6c239052172f296bfb5e724047
-        90a2181014f3b94a4e97d117b4381303 68cc39dbb2d198065ae3986547926cd2
-        162f40a29f0c3c8745c0f50fba3852e5 66d44575c29d39a03f0cda721984b6f4
-        40591f355e12d439ff150aab7613499d bd49adabc8676eef023b15b65bfc5ca0
-        6948109f23f350db82123535eb8a7433 bdabcb909271a6ecbcb58b936a88cd4e
-        8f2e6ff5800175f113253d8fa9ca8885 c2f552e657dc603f252e1a8e308f76f0
-        be79e2fb8f5d5fbbe2e30ecadd220723 c8c0aea8078cdfcb3868263ff8f09400
-        54da48781893a7e49ad5aff4af300cd8 04a6b6279ab3ff3afb64491c85194aab
-        760d58a606654f9f4400e8b38591356f bf6425aca26dc85244259ff2b19c41b9
-        f96f3ca9ec1dde434da7d2d392b905dd f3d1f9af93d1af5950bd493f5aa731b4
-        056df31bd267b6b90a079831aaf579be 0a39013137aac6d404f518cfd4684064
-        7e78bfe706ca4cf5e9c5453e9f7cfd2b 8b4c8d169a44e55c88d4a9a7f9474241
-        e221af44860018ab0856972e194cd934
-    """);
+    let pkt = SAMPLE_INITIAL_PACKET;
     let expected_payload = &hex_literal::hex!("""
         060040f1010000ed0303ebf8fa56f129 39b9584a3896472ec40bb863cfd3e868
         04fe3a47f06a2b69484c000004130113 02010000c000000010000e00000b6578
@@ -259,9 +390,203 @@ fn test_decode_packet() {
         3900320408ffffffffffffffff050480 00ffff07048000ffff08011001048000
         75300901100f088394c8f03e51570806 048000ffff
     """);
-    let pkt = InitialPacket::decode(Bytes::from_static(pkt)).unwrap();
+    let pkt = InitialPacket::decode(Bytes::from_static(pkt), false).unwrap();
+    assert!(pkt.scid.is_empty());
     assert!(pkt.payload.starts_with(expected_payload));
 
     let msg = pkt.crypto_message().unwrap();
     assert_eq!(msg.remaining(), 241);
+    let hello = pkt.client_hello().unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+    assert_eq!(hello.alpn, ["alpn"]);
+}
+
+#[test]
+fn test_decode_greased_packet() {
+    let plain = InitialPacket::decode(Bytes::from_static(SAMPLE_INITIAL_PACKET), false).unwrap();
+    // Re-protect the sample (RFC 9001 A.2) with the fixed bit cleared
+    let mut header = hex_literal::hex!("8300000001088394c8f03e5157080000449e00000002").to_vec();
+    let pn_offset = header.len() - 4;
+    let init_secret = InitialSecret::new(version_params(1).unwrap(), &header[6..14]).unwrap();
+    let key: LessSafeKey = (&init_secret).try_into().unwrap();
+    let mut payload = plain.payload.to_vec();
+    key.seal_in_place_append_tag(
+        init_secret.nonce(2).unwrap(),
+        Aad::from(&header),
+        &mut payload,
+    )
+    .unwrap();
+    let header_key: HeaderProtectionKey = (&init_secret).try_into().unwrap();
+    let mask = header_key.new_mask(&payload[..16]).unwrap();
+    header[0] ^= mask[0] & 0x0f;
+    for i in 0..4 {
+        header[pn_offset + i] ^= mask[1 + i];
+    }
+    header.extend_from_slice(&payload);
+    let pkt = Bytes::from(header);
+
+    assert!(matches!(
+        InitialPacket::decode(pkt.clone(), false),
+        Err(ParseError::NotInitialPacket)
+    ));
+    assert!(peek_initial_scid(&pkt, false).is_none());
+    let init = InitialPacket::decode(pkt.clone(), true).unwrap();
+    assert_eq!(init.payload, plain.payload);
+    let hello = init.client_hello().unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("example.com"));
+    assert!(peek_initial_scid(&pkt, true).unwrap().is_empty());
+}
+
+/// Client initial packet from RFC 9369, Appendix A.2, the same ClientHello
+/// as `SAMPLE_INITIAL_PACKET` in QUIC v2
+#[cfg(test)]
+const SAMPLE_V2_INITIAL_PACKET: &[u8] = &hex_literal::hex!("""
+    d76b3343cf088394c8f03e5157080000 449ea0c95e82ffe67b6abcdb4298b485
+    dd04de806071bf03dceebfa162e75d6c 96058bdbfb127cdfcbf903388e99ad04
+    9f9a3dd4425ae4d0992cfff18ecf0fdb 5a842d09747052f17ac2053d21f57c5d
+    250f2c4f0e0202b70785b7946e992e58 a59ac52dea6774d4f03b55545243cf1a
+    12834e3f249a78d395e0d18f4d766004 f1a2674802a747eaa901c3f10cda5500
+    cb9122faa9f1df66c392079a1b40f0de 1c6054196a11cbea40afb6ef5253cd68
+    18f6625efce3b6def6ba7e4b37a40f77 32e093daa7d52190935b8da58976ff33
+    12ae50b187c1433c0f028edcc4c2838b 6a9bfc226ca4b4530e7a4ccee1bfa2a3
+    d396ae5a3fb512384b2fdd851f784a65 e03f2c4fbe11a53c7777c023462239dd
+    6f7521a3f6c7d5dd3ec9b3f233773d4b 46d23cc375eb198c63301c21801f6520
+    bcfb7966fc49b393f0061d974a2706df 8c4a9449f11d7f3d2dcbb90c6b877045
+    636e7c0c0fe4eb0f697545460c806910 d2c355f1d253bc9d2452aaa549e27a1f
+    ac7cf4ed77f322e8fa894b6a83810a34 b361901751a6f5eb65a0326e07de7c12
+    16ccce2d0193f958bb3850a833f7ae43 2b65bc5a53975c155aa4bcb4f7b2c4e5
+    4df16efaf6ddea94e2c50b4cd1dfe060 17e0e9d02900cffe1935e0491d77ffb4
+    fdf85290fdd893d577b1131a610ef6a5 c32b2ee0293617a37cbb08b847741c3b
+    8017c25ca9052ca1079d8b78aebd4787 6d330a30f6a8c6d61dd1ab5589329de7
+    14d19d61370f8149748c72f132f0fc99 f34d766c6938597040d8f9e2bb522ff9
+    9c63a344d6a2ae8aa8e51b7b90a4a806 105fcbca31506c446151adfeceb51b91
+    abfe43960977c87471cf9ad4074d30e1 0d6a7f03c63bd5d4317f68ff325ba3bd
+    80bf4dc8b52a0ba031758022eb025cdd 770b44d6d6cf0670f4e990b22347a7db
+    848265e3e5eb72dfe8299ad7481a4083 22cac55786e52f633b2fb6b614eaed18
+    d703dd84045a274ae8bfa73379661388 d6991fe39b0d93debb41700b41f90a15
+    c4d526250235ddcd6776fc77bc97e7a4 17ebcb31600d01e57f32162a8560cacc
+    7e27a096d37a1a86952ec71bd89a3e9a 30a2a26162984d7740f81193e8238e61
+    f6b5b984d4d3dfa033c1bb7e4f0037fe bf406d91c0dccf32acf423cfa1e70710
+    10d3f270121b493ce85054ef58bada42 310138fe081adb04e2bd901f2f13458b
+    3d6758158197107c14ebb193230cd115 7380aa79cae1374a7c1e5bbcb80ee23e
+    06ebfde206bfb0fcbc0edc4ebec30966 1bdd908d532eb0c6adc38b7ca7331dce
+    8dfce39ab71e7c32d318d136b6100671 a1ae6a6600e3899f31f0eed19e3417d1
+    34b90c9058f8632c798d4490da498730 7cba922d61c39805d072b589bd52fdf1
+    e86215c2d54e6670e07383a27bbffb5a ddf47d66aa85a0c6f9f32e59d85a44dd
+    5d3b22dc2be80919b490437ae4f36a0a e55edf1d0b5cb4e9a3ecabee93dfc6e3
+    8d209d0fa6536d27a5d6fbb17641cde2 7525d61093f1b28072d111b2b4ae5f89
+    d5974ee12e5cf7d5da4d6a31123041f3 3e61407e76cffcdcfd7e19ba58cf4b53
+    6f4c4938ae79324dc402894b44faf8af bab35282ab659d13c93f70412e85cb19
+    9a37ddec600545473cfb5a05e08d0b20 9973b2172b4d21fb69745a262ccde96b
+    a18b2faa745b6fe189cf772a9f84cbfc 
+""");
+
+#[test]
+fn test_decode_v2_packet() {
+    let pkt = Bytes::from_static(SAMPLE_V2_INITIAL_PACKET);
+    let v1 = InitialPacket::decode(Bytes::from_static(SAMPLE_INITIAL_PACKET), false).unwrap();
+    let v2 = InitialPacket::decode(pkt.clone(), false).unwrap();
+    assert_eq!(v2.payload, v1.payload);
+    assert_eq!(v2.client_hello(), v1.client_hello());
+    assert!(peek_initial_scid(&pkt, false).unwrap().is_empty());
+    assert!(forge_version_negotiation(&pkt).is_none());
+    // Type bits of v1 Initial are Retry in v2, RFC 9369 3.2
+    let mut zero_rtt = pkt.to_vec();
+    zero_rtt[0] &= 0xcf;
+    let zero_rtt = Bytes::from(zero_rtt);
+    assert!(matches!(
+        InitialPacket::decode(zero_rtt.clone(), false),
+        Err(ParseError::NotInitialPacket)
+    ));
+    assert!(peek_initial_scid(&zero_rtt, false).is_none());
+}
+
+#[test]
+fn test_decode_unsupported_version() {
+    // Draft-29, keyed by another salt
+    let mut pkt = SAMPLE_INITIAL_PACKET.to_vec();
+    pkt[1..5].copy_from_slice(&0xff00001du32.to_be_bytes());
+    assert!(matches!(
+        InitialPacket::decode(pkt.into(), false),
+        Err(ParseError::UnsupportedVersion)
+    ));
+}
+
+#[test]
+fn test_decode_malformed_packet() {
+    let header = &hex_literal::hex!("c000000001 08 8394c8f03e515708 00");
+    let mut pkt = header.to_vec();
+    // Token takes up the whole rest, leaving no payload length
+    let token_len = MIN_INITIAL_PACKET_SIZE_BYTES - pkt.len() - 2;
+    pkt.extend_from_slice(&(0x4000 | token_len as u16).to_be_bytes());
+    pkt.resize(MIN_INITIAL_PACKET_SIZE_BYTES, 0);
+    assert!(InitialPacket::decode(pkt.into(), false).is_err());
+    // Payload too short to hold packet number and sample
+    let mut pkt = header.to_vec();
+    pkt.extend_from_slice(&[0x00, 0x01]);
+    pkt.resize(MIN_INITIAL_PACKET_SIZE_BYTES, 0);
+    assert!(InitialPacket::decode(pkt.into(), false).is_err());
+    // Truncated or oversized CRYPTO frames
+    for payload in [&[0x06, 0x40][..], &[0x06, 0x00, 0x10, 0x01], &[0x06, 0x00]] {
+        let init = InitialPacket {
+            scid: Bytes::new(),
+            payload: Bytes::copy_from_slice(payload),
+        };
+        assert!(init.crypto_message().is_err());
+    }
+}
+
+#[test]
+fn test_stateless_reset_heuristic() {
+    let init = Bytes::from_static(&hex_literal::hex!("c000000001 04 01020304 02 0a0b 00"));
+    let scid = peek_initial_scid(&init, false).unwrap();
+    assert_eq!(&scid[..], [0x0a, 0x0b]);
+    assert!(
+        peek_initial_scid(&Bytes::from_static(SAMPLE_INITIAL_PACKET), false)
+            .unwrap()
+            .is_empty()
+    );
+
+    let mut pkt = vec![0x41, 0x0a, 0x0b];
+    pkt.resize(32, 0xff);
+    assert!(!is_likely_stateless_reset(&pkt, &scid));
+    pkt[1] = 0x42;
+    assert!(is_likely_stateless_reset(&pkt, &scid));
+    assert!(!is_likely_stateless_reset(&pkt[..20], &scid));
+    assert!(!is_likely_stateless_reset(&pkt, &[]));
+    // Long header
+    pkt[0] = 0xc1;
+    assert!(!is_likely_stateless_reset(&pkt, &scid));
+}
+
+#[test]
+fn test_version_negotiation() {
+    let pkt = hex_literal::hex!("80 00000000 02 0a0b 04 01020304 00000001 6b3343cf");
+    assert_eq!(
+        negotiated_versions(&Bytes::copy_from_slice(&pkt)),
+        Some(vec![1, 0x6b3343cf])
+    );
+    assert!(negotiated_versions(&Bytes::copy_from_slice(&pkt[..8])).is_none());
+    assert!(negotiated_versions(&Bytes::from_static(SAMPLE_INITIAL_PACKET)).is_none());
+    let mut short = pkt;
+    short[0] = 0x40;
+    assert!(negotiated_versions(&Bytes::copy_from_slice(&short)).is_none());
+}
+
+#[test]
+fn test_forge_version_negotiation() {
+    let mut pkt = SAMPLE_INITIAL_PACKET.to_vec();
+    assert!(forge_version_negotiation(&Bytes::from(pkt.clone())).is_none());
+    pkt[1..5].copy_from_slice(&0xff00001du32.to_be_bytes());
+    let pkt = Bytes::from(pkt);
+    let vn = forge_version_negotiation(&pkt).unwrap();
+    assert_eq!(negotiated_versions(&vn), Some(vec![1, QUIC_V2]));
+    let mut client = pkt.slice(5..);
+    let dcid = decode_conn_id(&mut client).unwrap();
+    let scid = decode_conn_id(&mut client).unwrap();
+    let mut server = vn.slice(5..);
+    assert_eq!(decode_conn_id(&mut server).unwrap(), scid);
+    assert_eq!(decode_conn_id(&mut server).unwrap(), dcid);
+    // Not for ones that can't open a conn
+    assert!(forge_version_negotiation(&pkt.slice(..1000)).is_none());
 }
@@ -0,0 +1,95 @@
+use std::{
+    io,
+    sync::{mpsc as std_mpsc, Arc},
+    thread,
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tracing::debug;
+
+use super::conn::FirstPacket;
+use crate::app::types::{ClientAddr, RemoteAddr};
+
+/// Jobs queued per worker before new ones get rejected
+const QUEUE_PER_WORKER: usize = 64;
+
+type ConnKey = (ClientAddr, RemoteAddr);
+
+struct DecodeJob {
+    key: ConnKey,
+    pkt: Bytes,
+}
+
+#[derive(Debug)]
+pub(crate) struct Decoded {
+    pub(crate) key: ConnKey,
+    pub(crate) first: FirstPacket,
+}
+
+/// Fixed threads decoding first packets of new conns, so that a flood of
+/// them doesn't starve the forwarding of established conns on AEAD work.
+/// Workers exit once the pool is dropped.
+pub(crate) struct DecodePool {
+    jobs: std_mpsc::SyncSender<DecodeJob>,
+    results: mpsc::Receiver<Decoded>,
+}
+
+impl DecodePool {
+    pub(crate) fn launch(workers: usize, tolerate_greased_bit: bool) -> io::Result<Self> {
+        let capacity = workers * QUEUE_PER_WORKER;
+        let (jobs, job_receiver) = std_mpsc::sync_channel::<DecodeJob>(capacity);
+        let (result_sender, results) = mpsc::channel(capacity + workers);
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for i in 0..workers {
+            let job_receiver = job_receiver.clone();
+            let result_sender = result_sender.clone();
+            thread::Builder::new()
+                .name(format!("quproxy-decode-{}", i))
+                .spawn(move || loop {
+                    let job = match job_receiver.lock().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let first = FirstPacket::inspect(&job.pkt, true, tolerate_greased_bit);
+                    let decoded = Decoded {
+                        key: job.key,
+                        first,
+                    };
+                    if result_sender.blocking_send(decoded).is_err() {
+                        break;
+                    }
+                })?;
+        }
+        debug!("Launched {} decode workers", workers);
+        Ok(Self { jobs, results })
+    }
+
+    /// Queue first packet of conn `key`, return false if the queue is full.
+    pub(crate) fn submit(&self, key: ConnKey, pkt: Bytes) -> bool {
+        self.jobs.try_send(DecodeJob { key, pkt }).is_ok()
+    }
+
+    pub(crate) async fn recv(&mut self) -> Option<Decoded> {
+        self.results.recv().await
+    }
+}
+
+#[tokio::test]
+async fn test_decode_pool() {
+    use super::{conn::NameLookup, packet::SAMPLE_INITIAL_PACKET, QuicConn};
+
+    let mut pool = DecodePool::launch(2, false).unwrap();
+    let key = (
+        ClientAddr(([127, 0, 0, 1], 1).into()),
+        RemoteAddr(([127, 0, 0, 1], 443).into()),
+    );
+    assert!(pool.submit(key, Bytes::from_static(SAMPLE_INITIAL_PACKET)));
+    let decoded = pool.recv().await.unwrap();
+    assert_eq!(decoded.key, key);
+    let conn = QuicConn::with_first_packet(key.1, key.0, decoded.first);
+    assert_eq!(conn.name_lookup, NameLookup::Found);
+    assert_eq!(conn.remote_name.as_deref(), Some("example.com"));
+    assert_eq!(conn.alpn, ["alpn"]);
+}
@@ -4,7 +4,7 @@ use tracing::debug;
 macro_rules! pkt_assert {
     ($e:expr, $err:expr) => {
         if !$e {
-            debug!("Failed to get SNI: {}", $err);
+            debug!("Failed to parse ClientHello: {}", $err);
             return None;
         }
     };
@@ -13,7 +13,7 @@ macro_rules! pkt_assert {
 macro_rules! impl_get {
     ($t:ident, $get:ident) => {
         fn $get(&mut self) -> Option<$t> {
-            if !self.inner.has_remaining() {
+            if self.inner.remaining() < std::mem::size_of::<$t>() {
                 None
             } else {
                 Some(self.inner.$get())
@@ -44,7 +44,15 @@ impl<T: Buf> Reader<T> {
     impl_get!(u16, get_u16);
 }
 
-pub(super) fn get_server_name_from_client_hello<T: Buf>(buf: T) -> Option<String> {
+/// What's taken from a TLS ClientHello.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(super) struct ClientHello {
+    pub(super) server_name: Option<String>,
+    /// Protocols offered in the ALPN extension, empty if there isn't one
+    pub(super) alpn: Vec<String>,
+}
+
+pub(super) fn parse_client_hello<T: Buf>(buf: T) -> Option<ClientHello> {
     let mut buf = Reader::new(buf);
     pkt_assert!(buf.get_u8()? == 0x01, "Type != ClientHello");
     let len = (buf.get_u8()? as usize) << 8 | (buf.get_u16()? as usize);
@@ -62,16 +70,18 @@ pub(super) fn get_server_name_from_client_hello<T: Buf>(buf: T) -> Option<String
     let len = buf.get_u16()? as usize; // Extension
     pkt_assert!(buf.inner.remaining() == len, "Extension length mismatched");
 
+    let mut hello = ClientHello::default();
     while buf.inner.has_remaining() {
         let tag = buf.get_u16()?;
         let len = buf.get_u16()? as usize;
         pkt_assert!(buf.inner.remaining() >= len, "Truncted extension");
+        let next = buf.inner.remaining() - len;
         match tag {
             0x0000 => {
                 // SNI
                 let mut ext_len = buf.get_u16()? as usize;
-                pkt_assert!(ext_len <= len - 2, "Truncted SNI");
-                while ext_len > 3 {
+                pkt_assert!(ext_len + 2 <= len, "Truncted SNI");
+                while ext_len > 3 && hello.server_name.is_none() {
                     let name_type = buf.get_u8()?;
                     let name_len = buf.get_u16()? as usize;
                     ext_len -= 3;
@@ -87,7 +97,8 @@ pub(super) fn get_server_name_from_client_hello<T: Buf>(buf: T) -> Option<String
                                 .chars()
                                 .all(|c| c.is_ascii_alphanumeric() | "-._".contains(c));
                             pkt_assert!(valid, "Domain name contains illegal character");
-                            return Some(name);
+                            ext_len -= name_len;
+                            hello.server_name = Some(name);
                         }
                         _ => {
                             // Ignore other types
@@ -96,31 +107,125 @@ pub(super) fn get_server_name_from_client_hello<T: Buf>(buf: T) -> Option<String
                         }
                     }
                 }
-                buf.advance(ext_len);
             }
-            _ => {
-                // Ignore other extension
-                buf.advance(len);
+            0x0010 => {
+                // ALPN, a malformed one doesn't cost us the SNI
+                match parse_alpn(&mut buf, len) {
+                    Some(alpn) => hello.alpn = alpn,
+                    None => debug!("Ignore malformed ALPN extension"),
+                }
             }
+            // Ignore other extension
+            _ => (),
         }
+        // Skip the rest of the extension
+        buf.advance(buf.inner.remaining() - next)?;
     }
-    None
+    Some(hello)
 }
 
+/// Protocols of an ALPN extension of `len` bytes, `None` if malformed.
+/// Never reads beyond the extension.
+fn parse_alpn<T: Buf>(buf: &mut Reader<T>, len: usize) -> Option<Vec<String>> {
+    pkt_assert!(len >= 2, "Truncted ALPN");
+    let mut list_len = buf.get_u16()? as usize;
+    pkt_assert!(list_len + 2 <= len, "Truncted ALPN");
+    let mut alpn = Vec::new();
+    while list_len > 0 {
+        let name_len = buf.get_u8()? as usize;
+        pkt_assert!(name_len > 0, "Zero-sized protocol name");
+        pkt_assert!(name_len < list_len, "Truncted ALPN list");
+        let mut name_buf = vec![0u8; name_len];
+        buf.inner.copy_to_slice(&mut name_buf);
+        list_len -= 1 + name_len;
+        // Opaque bytes, only printable ones are of use
+        match String::from_utf8(name_buf) {
+            Ok(name) if name.chars().all(|c| c.is_ascii_graphic()) => alpn.push(name),
+            _ => debug!("Ignore unprintable ALPN protocol"),
+        }
+    }
+    Some(alpn)
+}
+
+#[cfg(test)]
+const SAMPLE_CLIENT_HELLO: &[u8] = &hex_literal::hex!("""
+    0100011e03032d9a20d602eadf5581c4 3119415208653176e86fb0c535c8a0c3
+    aa8742dc65c300000613011302130301 0000ef00390060fb5c27d8ca3e448804
+    a09432370f00030245c080ff73db0c00 0000018aaa3a0a000000017127048002
+    ae500802406409024067040480f00000 06048060000001048000753020048001
+    00000704806000008000475204000000 01050480600000001000050003026833
+    00000013001100000e7777772e676f6f 676c652e636f6d000a00080006001d00
+    170018002d0002010100330026002400 1d00203e65bd93cf09572df162e5e1f1
+    e67c2aa7a2c25faa35d289a422aa2462 e24a47000d0014001204030804040105
+    0308050501080606010201001b000302 0002002b000302030444690005000302
+    6833
+""");
+
 #[test]
 fn test_parse_client_hello() {
-    let buf = &hex_literal::hex!("""
-        0100011e03032d9a20d602eadf5581c4 3119415208653176e86fb0c535c8a0c3
-        aa8742dc65c300000613011302130301 0000ef00390060fb5c27d8ca3e448804
-        a09432370f00030245c080ff73db0c00 0000018aaa3a0a000000017127048002
-        ae500802406409024067040480f00000 06048060000001048000753020048001
-        00000704806000008000475204000000 01050480600000001000050003026833
-        00000013001100000e7777772e676f6f 676c652e636f6d000a00080006001d00
-        170018002d0002010100330026002400 1d00203e65bd93cf09572df162e5e1f1
-        e67c2aa7a2c25faa35d289a422aa2462 e24a47000d0014001204030804040105
-        0308050501080606010201001b000302 0002002b000302030444690005000302
-        6833
-    """);
-    let name = get_server_name_from_client_hello(bytes::Bytes::from_static(buf)).unwrap();
-    assert_eq!(&name, "www.google.com")
+    let buf = bytes::Bytes::from_static(SAMPLE_CLIENT_HELLO);
+    let hello = parse_client_hello(buf).unwrap();
+    assert_eq!(hello.server_name.as_deref(), Some("www.google.com"));
+    assert_eq!(hello.alpn, ["h3"]);
+}
+
+#[test]
+fn test_parse_client_hello_alpn() {
+    use hex_literal::hex;
+
+    // Replace the ALPN extension of the sample, fixing up lengths
+    let with_alpn = |ext: &[u8]| {
+        let alpn_at = 0x97;
+        assert_eq!(
+            SAMPLE_CLIENT_HELLO[alpn_at..alpn_at + 9],
+            hex!("0010 0005 0003 026833")
+        );
+        let mut buf = SAMPLE_CLIENT_HELLO[..alpn_at].to_vec();
+        buf.extend_from_slice(ext);
+        buf.extend_from_slice(&SAMPLE_CLIENT_HELLO[alpn_at + 9..]);
+        let msg_len = (buf.len() - 4) as u32;
+        buf[1..4].copy_from_slice(&msg_len.to_be_bytes()[1..]);
+        let ext_len = (buf.len() - 0x33) as u16;
+        buf[0x31..0x33].copy_from_slice(&ext_len.to_be_bytes());
+        parse_client_hello(&buf[..])
+    };
+    let hello = with_alpn(&hex!("0010 000b 0009 026833 0568332d3239")).unwrap();
+    assert_eq!(hello.alpn, ["h3", "h3-29"]);
+    assert_eq!(hello.server_name.as_deref(), Some("www.google.com"));
+    // Missing, empty or unprintable, SNI is still there
+    for ext in [
+        &[][..],
+        &hex!("0010 0002 0000"),
+        &hex!("0010 0005 0003 0200ff"),
+    ] {
+        let hello = with_alpn(ext).unwrap();
+        assert!(hello.alpn.is_empty());
+        assert_eq!(hello.server_name.as_deref(), Some("www.google.com"));
+    }
+    // Malformed lengths, ALPN is dropped but SNI is kept
+    for ext in [
+        &hex!("0010 0005 0009 026833")[..],
+        &hex!("0010 0005 0003 036833"),
+        &hex!("0010 0005 0003 006833"),
+        &hex!("0010 0001 00"),
+        &hex!("0010 0000"),
+    ] {
+        let hello = with_alpn(ext).unwrap();
+        assert!(hello.alpn.is_empty());
+        assert_eq!(hello.server_name.as_deref(), Some("www.google.com"));
+    }
+    // Unless the extension itself overruns
+    assert!(with_alpn(&hex!("0010 ff00 0003 026833")).is_none());
+}
+
+#[test]
+fn test_parse_malformed_client_hello() {
+    for i in 0..SAMPLE_CLIENT_HELLO.len() {
+        for byte in [0x00, 0x01, 0xff] {
+            let mut buf = SAMPLE_CLIENT_HELLO.to_vec();
+            buf[i] = byte;
+            parse_client_hello(&buf[..]);
+        }
+        parse_client_hello(&SAMPLE_CLIENT_HELLO[..i]);
+    }
 }
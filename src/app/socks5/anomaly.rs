@@ -0,0 +1,188 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+use crate::{
+    app::types::{ClientAddr, RemoteAddr},
+    cli::CliArgs,
+};
+
+/// Period over which packets & new conns are counted
+const WINDOW: Duration = Duration::from_secs(10);
+/// Min interval between two warnings
+const WARN_INTERVAL: Duration = Duration::from_secs(60);
+/// Windows with fewer packets are too quiet to judge the ratio
+const MIN_PACKETS: usize = 100;
+
+/// Warn on traffic patterns a routing loop or a scan would produce: too
+/// many new conns among packets, or a client opening conns to too many
+/// remotes. Loops are usually misconfigured TPROXY rules intercepting
+/// quproxy's own traffic again, not all of them are caught by the
+/// self-address check.
+pub(super) struct AnomalyDetector {
+    max_conn_ratio: Option<f64>,
+    max_remotes_per_client: Option<usize>,
+    window_start: Instant,
+    packets: usize,
+    new_conns: usize,
+    /// Distinct remotes of new conns by client IP
+    client_remotes: HashMap<IpAddr, HashSet<RemoteAddr>>,
+    warned_at: Option<Instant>,
+}
+
+/// What an `AnomalyDetector` found in a window.
+#[derive(Debug, PartialEq)]
+pub(super) enum Anomaly {
+    ConnRatio { new_conns: usize, packets: usize },
+    ClientRemotes { client: IpAddr, remotes: usize },
+}
+
+impl AnomalyDetector {
+    pub(super) fn new(args: &CliArgs) -> Self {
+        Self {
+            max_conn_ratio: args.loop_warn_conn_ratio,
+            max_remotes_per_client: args.loop_warn_remotes_per_client,
+            window_start: Instant::now(),
+            packets: 0,
+            new_conns: 0,
+            client_remotes: Default::default(),
+            warned_at: None,
+        }
+    }
+
+    pub(super) fn is_enabled(&self) -> bool {
+        self.max_conn_ratio.is_some() || self.max_remotes_per_client.is_some()
+    }
+
+    pub(super) fn observe_packets(&mut self, n: usize) {
+        self.packets += n;
+    }
+
+    pub(super) fn observe_new_conn(&mut self, client: ClientAddr, remote: RemoteAddr) {
+        self.new_conns += 1;
+        self.client_remotes
+            .entry(client.0.ip())
+            .or_default()
+            .insert(remote);
+    }
+
+    /// Close the window if it's over, return its anomaly if any.
+    pub(super) fn check(&mut self, now: Instant) -> Option<Anomaly> {
+        if now.duration_since(self.window_start) < WINDOW {
+            return None;
+        }
+        let anomaly = self.anomaly();
+        self.window_start = now;
+        self.packets = 0;
+        self.new_conns = 0;
+        self.client_remotes.clear();
+        anomaly
+    }
+
+    /// Whether to warn now, false if warned recently.
+    pub(super) fn should_warn(&mut self, now: Instant) -> bool {
+        match self.warned_at {
+            Some(at) if now.duration_since(at) < WARN_INTERVAL => false,
+            _ => {
+                self.warned_at = Some(now);
+                true
+            }
+        }
+    }
+
+    fn anomaly(&self) -> Option<Anomaly> {
+        if let Some(max_ratio) = self.max_conn_ratio {
+            if self.packets >= MIN_PACKETS
+                && self.new_conns as f64 > self.packets as f64 * max_ratio
+            {
+                return Some(Anomaly::ConnRatio {
+                    new_conns: self.new_conns,
+                    packets: self.packets,
+                });
+            }
+        }
+        let max_remotes = self.max_remotes_per_client?;
+        self.client_remotes
+            .iter()
+            .map(|(client, remotes)| (*client, remotes.len()))
+            .max_by_key(|(_, n)| *n)
+            .filter(|(_, n)| *n > max_remotes)
+            .map(|(client, remotes)| Anomaly::ClientRemotes { client, remotes })
+    }
+}
+
+impl Anomaly {
+    pub(super) fn warn(&self) {
+        match self {
+            Self::ConnRatio { new_conns, packets } => warn!(
+                "{} new conns among {} packets in {:?}, possible routing loop or scan, \
+                 check TPROXY rules",
+                new_conns, packets, WINDOW
+            ),
+            Self::ClientRemotes { client, remotes } => warn!(
+                "{} opened conns to {} remotes in {:?}, possible routing loop or scan, \
+                 check TPROXY rules",
+                client, remotes, WINDOW
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_anomaly_detector() {
+    use clap::Parser;
+
+    let args = CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "--loop-warn-conn-ratio",
+        "0.5",
+        "--loop-warn-remotes-per-client",
+        "3",
+    ]);
+    let mut detector = AnomalyDetector::new(&args);
+    assert!(detector.is_enabled());
+    let t0 = detector.window_start;
+    let client = ClientAddr(([10, 0, 0, 1], 50000).into());
+    let remote = |i| RemoteAddr(([192, 0, 2, i], 443).into());
+
+    // Normal: a few conns with plenty of packets
+    detector.observe_packets(200);
+    (0..3).for_each(|i| detector.observe_new_conn(client, remote(i)));
+    assert_eq!(detector.check(t0 + WINDOW / 2), None);
+    assert_eq!(detector.check(t0 + WINDOW), None);
+
+    // Many conns to the same remotes aren't counted twice
+    detector.observe_packets(200);
+    (0..20).for_each(|i| detector.observe_new_conn(client, remote(i % 3)));
+    assert_eq!(detector.check(t0 + WINDOW * 2), None);
+
+    // One client to many remotes
+    (0..5).for_each(|i| detector.observe_new_conn(client, remote(i)));
+    let anomaly = detector.check(t0 + WINDOW * 3);
+    let expected = Anomaly::ClientRemotes {
+        client: client.0.ip(),
+        remotes: 5,
+    };
+    assert_eq!(anomaly, Some(expected));
+    assert!(detector.should_warn(t0 + WINDOW * 3));
+
+    // Packets are mostly new conns
+    detector.observe_packets(100);
+    (0..60)
+        .for_each(|i| detector.observe_new_conn(ClientAddr(([10, 0, 1, i], 1).into()), remote(0)));
+    let anomaly = detector.check(t0 + WINDOW * 4);
+    let expected = Anomaly::ConnRatio {
+        new_conns: 60,
+        packets: 100,
+    };
+    assert_eq!(anomaly, Some(expected));
+    // Throttled
+    assert!(!detector.should_warn(t0 + WINDOW * 4));
+    assert!(detector.should_warn(t0 + WINDOW * 3 + WARN_INTERVAL));
+}
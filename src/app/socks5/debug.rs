@@ -0,0 +1,90 @@
+use std::net::SocketAddr;
+
+use bytes::Bytes;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::app::{types::RemoteAddr, AppContext};
+
+use super::{forward::select_server, select::ConnContext, SocksTarget};
+
+/// Magic prefix of debug queries, followed by "<remote-addr> [sni]", and
+/// padded with spaces or zeros to make room for the reply.
+const QUERY_MAGIC: &[u8] = b"QUPROXY?";
+
+/// Answer queries on which upstream would be selected for a given remote
+/// and SNI, without forwarding anything. Read-only debug aid, for packets
+/// intercepted to `--debug-query-port`.
+pub(super) struct DebugQuery<'a> {
+    context: &'a AppContext,
+}
+
+#[derive(Debug, Serialize)]
+struct DebugReply<'a> {
+    remote: Option<SocketAddr>,
+    sni: Option<&'a str>,
+    pinned: Option<String>,
+    server: Option<String>,
+    error: Option<&'static str>,
+}
+
+impl<'a> DebugQuery<'a> {
+    pub(super) fn new(context: &'a AppContext) -> Self {
+        Self { context }
+    }
+
+    /// Reply to `pkt`, `None` if it isn't a query. Replies never exceed the
+    /// query in size, so they can't be used for amplification; queries too
+    /// short for their replies are dropped.
+    pub(super) fn reply(&self, pkt: &[u8]) -> Option<Bytes> {
+        let query = pkt.strip_prefix(QUERY_MAGIC)?;
+        let query = String::from_utf8_lossy(query);
+        let reply = serde_json::to_vec(&self.answer(query.trim_end_matches('\0'))).unwrap();
+        if reply.len() > pkt.len() {
+            debug!(
+                "Drop debug query of {} bytes, pad it to {} bytes",
+                pkt.len(),
+                reply.len()
+            );
+            return None;
+        }
+        Some(reply.into())
+    }
+
+    fn answer<'q>(&self, query: &'q str) -> DebugReply<'q> {
+        let mut args = query.split_whitespace();
+        let remote: Option<SocketAddr> = args.next().and_then(|s| s.parse().ok());
+        let sni = args.next();
+        let mut reply = DebugReply {
+            remote,
+            sni,
+            pinned: sni.and_then(|name| self.context.pinned_server_name(name)),
+            server: None,
+            error: None,
+        };
+        let remote = match remote {
+            Some(remote) => RemoteAddr::from(remote),
+            None => {
+                reply.error = Some("invalid remote address");
+                return reply;
+            }
+        };
+        let target: SocksTarget = match sni {
+            Some(name) if self.context.cli_args.remote_dns => {
+                (name.to_string(), remote.0.port()).into()
+            }
+            _ => remote.0.into(),
+        };
+        let conn = ConnContext {
+            client: None,
+            remote,
+            remote_name: sni,
+            alpn: &[],
+        };
+        reply.server = select_server(self.context, &conn, target.proto()).map(|s| s.name.clone());
+        if reply.server.is_none() {
+            reply.error = Some("no available proxy");
+        }
+        reply
+    }
+}
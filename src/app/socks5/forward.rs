@@ -1,37 +1,242 @@
-use std::io::{self, ErrorKind};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
 
 use bytes::Bytes;
 use futures::{Stream, StreamExt};
-use lru_time_cache::LruCache;
-use tracing::{debug, info, trace, warn};
+use lru_time_cache::{Entry, LruCache};
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::interval,
+};
+use tracing::{debug, field, info, instrument, trace, warn, Span};
 
 use crate::app::{
     checking::Healthy,
+    limit::{EgressDrop, EgressLimiter},
     net::{MsgArrayWriteBuffer, UDP_BATCH_SIZE},
-    quic::QuicConn,
-    quic::MIN_INITIAL_PACKET_SIZE_BYTES,
-    tproxy::TProxySenderCache,
+    quic::{forge_version_negotiation, ConnIdIndex, DecodePool, Decoded, FirstPacket, QuicConn},
+    tproxy::{ReplyBatcher, TProxySenderCache},
     types::{ClientAddr, RemoteAddr, UdpPackets},
     AppContext,
 };
 
-use super::{session::SocksSession, SocksTarget};
+use crate::cli::ConnKey;
+
+use super::{
+    anomaly::AnomalyDetector, debug::DebugQuery, select::ConnContext, server::AppProto,
+    session::SocksSession, SocksServer, SocksTarget,
+};
+
+/// Period of removing dropped TProxy senders, in addition to opportunistic
+/// cleanup on creating new senders.
+const SENDERS_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
 
 pub(crate) struct SocksForwardService {
     context: AppContext,
     conns: LruCache<(ClientAddr, RemoteAddr), QuicConn>,
     senders: TProxySenderCache,
     buf: MsgArrayWriteBuffer<2>,
+    rebalancer: Rebalancer,
+    egress_limiter: EgressLimiter,
+    admin_queries: mpsc::Receiver<AdminQuery>,
+    reply_batcher: Option<ReplyBatcher>,
+    decode_pool: Option<DecodePool>,
+    /// Packets of new conns waiting for `decode_pool`, in arrival order
+    pending_decodes: HashMap<(ClientAddr, RemoteAddr), Vec<UdpPackets>>,
+    /// See `--follow-client-port`
+    conn_ids: Option<ConnIdIndex>,
+    anomalies: AnomalyDetector,
+    /// Upstreams last selected by client IP, see `--sticky-client`
+    sticky_clients: Option<LruCache<IpAddr, Arc<SocksServer>>>,
 }
 
-impl SocksForwardService {
-    pub(crate) fn new(context: &AppContext) -> Self {
+/// Max batches of packets held for a conn waiting for its first packet
+/// to be decoded, later ones are dropped.
+const MAX_PENDING_BATCHES: usize = 16;
+
+/// Queries from the admin API, handled by forward task as it owns the conns.
+#[derive(Debug)]
+pub(crate) enum AdminQuery {
+    /// Stop selecting the named server and migrate its conns away.
+    Drain {
+        server: String,
+        reply: oneshot::Sender<Option<DrainReport>>,
+    },
+    /// Dry-run how a new packet of the flow would be forwarded.
+    Explain {
+        client: Option<ClientAddr>,
+        remote: RemoteAddr,
+        sni: Option<String>,
+        reply: oneshot::Sender<FlowExplanation>,
+    },
+    /// Number of tracked QUIC conns.
+    ConnCount { reply: oneshot::Sender<usize> },
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DrainReport {
+    server: String,
+    /// Conns migrated by this query
+    migrated: usize,
+    /// Conns left to be migrated later, at the bounded rate
+    remaining: usize,
+}
+
+/// Why a flow would go to a server or be dropped, see `AdminQuery::Explain`.
+#[derive(Debug, Serialize)]
+pub(crate) struct FlowExplanation {
+    /// Filters on the forwarding path, in order
+    checks: Vec<FlowCheck>,
+    /// All upstreams with why it can't take the flow, empty if the flow
+    /// stays on its existing conn
+    candidates: Vec<CandidateReport>,
+    /// Where the flow would go, `None` if dropped
+    server: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct FlowCheck {
+    name: &'static str,
+    passed: bool,
+    detail: String,
+}
+
+impl FlowCheck {
+    fn new<D: Into<String>>(name: &'static str, passed: bool, detail: D) -> Self {
         Self {
+            name,
+            passed,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct CandidateReport {
+    name: String,
+    /// `None` if eligible
+    excluded: Option<&'static str>,
+    /// Deprioritized for approaching RX limit
+    near_rx_limit: bool,
+}
+
+/// Proactively migrate long-lived conns to the best server, or conns away
+/// from draining servers, with at most `max_migrations` per `period`.
+struct Rebalancer {
+    after: Option<Duration>,
+    period: Duration,
+    max_migrations: usize,
+    cycle_start: Instant,
+    migrated: usize,
+}
+
+impl Rebalancer {
+    fn new(context: &AppContext) -> Self {
+        Self {
+            after: context.cli_args.rebalance_after,
+            period: context.cli_args.check_interval,
+            max_migrations: context.cli_args.rebalance_max_migrations,
+            cycle_start: Instant::now(),
+            migrated: 0,
+        }
+    }
+
+    fn has_budget(&mut self) -> bool {
+        if self.cycle_start.elapsed() >= self.period {
+            self.cycle_start = Instant::now();
+            self.migrated = 0;
+        }
+        self.migrated < self.max_migrations
+    }
+
+    /// Whether to migrate `conn` away, if it's not where the selection
+    /// would place it now. `sticky` is the server remembered for its
+    /// client, see `--sticky-client`.
+    fn should_migrate(
+        &mut self,
+        context: &AppContext,
+        conn: &QuicConn,
+        sticky: Option<&Arc<SocksServer>>,
+    ) -> bool {
+        let proxy = match conn.proxy() {
+            Some(proxy) => proxy,
+            None => return false,
+        };
+        if proxy.server.is_draining() {
+            if self.has_budget() {
+                self.migrated += 1;
+                return true;
+            }
+            return false;
+        }
+        match self.after {
+            Some(after) if conn.created_at.elapsed() >= after => (),
+            _ => return false,
+        }
+        if !self.has_budget() {
+            return false;
+        }
+        if sticky.is_some_and(|sticky| Arc::ptr_eq(sticky, &proxy.server)) {
+            return false;
+        }
+        let conn_ctx = ConnContext {
+            client: Some(conn.client),
+            remote: conn.remote,
+            remote_name: conn.remote_name.as_deref(),
+            alpn: &conn.alpn,
+        };
+        if is_placed(context, &conn_ctx, proxy.target().proto(), &proxy.server) {
+            return false;
+        }
+        self.migrated += 1;
+        true
+    }
+}
+
+impl SocksForwardService {
+    pub(crate) fn new(
+        context: &AppContext,
+        admin_queries: mpsc::Receiver<AdminQuery>,
+    ) -> io::Result<Self> {
+        let args = context.cli_args;
+        // Nothing to decrypt without the need of server names
+        let decode_pool = match args.decode_workers {
+            Some(workers) if args.decode_initial() => Some(DecodePool::launch(
+                workers.into(),
+                args.tolerate_greased_bit,
+            )?),
+            _ => None,
+        };
+        Ok(Self {
             context: context.clone(),
             conns: context.new_lru_cache_for_sessions(),
-            senders: TProxySenderCache::new(),
+            senders: TProxySenderCache::new(
+                context.cli_args.max_tproxy_senders,
+                context.cli_args.reply_mode,
+            ),
             buf: MsgArrayWriteBuffer::with_capacity(UDP_BATCH_SIZE),
-        }
+            rebalancer: Rebalancer::new(context),
+            egress_limiter: EgressLimiter::new(context),
+            admin_queries,
+            reply_batcher: context
+                .cli_args
+                .reply_batch_interval
+                .map(ReplyBatcher::launch),
+            decode_pool,
+            pending_decodes: Default::default(),
+            conn_ids: (args.follow_client_port && args.conn_key == ConnKey::Full)
+                .then(ConnIdIndex::default),
+            anomalies: AnomalyDetector::new(args),
+            sticky_clients: args
+                .sticky_client
+                .then(|| context.new_lru_cache_for_sessions()),
+        })
     }
 
     pub(crate) async fn serve<R>(mut self, receiver: R)
@@ -40,88 +245,1499 @@ impl SocksForwardService {
     {
         debug!("SOCKS forward service started");
         let mut receiver = Box::pin(receiver);
-        while let Some((client, remote, pkts)) = receiver.next().await {
-            if pkts.is_empty() {
-                warn!("Empty list of packets");
-                continue;
-            }
-            if let Err(err) = self.forward_client_to_remote(client, remote, &pkts).await {
-                info!("Error on sending packet to proxy: {}", err);
+        let mut sweep_interval = interval(SENDERS_SWEEP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = sweep_interval.tick() => {
+                    let live = self.senders.sweep();
+                    self.context.stats.tproxy_senders.store(live, Ordering::Relaxed);
+                    if self.context.cli_args.reset_teardown {
+                        self.teardown_reset_conns();
+                    }
+                    if let Some(lifetime) = self.context.cli_args.max_conn_lifetime {
+                        self.evict_old_conns(lifetime);
+                    }
+                    self.retire_removed_servers(self.context.cli_args.drain_timeout);
+                    if let Some(conn_ids) = &self.conn_ids {
+                        let conns = &self.conns;
+                        conn_ids.retain(|client, remote| conns.contains_key(&(client, remote)));
+                    }
+                }
+                Some(query) = self.admin_queries.recv() => self.handle_admin_query(query),
+                Some(decoded) = recv_decoded(&mut self.decode_pool) => {
+                    self.handle_decoded(decoded).await
+                }
+                next = receiver.next() => match next {
+                    Some((client, remote, pkts)) => self.handle_packets(client, remote, pkts).await,
+                    None => break,
+                },
             }
         }
         warn!("SOCKS forward service exited");
     }
 
-    async fn forward_client_to_remote(
+    async fn handle_packets(
+        &mut self,
+        client: ClientAddr,
+        remote: RemoteAddr,
+        mut pkts: Box<[Bytes]>,
+    ) {
+        if pkts.is_empty() {
+            warn!("Empty list of packets");
+            return;
+        }
+        // Zero-length datagrams can't be QUIC, often probes or abuses
+        if !self.context.cli_args.allow_empty_udp && pkts.iter().any(Bytes::is_empty) {
+            let (pkts_kept, empty): (Vec<_>, Vec<_>) =
+                pkts.into_vec().into_iter().partition(|pkt| !pkt.is_empty());
+            trace!("Drop {} empty datagrams from {:?}", empty.len(), client);
+            self.context
+                .stats
+                .empty_datagrams
+                .fetch_add(empty.len(), Ordering::Relaxed);
+            if pkts_kept.is_empty() {
+                return;
+            }
+            pkts = pkts_kept.into_boxed_slice();
+        }
+        if self.context.cli_args.debug_query_port == Some(remote.0.port()) {
+            if let Err(err) = self.answer_debug_queries(client, remote, &pkts).await {
+                debug!("Failed to answer debug query of {:?}: {}", client, err);
+            }
+            return;
+        }
+        if let Some(pool) = &self.decode_pool {
+            let key = conn_key(self.context.cli_args.conn_key, client, remote);
+            if let Some(pending) = self.pending_decodes.get_mut(&key) {
+                if pending.len() < MAX_PENDING_BATCHES {
+                    pending.push((client, remote, pkts));
+                } else {
+                    trace!(
+                        "Drop {} packets from {:?} pending decode",
+                        pkts.len(),
+                        client
+                    );
+                    self.context
+                        .stats
+                        .decode_pending_drops
+                        .fetch_add(1, Ordering::Relaxed);
+                }
+                return;
+            }
+            if !self.conns.contains_key(&key) {
+                if pool.submit(key, pkts[0].clone()) {
+                    self.pending_decodes
+                        .insert(key, vec![(client, remote, pkts)]);
+                } else {
+                    trace!("Decode queue full, drop packets from {:?}", client);
+                    self.context
+                        .stats
+                        .decode_overflows
+                        .fetch_add(pkts.len(), Ordering::Relaxed);
+                }
+                return;
+            }
+        }
+        if let Err(err) = self
+            .forward_client_to_remote(client, remote, &pkts, None)
+            .await
+        {
+            info!("Error on sending packet to proxy: {}", err);
+        }
+    }
+
+    /// Reply to debug queries as from `remote`, never forward them.
+    async fn answer_debug_queries(
         &mut self,
         client: ClientAddr,
         remote: RemoteAddr,
         pkts: &[Bytes],
     ) -> io::Result<()> {
-        let key = &(client, remote);
-        let conn = if !self.conns.contains_key(key) {
-            // Start new QUIC conn
-            let conn = if self.context.cli_args.remote_dns
-                && pkts[0].len() >= MIN_INITIAL_PACKET_SIZE_BYTES
+        let query = DebugQuery::new(&self.context);
+        let replies: Vec<_> = pkts.iter().filter_map(|pkt| query.reply(pkt)).collect();
+        if replies.is_empty() {
+            return Ok(());
+        }
+        let sender = self.senders.get_or_create(remote)?;
+        let mut buf = MsgArrayWriteBuffer::<1>::with_capacity(replies.len());
+        for reply in replies {
+            buf.push([reply], Some(client.0));
+        }
+        (*sender).as_ref().batch_send(&mut buf).await?;
+        Ok(())
+    }
+
+    /// Open the conn with its decoded first packet, and forward packets
+    /// held meanwhile.
+    async fn handle_decoded(&mut self, decoded: Decoded) {
+        let pending = match self.pending_decodes.remove(&decoded.key) {
+            Some(pending) => pending,
+            None => return,
+        };
+        let mut first = Some(decoded.first);
+        for (client, remote, pkts) in pending {
+            if let Err(err) = self
+                .forward_client_to_remote(client, remote, &pkts, first.take())
+                .await
             {
-                QuicConn::new(remote, client, Some(pkts[0].clone()))
+                info!("Error on sending packet to proxy: {}", err);
+            }
+        }
+    }
+
+    fn handle_admin_query(&mut self, query: AdminQuery) {
+        match query {
+            AdminQuery::Drain { server, reply } => {
+                let _ = reply.send(self.drain_server(&server));
+            }
+            AdminQuery::Explain {
+                client,
+                remote,
+                sni,
+                reply,
+            } => {
+                let _ = reply.send(self.explain_flow(client, remote, sni.as_deref()));
+            }
+            AdminQuery::ConnCount { reply } => {
+                let _ = reply.send(self.conns.len());
+            }
+        }
+    }
+
+    /// Mark the server as draining & migrate as many of its conns as the
+    /// rebalancer's budget allows. The rest get migrated on later packets.
+    fn drain_server(&mut self, name: &str) -> Option<DrainReport> {
+        let server = self.context.find_socks5_server(|p| p.name == name)?;
+        server.set_draining();
+        info!("Draining upstream [{}]", server.name);
+        Some(self.migrate_conns(&server))
+    }
+
+    /// Keys of conns with sessions, duplicated ones included, on `server`.
+    fn conns_on(&self, server: &Arc<SocksServer>) -> Vec<(ClientAddr, RemoteAddr)> {
+        self.conns
+            .peek_iter()
+            .filter(|(_, conn)| {
+                conn.proxy()
+                    .into_iter()
+                    .chain(conn.duplicate_proxy())
+                    .any(|proxy| Arc::ptr_eq(&proxy.server, server))
+            })
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Migrate conns away from `server` within the rebalancer's budget.
+    fn migrate_conns(&mut self, server: &Arc<SocksServer>) -> DrainReport {
+        let keys = self.conns_on(server);
+        let mut migrated = 0;
+        for key in &keys {
+            if !self.rebalancer.has_budget() {
+                break;
+            }
+            if let Some(conn) = self.conns.get_mut(key) {
+                conn.clear_proxy();
+                self.rebalancer.migrated += 1;
+                migrated += 1;
+            }
+        }
+        DrainReport {
+            server: server.name.clone(),
+            migrated,
+            remaining: keys.len() - migrated,
+        }
+    }
+
+    /// Drop servers removed on reload from the list once their sessions
+    /// all end, migrating their conns meanwhile. Conns left after `timeout`
+    /// are migrated regardless of the budget.
+    fn retire_removed_servers(&mut self, timeout: Duration) {
+        let removed: Vec<_> = self
+            .context
+            .socks5_servers()
+            .into_iter()
+            .filter_map(|p| Some((p.removed_at()?, p)))
+            .collect();
+        for (removed_at, server) in removed {
+            let timed_out = removed_at.elapsed() >= timeout;
+            if timed_out {
+                for key in self.conns_on(&server) {
+                    if let Some(conn) = self.conns.get_mut(&key) {
+                        conn.clear_proxy();
+                    }
+                }
             } else {
-                QuicConn::new(remote, client, None)
+                let report = self.migrate_conns(&server);
+                trace!("Retiring [{}]: {:?}", server.name, report);
+            }
+            let sessions = server.status.usage.active_sessions();
+            if sessions == 0 || timed_out {
+                info!(
+                    "Remove upstream [{}], {} sessions left",
+                    server.name, sessions
+                );
+                self.context
+                    .update_socks5_servers(|servers| servers.retain(|p| !Arc::ptr_eq(p, &server)));
+            }
+        }
+    }
+
+    /// Follow the decisions of `forward_client_to_remote()` for a packet of
+    /// the flow without forwarding it, recording the reasons.
+    fn explain_flow(
+        &self,
+        client: Option<ClientAddr>,
+        remote: RemoteAddr,
+        sni: Option<&str>,
+    ) -> FlowExplanation {
+        let args = self.context.cli_args;
+        let mut checks = vec![FlowCheck::new(
+            "egress_limit",
+            true,
+            match self.egress_limiter.is_enabled() {
+                true => "enabled, depends on the current rate",
+                false => "disabled",
+            },
+        )];
+        let conn =
+            client.and_then(|client| self.conns.peek(&conn_key(args.conn_key, client, remote)));
+        if let Some(conn) = conn {
+            if let Some(proxy) = conn.proxy() {
+                if exclusion(&proxy.server, proxy.target().proto()).is_none() {
+                    checks.push(FlowCheck::new(
+                        "existing_conn",
+                        true,
+                        format!("stay on {}", conn),
+                    ));
+                    return FlowExplanation {
+                        checks,
+                        candidates: vec![],
+                        server: Some(proxy.server.name.clone()),
+                    };
+                }
+            }
+            checks.push(FlowCheck::new(
+                "existing_conn",
+                true,
+                format!("reconnect {}", conn),
+            ));
+        }
+        // Server name is only known if the initial packet is decoded
+        let sni = match sni {
+            Some(_) if !args.remote_dns && !args.local_dns => {
+                checks.push(FlowCheck::new(
+                    "sni",
+                    true,
+                    "ignored without --remote-dns or --local-dns",
+                ));
+                None
+            }
+            sni => sni,
+        };
+        let proto = match sni {
+            Some(_) if args.remote_dns && !args.local_dns => AppProto::Any,
+            _ => SocksTarget::from(remote.0).proto(),
+        };
+        let permits = self.context.reply_tasks.available_permits();
+        checks.push(FlowCheck::new(
+            "reply_tasks",
+            permits > 0,
+            format!("{} available", permits),
+        ));
+        if let Some(pinned) = sni.and_then(|name| self.context.pinned_server_name(name)) {
+            let reason = match self.context.find_socks5_server(|p| p.name == pinned) {
+                Some(server) => exclusion(&server, proto),
+                None => Some("not found"),
             };
-            debug!("Open {}", conn);
-            self.conns.entry(*key).or_insert(conn)
+            checks.push(FlowCheck::new(
+                "pin",
+                reason.is_none(),
+                format!("[{}] {}", pinned, reason.unwrap_or("usable")),
+            ));
+        }
+        let candidates = self
+            .context
+            .socks5_servers()
+            .iter()
+            .map(|server| CandidateReport {
+                name: server.name.clone(),
+                excluded: exclusion(server, proto),
+                near_rx_limit: server.near_rx_limit(),
+            })
+            .collect();
+        let conn = ConnContext {
+            client,
+            remote,
+            remote_name: sni,
+            alpn: &[],
+        };
+        let server = select_server(&self.context, &conn, proto);
+        checks.push(FlowCheck::new(
+            "upstream",
+            server.is_some(),
+            format!("{:?} mode", args.select_mode),
+        ));
+        FlowExplanation {
+            checks,
+            candidates,
+            server: server
+                .filter(|_| permits > 0)
+                .map(|server| server.name.clone()),
+        }
+    }
+
+    /// Close conns whose latest packet from remote is a suspected stateless
+    /// reset, instead of waiting for them to expire.
+    fn teardown_reset_conns(&mut self) {
+        let keys: Vec<_> = self
+            .conns
+            .peek_iter()
+            .filter(|(_, conn)| conn.is_likely_reset())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &keys {
+            if let Some(conn) = self.conns.remove(key) {
+                debug!("Tear down {} on suspected stateless reset", conn);
+            }
+        }
+        self.context
+            .stats
+            .reset_teardowns
+            .fetch_add(keys.len(), Ordering::Relaxed);
+    }
+
+    /// Evict conns created longer than `lifetime` ago, active or not, so
+    /// that no flow pins a session forever.
+    fn evict_old_conns(&mut self, lifetime: Duration) {
+        let keys: Vec<_> = self
+            .conns
+            .peek_iter()
+            .filter(|(_, conn)| conn.created_at.elapsed() > lifetime)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in &keys {
+            if let Some(conn) = self.conns.remove(key) {
+                debug!("Evict {} for exceeding max lifetime", conn);
+            }
+        }
+        self.context
+            .stats
+            .lifetime_evictions
+            .fetch_add(keys.len(), Ordering::Relaxed);
+    }
+
+    /// Move the conn of `client` from its previous port, if `pkt` carries
+    /// a CID of it and there is no conn for the current one.
+    fn follow_client_port(&mut self, client: ClientAddr, remote: RemoteAddr, pkt: &Bytes) {
+        let conn_ids = match &self.conn_ids {
+            Some(conn_ids) => conn_ids,
+            None => return,
+        };
+        if self.conns.contains_key(&(client, remote)) {
+            return;
+        }
+        let (old_client, cid) = match conn_ids.find(remote, pkt) {
+            Some((old, cid)) if old != client && old.0.ip() == client.0.ip() => (old, cid),
+            _ => return,
+        };
+        if let Some(conn) = self.conns.remove(&(old_client, remote)) {
+            debug!("{} moves to port {}", conn, client.0.port());
+            conn_ids.insert(remote, cid, client);
+            self.context
+                .stats
+                .client_port_follows
+                .fetch_add(1, Ordering::Relaxed);
+            self.conns.insert((client, remote), conn);
+        }
+    }
+
+    #[instrument(skip_all, fields(server = field::Empty, group = field::Empty))]
+    async fn forward_client_to_remote(
+        &mut self,
+        client: ClientAddr,
+        remote: RemoteAddr,
+        pkts: &[Bytes],
+        first: Option<FirstPacket>,
+    ) -> io::Result<()> {
+        let limited_pkts: Vec<_>;
+        let pkts = if self.egress_limiter.is_enabled() {
+            let stats = &self.context.stats;
+            limited_pkts = pkts
+                .iter()
+                .filter(|pkt| match self.egress_limiter.allow(client, pkt.len()) {
+                    Ok(()) => true,
+                    Err(EgressDrop::Limited) => {
+                        stats.egress_limited.fetch_add(1, Ordering::Relaxed);
+                        false
+                    }
+                    Err(EgressDrop::Shaped) => {
+                        stats.egress_shaped.fetch_add(1, Ordering::Relaxed);
+                        false
+                    }
+                })
+                .cloned()
+                .collect();
+            let dropped = pkts.len() - limited_pkts.len();
+            if dropped > 0 {
+                trace!(
+                    "Drop {} packets from {:?} by egress limits",
+                    dropped,
+                    client
+                );
+            }
+            if limited_pkts.is_empty() {
+                return Ok(());
+            }
+            &limited_pkts[..]
         } else {
-            self.conns.get_mut(key).unwrap()
+            pkts
         };
+        for pkt in pkts {
+            self.context.stats.tx_sizes.record(pkt.len());
+        }
+        if self.anomalies.is_enabled() {
+            self.anomalies.observe_packets(pkts.len());
+            if let Some(anomaly) = self.anomalies.check(Instant::now()) {
+                self.context
+                    .stats
+                    .loop_suspects
+                    .fetch_add(1, Ordering::Relaxed);
+                if self.anomalies.should_warn(Instant::now()) {
+                    anomaly.warn();
+                }
+            }
+        }
+        if self.conn_ids.is_some() {
+            self.follow_client_port(client, remote, &pkts[0]);
+        }
+        let key = &conn_key(self.context.cli_args.conn_key, client, remote);
+        // One lookup for the established conns, the hot path
+        let conn = match self.conns.entry(*key) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                // Misconfigured TPROXY rules may loop our own traffic back
+                if self.context.is_self_addr(remote.0) {
+                    warn!(
+                        "Drop packets from {:?} to quproxy itself ({}), check TPROXY rules",
+                        client.0, remote.0
+                    );
+                    self.context
+                        .stats
+                        .self_destined
+                        .fetch_add(pkts.len(), Ordering::Relaxed);
+                    return Ok(());
+                }
+                let args = self.context.cli_args;
+                if args.force_quic_v1 {
+                    if let Some(vn) = forge_version_negotiation(&pkts[0]) {
+                        debug!("Ask {:?} to use QUIC v1 or v2 for {:?}", client.0, remote.0);
+                        let sender = self.senders.get_or_create(remote)?;
+                        let mut buf = MsgArrayWriteBuffer::<1>::with_capacity(1);
+                        buf.push([vn], Some(client.0));
+                        (*sender).as_ref().batch_send(&mut buf).await?;
+                        self.context
+                            .stats
+                            .forged_version_negotiations
+                            .fetch_add(1, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                }
+                // Start new QUIC conn
+                let conn = match first {
+                    Some(first) => QuicConn::with_first_packet(remote, client, first),
+                    None => QuicConn::new(
+                        remote,
+                        client,
+                        &pkts[0],
+                        args.decode_initial(),
+                        args.tolerate_greased_bit,
+                    ),
+                }
+                .with_first_reply_timeout(args.first_reply_timeout)
+                .with_initial_resend(args.initial_resend_after, &pkts[0])
+                .with_client_mtu(args.client_mtu)
+                .with_conn_id_index(self.conn_ids.as_ref());
+                if !args.alpn_allowed(&conn.alpn) {
+                    debug!("Drop {}, ALPN {:?} not allowed", conn, conn.alpn);
+                    self.context
+                        .stats
+                        .alpn_dropped
+                        .fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                debug!(
+                    "Open {}, SCID len {:?}, ALPN {:?}",
+                    conn,
+                    conn.scid.as_ref().map(Bytes::len),
+                    conn.alpn
+                );
+                self.context.stats.name_lookups[conn.name_lookup as usize]
+                    .fetch_add(1, Ordering::Relaxed);
+                if conn.name_lookup.is_missed() {
+                    if args.log_no_sni {
+                        info!("{} has no name ({}), route by IP", conn, conn.name_lookup);
+                    } else {
+                        debug!("{} has no name ({}), route by IP", conn, conn.name_lookup);
+                    }
+                }
+                if self.anomalies.is_enabled() {
+                    self.anomalies.observe_new_conn(client, remote);
+                }
+                entry.insert(conn)
+            }
+        };
+        // Client port changed with `--conn-key client-ip` or followed by
+        // `--follow-client-port`, reply to the new one by reconnecting proxy
+        if conn.client != client {
+            debug!("{} rebinds to {:?}", conn, client.0);
+            conn.client = client;
+            conn.clear_proxy();
+        }
         // Check if to do migration
+        let mut avoid = None;
         if let Some(proxy) = conn.proxy() {
+            record_server_span(&proxy.server);
             if !proxy.server.is_healthy() {
                 debug!("Migrating {:?} away from [{}]", client, proxy.server.name);
                 conn.clear_proxy();
+            } else if self.context.cli_args.migrate_on_no_reply && conn.missed_first_reply() {
+                debug!(
+                    "Migrating {:?} away from [{}] for no reply",
+                    client, proxy.server.name
+                );
+                avoid = Some(Avoid::Prefer(proxy.server.clone()));
+                conn.clear_proxy();
+            } else if self.rebalancer.should_migrate(
+                &self.context,
+                conn,
+                self.sticky_clients
+                    .as_ref()
+                    .and_then(|clients| clients.peek(&conn.client.0.ip())),
+            ) {
+                debug!("Rebalancing {:?} away from [{}]", client, proxy.server.name);
+                conn.clear_proxy();
+            } else if let Some(duplicate) = conn.duplicate_proxy() {
+                if !duplicate.server.is_healthy() {
+                    debug!(
+                        "Migrating {:?} away from [{}]",
+                        client, duplicate.server.name
+                    );
+                    conn.clear_proxy();
+                }
             }
         }
-        // Connect to proxy
-        if conn.proxy().is_none() {
-            let target = if let Some(name) = &conn.remote_name {
-                (name.clone(), conn.remote.0.port()).into()
-            } else {
-                conn.remote.0.into()
-            };
-            let proxy = select_proxy(&self.context, target).await?;
-            conn.set_proxy(proxy, self.senders.get_or_create(remote)?);
-        }
-        // Forward packet
-        if let Some(proxy) = conn.proxy() {
-            trace!(
-                "{:?} => {:?} via {}: {} packets",
-                client,
-                remote,
-                proxy.server.name,
-                pkts.len(),
-            );
-            if let Err(err) = proxy.send_to_remote(pkts, &mut self.buf).await {
-                proxy.server.set_troubleness(true);
-                // TODO: retry with new upstream?
-                info!(
-                    "failed to forward {} packets to remote {:?} via {}: {}",
-                    pkts.len(),
+        // One route lookup per batch, not counted again on retry
+        let stats = &self.context.stats;
+        match conn.proxy() {
+            Some(_) => stats.sticky_hits.fetch_add(1, Ordering::Relaxed),
+            None => stats.sticky_misses.fetch_add(1, Ordering::Relaxed),
+        };
+        // Retried once on another upstream if sending fails
+        let mut retried = false;
+        let mut pkts = pkts;
+        loop {
+            // Connect to proxy
+            if conn.proxy().is_none() {
+                let target: SocksTarget = match &conn.remote_name {
+                    // Never wait for lookups here, it would hold up all conns
+                    Some(name) if self.context.cli_args.local_dns => {
+                        match self.context.dns_cache.cached_like(name, conn.remote.0) {
+                            Some(addr) => addr.into(),
+                            None => {
+                                debug!("{} not resolved yet, route {} by IP", name, conn);
+                                conn.remote.0.into()
+                            }
+                        }
+                    }
+                    Some(name) => (name.clone(), conn.remote.0.port()).into(),
+                    None => conn.remote.0.into(),
+                };
+                let duplicate_target = match &conn.remote_name {
+                    Some(name) if self.context.is_duplicated(name) => Some(target.clone()),
+                    _ => None,
+                };
+                let conn_ctx = ConnContext {
+                    client: Some(conn.client),
+                    remote,
+                    remote_name: conn.remote_name.as_deref(),
+                    alpn: &conn.alpn,
+                };
+                let sticky = self
+                    .sticky_clients
+                    .as_mut()
+                    .and_then(|clients| clients.get(&conn.client.0.ip()).cloned());
+                let proto = target.proto();
+                if !retried {
+                    record_proto_mismatches(&self.context, proto);
+                }
+                // Pinned ones don't say where the client's other conns go
+                let pinned = pinned_server(&self.context, &conn_ctx, proto).is_some();
+                // Taken before binding, so refused conns cost no socket
+                let reply_tasks = &self.context.reply_tasks;
+                let permit = match reply_tasks.clone().try_acquire_owned() {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        stats.reply_tasks_exhausted.fetch_add(1, Ordering::Relaxed);
+                        io_error!("Too many reply tasks");
+                    }
+                };
+                let proxy = select_proxy(&self.context, &conn_ctx, target, sticky, avoid).await?;
+                let duplicate = match duplicate_target {
+                    Some(target) => match reply_tasks.clone().try_acquire_owned() {
+                        Ok(permit) => select_duplicate(&self.context, &proxy.server, target)
+                            .await
+                            .map(|duplicate| (duplicate, permit)),
+                        Err(_) => {
+                            debug!("Skip duplicating {}, too many reply tasks", target);
+                            None
+                        }
+                    },
+                    None => None,
+                };
+                record_server_span(&proxy.server);
+                let sender = self.senders.get_or_create(remote)?;
+                let batcher = self.reply_batcher.as_ref();
+                conn.set_proxy(proxy, permit, duplicate, sender, stats, batcher);
+                match (&mut self.sticky_clients, conn.proxy()) {
+                    (Some(clients), Some(proxy)) if !pinned => {
+                        clients.insert(conn.client.0.ip(), proxy.server.clone());
+                    }
+                    _ => (),
+                }
+                if let (Some(old), Some(proxy)) = (conn.take_migrated_from(), conn.proxy()) {
+                    stats.migrations.fetch_add(1, Ordering::Relaxed);
+                    let new = &proxy.server.name;
+                    if self.context.cli_args.log_migrations || retried {
+                        info!("{} migrated [{}] -> [{}]", conn, old, new);
+                    } else {
+                        debug!("{} migrated [{}] -> [{}]", conn, old, new);
+                    }
+                }
+            }
+            // Forward packet, via both sessions if duplicated
+            let mut failed = None;
+            for (i, proxy) in conn
+                .proxy()
+                .into_iter()
+                .chain(conn.duplicate_proxy())
+                .enumerate()
+            {
+                trace!(
+                    "{:?} => {:?} via {}: {} packets",
+                    client,
                     remote,
                     proxy.server.name,
-                    err
+                    pkts.len(),
                 );
+                if !proxy.within_tx_limit(pkts) {
+                    continue;
+                }
+                // Upstream is marked in trouble by the session if unreachable
+                if let Err(err) = proxy.send_to_remote(pkts, &mut self.buf).await {
+                    info!(
+                        "failed to forward {} packets to remote {:?} via {}: {}",
+                        pkts.len(),
+                        remote,
+                        proxy.server.name,
+                        err
+                    );
+                    // The duplicate is best effort, not worth a retry
+                    if i == 0 {
+                        failed = Some((proxy.server.clone(), err.sent));
+                    }
+                }
+            }
+            match failed {
+                Some((server, sent)) if !retried => {
+                    // Resume from the failed one
+                    pkts = &pkts[sent..];
+                    info!(
+                        "Retry {} packets of {} off [{}]",
+                        pkts.len(),
+                        conn,
+                        server.name
+                    );
+                    retried = true;
+                    avoid = Some(Avoid::Strict(server));
+                    conn.clear_proxy();
+                }
+                _ => break,
             }
         }
         Ok(())
     }
 }
 
-async fn select_proxy(context: &AppContext, target: SocksTarget) -> io::Result<SocksSession> {
+/// Why `server` can't take new conns of `proto`, `None` if it can.
+fn exclusion(server: &SocksServer, proto: AppProto) -> Option<&'static str> {
+    if !server.inner_proto.get().capable(proto) {
+        Some("incapable of the target protocol")
+    } else if !server.is_healthy() {
+        Some("unhealthy")
+    } else if server.is_draining() {
+        Some("draining")
+    } else {
+        None
+    }
+}
+
+/// Next decoded first packet from `pool`, never if there is no pool.
+async fn recv_decoded(pool: &mut Option<DecodePool>) -> Option<Decoded> {
+    match pool {
+        Some(pool) => pool.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+fn conn_key(mode: ConnKey, client: ClientAddr, remote: RemoteAddr) -> (ClientAddr, RemoteAddr) {
+    match mode {
+        ConnKey::Full => (client, remote),
+        ConnKey::ClientIp => (ClientAddr(SocketAddr::new(client.0.ip(), 0)), remote),
+    }
+}
+
+/// Tag current span with the selected server & its group.
+fn record_server_span(server: &SocksServer) {
+    let span = Span::current();
+    span.record("server", server.name.as_str());
+    if let Some(group) = &server.group {
+        span.record("group", group.as_str());
+    }
+}
+
+/// Count a mismatch on healthy servers incapable of `proto`.
+fn record_proto_mismatches(context: &AppContext, proto: AppProto) {
+    for server in context.socks5_servers() {
+        if server.is_healthy() && !server.inner_proto.get().capable(proto) {
+            trace!(
+                "[{}] ({:?}) incapable of {:?}",
+                server.name,
+                server.inner_proto.get(),
+                proto
+            );
+            server.record_proto_mismatch();
+        }
+    }
+}
+
+/// Open a second session on another usable server, `None` if there isn't
+/// any. Duplication is best effort, the conn works without it.
+async fn select_duplicate(
+    context: &AppContext,
+    primary: &Arc<SocksServer>,
+    target: SocksTarget,
+) -> Option<SocksSession> {
+    let server = candidates(context, target.proto(), Some(primary))
+        .into_iter()
+        .next();
+    let server = match server {
+        Some(server) => server,
+        None => {
+            debug!("No other upstream to duplicate {} on", target);
+            return None;
+        }
+    };
+    match bind_session(context, &server, target).await {
+        Ok(session) => Some(session),
+        Err(err) => {
+            debug!("Failed to duplicate on [{}]: {}", server.name, err);
+            None
+        }
+    }
+}
+
+/// Server to move a conn away from.
+enum Avoid {
+    /// Got no reply via it, still taken if it's the only usable one
+    Prefer(Arc<SocksServer>),
+    /// Failed to send via it, never taken
+    Strict(Arc<SocksServer>),
+}
+
+async fn select_proxy(
+    context: &AppContext,
+    conn: &ConnContext<'_>,
+    target: SocksTarget,
+    sticky: Option<Arc<SocksServer>>,
+    avoid: Option<Avoid>,
+) -> io::Result<SocksSession> {
     let proto = target.proto();
-    let proxy = context
+    // Take another one if the current just failed the conn
+    let (avoid, strict) = match avoid {
+        Some(Avoid::Prefer(server)) => (Some(server), false),
+        Some(Avoid::Strict(server)) => (Some(server), true),
+        None => (None, false),
+    };
+    // Pins go first, then the client's upstream if it's still a candidate
+    let sticky = sticky.filter(|sticky| {
+        pinned_server(context, conn, proto).is_none()
+            && candidates(context, proto, avoid.as_ref())
+                .iter()
+                .any(|p| Arc::ptr_eq(p, sticky))
+    });
+    let selected = sticky.or_else(|| select_server_except(context, conn, proto, avoid.as_ref()));
+    let proxy = match (selected, avoid) {
+        (Some(proxy), _) => proxy,
+        (None, Some(_)) if strict => io_error!(NotFound, "No other available proxy"),
+        // Still taken if it's the only usable one
+        (None, Some(avoid)) if exclusion(&avoid, proto).is_none() => avoid,
+        (None, _) => io_error!(NotFound, "No available proxy"),
+    };
+    bind_session(context, &proxy, target).await
+}
+
+/// Open a session on `server` per command line options.
+async fn bind_session(
+    context: &AppContext,
+    server: &Arc<SocksServer>,
+    target: SocksTarget,
+) -> io::Result<SocksSession> {
+    let args = context.cli_args;
+    let session = match args.socks_socket_pool {
+        Some(size) => {
+            let connected = args.socks_udp_connected;
+            server.bind_pooled(target, size.into(), connected).await?
+        }
+        _ => server.bind(target, args.socks_udp_connected).await?,
+    };
+    Ok(session
+        .with_frag_size(args.socks_frag_size.map(usize::from))
+        .with_reassembly_mem(context.reassembly_mem.clone())
+        .with_log_resolved_addr(args.log_resolved_addr)
+        .with_reply_addr_check(args.validate_socks_reply_addr))
+}
+
+pub(super) fn select_server(
+    context: &AppContext,
+    conn: &ConnContext,
+    proto: AppProto,
+) -> Option<Arc<SocksServer>> {
+    select_server_except(context, conn, proto, None)
+}
+
+/// Like `select_server()`, but never take `except`.
+fn select_server_except(
+    context: &AppContext,
+    conn: &ConnContext,
+    proto: AppProto,
+    except: Option<&Arc<SocksServer>>,
+) -> Option<Arc<SocksServer>> {
+    if let Some(name) = conn.remote_name {
+        if let Some(pinned) = context.pinned_server_name(name) {
+            match pinned_server(context, conn, proto) {
+                Some(proxy) if !except.is_some_and(|p| Arc::ptr_eq(p, &proxy)) => {
+                    return Some(proxy)
+                }
+                _ => warn!("Upstream [{}] pinned for {} is unavailable", pinned, name),
+            }
+        }
+    }
+    let candidates = candidates(context, proto, except);
+    let server = context.selection_policy.select(&candidates, conn);
+    if let (Some(server), Some(client)) = (&server, conn.client) {
+        trace!(
+            "Select [{}] for {:?} => {:?} {:?}",
+            server.name,
+            client.0,
+            conn.remote.0,
+            conn.alpn
+        );
+    }
+    server
+}
+
+/// Whether a conn on `current` is where `select_server()` would place it,
+/// or one of the places if the selection is random.
+fn is_placed(
+    context: &AppContext,
+    conn: &ConnContext,
+    proto: AppProto,
+    current: &Arc<SocksServer>,
+) -> bool {
+    if let Some(pinned) = pinned_server(context, conn, proto) {
+        return Arc::ptr_eq(&pinned, current);
+    }
+    let candidates = candidates(context, proto, None);
+    context.selection_policy.keeps(current, &candidates, conn)
+}
+
+/// The usable server pinned for the remote name, if any.
+fn pinned_server(
+    context: &AppContext,
+    conn: &ConnContext,
+    proto: AppProto,
+) -> Option<Arc<SocksServer>> {
+    let pinned = context.pinned_server_name(conn.remote_name?)?;
+    context
+        .find_socks5_server(|p| p.name == pinned)
+        .filter(|proxy| exclusion(proxy, proto).is_none())
+}
+
+/// Servers usable for `proto` other than `except`, sorted by score, for a
+/// `SelectionPolicy`.
+fn candidates(
+    context: &AppContext,
+    proto: AppProto,
+    except: Option<&Arc<SocksServer>>,
+) -> Vec<Arc<SocksServer>> {
+    let mut candidates: Vec<_> = context
         .socks5_servers()
         .into_iter()
-        .find(|p| p.inner_proto.get().capable(proto) && p.is_healthy())
-        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "No avaiable proxy"))?
-        .clone();
-    proxy.bind(target).await
+        .filter(|p| exclusion(p, proto).is_none())
+        .filter(|p| !except.is_some_and(|except| Arc::ptr_eq(p, except)))
+        .collect();
+    // Servers of contradictory probe results are the last resort
+    if candidates.iter().any(|p| !p.is_proto_unknown()) {
+        candidates.retain(|p| !p.is_proto_unknown());
+    }
+    // So are ones of zero weight, whatever the policy is
+    if candidates.iter().any(|p| p.weight > 0) {
+        candidates.retain(|p| p.weight > 0);
+    }
+    candidates
+}
+
+#[test]
+fn test_conn_key() {
+    let remote = RemoteAddr(([192, 0, 2, 1], 443).into());
+    let a = ClientAddr(([10, 0, 0, 1], 50000).into());
+    let b = ClientAddr(([10, 0, 0, 1], 50001).into());
+    assert_ne!(
+        conn_key(ConnKey::Full, a, remote),
+        conn_key(ConnKey::Full, b, remote)
+    );
+    assert_eq!(
+        conn_key(ConnKey::ClientIp, a, remote),
+        conn_key(ConnKey::ClientIp, b, remote)
+    );
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_forward_loopback() {
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    // Fake SOCKSv5 UDP relay, client and (unused) remote address
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let relay_addr = relay.local_addr().unwrap().to_string();
+    let args = crate::cli::CliArgs::parse_from(["quproxy", "-p", "0", "-u", &relay_addr]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+    let pkts: Box<[Bytes]> = vec![Bytes::from_static(b"hello")].into();
+    let incoming =
+        futures::stream::iter([(client.local_addr().unwrap().into(), remote.into(), pkts)]);
+    tokio::spawn(service.serve(incoming.chain(futures::stream::pending())));
+
+    // Client to relay, SOCKS-framed
+    let mut header = vec![0x00, 0x00, 0x00, 0x01, 127, 0, 0, 1];
+    header.extend_from_slice(&remote.port().to_be_bytes());
+    let mut buf = [0u8; 64];
+    let recv = relay.recv_from(&mut buf);
+    let (len, session) = tokio::time::timeout(Duration::from_secs(5), recv)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], [&header[..], b"hello"].concat());
+
+    // Relay to client, via sender bound on remote address
+    relay
+        .send_to(&[&header[..], b"world"].concat(), session)
+        .await
+        .unwrap();
+    let recv = client.recv_from(&mut buf);
+    let (len, from) = tokio::time::timeout(Duration::from_secs(5), recv)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&buf[..len], b"world");
+    assert_eq!(from, remote);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_debug_query() {
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        "127.0.0.1:1080",
+        "--debug-query-port",
+        &remote.port().to_string(),
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+    let client_addr = client.local_addr().unwrap().into();
+
+    // Too short for the reply, dropped rather than amplified
+    let query = Bytes::from_static(b"QUPROXY?192.0.2.1:443 example.com");
+    let padded = Bytes::from([&query[..], &[b' '; 200]].concat());
+    let pkts: Box<[Bytes]> = vec![query, padded.clone()].into();
+    service
+        .handle_packets(client_addr, remote.into(), pkts)
+        .await;
+    assert!(service.conns.is_empty());
+
+    let mut buf = [0u8; 512];
+    let recv = client.recv_from(&mut buf);
+    let (len, from) = tokio::time::timeout(Duration::from_secs(5), recv)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(from, remote);
+    assert!(len <= padded.len());
+    let reply: serde_json::Value = serde_json::from_slice(&buf[..len]).unwrap();
+    assert_eq!(reply["sni"], "example.com");
+    assert_eq!(reply["server"], "127.0.0.1:1080");
+    // Only one reply
+    assert!(client.try_recv_from(&mut buf).is_err());
+}
+
+#[tokio::test]
+async fn test_forward_retry() {
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    // Sending to broadcast fails without SO_BROADCAST
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = relay.local_addr().unwrap().to_string();
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        "255.255.255.255:1080",
+        "-u",
+        &relay_addr,
+        "--socks-udp-connected",
+        "false",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+    let client = ClientAddr(([127, 0, 0, 1], 1).into());
+    let remote = RemoteAddr(([127, 0, 0, 1], 2).into());
+    let pkts = [Bytes::from_static(b"hello")];
+    service
+        .forward_client_to_remote(client, remote, &pkts, None)
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 64];
+    let recv = relay.recv_from(&mut buf);
+    let (len, _) = tokio::time::timeout(Duration::from_secs(5), recv)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(buf[..len].ends_with(b"hello"));
+    let conn = service.conns.peek(&(client, remote)).unwrap();
+    assert_eq!(
+        conn.proxy().unwrap().server.udp_addr.to_string(),
+        relay_addr
+    );
+}
+
+#[tokio::test]
+async fn test_rebalance_keeps_hashed() {
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    let relays = [
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let relay_addrs = relays
+        .each_ref()
+        .map(|relay| relay.local_addr().unwrap().to_string());
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        &relay_addrs[0],
+        "-u",
+        &relay_addrs[1],
+        "--select-mode",
+        "consistent-hash",
+        "--rebalance-after",
+        "0s",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+    // A remote hashed to other than the best server
+    let best = context.socks5_servers()[0].clone();
+    let remote = (1..=64)
+        .map(|i| RemoteAddr(([127, 0, 0, i], 443).into()))
+        .find(|remote| {
+            let conn = ConnContext {
+                client: None,
+                remote: *remote,
+                remote_name: None,
+                alpn: &[],
+            };
+            let server = select_server(&context, &conn, AppProto::IPv4).unwrap();
+            !Arc::ptr_eq(&server, &best)
+        })
+        .unwrap();
+    let client = ClientAddr(([127, 0, 0, 1], 1).into());
+    let pkts = [Bytes::from_static(b"hello")];
+    for _ in 0..3 {
+        service
+            .forward_client_to_remote(client, remote, &pkts, None)
+            .await
+            .unwrap();
+    }
+    let conn = service.conns.peek(&(client, remote)).unwrap();
+    assert!(!Arc::ptr_eq(&conn.proxy().unwrap().server, &best));
+    assert_eq!(context.stats.migrations.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_zero_weight_excluded() {
+    use crate::app::InnerProto;
+    use clap::Parser;
+
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        "127.0.0.1:1080",
+        "--select-mode",
+        "consistent-hash",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let spare = SocksServer::new(
+        ([127, 0, 0, 1], 1081).into(),
+        "spare".into(),
+        InnerProto::Inet,
+    );
+    let spare = Arc::new(spare.with_weight(0));
+    context.update_socks5_servers(|servers| servers.insert(0, spare.clone()));
+    for i in 1..=64 {
+        let conn = ConnContext {
+            client: None,
+            remote: RemoteAddr(([127, 0, 0, i], 443).into()),
+            remote_name: None,
+            alpn: &[],
+        };
+        let server = select_server(&context, &conn, AppProto::IPv4).unwrap();
+        assert!(!Arc::ptr_eq(&server, &spare));
+    }
+    // Taken if it's the only one left
+    context.update_socks5_servers(|servers| servers.retain(|p| Arc::ptr_eq(p, &spare)));
+    let conn = ConnContext {
+        client: None,
+        remote: RemoteAddr(([127, 0, 0, 1], 443).into()),
+        remote_name: None,
+        alpn: &[],
+    };
+    let server = select_server(&context, &conn, AppProto::IPv4).unwrap();
+    assert!(Arc::ptr_eq(&server, &spare));
+}
+
+#[tokio::test]
+async fn test_select_proxy_avoid() {
+    use clap::Parser;
+
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        "127.0.0.1:1080",
+        "-u",
+        "127.0.0.1:1081",
+        "-u",
+        "127.0.0.1:1082",
+        "--select-mode",
+        "consistent-hash",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let first = context.socks5_servers()[0].clone();
+    let mut fallbacks = vec![];
+    for i in 1..=64 {
+        let remote = RemoteAddr(([127, 0, 0, i], 443).into());
+        let conn = ConnContext {
+            client: None,
+            remote,
+            remote_name: None,
+            alpn: &[],
+        };
+        if !Arc::ptr_eq(
+            &select_server(&context, &conn, AppProto::IPv4).unwrap(),
+            &first,
+        ) {
+            continue;
+        }
+        let avoid = Some(Avoid::Strict(first.clone()));
+        let session = select_proxy(&context, &conn, remote.0.into(), None, avoid)
+            .await
+            .unwrap();
+        assert!(!Arc::ptr_eq(&session.server, &first));
+        fallbacks.push(session.server.name.clone());
+    }
+    // Rehashed among the rest, not always the next one
+    fallbacks.sort();
+    fallbacks.dedup();
+    assert_eq!(fallbacks.len(), 2);
+
+    // The avoided one is taken only if preferred to avoid
+    context.update_socks5_servers(|servers| servers.truncate(1));
+    let conn = ConnContext {
+        client: None,
+        remote: RemoteAddr(([127, 0, 0, 1], 443).into()),
+        remote_name: None,
+        alpn: &[],
+    };
+    let target = conn.remote.0;
+    let avoid = Some(Avoid::Strict(first.clone()));
+    let strict = select_proxy(&context, &conn, target.into(), None, avoid).await;
+    assert!(strict.is_err_and(|err| err.kind() == io::ErrorKind::NotFound));
+    let avoid = Some(Avoid::Prefer(first.clone()));
+    let session = select_proxy(&context, &conn, target.into(), None, avoid)
+        .await
+        .unwrap();
+    assert!(Arc::ptr_eq(&session.server, &first));
+}
+
+#[tokio::test]
+async fn test_reply_tasks_exhausted() {
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = relay.local_addr().unwrap().to_string();
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        &relay_addr,
+        "--max-reply-tasks",
+        "1",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+    let client = ClientAddr(([127, 0, 0, 1], 1).into());
+    let pkts = [Bytes::from_static(b"hello")];
+    for port in [2, 3] {
+        let remote = RemoteAddr(([127, 0, 0, 1], port).into());
+        let _ = service
+            .forward_client_to_remote(client, remote, &pkts, None)
+            .await;
+    }
+    // Refused before binding a session for it
+    let server = &context.socks5_servers()[0];
+    assert_eq!(server.status.usage.total_sessions(), 1);
+    let stats = &context.stats;
+    assert_eq!(stats.reply_tasks_exhausted.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn test_sticky_client() {
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    let relays = [
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let relay_addrs = relays
+        .each_ref()
+        .map(|relay| relay.local_addr().unwrap().to_string());
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        &relay_addrs[0],
+        "-u",
+        &relay_addrs[1],
+        "--sticky-client",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+    let remote = RemoteAddr(([127, 0, 0, 1], 2).into());
+    let pkts = [Bytes::from_static(b"hello")];
+    let mut selected = vec![];
+    for port in [1, 2] {
+        let client = ClientAddr(([127, 0, 0, 1], port).into());
+        service
+            .forward_client_to_remote(client, remote, &pkts, None)
+            .await
+            .unwrap();
+        let conn = service.conns.peek(&(client, remote)).unwrap();
+        selected.push(conn.proxy().unwrap().server.clone());
+        // The other one would be selected if not sticky
+        context.update_socks5_servers(|servers| servers.reverse());
+    }
+    assert!(Arc::ptr_eq(&selected[0], &selected[1]));
+}
+
+#[tokio::test]
+async fn test_sticky_client_pinned() {
+    use crate::app::quic::SAMPLE_INITIAL_PACKET;
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    let relays = [
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let path = std::env::temp_dir().join(format!("quproxy-pins-{}.toml", std::process::id()));
+    let mut list: String = ["a", "b"]
+        .iter()
+        .zip(&relays)
+        .map(|(name, relay)| {
+            let addr = relay.local_addr().unwrap();
+            format!("[upstreams.{}]\naddr = \"{}\"\n", name, addr)
+        })
+        .collect();
+    list.push_str("[pins]\n\"example.com\" = \"b\"\n");
+    std::fs::write(&path, list).unwrap();
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-l",
+        path.to_str().unwrap(),
+        "--sticky-client",
+        "--remote-dns",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    context.update_socks5_servers(|servers| servers.sort_by(|a, b| a.name.cmp(&b.name)));
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+
+    let hello = [Bytes::from_static(b"hello")];
+    let initial = [Bytes::from_static(SAMPLE_INITIAL_PACKET)];
+    let mut selected = vec![];
+    // Sticky to a, then example.com pinned to b, then still a
+    for (port, pkts) in [(1, &hello), (2, &initial), (3, &hello)] {
+        let client = ClientAddr(([127, 0, 0, 1], port).into());
+        let remote = RemoteAddr(([127, 0, 0, 1], port).into());
+        service
+            .forward_client_to_remote(client, remote, pkts, None)
+            .await
+            .unwrap();
+        let conn = service.conns.peek(&(client, remote)).unwrap();
+        selected.push(conn.proxy().unwrap().server.name.clone());
+    }
+    assert_eq!(selected, ["a", "b", "a"]);
+}
+
+#[tokio::test]
+async fn test_forward_via_decode_pool() {
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client: SocketAddr = "127.0.0.1:1".parse().unwrap();
+    let remote: SocketAddr = "127.0.0.1:2".parse().unwrap();
+    let relay_addr = relay.local_addr().unwrap().to_string();
+    let args = crate::cli::CliArgs::parse_from([
+        "quproxy",
+        "-p",
+        "0",
+        "-u",
+        &relay_addr,
+        "--remote-dns",
+        "--decode-workers",
+        "1",
+    ]);
+    let context = AppContext::from_cli_args(args).unwrap();
+    let (_admin_tx, admin_rx) = mpsc::channel(1);
+    let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+    service.senders = TProxySenderCache::new_local(16);
+    // The second batch arrives while the first packet is being decoded
+    let batches = [b"hello", b"again"].map(|pkt| {
+        let pkts: Box<[Bytes]> = vec![Bytes::from_static(pkt)].into();
+        (client.into(), remote.into(), pkts)
+    });
+    tokio::spawn(service.serve(futures::stream::iter(batches).chain(futures::stream::pending())));
+
+    // Not a QUIC Initial, forwarded by IP in order
+    let mut header = vec![0x00, 0x00, 0x00, 0x01, 127, 0, 0, 1];
+    header.extend_from_slice(&remote.port().to_be_bytes());
+    let mut buf = [0u8; 64];
+    for expected in [b"hello", b"again"] {
+        let recv = relay.recv_from(&mut buf);
+        let (len, _) = tokio::time::timeout(Duration::from_secs(5), recv)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buf[..len], [&header[..], expected].concat());
+    }
+    // Selected once, then stuck to
+    let stats = &context.stats;
+    assert_eq!(stats.sticky_misses.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.sticky_hits.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.migrations.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn test_alpn_lists() {
+    use crate::app::quic::SAMPLE_INITIAL_PACKET;
+    use clap::Parser;
+    use tokio::net::UdpSocket;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let relay_addr = relay.local_addr().unwrap().to_string();
+    // The sample offers "alpn" only
+    for (list, allowed) in [
+        (["--allow-alpn", "h3", "alpn"], true),
+        (["--allow-alpn", "h3", "h3-29"], false),
+        (["--deny-alpn", "doq", "alpn"], false),
+        (["--deny-alpn", "doq", "doq-i11"], true),
+    ] {
+        let args = crate::cli::CliArgs::parse_from(
+            ["quproxy", "-p", "0", "-u", &relay_addr]
+                .into_iter()
+                .chain(list),
+        );
+        let context = AppContext::from_cli_args(args).unwrap();
+        let (_admin_tx, admin_rx) = mpsc::channel(1);
+        let mut service = SocksForwardService::new(&context, admin_rx).unwrap();
+        service.senders = TProxySenderCache::new_local(16);
+        let client = ClientAddr(([127, 0, 0, 1], 1).into());
+        let remote = RemoteAddr(([127, 0, 0, 1], 2).into());
+        let initial = [Bytes::from_static(SAMPLE_INITIAL_PACKET)];
+        service
+            .forward_client_to_remote(client, remote, &initial, None)
+            .await
+            .unwrap();
+        assert_eq!(service.conns.contains_key(&(client, remote)), allowed);
+        let dropped = context.stats.alpn_dropped.load(Ordering::Relaxed);
+        assert_eq!(dropped, !allowed as usize);
+    }
 }
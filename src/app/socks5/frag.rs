@@ -0,0 +1,266 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use tracing::{debug, trace};
+
+/// Fragment positions are 1 to 127, the high-order bit marks the last one
+pub(super) const FRAG_MAX_COUNT: usize = 127;
+pub(super) const FRAG_END: u8 = 0x80;
+
+/// Partial datagrams older than it are dropped, RFC 1928 asks for >= 5s
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Max bytes of a reassembled datagram
+const MAX_REASSEMBLED_SIZE: usize = u16::MAX as usize;
+/// Max datagrams being reassembled at once, the oldest one is dropped
+const MAX_PARTIALS: usize = 8;
+
+/// Bytes held by all reassemblers, capped by `--max-reassembly-mem`.
+#[derive(Debug)]
+pub(crate) struct ReassemblyMem {
+    used: AtomicUsize,
+    limit: usize,
+    /// Fragments dropped for the cap
+    dropped: AtomicUsize,
+}
+
+impl Default for ReassemblyMem {
+    fn default() -> Self {
+        Self::new(usize::MAX)
+    }
+}
+
+impl ReassemblyMem {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            used: Default::default(),
+            limit,
+            dropped: Default::default(),
+        }
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn try_reserve(&self, len: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                used.checked_add(len).filter(|used| *used <= self.limit)
+            })
+            .is_ok()
+    }
+
+    fn release(&self, len: usize) {
+        self.used.fetch_sub(len, Ordering::Relaxed);
+    }
+}
+
+/// Reassembly of fragmented SOCKSv5 UDP replies per RFC 1928 §7, one
+/// queue per remote address (`None` for unparsed ones). A standalone
+/// datagram, or a fragment of a position lower than seen, abandons the
+/// queue of its remote. Held bytes are accounted in `mem`, shared with
+/// other sessions.
+#[derive(Debug, Default)]
+pub(super) struct Reassembler {
+    partials: HashMap<Option<SocketAddr>, Partial>,
+    mem: Arc<ReassemblyMem>,
+}
+
+#[derive(Debug)]
+struct Partial {
+    started_at: Instant,
+    /// Data by fragment position
+    frags: BTreeMap<u8, Bytes>,
+    len: usize,
+}
+
+impl Reassembler {
+    pub(super) fn new(mem: Arc<ReassemblyMem>) -> Self {
+        Self {
+            partials: Default::default(),
+            mem,
+        }
+    }
+
+    /// Take `payload` with fragment number `frag` from `remote`, return the
+    /// whole datagram once it's complete.
+    pub(super) fn push(
+        &mut self,
+        remote: Option<SocketAddr>,
+        frag: u8,
+        payload: &[u8],
+        now: Instant,
+    ) -> Option<Bytes> {
+        if frag == 0 {
+            if self.remove(&remote).is_some() {
+                debug!("Abandon fragments from {:?}, got standalone", remote);
+            }
+            return Some(Bytes::copy_from_slice(payload));
+        }
+        let position = frag & !FRAG_END;
+        if position == 0 {
+            debug!("Drop fragment from {:?} of position 0", remote);
+            return None;
+        }
+        self.expire(now);
+        if !self.partials.contains_key(&remote) && self.partials.len() >= MAX_PARTIALS {
+            let oldest = self
+                .partials
+                .iter()
+                .min_by_key(|(_, partial)| partial.started_at)
+                .map(|(remote, _)| *remote);
+            if let Some(oldest) = oldest {
+                debug!("Abandon fragments from {:?}, too many partials", oldest);
+                self.remove(&oldest);
+            }
+        }
+        if !self.mem.try_reserve(payload.len()) {
+            debug!(
+                "Abandon fragments from {:?}, reassembly memory full",
+                remote
+            );
+            self.mem.dropped.fetch_add(1, Ordering::Relaxed);
+            self.remove(&remote);
+            return None;
+        }
+        let partial = self.partials.entry(remote).or_insert_with(|| Partial {
+            started_at: now,
+            frags: Default::default(),
+            len: 0,
+        });
+        if partial
+            .frags
+            .keys()
+            .next_back()
+            .is_some_and(|last| *last > position)
+        {
+            debug!("Restart fragments from {:?} at {}", remote, position);
+            self.mem.release(partial.len);
+            partial.started_at = now;
+            partial.frags.clear();
+            partial.len = 0;
+        }
+        if let Some(old) = partial
+            .frags
+            .insert(position, Bytes::copy_from_slice(payload))
+        {
+            self.mem.release(old.len());
+            partial.len -= old.len();
+        }
+        partial.len += payload.len();
+        if partial.len > MAX_REASSEMBLED_SIZE {
+            debug!("Abandon fragments from {:?}, too large", remote);
+            self.remove(&remote);
+            return None;
+        }
+        if frag & FRAG_END == 0 {
+            return None;
+        }
+        let partial = self.remove(&remote)?;
+        if partial.frags.len() != position as usize {
+            debug!(
+                "Drop fragments from {:?}, {} of {} received",
+                remote,
+                partial.frags.len(),
+                position
+            );
+            return None;
+        }
+        trace!("Reassemble {} fragments from {:?}", position, remote);
+        let mut buf = BytesMut::with_capacity(partial.len);
+        partial
+            .frags
+            .values()
+            .for_each(|data| buf.extend_from_slice(data));
+        Some(buf.freeze())
+    }
+
+    fn remove(&mut self, remote: &Option<SocketAddr>) -> Option<Partial> {
+        let partial = self.partials.remove(remote)?;
+        self.mem.release(partial.len);
+        Some(partial)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        let mem = &self.mem;
+        self.partials.retain(|remote, partial| {
+            let alive = now.duration_since(partial.started_at) < REASSEMBLY_TIMEOUT;
+            if !alive {
+                debug!("Drop fragments from {:?}, timed out", remote);
+                mem.release(partial.len);
+            }
+            alive
+        });
+    }
+}
+
+impl Drop for Reassembler {
+    fn drop(&mut self) {
+        let held = self.partials.values().map(|partial| partial.len).sum();
+        self.mem.release(held);
+    }
+}
+
+#[test]
+fn test_reassemble() {
+    let remote = Some(([192, 0, 2, 1], 443).into());
+    let now = Instant::now();
+    let mut reassembler = Reassembler::default();
+    assert_eq!(reassembler.push(remote, 1, b"hello, ", now), None);
+    let whole = reassembler.push(remote, 2 | FRAG_END, b"world", now);
+    assert_eq!(whole.as_deref(), Some(&b"hello, world"[..]));
+    assert!(reassembler.partials.is_empty());
+
+    // Standalone passes through, abandoning partials of its remote
+    assert_eq!(reassembler.push(remote, 1, b"lost", now), None);
+    let standalone = reassembler.push(remote, 0, b"hi", now);
+    assert_eq!(standalone.as_deref(), Some(&b"hi"[..]));
+    assert_eq!(reassembler.push(remote, 2 | FRAG_END, b"!", now), None);
+
+    // Lower position restarts the sequence
+    assert_eq!(reassembler.push(remote, 2, b"stale", now), None);
+    assert_eq!(reassembler.push(remote, 1, b"a", now), None);
+    let whole = reassembler.push(remote, 2 | FRAG_END, b"b", now);
+    assert_eq!(whole.as_deref(), Some(&b"ab"[..]));
+
+    // Stale partials time out
+    assert_eq!(reassembler.push(remote, 1, b"slow", now), None);
+    let later = now + REASSEMBLY_TIMEOUT;
+    assert_eq!(reassembler.push(remote, 2 | FRAG_END, b"!", later), None);
+}
+
+#[test]
+fn test_reassembly_mem() {
+    let (a, b) = (
+        Some(([192, 0, 2, 1], 443).into()),
+        Some(([192, 0, 2, 2], 443).into()),
+    );
+    let now = Instant::now();
+    let mem = Arc::new(ReassemblyMem::new(8));
+    let mut first = Reassembler::new(mem.clone());
+    let mut second = Reassembler::new(mem.clone());
+    assert_eq!(first.push(a, 1, b"12345", now), None);
+    assert_eq!(mem.used(), 5);
+    // Over the cap shared by both
+    assert_eq!(second.push(b, 1, b"6789", now), None);
+    assert_eq!((mem.used(), mem.dropped()), (5, 1));
+    assert!(second.partials.is_empty());
+    let whole = first.push(a, 2 | FRAG_END, b"678", now);
+    assert_eq!(whole.as_deref(), Some(&b"12345678"[..]));
+    assert_eq!(mem.used(), 0);
+    assert_eq!(second.push(b, 1, b"6789", now), None);
+    drop(second);
+    assert_eq!(mem.used(), 0);
+}
@@ -1,11 +1,20 @@
+mod anomaly;
+mod debug;
 mod forward;
+mod frag;
+mod pool;
 mod refer;
+mod select;
 mod server;
 mod session;
 mod traffic;
 
-pub(crate) use forward::SocksForwardService;
+pub(crate) use forward::{AdminQuery, SocksForwardService};
+pub(crate) use frag::ReassemblyMem;
 pub(crate) use refer::SocksReferService;
-pub(crate) use server::{InnerProto, SocksServer, SocksServerReferrer};
+pub(crate) use select::{selection_policy, SelectionPolicy};
+pub(crate) use server::{
+    CheckDnsServers, Credentials, InnerProto, InnerProtoProbe, SocksServer, SocksServerReferrer,
+};
 pub(crate) use session::{SocksSession, SocksTarget};
 pub(super) use traffic::{Traffic, Usage};
@@ -0,0 +1,183 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+};
+
+use bytes::Bytes;
+use parking_lot::Mutex;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace};
+
+use crate::app::{
+    checking::Healthy,
+    net::{AsyncUdpSocket, MsgArrayReadBuffer, UDP_BATCH_SIZE, UDP_MAX_SIZE},
+    types::canonicalize_socket_addr,
+};
+
+use super::{
+    session::{decode_packet, is_unreachable},
+    SocksServer,
+};
+
+/// Replies queued for a session on a pooled socket, later ones are dropped
+const ROUTE_QUEUE_SIZE: usize = 256;
+
+/// UDP sockets toward an upstream, shared by its sessions, see
+/// `--socks-socket-pool`. Each socket serves at most one session for a
+/// given target, since replies are told apart only by the address in
+/// their SOCKSv5 header. Dispatching tasks stop once the pool is dropped.
+/// Unless connected, replies from the upstream's IP are taken from any
+/// port, as with `--socks-udp-connected false`.
+pub(super) struct SocketPool {
+    sockets: Vec<Arc<PooledSocket>>,
+    next: AtomicUsize,
+    shutdown: CancellationToken,
+}
+
+pub(super) struct PooledSocket {
+    pub(super) socket: AsyncUdpSocket,
+    /// Sessions by target address, to dispatch replies to
+    routes: Mutex<HashMap<SocketAddr, mpsc::Sender<Bytes>>>,
+}
+
+/// A session's place on a pooled socket, removed on drop.
+pub(super) struct PooledRoute {
+    pub(super) socket: Arc<PooledSocket>,
+    target: SocketAddr,
+}
+
+impl Drop for PooledRoute {
+    fn drop(&mut self) {
+        self.socket.routes.lock().remove(&self.target);
+    }
+}
+
+impl SocketPool {
+    pub(super) async fn new(
+        server: &Arc<SocksServer>,
+        size: usize,
+        connected: bool,
+    ) -> io::Result<Self> {
+        let shutdown = CancellationToken::new();
+        let mut sockets = Vec::with_capacity(size);
+        for _ in 0..size {
+            let (addr, opts) = (&server.udp_addr, &server.egress_opts());
+            let socket = match connected {
+                true => AsyncUdpSocket::connect(addr, opts).await,
+                false => AsyncUdpSocket::unconnected(addr, opts).await,
+            };
+            let socket = Arc::new(PooledSocket {
+                socket: socket?,
+                routes: Default::default(),
+            });
+            tokio::spawn(dispatch(
+                socket.clone(),
+                Arc::downgrade(server),
+                server.udp_addr.ip(),
+                shutdown.clone(),
+            ));
+            sockets.push(socket);
+        }
+        debug!("Open {} pooled sockets to [{}]", size, server.name);
+        Ok(Self {
+            sockets,
+            next: Default::default(),
+            shutdown,
+        })
+    }
+
+    /// Take a place for `target` on the next socket not serving it yet,
+    /// round-robin. `None` if all of them are.
+    pub(super) fn register(
+        &self,
+        target: SocketAddr,
+    ) -> Option<(PooledRoute, mpsc::Receiver<Bytes>)> {
+        let target = canonicalize_socket_addr(target);
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.sockets.len()).find_map(|i| {
+            let socket = &self.sockets[(start + i) % self.sockets.len()];
+            match socket.routes.lock().entry(target) {
+                Entry::Occupied(_) => None,
+                Entry::Vacant(entry) => {
+                    let (sender, receiver) = mpsc::channel(ROUTE_QUEUE_SIZE);
+                    entry.insert(sender);
+                    let route = PooledRoute {
+                        socket: socket.clone(),
+                        target,
+                    };
+                    Some((route, receiver))
+                }
+            }
+        })
+    }
+}
+
+impl Drop for SocketPool {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+/// Receive replies on `socket` and pass them, still SOCKS-framed, to the
+/// session of the address in their header.
+async fn dispatch(
+    socket: Arc<PooledSocket>,
+    server: Weak<SocksServer>,
+    relay_ip: IpAddr,
+    shutdown: CancellationToken,
+) {
+    let mut buf: std::pin::Pin<Box<MsgArrayReadBuffer<UDP_BATCH_SIZE, UDP_MAX_SIZE>>> =
+        MsgArrayReadBuffer::new();
+    loop {
+        buf.clear();
+        let result = tokio::select! {
+            result = socket.socket.batch_recv(&mut buf) => result,
+            _ = shutdown.cancelled() => break,
+        };
+        if let Err(err) = result {
+            debug!("Error on pooled socket: {}", err);
+            match server.upgrade() {
+                Some(server) if is_unreachable(&err) => server.set_troubleness(true),
+                Some(_) => (),
+                None => break,
+            }
+            continue;
+        }
+        let routes = socket.routes.lock();
+        for msg in buf.iter() {
+            // Port is not checked as it may be changed by NAT, connected
+            // sockets are filtered by kernel anyway
+            if msg
+                .src_addr
+                .is_some_and(|src| canonicalize_socket_addr(src).ip() != relay_ip)
+            {
+                trace!("Drop packet from unexpected {:?}", msg.src_addr);
+                continue;
+            }
+            let addr = match decode_packet(msg.buf) {
+                Ok((Some(addr), _)) => canonicalize_socket_addr(addr),
+                _ => {
+                    if let Some(server) = server.upgrade() {
+                        server.record_malformed_reply();
+                    }
+                    continue;
+                }
+            };
+            match routes.get(&addr) {
+                Some(route) => {
+                    if route.try_send(Bytes::copy_from_slice(msg.buf)).is_err() {
+                        trace!("Drop reply from {}, session busy or closed", addr);
+                    }
+                }
+                None => trace!("Drop reply from {}, no session", addr),
+            }
+        }
+    }
+    trace!("Stop dispatching pooled socket");
+}
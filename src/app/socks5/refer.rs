@@ -1,10 +1,16 @@
 use std::{
-    collections::{hash_map::Entry, HashMap, HashSet},
+    collections::{HashMap, HashSet},
+    io,
     sync::Arc,
 };
 
 use derivative::Derivative;
-use tokio::time::{interval_at, Instant};
+use futures::{stream, StreamExt};
+use socket2::TcpKeepalive;
+use tokio::{
+    net::TcpStream,
+    time::{interval_at, Instant},
+};
 use tracing::{debug, info, instrument, trace, warn};
 
 use super::{server::ReferredSocksServer, SocksServerReferrer};
@@ -40,13 +46,32 @@ impl SocksReferService {
     #[instrument(skip_all)]
     async fn check_all(&mut self) {
         trace!("Start checking all SOCKSv5 server referrers");
-        // Remove dead connections
+        // Remove dead connections, and those removed or reconfigured on reload
+        let referrers = self.context.socks5_referrers();
         let mut dead_referrers = HashSet::new();
         #[allow(clippy::mutable_key_type)]
         let mut dead_servers = HashSet::new();
+        // Servers of reconfigured referrers, to be taken over by new ones
+        let mut reconfigured = HashMap::new();
         for (referrer, referred) in &self.referred_servers {
             trace!("Checking {} ({:?})", referrer.name, referred.stream);
-            if let Err(err) = referred.stream.readable().await {
+            match referrers.iter().find(|r| *r == referrer) {
+                None => {
+                    info!("SOCKSv5 [{}] removed", referrer.name);
+                    dead_referrers.insert(referrer.clone());
+                    dead_servers.insert(referred.server.clone());
+                    continue;
+                }
+                Some(current) if !current.same_config(referrer) => {
+                    info!("SOCKSv5 [{}] reconfigured", referrer.name);
+                    dead_referrers.insert(referrer.clone());
+                    dead_servers.insert(referred.server.clone());
+                    reconfigured.insert(referrer.name.clone(), referred.server.clone());
+                    continue;
+                }
+                Some(_) => (),
+            }
+            if let Err(err) = check_alive(&referred.stream) {
                 info!(
                     "SOCKSv5 [{}]({:?}) disconnected: {}",
                     referrer.name, referred.stream, err
@@ -58,23 +83,47 @@ impl SocksReferService {
         self.referred_servers
             .retain(|key, _| !dead_referrers.contains(key));
 
-        // Start new connections
+        // Start new connections, a limited number at a time to not flood
+        // the network after an outage
+        let pending: Vec<_> = referrers
+            .into_iter()
+            .filter(|referrer| !self.referred_servers.contains_key(referrer))
+            .collect();
+        let args = self.context.cli_args;
+        let keepalive = args.socks5_tcp_keepalive.map(|idle| {
+            TcpKeepalive::new()
+                .with_time(idle)
+                .with_interval(args.socks5_tcp_keepalive_interval)
+        });
+        let keepalive = keepalive.as_ref();
+        let results: Vec<_> = stream::iter(pending)
+            .map(|referrer| async move {
+                let result = referrer.negotiate(keepalive).await;
+                (referrer, result)
+            })
+            .buffer_unordered(self.context.cli_args.refer_reconnect_concurrency.into())
+            .collect()
+            .await;
         #[allow(clippy::mutable_key_type)]
         let mut new_servers = HashSet::new();
-        for referrer in self.context.socks5_referrers() {
-            if let Entry::Vacant(entry) = self.referred_servers.entry(referrer) {
-                match entry.key().negotiate().await {
-                    Ok(referred) => {
-                        info!(
-                            "Connected with {}, UDP endpoint {:?}",
-                            entry.key().name,
-                            referred.server.udp_addr
-                        );
-                        new_servers.insert(referred.server.clone());
-                        entry.insert(referred);
+        for (referrer, result) in results {
+            match result {
+                Ok(referred) => {
+                    if let Some(old) = reconfigured.remove(&referrer.name) {
+                        referred.server.carry_over_from(&old);
+                    } else if let Some(pings) =
+                        self.context.take_saved_referred_pings(&referrer.name)
+                    {
+                        *referred.server.status.pings.lock() = pings;
                     }
-                    Err(err) => warn!("Failed to negotiate with {}: {}", entry.key().name, err),
+                    info!(
+                        "Connected with {}, UDP endpoint {:?}",
+                        referrer.name, referred.server.udp_addr
+                    );
+                    new_servers.insert(referred.server.clone());
+                    self.referred_servers.insert(referrer, referred);
                 }
+                Err(err) => warn!("Failed to negotiate with {}: {}", referrer.name, err),
             }
         }
 
@@ -86,3 +135,38 @@ impl SocksReferService {
         });
     }
 }
+
+/// Check the control connection without waiting on it. Servers send
+/// nothing on it after negotiation, so anything readable is either EOF or
+/// an error, e.g. from failed keepalive probes.
+fn check_alive(stream: &TcpStream) -> io::Result<()> {
+    let mut buf = [0u8; 64];
+    match stream.try_read(&mut buf) {
+        Ok(0) => io_error!(UnexpectedEof, "closed by server"),
+        Ok(n) => {
+            trace!("Discard {} unexpected bytes from server", n);
+            Ok(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+#[tokio::test]
+async fn test_check_alive() {
+    use tokio::{io::AsyncWriteExt, net::TcpListener};
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let stream = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut server, _) = listener.accept().await.unwrap();
+    // Idle connection doesn't block the check
+    assert!(check_alive(&stream).is_ok());
+    server.write_all(b"?").await.unwrap();
+    stream.readable().await.unwrap();
+    assert!(check_alive(&stream).is_ok());
+    drop(server);
+    stream.readable().await.unwrap();
+    assert!(check_alive(&stream).is_err());
+}
@@ -0,0 +1,393 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use rand::{distributions::WeightedIndex, prelude::Distribution, Rng};
+use ring::digest::{Context, SHA256};
+
+use super::SocksServer;
+use crate::{
+    app::{
+        checking::ScoreParams,
+        types::{ClientAddr, RemoteAddr},
+    },
+    cli::{CliArgs, SelectMode, TieBreak},
+};
+
+/// The conn an upstream is being selected for.
+pub(crate) struct ConnContext<'a> {
+    /// `None` if it's asked for a remote alone, e.g. by debug queries
+    pub(crate) client: Option<ClientAddr>,
+    pub(crate) remote: RemoteAddr,
+    /// SNI, or other names of the remote
+    pub(crate) remote_name: Option<&'a str>,
+    /// ALPN protocols offered by the client, empty if unknown
+    pub(crate) alpn: &'a [String],
+}
+
+/// How to pick an upstream for a conn among usable ones. Pinning, health
+/// & capability filtering are done before, `candidates` are all usable and
+/// sorted by score, best first.
+pub(crate) trait SelectionPolicy: Send + Sync {
+    fn select(
+        &self,
+        candidates: &[Arc<SocksServer>],
+        conn: &ConnContext,
+    ) -> Option<Arc<SocksServer>>;
+
+    /// Whether a conn on `current` is where the policy would put it, i.e.
+    /// not worth a migration. Random policies keep any choice they could
+    /// have made.
+    fn keeps(
+        &self,
+        current: &Arc<SocksServer>,
+        candidates: &[Arc<SocksServer>],
+        conn: &ConnContext,
+    ) -> bool {
+        self.select(candidates, conn)
+            .is_some_and(|server| Arc::ptr_eq(&server, current))
+    }
+}
+
+/// The policy of `--select-mode`.
+pub(crate) fn selection_policy(args: &CliArgs) -> Arc<dyn SelectionPolicy> {
+    match args.select_mode {
+        SelectMode::Best => Arc::new(Best {
+            params: ScoreParams::from_args(args),
+            tie_break: args.tie_break,
+            rotation: Default::default(),
+        }),
+        SelectMode::ConsistentHash => Arc::new(ConsistentHash {
+            salt: args.hash_salt.clone(),
+            load_factor: args.hash_load_factor,
+        }),
+    }
+}
+
+/// The one with best score, unless it's approaching its RX limit, ties
+/// broken by `--tie-break` on each selection. If upstreams are weighted
+/// differently, a random one by `weighted_pick()`.
+struct Best {
+    params: ScoreParams,
+    tie_break: TieBreak,
+    /// Selections made among ties, for `TieBreak::Rotate`
+    rotation: AtomicUsize,
+}
+
+impl Best {
+    /// Candidates sharing the best score, in their order. Only the first
+    /// one without tie-break, as servers are kept sorted.
+    fn ties<'a>(&self, candidates: &'a [Arc<SocksServer>]) -> Vec<&'a Arc<SocksServer>> {
+        if self.tie_break == TieBreak::None {
+            return candidates.first().into_iter().collect();
+        }
+        let scores: Vec<_> = candidates
+            .iter()
+            .map(|p| p.status.pings.lock().score_with(&self.params))
+            .collect();
+        let best = scores.iter().min();
+        candidates
+            .iter()
+            .zip(&scores)
+            .filter(|(_, score)| Some(*score) == best)
+            .map(|(p, _)| p)
+            .collect()
+    }
+}
+
+impl SelectionPolicy for Best {
+    fn select(&self, candidates: &[Arc<SocksServer>], _: &ConnContext) -> Option<Arc<SocksServer>> {
+        let unlimited: Vec<_> = candidates
+            .iter()
+            .filter(|p| !p.near_rx_limit())
+            .cloned()
+            .collect();
+        let candidates = if unlimited.is_empty() {
+            candidates
+        } else {
+            &unlimited
+        };
+        if is_weighted(candidates) {
+            let shares: Vec<_> = candidates
+                .iter()
+                .map(|p| (p.weight, p.status.pings.lock().score_with(&self.params)))
+                .collect();
+            if let Some(i) = weighted_pick(&shares, &mut rand::thread_rng()) {
+                return Some(candidates[i].clone());
+            }
+        }
+        let ties = self.ties(candidates);
+        let picked = match self.tie_break {
+            TieBreak::None => ties.first(),
+            TieBreak::Sessions => ties.iter().min_by_key(|p| p.status.usage.active_sessions()),
+            TieBreak::Rotate if ties.is_empty() => None,
+            TieBreak::Rotate => {
+                let i = self.rotation.fetch_add(1, Ordering::Relaxed);
+                ties.get(i % ties.len())
+            }
+        };
+        picked.map(|p| (*p).clone())
+    }
+
+    fn keeps(
+        &self,
+        current: &Arc<SocksServer>,
+        candidates: &[Arc<SocksServer>],
+        conn: &ConnContext,
+    ) -> bool {
+        if is_weighted(candidates) {
+            candidates
+                .iter()
+                .any(|p| Arc::ptr_eq(p, current) && p.weight > 0)
+        } else if self.tie_break == TieBreak::None {
+            self.select(candidates, conn)
+                .is_some_and(|server| Arc::ptr_eq(&server, current))
+        } else {
+            // Any of the ties could have been picked
+            let unlimited: Vec<_> = candidates
+                .iter()
+                .filter(|p| !p.near_rx_limit())
+                .cloned()
+                .collect();
+            let candidates = if unlimited.is_empty() {
+                candidates
+            } else {
+                &unlimited
+            };
+            self.ties(candidates)
+                .into_iter()
+                .any(|p| Arc::ptr_eq(p, current))
+        }
+    }
+}
+
+/// Whether servers are weighted differently, see `Upstream::weight`.
+fn is_weighted(servers: &[Arc<SocksServer>]) -> bool {
+    servers.iter().any(|p| p.weight != servers[0].weight)
+}
+
+/// Index of a random one among (weight, score) `shares`, with probability
+/// proportional to weight / score. `None` if all weights are 0.
+fn weighted_pick<R: Rng + ?Sized>(shares: &[(u32, i16)], rng: &mut R) -> Option<usize> {
+    let ratios = shares
+        .iter()
+        .map(|(weight, score)| *weight as f64 / (*score).max(1) as f64);
+    let index = WeightedIndex::new(ratios).ok()?;
+    Some(index.sample(rng))
+}
+
+/// See `consistent_hash()`, keyed by remote name if any, or by its IP.
+struct ConsistentHash {
+    salt: Option<String>,
+    load_factor: f64,
+}
+
+impl SelectionPolicy for ConsistentHash {
+    fn select(
+        &self,
+        candidates: &[Arc<SocksServer>],
+        conn: &ConnContext,
+    ) -> Option<Arc<SocksServer>> {
+        let key = match (conn.remote_name, conn.remote.0.ip()) {
+            (Some(name), _) => name.as_bytes().to_vec(),
+            (None, IpAddr::V4(ip)) => ip.octets().to_vec(),
+            (None, IpAddr::V6(ip)) => ip.octets().to_vec(),
+        };
+        let salt = self.salt.as_deref();
+        consistent_hash(candidates, &key, salt, self.load_factor).cloned()
+    }
+}
+
+/// Weighted rendezvous (HRW) hashing with bounded loads: rank candidates
+/// by `-weight / ln(h)` where `h` is hash of (salt, key, server name) in
+/// (0, 1), pick the first one with no more than `load_factor` times its
+/// weighted share of active sessions.
+///
+/// Instances with the same salt rank the same way, even of other builds,
+/// so a key goes to the same server fleet-wide unless loads differ.
+fn consistent_hash<'a>(
+    candidates: &'a [Arc<SocksServer>],
+    key: &[u8],
+    salt: Option<&str>,
+    load_factor: f64,
+) -> Option<&'a Arc<SocksServer>> {
+    let salt = salt.unwrap_or_default().as_bytes();
+    let mut ranked: Vec<_> = candidates
+        .iter()
+        .map(|server| {
+            let hash = rendezvous_hash(&[salt, key, server.name.as_bytes()]);
+            let h = (hash as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+            (-(server.weight as f64) / h.ln(), server)
+        })
+        .collect();
+    ranked.sort_unstable_by(|(a, _), (b, _)| b.total_cmp(a));
+
+    // Count in the one about to be added
+    let total: usize = candidates
+        .iter()
+        .map(|s| s.status.usage.active_sessions())
+        .sum();
+    let total_weight: u64 = candidates.iter().map(|s| s.weight as u64).sum();
+    let share = |server: &SocksServer| match total_weight {
+        0 => 1.0 / candidates.len() as f64,
+        _ => server.weight as f64 / total_weight as f64,
+    };
+    ranked
+        .iter()
+        .find(|(_, server)| {
+            let bound = ((total + 1) as f64 * share(server) * load_factor.max(1.0)).ceil();
+            (server.status.usage.active_sessions() as f64) < bound
+        })
+        .or_else(|| ranked.first())
+        .map(|(_, server)| *server)
+}
+
+/// First 64 bits of SHA-256 over length-prefixed `parts`. Unlike
+/// `DefaultHasher`, it never changes across Rust releases.
+fn rendezvous_hash(parts: &[&[u8]]) -> u64 {
+    let mut context = Context::new(&SHA256);
+    for part in parts {
+        context.update(&(part.len() as u64).to_be_bytes());
+        context.update(part);
+    }
+    let digest = context.finish();
+    u64::from_be_bytes(digest.as_ref()[..8].try_into().unwrap())
+}
+
+#[test]
+fn test_consistent_hash() {
+    use crate::app::InnerProto;
+
+    let servers: Vec<Arc<SocksServer>> = (0..4)
+        .map(|i| {
+            let addr = ([127, 0, 0, 1], 1080 + i).into();
+            Arc::new(SocksServer::new(addr, format!("s{}", i), InnerProto::Inet))
+        })
+        .collect();
+    // Fixed across builds & platforms
+    let hash = rendezvous_hash(&[b"", b"example.com", b"s0"]);
+    assert_eq!(hash, 0x1849_ebd1_ac2b_f37b);
+    let chosen = consistent_hash(&servers, b"example.com", None, 1.25).unwrap();
+    // Stable for the same key, regardless of candidates order
+    let mut reversed = servers.clone();
+    reversed.reverse();
+    let again = consistent_hash(&reversed, b"example.com", None, 1.25).unwrap();
+    assert!(Arc::ptr_eq(chosen, again));
+    // Salt reshuffles the ranking
+    let salted: Vec<_> = (0..32)
+        .map(|i: u32| consistent_hash(&servers, &i.to_be_bytes(), Some("fleet"), 1.25).unwrap())
+        .collect();
+    let unsalted: Vec<_> = (0..32)
+        .map(|i: u32| consistent_hash(&servers, &i.to_be_bytes(), None, 1.25).unwrap())
+        .collect();
+    assert!(salted
+        .iter()
+        .zip(&unsalted)
+        .any(|(a, b)| !Arc::ptr_eq(a, b)));
+    // Overloaded server gets skipped
+    (0..4).for_each(|_| chosen.status.usage.open_session());
+    let other = consistent_hash(&servers, b"example.com", None, 1.25).unwrap();
+    assert!(!Arc::ptr_eq(chosen, other));
+
+    // Weight 3:1 takes keys 3:1
+    let weighted: Vec<Arc<SocksServer>> = [3, 1]
+        .into_iter()
+        .enumerate()
+        .map(|(i, weight)| {
+            let addr = ([127, 0, 0, 1], 1090 + i as u16).into();
+            let server = SocksServer::new(addr, format!("w{}", i), InnerProto::Inet);
+            Arc::new(server.with_weight(weight))
+        })
+        .collect();
+    let heavy = (0..4000)
+        .filter(|i: &u32| {
+            let server = consistent_hash(&weighted, &i.to_be_bytes(), None, 1.25).unwrap();
+            Arc::ptr_eq(server, &weighted[0])
+        })
+        .count();
+    assert!((2800..3200).contains(&heavy), "{}", heavy);
+}
+
+#[test]
+fn test_weighted_pick() {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut counts = [0; 3];
+    // Same score, weight 3:1, and a zero weight one never picked
+    let shares = [(3, 100), (1, 100), (0, 10)];
+    for _ in 0..4000 {
+        counts[weighted_pick(&shares, &mut rng).unwrap()] += 1;
+    }
+    assert!((2800..3200).contains(&counts[0]), "{:?}", counts);
+    assert_eq!(counts[2], 0);
+    // Same weight, score 2x better takes 2x conns
+    let shares = [(1, 50), (1, 100)];
+    let picked_first = (0..3000)
+        .filter(|_| weighted_pick(&shares, &mut rng) == Some(0))
+        .count();
+    assert!((1800..2200).contains(&picked_first), "{}", picked_first);
+    assert_eq!(weighted_pick(&[(0, 10), (0, 20)], &mut rng), None);
+    assert_eq!(weighted_pick(&[], &mut rng), None);
+}
+
+#[test]
+fn test_selection_policy() {
+    use crate::app::InnerProto;
+    use clap::Parser;
+
+    let servers: Vec<Arc<SocksServer>> = (0..4)
+        .map(|i| {
+            let addr = ([127, 0, 0, 1], 1080 + i).into();
+            Arc::new(SocksServer::new(addr, format!("s{}", i), InnerProto::Inet))
+        })
+        .collect();
+    let conn = ConnContext {
+        client: None,
+        remote: RemoteAddr(([192, 0, 2, 1], 443).into()),
+        remote_name: Some("example.com"),
+        alpn: &[],
+    };
+    let args = CliArgs::parse_from(["quproxy", "-p", "0"]);
+    let best = selection_policy(&args).select(&servers, &conn).unwrap();
+    assert!(Arc::ptr_eq(&best, &servers[0]));
+    // Others of weight 0 are never taken over a weighted one
+    let weighted: Vec<Arc<SocksServer>> = (0..4)
+        .map(|i| {
+            let addr = ([127, 0, 0, 1], 1080 + i).into();
+            let server = SocksServer::new(addr, format!("s{}", i), InnerProto::Inet);
+            Arc::new(server.with_weight((i == 2).into()))
+        })
+        .collect();
+    let policy = selection_policy(&args);
+    for _ in 0..16 {
+        let picked = policy.select(&weighted, &conn).unwrap();
+        assert!(Arc::ptr_eq(&picked, &weighted[2]));
+    }
+
+    // Ties broken on every selection, not just on resort
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "--tie-break", "rotate"]);
+    let policy = selection_policy(&args);
+    let picked: Vec<_> = (0..8)
+        .map(|_| policy.select(&servers, &conn).unwrap().name.clone())
+        .collect();
+    assert_eq!(picked, ["s0", "s1", "s2", "s3", "s0", "s1", "s2", "s3"]);
+    assert!(policy.keeps(&servers[2], &servers, &conn));
+    servers[3].status.usage.open_session();
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "--tie-break", "sessions"]);
+    let policy = selection_policy(&args);
+    let reversed: Vec<_> = servers.iter().rev().cloned().collect();
+    let picked = policy.select(&reversed, &conn).unwrap();
+    assert!(Arc::ptr_eq(&picked, &servers[2]));
+    servers[3].status.usage.close_session();
+
+    let args = CliArgs::parse_from(["quproxy", "-p", "0", "--select-mode", "consistent-hash"]);
+    let hashed = selection_policy(&args).select(&servers, &conn).unwrap();
+    let expected = consistent_hash(&servers, b"example.com", None, 1.25).unwrap();
+    assert!(Arc::ptr_eq(&hashed, expected));
+    assert!(selection_policy(&args).select(&[], &conn).is_none());
+}
@@ -1,20 +1,31 @@
 use std::{
     io,
-    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     sync::{
-        atomic::{AtomicU8, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, OnceLock,
     },
+    time::{Duration, Instant},
 };
 
 use derivative::Derivative;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
+use socket2::{SockRef, TcpKeepalive};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    net::{lookup_host, TcpSocket, TcpStream},
 };
 
-use crate::app::ServerStatus;
+use super::pool::SocketPool;
+use crate::{
+    app::{
+        limit::{RateLimits, TokenBucket},
+        net::{in_netns, EgressOpts},
+        types::canonicalize_socket_addr,
+        ServerStatus,
+    },
+    cli::UpstreamAddr,
+};
 
 const INNER_PROTO_IPV4: u8 = 1;
 const INNER_PROTO_IPV6: u8 = 2;
@@ -25,7 +36,7 @@ pub(crate) struct AtomicInnerProto {
     inner: AtomicU8,
 }
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
 pub(crate) enum InnerProto {
     #[default]
     #[serde(alias = "auto")]
@@ -39,6 +50,22 @@ pub(crate) enum InnerProto {
     Inet,
 }
 
+/// Outcome of probing inner protocol, with the counts it's based on.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct InnerProtoProbe {
+    pub(crate) proto: InnerProto,
+    pub(crate) v4_ok: usize,
+    pub(crate) v6_ok: usize,
+    pub(crate) tests: usize,
+    /// Monotonic, so that clock steps don't skew its age
+    #[serde(rename = "probed_secs_ago", serialize_with = "serialize_elapsed_secs")]
+    pub(crate) probed_at: Instant,
+}
+
+fn serialize_elapsed_secs<S: Serializer>(at: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(at.elapsed().as_secs())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum AppProto {
     IPv4,
@@ -88,6 +115,14 @@ impl InnerProto {
     }
 }
 
+/// Per-upstream DNS servers for availability check, `None` to use the
+/// global `--check-dns-server-v*`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CheckDnsServers {
+    pub(crate) v4: Option<SocketAddrV4>,
+    pub(crate) v6: Option<SocketAddrV6>,
+}
+
 #[derive(Derivative)]
 #[derivative(Debug, Hash, PartialEq, Eq)]
 pub(crate) struct SocksServer {
@@ -97,9 +132,50 @@ pub(crate) struct SocksServer {
     #[derivative(PartialEq = "ignore")]
     #[derivative(Hash = "ignore")]
     pub(crate) inner_proto: AtomicInnerProto,
+    /// As in config, `inner_proto` may be changed by probes
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    configured_proto: InnerProto,
     #[derivative(PartialEq = "ignore")]
     #[derivative(Hash = "ignore")]
     pub(crate) status: ServerStatus,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    rate_limits: RateLimits,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) tx_bucket: Option<TokenBucket>,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) rx_limit: Option<u64>,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) max_rtt: Option<Duration>,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) group: Option<String>,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) check_dns: CheckDnsServers,
+    /// Local address to send from, the kernel picks one if `None`
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) bind_from: Option<IpAddr>,
+    /// Relative share of conns, see `Upstream::weight`
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) weight: u32,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    draining: AtomicBool,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    removed_at: OnceLock<Instant>,
+    /// Sockets shared by sessions, see `--socks-socket-pool`
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    #[derivative(Debug = "ignore")]
+    pub(super) socket_pool: OnceLock<SocketPool>,
 }
 
 impl From<SocketAddr> for SocksServer {
@@ -112,11 +188,178 @@ impl SocksServer {
     pub(crate) fn new(udp_addr: SocketAddr, name: String, inner_proto: InnerProto) -> Self {
         Self {
             name,
-            udp_addr,
+            udp_addr: canonicalize_socket_addr(udp_addr),
             inner_proto: inner_proto.into(),
+            configured_proto: inner_proto,
             status: Default::default(),
+            rate_limits: Default::default(),
+            tx_bucket: None,
+            rx_limit: None,
+            max_rtt: None,
+            group: None,
+            check_dns: Default::default(),
+            bind_from: None,
+            weight: 1,
+            draining: Default::default(),
+            removed_at: Default::default(),
+            socket_pool: Default::default(),
+        }
+    }
+
+    pub(crate) fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits = limits;
+        self.tx_bucket = limits.tx.map(TokenBucket::new);
+        self.rx_limit = limits.rx;
+        self
+    }
+
+    pub(crate) fn with_max_rtt(mut self, max_rtt: Option<Duration>) -> Self {
+        self.max_rtt = max_rtt;
+        self
+    }
+
+    pub(crate) fn with_group(mut self, group: Option<String>) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub(crate) fn with_check_dns(mut self, check_dns: CheckDnsServers) -> Self {
+        self.check_dns = check_dns;
+        self
+    }
+
+    pub(crate) fn with_bind_from(mut self, bind_from: Option<IpAddr>) -> Self {
+        self.bind_from = bind_from;
+        self
+    }
+
+    pub(crate) fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Whether `other` is configured the same, besides name & address
+    /// compared by `==`.
+    pub(crate) fn same_config(&self, other: &Self) -> bool {
+        self.configured_proto == other.configured_proto
+            && self.rate_limits == other.rate_limits
+            && self.max_rtt == other.max_rtt
+            && self.group == other.group
+            && self.check_dns == other.check_dns
+            && self.bind_from == other.bind_from
+            && self.weight == other.weight
+    }
+
+    /// Take over health & probe results of `old`, which is replaced by
+    /// this one on reload. Traffic counters start over.
+    pub(crate) fn carry_over_from(&self, old: &Self) {
+        if self.configured_proto == old.configured_proto {
+            if let Some(probe) = old.inner_proto_probe() {
+                self.set_inner_proto_probe(probe);
+            }
+        }
+        self.status.carry_over_from(&old.status);
+    }
+
+    pub(crate) fn egress_opts(&self) -> EgressOpts {
+        EgressOpts {
+            bind_from: self.bind_from,
+            ..Default::default()
+        }
+    }
+
+    /// Return true if recent RX rate is close to `rx_limit`.
+    pub(crate) fn near_rx_limit(&self) -> bool {
+        match (self.rx_limit, self.status.meter.lock().rx_rate()) {
+            (Some(limit), Some(rate)) => rate >= limit / 10 * 9,
+            _ => false,
+        }
+    }
+
+    /// Draining server is not selected for new conns, and its existing
+    /// conns get migrated away.
+    pub(crate) fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Take new conns again, return false if it was removed on reload.
+    pub(crate) fn undrain(&self) -> bool {
+        if self.removed_at().is_some() {
+            return false;
+        }
+        self.draining.store(false, Ordering::Relaxed);
+        true
+    }
+
+    /// When the server was removed from config on reload, it's kept
+    /// draining until its sessions all end or `--drain-timeout` passes.
+    pub(crate) fn removed_at(&self) -> Option<Instant> {
+        self.removed_at.get().copied()
+    }
+
+    pub(crate) fn set_removed(&self) {
+        self.set_draining();
+        let _ = self.removed_at.set(Instant::now());
+    }
+
+    pub(crate) fn inner_proto_probe(&self) -> Option<InnerProtoProbe> {
+        *self.status.inner_proto_probe.lock()
+    }
+
+    pub(crate) fn set_inner_proto_probe(&self, probe: InnerProtoProbe) {
+        self.inner_proto.set(probe.proto);
+        *self.status.inner_proto_probe.lock() = Some(probe);
+    }
+
+    /// Whether the latest inner protocol probe found neither IPv4 nor IPv6
+    /// working, though the check before it passed. Unlike a never-probed
+    /// server, it's likely to fail real conns, so others are preferred
+    /// until a later probe settles it.
+    pub(crate) fn is_proto_unknown(&self) -> bool {
+        self.inner_proto.get() == InnerProto::Unspecified
+            && self
+                .inner_proto_probe()
+                .is_some_and(|probe| probe.proto == InnerProto::Unspecified)
+    }
+
+    /// Count a new conn that this server can't take due to its inner
+    /// protocol, see `InnerProto::capable()`.
+    pub(crate) fn record_proto_mismatch(&self) {
+        self.status.proto_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn proto_mismatches(&self) -> usize {
+        self.status.proto_mismatches.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_reply_addr_mismatch(&self) {
+        self.status
+            .reply_addr_mismatches
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reply_addr_mismatches(&self) -> usize {
+        self.status.reply_addr_mismatches.load(Ordering::Relaxed)
+    }
+
+    /// Take `len` bytes from the TX bucket, count a drop if exceeded.
+    pub(crate) fn take_tx(&self, len: usize) -> bool {
+        match &self.tx_bucket {
+            Some(bucket) if bucket.try_take(len).is_err() => {
+                self.status.tx_limited.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            _ => true,
         }
     }
+
+    pub(crate) fn tx_limited(&self) -> usize {
+        self.status.tx_limited.load(Ordering::Relaxed)
+    }
 }
 
 const ATYP_IPV4: u8 = 0x01;
@@ -126,10 +369,41 @@ const ATYP_IPV6: u8 = 0x04;
 #[derivative(Debug, Clone, Hash, PartialEq, Eq)]
 pub(crate) struct SocksServerReferrer {
     pub(crate) name: String,
-    pub(crate) tcp_addr: SocketAddr,
+    pub(crate) tcp_addr: UpstreamAddr,
     #[derivative(PartialEq = "ignore")]
     #[derivative(Hash = "ignore")]
     pub(crate) inner_proto: InnerProto,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) rate_limits: RateLimits,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) max_rtt: Option<Duration>,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) group: Option<String>,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) check_dns: CheckDnsServers,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) bind_from: Option<IpAddr>,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) weight: u32,
+    #[derivative(PartialEq = "ignore")]
+    #[derivative(Hash = "ignore")]
+    pub(crate) auth: Option<Credentials>,
+}
+
+/// Username & password for RFC 1929 authentication, the latter hidden
+/// from debug output.
+#[derive(Derivative, Clone, PartialEq, Eq)]
+#[derivative(Debug)]
+pub(crate) struct Credentials {
+    pub(crate) username: String,
+    #[derivative(Debug = "ignore")]
+    pub(crate) password: String,
 }
 
 #[derive(Debug)]
@@ -140,39 +414,144 @@ pub(crate) struct ReferredSocksServer {
 
 impl From<SocketAddr> for SocksServerReferrer {
     fn from(addr: SocketAddr) -> Self {
-        SocksServerReferrer::new(addr, addr.to_string(), InnerProto::Unspecified)
+        SocksServerReferrer::new(
+            UpstreamAddr::Ip(addr),
+            addr.to_string(),
+            InnerProto::Unspecified,
+        )
     }
 }
 
 impl SocksServerReferrer {
-    pub(crate) fn new(tcp_addr: SocketAddr, name: String, inner_proto: InnerProto) -> Self {
+    pub(crate) fn new(tcp_addr: UpstreamAddr, name: String, inner_proto: InnerProto) -> Self {
         Self {
             name,
             tcp_addr,
             inner_proto,
+            rate_limits: Default::default(),
+            max_rtt: None,
+            group: None,
+            check_dns: Default::default(),
+            bind_from: None,
+            weight: 1,
+            auth: None,
         }
     }
 
-    pub(crate) async fn negotiate(&self) -> io::Result<ReferredSocksServer> {
-        let mut stream = TcpStream::connect(self.tcp_addr).await?;
-        // Send request w/ auth method 0x00 (no auth)
-        stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    pub(crate) fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.rate_limits = limits;
+        self
+    }
+
+    /// Whether `other` is configured the same, besides name & address
+    /// compared by `==`.
+    pub(crate) fn same_config(&self, other: &Self) -> bool {
+        self.inner_proto == other.inner_proto
+            && self.rate_limits == other.rate_limits
+            && self.max_rtt == other.max_rtt
+            && self.group == other.group
+            && self.check_dns == other.check_dns
+            && self.bind_from == other.bind_from
+            && self.weight == other.weight
+            && self.auth == other.auth
+    }
+
+    pub(crate) fn with_max_rtt(mut self, max_rtt: Option<Duration>) -> Self {
+        self.max_rtt = max_rtt;
+        self
+    }
+
+    pub(crate) fn with_group(mut self, group: Option<String>) -> Self {
+        self.group = group;
+        self
+    }
+
+    pub(crate) fn with_check_dns(mut self, check_dns: CheckDnsServers) -> Self {
+        self.check_dns = check_dns;
+        self
+    }
+
+    pub(crate) fn with_bind_from(mut self, bind_from: Option<IpAddr>) -> Self {
+        self.bind_from = bind_from;
+        self
+    }
+
+    pub(crate) fn with_weight(mut self, weight: u32) -> Self {
+        self.weight = weight;
+        self
+    }
+
+    /// Offer username/password authentication with `auth`, in addition
+    /// to no authentication.
+    pub(crate) fn with_auth(mut self, auth: Option<Credentials>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// Resolve hostname of `tcp_addr` on every call, so that DNS changes
+    /// are picked up on reconnecting. Only addresses in the family of
+    /// `bind_from` are taken if it's set.
+    async fn resolve_tcp_addr(&self) -> io::Result<SocketAddr> {
+        let host = match &self.tcp_addr {
+            UpstreamAddr::Ip(addr) => return Ok(*addr),
+            UpstreamAddr::Host(host) => host,
+        };
+        let family_ok = |addr: &SocketAddr| match self.bind_from {
+            Some(ip) => ip.is_ipv4() == addr.is_ipv4(),
+            None => true,
+        };
+        match lookup_host(host.as_str()).await?.find(family_ok) {
+            Some(addr) => Ok(addr),
+            None => io_error!(NotFound, format!("{} resolved to nothing usable", host)),
+        }
+    }
+
+    pub(crate) async fn negotiate(
+        &self,
+        keepalive: Option<&TcpKeepalive>,
+    ) -> io::Result<ReferredSocksServer> {
+        let addr = self.resolve_tcp_addr().await?;
+        let bind_from = self.bind_from;
+        // In the same namespace as its UDP sessions, see `--netns`
+        let socket = in_netns(move || {
+            let socket = match addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            // Relays may only accept UDP from the address of the control
+            // connection
+            if let Some(ip) = bind_from {
+                socket.bind((ip, 0).into())?;
+            }
+            Ok(socket)
+        })
+        .await?;
+        let mut stream = socket.connect(addr).await?;
+        if let Some(keepalive) = keepalive {
+            SockRef::from(&stream).set_tcp_keepalive(keepalive)?;
+        }
+        // Send request w/ auth method 0x00 (no auth), and 0x02
+        // (username/password) if configured
+        match &self.auth {
+            Some(_) => stream.write_all(&[0x05, 0x02, 0x00, 0x02]).await?,
+            None => stream.write_all(&[0x05, 0x01, 0x00]).await?,
+        }
         // Server select auth method
         let mut buf = [0u8; 2];
         stream.read_exact(&mut buf).await?;
-        match buf {
+        match (buf, &self.auth) {
             // 0xff: no acceptable method
-            [0x05, 0xff] => io_error!("Auth required by SOCKS server"),
+            ([0x05, 0xff], _) => io_error!("Auth required by SOCKS server"),
             // 0x00：no auth required
-            [0x05, 0x00] => (),
+            ([0x05, 0x00], _) => (),
+            // 0x02: username/password
+            ([0x05, 0x02], Some(auth)) => auth.authenticate(&mut stream).await?,
             _ => io_error!("Unrecognized reply from SOCKS server"),
         }
         // Send UDP associate request
+        let control_addr = canonicalize_socket_addr(stream.peer_addr()?);
         stream
-            .write_all(&[
-                // VER, CMD (UDP), RSV, ATYP (IPv4), DST.ADDR (0.0.0.0), DST.PORT (0)
-                0x05, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-            ])
+            .write_all(&udp_associate_request(control_addr))
             .await?;
 
         // Get UDP socket address from server's reply
@@ -193,12 +572,222 @@ impl SocksServerReferrer {
             _ => io_error!("Unsupported address type from SOCKS server"),
         };
         let port = stream.read_u16().await?;
-        let udp_addr: SocketAddr = (ip, port).into();
+        let udp_addr = udp_relay_addr((ip, port).into(), control_addr);
 
-        let server = SocksServer::new(udp_addr, self.name.clone(), self.inner_proto);
+        let server = SocksServer::new(udp_addr, self.name.clone(), self.inner_proto)
+            .with_rate_limits(self.rate_limits)
+            .with_max_rtt(self.max_rtt)
+            .with_check_dns(self.check_dns)
+            .with_bind_from(self.bind_from)
+            .with_weight(self.weight)
+            .with_group(self.group.clone());
         Ok(ReferredSocksServer {
             server: server.into(),
             stream,
         })
     }
 }
+
+impl Credentials {
+    /// Username/password sub-negotiation, RFC 1929.
+    async fn authenticate(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let mut req = vec![0x01, self.username.len() as u8];
+        req.extend_from_slice(self.username.as_bytes());
+        req.push(self.password.len() as u8);
+        req.extend_from_slice(self.password.as_bytes());
+        stream.write_all(&req).await?;
+        let mut buf = [0u8; 2];
+        stream.read_exact(&mut buf).await?;
+        match buf {
+            [0x01, 0x00] => Ok(()),
+            [0x01, status] => io_error!(
+                PermissionDenied,
+                format!("SOCKS server rejected username/password ({:#04x})", status)
+            ),
+            _ => io_error!("Unrecognized auth reply from SOCKS server"),
+        }
+    }
+}
+
+/// UDP ASSOCIATE request with unspecified DST.ADDR in the same family as
+/// the control connection.
+fn udp_associate_request(control_addr: SocketAddr) -> Vec<u8> {
+    // VER, CMD (UDP), RSV
+    let mut req = vec![0x05, 0x03, 0x00];
+    match control_addr {
+        // ATYP, DST.ADDR (0.0.0.0)
+        SocketAddr::V4(_) => req.extend_from_slice(&[ATYP_IPV4, 0, 0, 0, 0]),
+        // ATYP, DST.ADDR (::)
+        SocketAddr::V6(_) => {
+            req.push(ATYP_IPV6);
+            req.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+        }
+    }
+    // DST.PORT (0)
+    req.extend_from_slice(&[0x00, 0x00]);
+    req
+}
+
+/// Get usable UDP relay address from BND.ADDR/PORT of UDP ASSOCIATE reply.
+/// IPv4-mapped addresses are converted to IPv4. Unspecified addresses, and
+/// loopback ones from non-loopback servers, are replaced with the address
+/// of control connection.
+fn udp_relay_addr(bnd_addr: SocketAddr, control_addr: SocketAddr) -> SocketAddr {
+    let bnd_addr = canonicalize_socket_addr(bnd_addr);
+    let control_addr = canonicalize_socket_addr(control_addr);
+    let ip = bnd_addr.ip();
+    if ip.is_unspecified() || (ip.is_loopback() && !control_addr.ip().is_loopback()) {
+        (control_addr.ip(), bnd_addr.port()).into()
+    } else {
+        bnd_addr
+    }
+}
+
+#[test]
+fn test_udp_relay_addr() {
+    let addr = |s: &str| -> SocketAddr { s.parse().unwrap() };
+    let remote4 = addr("192.0.2.1:1080");
+    let remote6 = addr("[2001:db8::1]:1080");
+    let mapped = addr("[::ffff:192.0.2.1]:1080");
+    // v4-mapped
+    assert_eq!(
+        udp_relay_addr(addr("[::ffff:192.0.2.2]:2000"), remote6),
+        addr("192.0.2.2:2000")
+    );
+    assert_eq!(
+        udp_relay_addr(addr("0.0.0.0:2000"), mapped),
+        addr("192.0.2.1:2000")
+    );
+    assert_eq!(
+        udp_relay_addr(addr("[::]:2000"), mapped),
+        addr("192.0.2.1:2000")
+    );
+    // Unspecified
+    assert_eq!(
+        udp_relay_addr(addr("0.0.0.0:2000"), remote6),
+        addr("[2001:db8::1]:2000")
+    );
+    assert_eq!(
+        udp_relay_addr(addr("[::]:2000"), remote4),
+        addr("192.0.2.1:2000")
+    );
+    // Loopback
+    let local4 = addr("127.0.0.1:1080");
+    let local6 = addr("[::1]:1080");
+    assert_eq!(
+        udp_relay_addr(addr("127.0.0.1:2000"), local4),
+        addr("127.0.0.1:2000")
+    );
+    assert_eq!(
+        udp_relay_addr(addr("[::1]:2000"), local6),
+        addr("[::1]:2000")
+    );
+    assert_eq!(
+        udp_relay_addr(addr("127.0.0.1:2000"), remote6),
+        addr("[2001:db8::1]:2000")
+    );
+    assert_eq!(
+        udp_relay_addr(addr("[::1]:2000"), remote4),
+        addr("192.0.2.1:2000")
+    );
+    // Normal
+    assert_eq!(
+        udp_relay_addr(addr("198.51.100.1:2000"), remote4),
+        addr("198.51.100.1:2000")
+    );
+}
+
+#[test]
+fn test_udp_associate_request() {
+    let mapped = canonicalize_socket_addr("[::ffff:192.0.2.1]:1080".parse().unwrap());
+    assert_eq!(udp_associate_request(mapped)[3], ATYP_IPV4);
+    assert_eq!(udp_associate_request(mapped).len(), 10);
+    let req = udp_associate_request("[2001:db8::1]:1080".parse().unwrap());
+    assert_eq!(req[3], ATYP_IPV6);
+    assert_eq!(req.len(), 22);
+}
+
+#[test]
+fn test_proto_unknown() {
+    let server = SocksServer::new(
+        ([127, 0, 0, 1], 1080).into(),
+        "s".into(),
+        Default::default(),
+    );
+    assert!(!server.is_proto_unknown());
+    let mut probe = InnerProtoProbe {
+        proto: InnerProto::Unspecified,
+        v4_ok: 0,
+        v6_ok: 0,
+        tests: 3,
+        probed_at: Instant::now(),
+    };
+    server.set_inner_proto_probe(probe);
+    assert!(server.is_proto_unknown());
+    let json = serde_json::to_value(probe).unwrap();
+    assert_eq!(json["probed_secs_ago"], 0);
+    probe.proto = InnerProto::IPv4;
+    server.set_inner_proto_probe(probe);
+    assert!(!server.is_proto_unknown());
+}
+
+#[tokio::test]
+async fn test_negotiate_auth() {
+    use tokio::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let auth = Credentials {
+        username: "user".into(),
+        password: "pass".into(),
+    };
+    let referrer =
+        SocksServerReferrer::from(listener.local_addr().unwrap()).with_auth(Some(auth.clone()));
+    // Mock server answering auth with `status`
+    let listener = &listener;
+    let serve = |status: u8| async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buf = [0u8; 14];
+        stream.read_exact(&mut buf[..4]).await.unwrap();
+        assert_eq!(buf[..4], [0x05, 0x02, 0x00, 0x02]);
+        stream.write_all(&[0x05, 0x02]).await.unwrap();
+        stream.read_exact(&mut buf[..11]).await.unwrap();
+        assert_eq!(&buf[..11], b"\x01\x04user\x04pass");
+        stream.write_all(&[0x01, status]).await.unwrap();
+        if status == 0 {
+            stream.read_exact(&mut buf[..10]).await.unwrap();
+            assert_eq!(buf[..2], [0x05, 0x03]);
+            let reply = [0x05, 0x00, 0x00, ATYP_IPV4, 127, 0, 0, 1, 0x04, 0x38];
+            stream.write_all(&reply).await.unwrap();
+        }
+        stream
+    };
+
+    let (referred, _stream) = tokio::join!(referrer.negotiate(None), serve(0));
+    let referred = referred.unwrap();
+    assert_eq!(referred.server.udp_addr, ([127, 0, 0, 1], 1080).into());
+
+    let (referred, _stream) = tokio::join!(referrer.negotiate(None), serve(1));
+    let err = referred.unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    assert!(format!("{:?}", referrer).contains("user"));
+    assert!(!format!("{:?}", referrer).contains("pass"));
+}
+
+#[tokio::test]
+async fn test_resolve_tcp_addr() {
+    let host = |host: &str| UpstreamAddr::Host(host.into());
+    let referrer = SocksServerReferrer::new(host("localhost:1080"), "r".into(), Default::default())
+        .with_bind_from(Some([127, 0, 0, 1].into()));
+    let addr = referrer.resolve_tcp_addr().await.unwrap();
+    assert_eq!(addr, ([127, 0, 0, 1], 1080).into());
+    let referrer = referrer.with_bind_from(Some(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    if let Ok(addr) = referrer.resolve_tcp_addr().await {
+        assert!(addr.is_ipv6());
+    }
+    let referrer = SocksServerReferrer::new(
+        host("upstream.invalid:1080"),
+        "r".into(),
+        Default::default(),
+    );
+    assert!(referrer.resolve_tcp_addr().await.is_err());
+}
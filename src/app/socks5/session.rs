@@ -1,10 +1,14 @@
 use std::{
+    cmp,
     fmt::{Display, Formatter},
     future::Future,
     io::{self, Read, Result},
-    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
     pin::Pin,
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock, Weak,
+    },
     task::{Context, Poll},
     time::Instant,
 };
@@ -12,20 +16,34 @@ use std::{
 use byteorder::{ReadBytesExt, BE};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use futures::{FutureExt, Stream};
-use tokio::sync::Notify;
-use tracing::{debug, instrument, trace};
+use parking_lot::Mutex;
+use tokio::sync::{mpsc, Notify};
+use tracing::{debug, info, instrument, trace, warn};
 
-use crate::app::net::{
-    AsyncUdpSocket, MsgArrayReadBuffer, MsgArrayWriteBuffer, UDP_BATCH_SIZE, UDP_MAX_SIZE,
+use crate::{
+    app::{
+        checking::Healthy,
+        net::{
+            AsyncUdpSocket, EgressOpts, MsgArrayReadBuffer, MsgArrayWriteBuffer, UDP_BATCH_SIZE,
+            UDP_MAX_SIZE,
+        },
+    },
+    cli::ReplyAddrCheck,
 };
 
-use super::{server::AppProto, traffic::AtomicTraffic, SocksServer};
+use super::{
+    frag::{Reassembler, ReassemblyMem, FRAG_END, FRAG_MAX_COUNT},
+    pool::{PooledRoute, SocketPool},
+    server::AppProto,
+    traffic::{AtomicTraffic, Traffic},
+    SocksServer,
+};
 
 const ATYP_IPV4: u8 = 0x01;
 const ATYP_IPV6: u8 = 0x04;
 const ATYP_NAME: u8 = 0x03;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum SocksTarget {
     V4(SocketAddrV4),
     V6(SocketAddrV6),
@@ -79,6 +97,23 @@ impl SocksTarget {
         }
     }
 
+    fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            SocksTarget::V4(target) => Some((*target).into()),
+            SocksTarget::V6(target) => Some((*target).into()),
+            SocksTarget::Name(_) => None,
+        }
+    }
+
+    /// Whether `addr` from a reply is this target, always true for names.
+    fn matches(&self, addr: SocketAddr) -> bool {
+        let target = match self.addr() {
+            Some(target) => target,
+            None => return true,
+        };
+        target.port() == addr.port() && target.ip().to_canonical() == addr.ip().to_canonical()
+    }
+
     pub(crate) fn proto(&self) -> AppProto {
         match self {
             SocksTarget::V4(_) => AppProto::IPv4,
@@ -89,20 +124,150 @@ impl SocksTarget {
 }
 
 impl SocksServer {
-    pub(crate) async fn bind(self: &Arc<Self>, target: SocksTarget) -> Result<SocksSession> {
-        let socket = AsyncUdpSocket::connect(&self.udp_addr)?;
-        Ok(SocksSession::new(self.clone(), socket, target))
+    /// Open a session with a connected socket, or an unconnected one if
+    /// `connected` is false.
+    pub(crate) async fn bind(
+        self: &Arc<Self>,
+        target: SocksTarget,
+        connected: bool,
+    ) -> Result<SocksSession> {
+        self.bind_with(target, connected, self.egress_opts()).await
+    }
+
+    /// Open a session for probing, its socket marked with `fwmark` and
+    /// `dscp` to be told apart from data sessions in the kernel.
+    pub(crate) async fn bind_probe(
+        self: &Arc<Self>,
+        target: SocksTarget,
+        connected: bool,
+        fwmark: Option<u32>,
+        dscp: Option<u8>,
+    ) -> Result<SocksSession> {
+        let opts = EgressOpts {
+            fwmark,
+            dscp,
+            ..self.egress_opts()
+        };
+        self.bind_with(target, connected, opts).await
+    }
+
+    async fn bind_with(
+        self: &Arc<Self>,
+        target: SocksTarget,
+        connected: bool,
+        opts: EgressOpts,
+    ) -> Result<SocksSession> {
+        let (socket, peer) = if connected {
+            (AsyncUdpSocket::connect(&self.udp_addr, &opts).await?, None)
+        } else {
+            (
+                AsyncUdpSocket::unconnected(&self.udp_addr, &opts).await?,
+                Some(self.udp_addr),
+            )
+        };
+        let socket = SessionSocket::Owned(socket);
+        Ok(SocksSession::new(self.clone(), socket, peer, target, None))
+    }
+
+    /// Open a session on one of `pool_size` sockets shared with other
+    /// sessions, or on a socket of its own if the target is a name or
+    /// already served by every pooled socket. Unconnected pooled sockets
+    /// take replies from the server's IP on any port.
+    pub(crate) async fn bind_pooled(
+        self: &Arc<Self>,
+        target: SocksTarget,
+        pool_size: usize,
+        connected: bool,
+    ) -> Result<SocksSession> {
+        let addr = match target.addr() {
+            Some(addr) => addr,
+            // Replies come from the resolved address, can't be told apart
+            None => return self.bind(target, connected).await,
+        };
+        let pool = match self.socket_pool.get() {
+            Some(pool) => pool,
+            None => {
+                // The loser of a race just gets dropped
+                let _ = self
+                    .socket_pool
+                    .set(SocketPool::new(self, pool_size, connected).await?);
+                self.socket_pool.get().unwrap()
+            }
+        };
+        match pool.register(addr) {
+            Some((route, replies)) => {
+                let socket = SessionSocket::Pooled(route);
+                let peer = (!connected).then_some(self.udp_addr);
+                Ok(SocksSession::new(
+                    self.clone(),
+                    socket,
+                    peer,
+                    target,
+                    Some(replies),
+                ))
+            }
+            None => self.bind(target, connected).await,
+        }
+    }
+}
+
+enum SessionSocket {
+    Owned(AsyncUdpSocket),
+    /// Shared with other sessions, replies come via `SocksSession::replies`
+    Pooled(PooledRoute),
+}
+
+impl AsRef<AsyncUdpSocket> for SessionSocket {
+    fn as_ref(&self) -> &AsyncUdpSocket {
+        match self {
+            SessionSocket::Owned(socket) => socket,
+            SessionSocket::Pooled(route) => &route.socket.socket,
+        }
     }
 }
 
 pub(crate) struct SocksSession {
     pub(crate) server: Arc<SocksServer>,
-    socket: AsyncUdpSocket,
+    socket: SessionSocket,
+    /// Replies dispatched from a pooled socket, taken by `incoming()`
+    replies: Mutex<Option<mpsc::Receiver<Bytes>>>,
+    /// Server's UDP address if `socket` isn't connected
+    peer: Option<SocketAddr>,
     target: SocksTarget,
     pub(super) traffic: AtomicTraffic,
     created_at: Instant,
     drop_notify: Arc<Notify>,
     header: Bytes,
+    /// Fragment datagrams larger than it, see `--socks-frag-size`
+    frag_size: Option<usize>,
+    /// Shared by reassemblers of all sessions, see `--max-reassembly-mem`
+    reassembly_mem: Arc<ReassemblyMem>,
+    /// Address the server resolved a name target to, seen on first reply
+    resolved_addr: OnceLock<SocketAddr>,
+    log_resolved_addr: bool,
+    reply_addr_check: ReplyAddrCheck,
+    /// Set once a reply from unexpected address is warned of
+    reply_addr_warned: AtomicBool,
+}
+
+/// Failure of `SocksSession::send_to_remote()`, after the first `sent`
+/// packets went out.
+#[derive(Debug)]
+pub(crate) struct SendError {
+    pub(crate) sent: usize,
+    pub(crate) err: io::Error,
+}
+
+impl Display for SendError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({} packets sent)", self.err, self.sent)
+    }
+}
+
+impl From<SendError> for io::Error {
+    fn from(err: SendError) -> Self {
+        err.err
+    }
 }
 
 impl Display for SocksSession {
@@ -112,7 +277,13 @@ impl Display for SocksSession {
 }
 
 impl SocksSession {
-    fn new(server: Arc<SocksServer>, socket: AsyncUdpSocket, target: SocksTarget) -> Self {
+    fn new(
+        server: Arc<SocksServer>,
+        socket: SessionSocket,
+        peer: Option<SocketAddr>,
+        target: SocksTarget,
+        replies: Option<mpsc::Receiver<Bytes>>,
+    ) -> Self {
         server.status.usage.open_session();
         let mut header = BytesMut::with_capacity(22);
         header.put_slice(&[0x00, 0x00, 0x00]);
@@ -120,32 +291,170 @@ impl SocksSession {
         SocksSession {
             server,
             socket,
+            replies: Mutex::new(replies),
+            peer,
             target,
             header: header.freeze(),
             created_at: Instant::now(),
             drop_notify: Default::default(),
             traffic: Default::default(),
+            frag_size: None,
+            reassembly_mem: Default::default(),
+            resolved_addr: OnceLock::new(),
+            log_resolved_addr: false,
+            reply_addr_check: ReplyAddrCheck::Off,
+            reply_addr_warned: Default::default(),
         }
     }
 
+    /// See `--validate-socks-reply-addr`.
+    pub(crate) fn with_reply_addr_check(mut self, check: ReplyAddrCheck) -> Self {
+        self.reply_addr_check = check;
+        self
+    }
+
+    /// Whether to accept a reply from `addr`, see `with_reply_addr_check()`.
+    fn check_reply_addr(&self, addr: SocketAddr) -> bool {
+        if self.reply_addr_check == ReplyAddrCheck::Off || self.target.matches(addr) {
+            return true;
+        }
+        self.server.record_reply_addr_mismatch();
+        match self.reply_addr_check {
+            ReplyAddrCheck::Drop => {
+                debug!("{} drop reply from unexpected {}", self, addr);
+                false
+            }
+            // Warned once per session, it's likely every reply
+            _ if !self.reply_addr_warned.swap(true, Ordering::Relaxed) => {
+                warn!("{} got reply from unexpected {}", self, addr);
+                true
+            }
+            _ => {
+                debug!("{} got reply from unexpected {}", self, addr);
+                true
+            }
+        }
+    }
+
+    pub(crate) fn with_reassembly_mem(mut self, mem: Arc<ReassemblyMem>) -> Self {
+        self.reassembly_mem = mem;
+        self
+    }
+
+    /// Log the address a name target resolved to at info level.
+    pub(crate) fn with_log_resolved_addr(mut self, enable: bool) -> Self {
+        self.log_resolved_addr = enable;
+        self
+    }
+
+    fn record_resolved_addr(&self, addr: SocketAddr) {
+        if self.resolved_addr.get().is_some() || self.resolved_addr.set(addr).is_err() {
+            return;
+        }
+        if self.log_resolved_addr {
+            info!("{} resolved to {}", self, addr);
+        } else {
+            debug!("{} resolved to {}", self, addr);
+        }
+    }
+
+    pub(crate) fn resolved_addr(&self) -> Option<SocketAddr> {
+        self.resolved_addr.get().copied()
+    }
+
+    pub(crate) fn with_frag_size(mut self, frag_size: Option<usize>) -> Self {
+        self.frag_size = frag_size;
+        self
+    }
+
+    /// Whether `pkts` fit in the server's `tx_limit`, they should be
+    /// dropped if not. Not for probes, which are never limited.
+    pub(crate) fn within_tx_limit(&self, pkts: &[Bytes]) -> bool {
+        if self.server.tx_bucket.is_none() {
+            return true;
+        }
+        let len = pkts.iter().map(|pkt| self.header.len() + pkt.len()).sum();
+        let allowed = self.server.take_tx(len);
+        if !allowed {
+            debug!(
+                "Exceed TX limit of [{}], drop {} bytes",
+                self.server.name, len
+            );
+        }
+        allowed
+    }
+
     #[instrument(skip_all, fields(pkts=pkts.len()))]
     pub(crate) async fn send_to_remote(
         &self,
         pkts: &[Bytes],
         buf: &mut MsgArrayWriteBuffer<2>,
-    ) -> Result<()> {
-        pkts.iter()
-            .for_each(|pkt| buf.push([self.header.clone(), pkt.clone()], None));
+    ) -> std::result::Result<(), SendError> {
+        // Number of messages up to the end of each packet
+        let mut pkt_ends = Vec::with_capacity(pkts.len());
+        let mut msgs = 0;
+        for pkt in pkts {
+            self.server.status.usage.tx_sizes.record(pkt.len());
+            match self.frag_size {
+                Some(size) if pkt.len() > size => {
+                    let frags = fragment(&self.header, pkt, size);
+                    msgs += frags.len();
+                    frags.into_iter().for_each(|bufs| buf.push(bufs, self.peer));
+                }
+                _ => {
+                    msgs += 1;
+                    buf.push([self.header.clone(), pkt.clone()], self.peer);
+                }
+            }
+            pkt_ends.push(msgs);
+        }
+        let mut msgs_sent = 0;
         while buf.has_remaining() {
-            let (n, len) = self.socket.batch_send(buf).await?;
+            let (n, len) = match self.socket.as_ref().batch_send(buf).await {
+                Ok(sent) => sent,
+                Err(err) => {
+                    self.check_unreachable(&err);
+                    // Or they would go with the next send, maybe elsewhere
+                    buf.clear();
+                    let sent = pkt_ends.iter().take_while(|end| **end <= msgs_sent).count();
+                    return Err(SendError { sent, err });
+                }
+            };
+            msgs_sent += n;
             buf.advance(n);
             trace!("Sent {}/{} packets, {} bytes", n, pkts.len(), len);
-            self.traffic.add_tx(len);
-            self.server.status.usage.traffic.add_tx(len);
+            self.traffic.add_tx(n, len);
+            self.server.status.usage.traffic.add_tx(n, len);
         }
         Ok(())
     }
 
+    /// Mark the server in trouble if `err` says its UDP relay is down.
+    fn check_unreachable(&self, err: &io::Error) {
+        if is_unreachable(err) {
+            debug!("[{}] unreachable: {}", self.server.name, err);
+            self.server.set_troubleness(true);
+        }
+    }
+
+    pub(crate) fn traffic(&self) -> Traffic {
+        self.traffic.get()
+    }
+
+    pub(crate) fn target(&self) -> &SocksTarget {
+        &self.target
+    }
+
+    /// Unconnected socket may receive from anyone, only accept those from
+    /// server's IP. Port is not checked as it may be changed by NAT.
+    fn is_from_server(&self, src_addr: Option<SocketAddr>) -> bool {
+        match (self.peer, src_addr) {
+            (None, _) => true,
+            (Some(peer), Some(src)) => peer.ip() == src.ip(),
+            (Some(_), None) => false,
+        }
+    }
+
     pub(crate) fn incoming(self: &Arc<Self>) -> SessionIncoming {
         SessionIncoming::new(self)
     }
@@ -168,6 +477,9 @@ pub(crate) struct SessionIncoming {
     session: Weak<SocksSession>,
     drop_notify: Pin<Box<dyn Future<Output = ()> + Sync + Send>>,
     buf: Pin<Box<MsgArrayReadBuffer<UDP_BATCH_SIZE, UDP_MAX_SIZE>>>,
+    /// Read from instead of the socket if it's pooled
+    replies: Option<mpsc::Receiver<Bytes>>,
+    reassembler: Reassembler,
 }
 
 impl SessionIncoming {
@@ -176,8 +488,15 @@ impl SessionIncoming {
             session: Arc::downgrade(session),
             drop_notify: Box::pin(wait_notify(session.drop_notify.clone())),
             buf: MsgArrayReadBuffer::new(),
+            replies: session.replies.lock().take(),
+            reassembler: Reassembler::new(session.reassembly_mem.clone()),
         }
     }
+
+    /// The session, if it's still alive.
+    pub(crate) fn session(&self) -> Option<Arc<SocksSession>> {
+        self.session.upgrade()
+    }
 }
 
 async fn wait_notify(notify: Arc<Notify>) {
@@ -199,61 +518,411 @@ impl Stream for SessionIncoming {
             None => return Poll::Ready(None),
         };
 
+        let this = &mut *self;
+        if let Some(replies) = &mut this.replies {
+            let mut msgs = Vec::new();
+            while msgs.len() < UDP_BATCH_SIZE {
+                match replies.poll_recv(cx) {
+                    Poll::Ready(Some(msg)) => msgs.push(msg),
+                    Poll::Ready(None) if msgs.is_empty() => return Poll::Ready(None),
+                    Poll::Pending if msgs.is_empty() => return Poll::Pending,
+                    _ => break,
+                }
+            }
+            // Source already checked by the pool
+            let msgs = msgs.iter().map(|msg| (session.peer, &msg[..]));
+            let pkts = session.decode_replies(msgs, &mut this.reassembler);
+            return Poll::Ready(Some(Ok(pkts)));
+        }
+
         // Fill buffer
-        self.buf.clear();
-        match session.socket.poll_batch_recv(cx, &mut self.buf) {
+        this.buf.clear();
+        match session.socket.as_ref().poll_batch_recv(cx, &mut this.buf) {
             Poll::Pending => return Poll::Pending,
-            Poll::Ready(Err(err)) => return Poll::Ready(Some(Err(err))),
+            Poll::Ready(Err(err)) => {
+                session.check_unreachable(&err);
+                return Poll::Ready(Some(Err(err)));
+            }
             Poll::Ready(Ok(())) => {
-                if self.buf.len() == UDP_BATCH_SIZE {
+                if this.buf.len() == UDP_BATCH_SIZE {
                     debug!("Upstream batch recv full ({} msgs)", UDP_BATCH_SIZE);
                 }
             }
         }
 
-        // Decode packets
-        let pkts: Box<[_]> = self
-            .buf
-            .iter()
-            .filter_map(|msg| match decode_packet(msg.buf) {
-                Ok(buf) => {
-                    session.traffic.add_rx(buf.len());
-                    session.server.status.usage.traffic.add_rx(buf.len());
-                    Some(Bytes::copy_from_slice(buf))
+        let msgs = this.buf.iter().map(|msg| (msg.src_addr, msg.buf));
+        Poll::Ready(Some(
+            Ok(session.decode_replies(msgs, &mut this.reassembler)),
+        ))
+    }
+}
+
+impl SocksSession {
+    /// Decode SOCKS-framed replies from `(source, datagram)`, drop those
+    /// unexpected or malformed. Fragments are held by `reassembler` until
+    /// their datagram is complete.
+    fn decode_replies<'a, I>(&self, msgs: I, reassembler: &mut Reassembler) -> Box<[Bytes]>
+    where
+        I: Iterator<Item = (Option<SocketAddr>, &'a [u8])>,
+    {
+        let session = self;
+        let now = Instant::now();
+        msgs.filter(|(src_addr, _)| {
+            let accepted = session.is_from_server(*src_addr);
+            if !accepted {
+                debug!("Drop packet from unexpected {:?}", src_addr);
+            }
+            accepted
+        })
+        .filter_map(|(_, msg)| {
+            match decode_packet(msg) {
+                Ok((Some(addr), _)) if !session.check_reply_addr(addr) => None,
+                Ok((addr, buf)) => {
+                    if let (SocksTarget::Name(_), Some(addr)) = (&session.target, addr) {
+                        session.record_resolved_addr(addr);
+                    }
+                    // Header is known to be complete once decoded
+                    let buf = reassembler.push(addr, msg[2], buf, now)?;
+                    session.traffic.add_rx(1, buf.len());
+                    session.server.status.usage.traffic.add_rx(1, buf.len());
+                    session.server.status.usage.rx_sizes.record(buf.len());
+                    Some(buf)
                 }
                 Err(err) => {
                     debug!("Failed to parse SOCKSv5 UDP: {}", { err });
+                    session.server.record_malformed_reply();
                     None
                 }
-            })
-            .collect();
-        Poll::Ready(Some(Ok(pkts)))
+            }
+        })
+        .collect()
     }
 }
 
-fn decode_packet(mut pkt: &[u8]) -> io::Result<&[u8]> {
+/// Split `pkt` into (header, data) fragments of at most `size` bytes of
+/// data, or larger ones if it would take more than 127 fragments.
+fn fragment(header: &Bytes, pkt: &Bytes, size: usize) -> Vec<[Bytes; 2]> {
+    let size = cmp::max(size, pkt.len().div_ceil(FRAG_MAX_COUNT));
+    let count = pkt.len().div_ceil(size);
+    (0..count)
+        .map(|i| {
+            let mut frag_header = BytesMut::from(&header[..]);
+            frag_header[2] = (i + 1) as u8;
+            if i + 1 == count {
+                frag_header[2] |= FRAG_END;
+            }
+            let data = pkt.slice(i * size..cmp::min((i + 1) * size, pkt.len()));
+            [frag_header.freeze(), data]
+        })
+        .collect()
+}
+
+/// Whether `err` is from an ICMP unreachable reported on connected socket,
+/// i.e. the UDP relay is down, rather than a transient error. Kernel reports
+/// it on the next send or recv after the ICMP message.
+pub(super) fn is_unreachable(err: &io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::ECONNREFUSED | libc::EHOSTUNREACH | libc::ENETUNREACH)
+    )
+}
+
+/// Skip the reserved & fragment fields, return the address type.
+/// Fragments are left to `Reassembler`.
+fn read_header_start(pkt: &mut &[u8]) -> io::Result<u8> {
     if pkt.len() < 10 {
         io_error!(UnexpectedEof, "UDP request too short");
     }
     pkt.read_u16::<BE>().unwrap(); // reversed
-    if pkt.read_u8().unwrap() != 0 {
-        // fragment number
-        io_error!(InvalidData, "Fragmented UDP, dropped");
-    }
-    // Skip remote address
-    match pkt.read_u8()? {
-        ATYP_IPV4 => pkt.read_exact(&mut [0; 4])?,
-        ATYP_IPV6 => pkt.read_exact(&mut [0; 16])?,
+    pkt.read_u8().unwrap(); // fragment number
+    Ok(pkt.read_u8().unwrap())
+}
+
+/// Return the remote address (`None` if a name) and the payload.
+pub(super) fn decode_packet(mut pkt: &[u8]) -> io::Result<(Option<SocketAddr>, &[u8])> {
+    let ip: Option<IpAddr> = match read_header_start(&mut pkt)? {
+        ATYP_IPV4 => {
+            let mut ip = [0; 4];
+            pkt.read_exact(&mut ip)?;
+            Some(ip.into())
+        }
+        ATYP_IPV6 => {
+            let mut ip = [0; 16];
+            pkt.read_exact(&mut ip)?;
+            Some(ip.into())
+        }
         ATYP_NAME => {
             let n = pkt.read_u8()?.into();
             if pkt.remaining() < n {
                 io_error!(UnexpectedEof, "Truncated UDP request");
             }
             pkt.advance(n);
+            None
         }
         _ => io_error!(InvalidData, "Invalid address type, dropped"),
+    };
+    let port = pkt.read_u16::<BE>()?;
+    Ok((ip.map(|ip| (ip, port).into()), pkt))
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_unconnected_session() {
+    use crate::app::InnerProto;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = Arc::new(SocksServer::new(
+        relay.local_addr().unwrap(),
+        "relay".into(),
+        InnerProto::Inet,
+    ));
+    let target: SocketAddr = ([127, 0, 0, 1], 443).into();
+    let session = Arc::new(server.bind(target.into(), false).await.unwrap());
+    let mut incoming = Box::pin(session.incoming());
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    session
+        .send_to_remote(&[Bytes::from_static(b"hello")], &mut buf)
+        .await
+        .unwrap();
+    let mut pkt = [0u8; 64];
+    let (len, from) = relay.recv_from(&mut pkt).await.unwrap();
+    assert!(pkt[..len].ends_with(b"hello"));
+
+    // Reply from another port of the same IP
+    let nat = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    nat.send_to(&[&pkt[..len - 5], b"world"].concat(), from)
+        .await
+        .unwrap();
+    let recv = tokio::time::timeout(Duration::from_secs(5), incoming.next());
+    let pkts = recv.await.unwrap().unwrap().unwrap();
+    assert_eq!(&pkts[..], [Bytes::from_static(b"world")]);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_refused_session() {
+    use crate::app::InnerProto;
+    use std::time::Duration;
+
+    // Nothing listens on the port after dropping
+    let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = closed.local_addr().unwrap();
+    drop(closed);
+    let server = Arc::new(SocksServer::new(addr, "closed".into(), InnerProto::Inet));
+    let target: SocketAddr = ([127, 0, 0, 1], 443).into();
+    let session = Arc::new(server.bind(target.into(), true).await.unwrap());
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    let pkts = [Bytes::from_static(b"hello")];
+    session.send_to_remote(&pkts, &mut buf).await.unwrap();
+    assert!(server.is_healthy());
+    // Wait for the ICMP port unreachable
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    buf.clear();
+    let err = session.send_to_remote(&pkts, &mut buf).await.unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionRefused, err.err.kind());
+    assert_eq!(err.sent, 0);
+    assert!(!server.is_healthy());
+}
+
+#[tokio::test]
+async fn test_tx_limit() {
+    use crate::app::{limit::RateLimits, InnerProto};
+
+    let limits = RateLimits {
+        tx: Some(100),
+        rx: None,
+    };
+    let server = SocksServer::new(([127, 0, 0, 1], 1080).into(), "a".into(), InnerProto::Inet)
+        .with_rate_limits(limits);
+    let server = Arc::new(server);
+    let target: SocketAddr = ([127, 0, 0, 1], 443).into();
+    let session = server.bind(target.into(), false).await.unwrap();
+    let pkts = [Bytes::from_static(&[0; 60])];
+    assert!(session.within_tx_limit(&pkts));
+    assert_eq!(server.tx_limited(), 0);
+    // Dropped rather than delayed
+    assert!(!session.within_tx_limit(&pkts));
+    assert_eq!(server.tx_limited(), 1);
+}
+
+#[test]
+fn test_fragment() {
+    let header = Bytes::from_static(&[0, 0, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80]);
+    let pkt = Bytes::from_static(b"0123456789");
+    let frags = fragment(&header, &pkt, 4);
+    let frag_nums: Vec<_> = frags.iter().map(|[header, _]| header[2]).collect();
+    assert_eq!(vec![1, 2, 3 | FRAG_END], frag_nums);
+    let data: Vec<_> = frags.iter().map(|[_, data]| &data[..]).collect();
+    assert_eq!(vec![&b"0123"[..], b"4567", b"89"], data);
+    assert!(frags.iter().all(|[h, _]| h[3..] == header[3..]));
+
+    // No more than 127 fragments
+    let pkt = Bytes::from(vec![0; 1000]);
+    let frags = fragment(&header, &pkt, 1);
+    assert_eq!(125, frags.len());
+    assert_eq!(
+        127 | FRAG_END,
+        fragment(&header, &Bytes::from(vec![0; 127]), 1)[126][0][2]
+    );
+}
+
+#[test]
+fn test_decode_resolved_addr() {
+    let reply = [0, 0, 0, ATYP_IPV4, 192, 0, 2, 1, 1, 187, b'h', b'i'];
+    let (addr, payload) = decode_packet(&reply).unwrap();
+    assert_eq!(Some(([192, 0, 2, 1], 443).into()), addr);
+    assert_eq!(b"hi", payload);
+    let reply = [0, 0, 0, ATYP_NAME, 1, b'a', 1, 187, b'h', b'i'];
+    assert_eq!((None, &b"hi"[..]), decode_packet(&reply).unwrap());
+}
+
+#[test]
+fn test_target_matches() {
+    let target = SocksTarget::from(SocketAddr::from(([192, 0, 2, 1], 443)));
+    assert!(target.matches(([192, 0, 2, 1], 443).into()));
+    assert!(target.matches("[::ffff:192.0.2.1]:443".parse().unwrap()));
+    assert!(!target.matches(([192, 0, 2, 2], 443).into()));
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_pooled_sessions() {
+    use crate::app::InnerProto;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = Arc::new(SocksServer::new(
+        relay.local_addr().unwrap(),
+        "relay".into(),
+        InnerProto::Inet,
+    ));
+    let targets: [SocketAddr; 2] = [([192, 0, 2, 1], 443).into(), ([192, 0, 2, 2], 443).into()];
+    // Two targets share the only pooled socket, the same target again
+    // gets a socket of its own
+    let mut sessions = Vec::new();
+    for target in [targets[0], targets[1], targets[0]] {
+        let session = server.bind_pooled(target.into(), 1, true).await.unwrap();
+        sessions.push(Arc::new(session));
+    }
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    let mut pkt = [0u8; 64];
+    let mut sources = Vec::new();
+    for session in &sessions {
+        session
+            .send_to_remote(&[Bytes::from_static(b"hello")], &mut buf)
+            .await
+            .unwrap();
+        buf.clear();
+        let (_, from) = relay.recv_from(&mut pkt).await.unwrap();
+        sources.push(from);
+    }
+    assert_eq!(sources[0], sources[1]);
+    assert_ne!(sources[0], sources[2]);
+
+    // Replies on the pooled socket go to the session of their address
+    for (i, target) in targets.iter().enumerate().rev() {
+        let mut reply = BytesMut::new();
+        reply.put_slice(&[0x00, 0x00, 0x00]);
+        SocksTarget::from(*target).write_to(&mut reply);
+        reply.put_u8(i as u8);
+        relay.send_to(&reply, sources[0]).await.unwrap();
+    }
+    for (i, session) in sessions[..2].iter().enumerate() {
+        let mut incoming = Box::pin(session.incoming());
+        let recv = tokio::time::timeout(Duration::from_secs(5), incoming.next());
+        let pkts = recv.await.unwrap().unwrap().unwrap();
+        assert_eq!(&pkts[..], [Bytes::from(vec![i as u8])]);
     }
-    // Skip port number
-    pkt.read_u16::<BE>()?;
-    Ok(pkt)
+    // Place taken back on drop, so the pooled socket is handed out again
+    let pooled_addr = sessions[0].socket.as_ref().local_addr().unwrap();
+    assert_ne!(
+        sessions[2].socket.as_ref().local_addr().unwrap(),
+        pooled_addr
+    );
+    drop(sessions);
+    let session = server
+        .bind_pooled(targets[0].into(), 1, true)
+        .await
+        .unwrap();
+    assert!(matches!(session.socket, SessionSocket::Pooled(_)));
+    assert_eq!(session.socket.as_ref().local_addr().unwrap(), pooled_addr);
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_bind_from() {
+    use crate::app::InnerProto;
+    use tokio::net::UdpSocket;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = SocksServer::new(
+        relay.local_addr().unwrap(),
+        "relay".into(),
+        InnerProto::Inet,
+    )
+    .with_bind_from(Some([127, 0, 0, 2].into()));
+    let server = Arc::new(server);
+    let target: SocketAddr = ([192, 0, 2, 1], 443).into();
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    let mut pkt = [0u8; 64];
+    for connected in [true, false] {
+        let session = server.bind(target.into(), connected).await.unwrap();
+        session
+            .send_to_remote(&[Bytes::from_static(b"hello")], &mut buf)
+            .await
+            .unwrap();
+        buf.clear();
+        let (_, from) = relay.recv_from(&mut pkt).await.unwrap();
+        assert_eq!(from.ip(), std::net::Ipv4Addr::new(127, 0, 0, 2));
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_unconnected_pooled_sessions() {
+    use crate::app::InnerProto;
+    use futures::StreamExt;
+    use std::time::Duration;
+    use tokio::net::UdpSocket;
+
+    let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server = Arc::new(SocksServer::new(
+        relay.local_addr().unwrap(),
+        "relay".into(),
+        InnerProto::Inet,
+    ));
+    let target: SocketAddr = ([192, 0, 2, 1], 443).into();
+    let session = Arc::new(server.bind_pooled(target.into(), 1, false).await.unwrap());
+    assert!(matches!(session.socket, SessionSocket::Pooled(_)));
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    session
+        .send_to_remote(&[Bytes::from_static(b"hello")], &mut buf)
+        .await
+        .unwrap();
+    let mut pkt = [0u8; 64];
+    let (_, source) = relay.recv_from(&mut pkt).await.unwrap();
+
+    // Other IPs are dropped, other ports of the relay's IP are taken
+    let mut reply = BytesMut::new();
+    reply.put_slice(&[0x00, 0x00, 0x00]);
+    SocksTarget::from(target).write_to(&mut reply);
+    let stranger = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+    stranger
+        .send_to(&[&reply[..], b"x"].concat(), source)
+        .await
+        .unwrap();
+    let natted = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    natted
+        .send_to(&[&reply[..], b"y"].concat(), source)
+        .await
+        .unwrap();
+    let mut incoming = Box::pin(session.incoming());
+    let recv = tokio::time::timeout(Duration::from_secs(5), incoming.next());
+    let pkts = recv.await.unwrap().unwrap().unwrap();
+    assert_eq!(&pkts[..], [Bytes::from_static(b"y")]);
 }
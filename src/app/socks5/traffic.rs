@@ -1,14 +1,22 @@
 use std::{
     fmt::Display,
-    ops::Sub,
+    ops::{AddAssign, Sub},
     sync::atomic::{AtomicU64, AtomicUsize, Ordering},
 };
 
 use bytesize::ByteSize;
+use serde::Serialize;
+
+use crate::app::stats::SizeHistogram;
 
 #[derive(Default, Debug)]
 pub(crate) struct Usage {
     pub(crate) traffic: AtomicTraffic,
+    /// Sizes of datagrams sent via this server (pings included), before
+    /// encapsulation
+    pub(crate) tx_sizes: SizeHistogram,
+    /// Sizes of datagrams received from this server, after decapsulation
+    pub(crate) rx_sizes: SizeHistogram,
     session_active: AtomicUsize,
     session_total: AtomicUsize,
 }
@@ -17,21 +25,27 @@ pub(crate) struct Usage {
 pub(crate) struct AtomicTraffic {
     tx_bytes: AtomicU64,
     rx_bytes: AtomicU64,
+    tx_pkts: AtomicU64,
+    rx_pkts: AtomicU64,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, Serialize)]
 pub(crate) struct Traffic {
     pub(crate) tx_bytes: u64,
     pub(crate) rx_bytes: u64,
+    pub(crate) tx_pkts: u64,
+    pub(crate) rx_pkts: u64,
 }
 
 impl Display for Traffic {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "TX {}, RX {}",
+            "TX {} ({} pkts), RX {} ({} pkts)",
             ByteSize(self.tx_bytes),
-            ByteSize(self.rx_bytes)
+            self.tx_pkts,
+            ByteSize(self.rx_bytes),
+            self.rx_pkts,
         )
     }
 }
@@ -49,18 +63,37 @@ impl Sub for Traffic {
                 .rx_bytes
                 .checked_sub(rhs.rx_bytes)
                 .expect("negtive RX bytes"),
+            tx_pkts: self
+                .tx_pkts
+                .checked_sub(rhs.tx_pkts)
+                .expect("negtive TX packets"),
+            rx_pkts: self
+                .rx_pkts
+                .checked_sub(rhs.rx_pkts)
+                .expect("negtive RX packets"),
         }
     }
 }
 
+impl AddAssign for Traffic {
+    fn add_assign(&mut self, rhs: Self) {
+        self.tx_bytes += rhs.tx_bytes;
+        self.rx_bytes += rhs.rx_bytes;
+        self.tx_pkts += rhs.tx_pkts;
+        self.rx_pkts += rhs.rx_pkts;
+    }
+}
+
 impl AtomicTraffic {
     #[inline]
-    pub(super) fn add_tx(&self, bytes: usize) {
+    pub(super) fn add_tx(&self, pkts: usize, bytes: usize) {
+        self.tx_pkts.fetch_add(pkts as u64, Ordering::Relaxed);
         self.tx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
     }
 
     #[inline]
-    pub(super) fn add_rx(&self, bytes: usize) {
+    pub(super) fn add_rx(&self, pkts: usize, bytes: usize) {
+        self.rx_pkts.fetch_add(pkts as u64, Ordering::Relaxed);
         self.rx_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
     }
 
@@ -68,6 +101,8 @@ impl AtomicTraffic {
         Traffic {
             tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
             rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_pkts: self.tx_pkts.load(Ordering::Relaxed),
+            rx_pkts: self.rx_pkts.load(Ordering::Relaxed),
         }
     }
 }
@@ -81,4 +116,12 @@ impl Usage {
     pub(super) fn close_session(&self) {
         self.session_active.fetch_sub(1, Ordering::Relaxed);
     }
+
+    pub(crate) fn active_sessions(&self) -> usize {
+        self.session_active.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn total_sessions(&self) -> usize {
+        self.session_total.load(Ordering::Relaxed)
+    }
 }
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use super::{net::UDP_MAX_SIZE, quic::NameLookup};
+
+/// Upper bounds (inclusive) of `SizeHistogram` buckets. The last bucket
+/// takes the rest, i.e. datagrams filled up the buffer and may have been
+/// truncated.
+pub(crate) const SIZE_BUCKETS: [usize; 4] = [512, 1200, 1500, UDP_MAX_SIZE - 1];
+
+/// Global counters of the forwarding path.
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    /// Zero-length datagrams dropped from clients
+    pub(crate) empty_datagrams: AtomicUsize,
+    /// Packets dropped by `--max-*egress-*` limits
+    pub(crate) egress_limited: AtomicUsize,
+    /// Packets dropped for exceeding their client's share, see
+    /// `--egress-fair-share`
+    pub(crate) egress_shaped: AtomicUsize,
+    /// Live sockets for sending replies to clients, updated on sweeping
+    pub(crate) tproxy_senders: AtomicUsize,
+    /// Conns closed early on suspected stateless resets from remote
+    pub(crate) reset_teardowns: AtomicUsize,
+    /// Conns evicted for exceeding `--max-conn-lifetime`
+    pub(crate) lifetime_evictions: AtomicUsize,
+    /// New conns refused as `--max-reply-tasks` exhausted
+    pub(crate) reply_tasks_exhausted: AtomicUsize,
+    /// Datagrams dropped for missing/malformed PROXY protocol header
+    pub(crate) proxy_protocol_malformed: AtomicUsize,
+    pub(crate) proxy_protocol_untrusted: AtomicUsize,
+    /// First Initial packets re-sent, see `--initial-resend-after`
+    pub(crate) initial_resends: AtomicUsize,
+    /// Datagrams of new conns dropped as `--decode-workers` queue is full
+    pub(crate) decode_overflows: AtomicUsize,
+    /// Batches of conns being decoded dropped for too many pending
+    pub(crate) decode_pending_drops: AtomicUsize,
+    /// Datagrams dropped for being destined to quproxy itself
+    pub(crate) self_destined: AtomicUsize,
+    /// Version Negotiation packets replied by remotes
+    pub(crate) version_negotiations: AtomicUsize,
+    /// Version Negotiation packets sent to clients, see `--force-quic-v1`
+    pub(crate) forged_version_negotiations: AtomicUsize,
+    /// New conns dropped by `--allow-alpn` & `--deny-alpn`
+    pub(crate) alpn_dropped: AtomicUsize,
+    /// Conns followed to a new client port, see `--follow-client-port`
+    pub(crate) client_port_follows: AtomicUsize,
+    /// 10s windows of traffic suggesting a routing loop or a scan, see
+    /// `--loop-warn-conn-ratio`
+    pub(crate) loop_suspects: AtomicUsize,
+    /// Replies dropped for exceeding `--client-mtu`
+    pub(crate) oversize_replies: AtomicUsize,
+    /// Route lookups, one per forwarded batch, served by the session the
+    /// conn already had
+    pub(crate) sticky_hits: AtomicUsize,
+    /// Route lookups selecting upstreams anew, for new conns or ones that
+    /// lost theirs
+    pub(crate) sticky_misses: AtomicUsize,
+    /// Selections moving conns to another session, a subset of misses
+    pub(crate) migrations: AtomicUsize,
+    /// New conns by outcome of name lookup, indexed by `NameLookup`
+    pub(crate) name_lookups: [AtomicUsize; NameLookup::ALL.len()],
+    /// Sizes of datagrams from clients to be forwarded
+    pub(crate) tx_sizes: SizeHistogram,
+    /// Sizes of datagrams replied to clients
+    pub(crate) rx_sizes: SizeHistogram,
+}
+
+/// Lock-free histogram of datagram sizes, bucketed by `SIZE_BUCKETS`.
+#[derive(Debug, Default)]
+pub(crate) struct SizeHistogram {
+    buckets: [AtomicUsize; SIZE_BUCKETS.len() + 1],
+    sum: AtomicU64,
+}
+
+impl SizeHistogram {
+    #[inline]
+    pub(crate) fn record(&self, len: usize) {
+        let i = SIZE_BUCKETS.partition_point(|&bound| bound < len);
+        self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// Count of each bucket, not cumulative.
+    pub(crate) fn counts(&self) -> [usize; SIZE_BUCKETS.len() + 1] {
+        let mut counts = [0; SIZE_BUCKETS.len() + 1];
+        for (n, bucket) in counts.iter_mut().zip(&self.buckets) {
+            *n = bucket.load(Ordering::Relaxed);
+        }
+        counts
+    }
+
+    /// Total bytes of all recorded datagrams.
+    pub(crate) fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+}
+
+#[test]
+fn test_size_histogram() {
+    let histogram = SizeHistogram::default();
+    for len in [1, 512, 513, 1200, 1252, 1500, 2047, 2048] {
+        histogram.record(len);
+    }
+    assert_eq!(histogram.counts(), [2, 2, 2, 1, 1]);
+    assert_eq!(histogram.sum(), 9073);
+}
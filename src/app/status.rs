@@ -1,16 +1,35 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::atomic::AtomicUsize};
 
 use parking_lot::Mutex;
 
 use super::{
-    checking::{Health, Meter, PingHistory},
-    socks5::Usage,
+    checking::{Health, Meter, PingExemplar, PingHistory, PingStats},
+    socks5::{InnerProtoProbe, Usage},
 };
 
 #[derive(Debug, Default)]
 pub(crate) struct ServerStatus {
     pub(super) pings: Mutex<PingHistory>,
+    pub(super) ping_stats: PingStats,
+    pub(super) last_ping_exemplar: Mutex<Option<PingExemplar>>,
     pub(super) usage: Usage,
     pub(super) meter: Mutex<Meter>,
     pub(super) health: Health,
+    pub(super) inner_proto_probe: Mutex<Option<InnerProtoProbe>>,
+    /// New conns not taken as the target is of other family
+    pub(super) proto_mismatches: AtomicUsize,
+    /// Replies with an address other than the target of their session
+    pub(super) reply_addr_mismatches: AtomicUsize,
+    /// Batches dropped for exceeding `tx_limit`
+    pub(super) tx_limited: AtomicUsize,
+}
+
+impl ServerStatus {
+    /// Copy pings & health from the status of a replaced server.
+    pub(super) fn carry_over_from(&self, old: &ServerStatus) {
+        *self.pings.lock() = old.pings.lock().clone();
+        *self.last_ping_exemplar.lock() = *old.last_ping_exemplar.lock();
+        self.ping_stats.carry_over_from(&old.ping_stats);
+        self.health.carry_over_from(&old.health);
+    }
 }
@@ -0,0 +1,192 @@
+use std::{io, sync::Arc, time::Duration};
+
+use bytes::Bytes;
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError},
+        Notify,
+    },
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, trace};
+
+use crate::app::{
+    net::{MsgArrayWriteBuffer, UDP_BATCH_SIZE},
+    types::ClientAddr,
+};
+
+use super::TProxySender;
+
+/// Max number of reply batches waiting to be sent. When it's full, conns
+/// have it flushed at once and wait for room, so replies stay in order.
+const QUEUE_CAPACITY: usize = 1024;
+
+struct Replies {
+    sender: Arc<TProxySender>,
+    client: ClientAddr,
+    pkts: Box<[Bytes]>,
+}
+
+/// Handle to the task sending replies of all conns, see
+/// `--reply-batch-interval`.
+#[derive(Clone)]
+pub(crate) struct ReplyBatcher {
+    queue: mpsc::Sender<Replies>,
+    /// Ask the task to send now rather than on next tick
+    flush: Arc<Notify>,
+}
+
+impl ReplyBatcher {
+    /// Spawn the task sending queued replies every `period`.
+    pub(crate) fn launch(period: Duration) -> Self {
+        let (queue, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let flush: Arc<Notify> = Default::default();
+        tokio::spawn(send_queued(receiver, period, flush.clone()));
+        Self { queue, flush }
+    }
+
+    /// Queue `pkts` to be sent to `client` via `sender`. If the queue is
+    /// full, flush it and wait for room. Give them back if the task has
+    /// exited.
+    pub(crate) async fn queue(
+        &self,
+        sender: &Arc<TProxySender>,
+        client: ClientAddr,
+        pkts: Box<[Bytes]>,
+    ) -> Result<(), Box<[Bytes]>> {
+        let replies = Replies {
+            sender: sender.clone(),
+            client,
+            pkts,
+        };
+        let replies = match self.queue.try_send(replies) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Closed(replies)) => return Err(replies.pkts),
+            Err(TrySendError::Full(replies)) => replies,
+        };
+        trace!("Reply queue full, flush it");
+        self.flush.notify_one();
+        self.queue.send(replies).await.map_err(|err| err.0.pkts)
+    }
+}
+
+async fn send_queued(mut receiver: mpsc::Receiver<Replies>, period: Duration, flush: Arc<Notify>) {
+    debug!("Reply batcher started");
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut pending = Vec::new();
+    let mut buf = MsgArrayWriteBuffer::<1>::with_capacity(UDP_BATCH_SIZE);
+    // Block on the queue while idle instead of ticking, the first replies
+    // after idle go out at once as the tick is overdue.
+    while let Some(replies) = receiver.recv().await {
+        pending.push(replies);
+        tokio::select! {
+            _ = ticks.tick() => (),
+            _ = flush.notified() => (),
+        }
+        while let Ok(replies) = receiver.try_recv() {
+            pending.push(replies);
+        }
+        // Replies to the same remote share one socket, so one sendmmsg.
+        // Stable, to keep replies of a conn in order.
+        pending.sort_by_key(|replies| Arc::as_ptr(&replies.sender));
+        for group in pending.chunk_by(|a, b| Arc::ptr_eq(&a.sender, &b.sender)) {
+            match send_group(group, &mut buf).await {
+                Err(err) => info!("Forwarding to client error: {}", err),
+                Ok((n, len)) => trace!("{} conns: {} pkts {}B", group.len(), n, len),
+            }
+        }
+        pending.clear();
+    }
+    debug!("Reply batcher exited");
+}
+
+/// Send replies sharing the same sender.
+async fn send_group(
+    group: &[Replies],
+    buf: &mut MsgArrayWriteBuffer<1>,
+) -> io::Result<(usize, usize)> {
+    buf.clear();
+    for replies in group {
+        for pkt in replies.pkts.iter() {
+            buf.push([pkt.clone()], Some(replies.client.0));
+        }
+    }
+    let sender = group[0].sender.as_ref().as_ref();
+    let mut total_n = 0;
+    let mut total_len = 0;
+    while buf.has_remaining() {
+        let (n, len) = sender.batch_send(buf).await?;
+        buf.advance(n);
+        total_n += n;
+        total_len += len;
+    }
+    Ok((total_n, total_len))
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_batch_replies() {
+    use crate::app::{tproxy::TProxySenderCache, types::RemoteAddr};
+    use tokio::net::UdpSocket;
+
+    let remote = RemoteAddr(([127, 0, 0, 1], 0).into());
+    let sender = TProxySenderCache::new_local(1)
+        .get_or_create(remote)
+        .unwrap();
+    let clients = [
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+        UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+    ];
+    let batcher = ReplyBatcher::launch(Duration::from_millis(10));
+    for (i, client) in clients.iter().enumerate() {
+        let client = ClientAddr(client.local_addr().unwrap());
+        let pkts = vec![Bytes::from(vec![i as u8; 2]); 2].into_boxed_slice();
+        assert!(batcher.queue(&sender, client, pkts).await.is_ok());
+    }
+    let mut buf = [0u8; 8];
+    for (i, client) in clients.iter().enumerate() {
+        for _ in 0..2 {
+            let n = tokio::time::timeout(Duration::from_secs(1), client.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(buf[..n], [i as u8; 2]);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_batch_replies_in_order() {
+    use crate::app::{tproxy::TProxySenderCache, types::RemoteAddr};
+    use std::{collections::HashMap, net::SocketAddr};
+    use tokio::net::UdpSocket;
+
+    let mut cache = TProxySenderCache::new_local(2);
+    let senders = [1, 2].map(|port| {
+        let remote = RemoteAddr(([127, 0, 0, 1], port).into());
+        cache.get_or_create(remote).unwrap()
+    });
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client_addr = ClientAddr(client.local_addr().unwrap());
+    let batcher = ReplyBatcher::launch(Duration::from_millis(10));
+    // Interleaved, sorted by sender in one batch
+    for i in 0..200u8 {
+        let pkts = vec![Bytes::from(vec![i])].into_boxed_slice();
+        let sender = &senders[i as usize % 2];
+        assert!(batcher.queue(sender, client_addr, pkts).await.is_ok());
+    }
+    let mut last: HashMap<SocketAddr, u8> = HashMap::new();
+    let mut buf = [0u8; 8];
+    for _ in 0..200 {
+        let recv = client.recv_from(&mut buf);
+        let (_, from) = tokio::time::timeout(Duration::from_secs(1), recv)
+            .await
+            .unwrap()
+            .unwrap();
+        if let Some(prev) = last.insert(from, buf[0]) {
+            assert!(buf[0] > prev, "{} after {}", buf[0], prev);
+        }
+    }
+}
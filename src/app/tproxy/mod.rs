@@ -1,5 +1,8 @@
+mod batch;
+mod proxy_protocol;
 mod receiver;
 mod sender;
 
+pub(crate) use batch::ReplyBatcher;
 pub(crate) use receiver::TProxyReceiver;
 pub(crate) use sender::{TProxySender, TProxySenderCache};
@@ -0,0 +1,74 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use byteorder::{ByteOrder, BE};
+
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const HEADER_LEN: usize = 16;
+const CMD_LOCAL: u8 = 0x20;
+const CMD_PROXY: u8 = 0x21;
+
+/// (source, destination)
+type Addrs = (SocketAddr, SocketAddr);
+
+/// Strip PROXY protocol v2 header from `pkt`, return (source, destination)
+/// addresses in it along with the rest payload. Addresses are `None` for
+/// LOCAL command or unspecified family, meaning the socket's ones apply.
+/// Return `None` if the header is missing or malformed.
+pub(super) fn strip_v2_header(pkt: &[u8]) -> Option<(Option<Addrs>, &[u8])> {
+    if pkt.len() < HEADER_LEN || !pkt.starts_with(SIGNATURE) {
+        return None;
+    }
+    let (cmd, family) = (pkt[12], pkt[13]);
+    let len = BE::read_u16(&pkt[14..16]) as usize;
+    if pkt.len() < HEADER_LEN + len {
+        return None;
+    }
+    let (header, payload) = pkt[HEADER_LEN..].split_at(len);
+    if cmd == CMD_LOCAL {
+        return Some((None, payload));
+    } else if cmd != CMD_PROXY {
+        return None;
+    }
+    let addrs = match family >> 4 {
+        // AF_UNSPEC
+        0x0 => None,
+        // AF_INET
+        0x1 if header.len() >= 12 => {
+            let src = Ipv4Addr::from(BE::read_u32(&header[0..4]));
+            let dst = Ipv4Addr::from(BE::read_u32(&header[4..8]));
+            let (src_port, dst_port) = (BE::read_u16(&header[8..]), BE::read_u16(&header[10..]));
+            Some(((src, src_port).into(), (dst, dst_port).into()))
+        }
+        // AF_INET6
+        0x2 if header.len() >= 36 => {
+            let src = Ipv6Addr::from(BE::read_u128(&header[0..16]));
+            let dst = Ipv6Addr::from(BE::read_u128(&header[16..32]));
+            let (src_port, dst_port) = (BE::read_u16(&header[32..]), BE::read_u16(&header[34..]));
+            Some(((src, src_port).into(), (dst, dst_port).into()))
+        }
+        _ => return None,
+    };
+    Some((addrs, payload))
+}
+
+#[test]
+fn test_strip_v2_header() {
+    let mut pkt = SIGNATURE.to_vec();
+    // PROXY, UDP over IPv4, 12 bytes of addresses
+    pkt.extend_from_slice(&[CMD_PROXY, 0x12, 0x00, 0x0c]);
+    pkt.extend_from_slice(&[10, 0, 0, 1, 192, 0, 2, 1, 0xc3, 0x50, 0x01, 0xbb]);
+    pkt.extend_from_slice(b"quic");
+    let (addrs, payload) = strip_v2_header(&pkt).unwrap();
+    let (src, dst) = addrs.unwrap();
+    assert_eq!(src, ([10, 0, 0, 1], 50000).into());
+    assert_eq!(dst, ([192, 0, 2, 1], 443).into());
+    assert_eq!(payload, b"quic");
+
+    // Truncated
+    assert!(strip_v2_header(&pkt[..20]).is_none());
+    // No header
+    assert!(strip_v2_header(b"quic packet without header").is_none());
+    // LOCAL
+    pkt[12] = CMD_LOCAL;
+    assert_eq!(strip_v2_header(&pkt).unwrap(), (None, &b"quic"[..]));
+}
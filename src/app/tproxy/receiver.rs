@@ -1,4 +1,4 @@
-use std::{collections::HashMap, io, pin::Pin};
+use std::{collections::HashMap, io, pin::Pin, sync::atomic::Ordering};
 
 use bytes::Bytes;
 use futures::Stream;
@@ -12,8 +12,10 @@ use crate::app::{
     AppContext,
 };
 
+use super::proxy_protocol::strip_v2_header;
+
 pub(crate) struct TProxyReceiver {
-    _context: AppContext,
+    context: AppContext,
     tproxy_socket: AsyncUdpSocket,
 }
 
@@ -22,13 +24,16 @@ impl TProxyReceiver {
         let bind_addr = (context.cli_args.host, context.cli_args.port).into();
         let tproxy_socket = AsyncUdpSocket::bind_tproxy(&bind_addr)?;
         Ok(Self {
-            _context: context.clone(),
+            context: context.clone(),
             tproxy_socket,
         })
     }
 
     pub(crate) fn incoming_packets(self) -> impl Stream<Item = UdpPackets> {
         let (sender, receiver) = mpsc::channel::<UdpPackets>(16);
+        let proxy_protocol = self.context.cli_args.ingress_proxy_protocol;
+        let trusted = self.context.cli_args.proxy_protocol_from.clone();
+        let stats = self.context.stats.clone();
         tokio::spawn(async move {
             let mut buf: Pin<Box<MsgArrayReadBuffer<UDP_BATCH_SIZE, UDP_MAX_SIZE>>> =
                 MsgArrayReadBuffer::new();
@@ -45,6 +50,29 @@ impl TProxyReceiver {
                 buf.iter()
                     .inspect(|msg| trace!("Receive from TProxy: {}", msg))
                     .filter_map(|msg| Some(((msg.src_addr?, msg.dst_addr?), msg.buf)))
+                    .filter_map(|(addrs, pkt)| {
+                        if !proxy_protocol {
+                            return Some((addrs, pkt));
+                        }
+                        let peer = addrs.0.ip();
+                        if !trusted.iter().any(|net| net.contains(peer)) {
+                            trace!("Drop datagram from untrusted PROXY peer {}", peer);
+                            stats
+                                .proxy_protocol_untrusted
+                                .fetch_add(1, Ordering::Relaxed);
+                            return None;
+                        }
+                        match strip_v2_header(pkt) {
+                            Some((inner, payload)) => Some((inner.unwrap_or(addrs), payload)),
+                            None => {
+                                trace!("Drop datagram without valid PROXY header");
+                                stats
+                                    .proxy_protocol_malformed
+                                    .fetch_add(1, Ordering::Relaxed);
+                                None
+                            }
+                        }
+                    })
                     .for_each(|(addrs, pkt)| {
                         addrs_pkts
                             .entry(addrs)
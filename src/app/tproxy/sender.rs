@@ -2,10 +2,14 @@ use parking_lot::Mutex;
 use std::{
     collections::{hash_map::Entry, HashMap},
     io,
+    net::SocketAddr,
     sync::{Arc, Weak},
 };
 
-use crate::app::{net::AsyncUdpSocket, types::RemoteAddr};
+use crate::{
+    app::{net::AsyncUdpSocket, types::RemoteAddr},
+    cli::ReplyMode,
+};
 
 struct WeakGuard<K, V> {
     key: Option<K>,
@@ -37,10 +41,21 @@ impl<K, V> WeakGuard<K, V> {
     }
 }
 
+/// Socket sending replies from a remote, shared by all conns to it. It
+/// carries no per-conn state: each reply task sends only what came via its
+/// own session, to its own client, so replies of conns sharing a remote
+/// can't cross.
 pub(crate) struct TProxySender {
     inner: WeakGuard<RemoteAddr, AsyncUdpSocket>,
 }
 
+impl TProxySender {
+    /// The remote it's keyed by & sends from.
+    pub(crate) fn remote(&self) -> Option<RemoteAddr> {
+        self.inner.key
+    }
+}
+
 impl AsRef<AsyncUdpSocket> for TProxySender {
     fn as_ref(&self) -> &AsyncUdpSocket {
         &self.inner.value
@@ -50,16 +65,40 @@ impl AsRef<AsyncUdpSocket> for TProxySender {
 pub(crate) struct TProxySenderCache {
     senders: HashMap<RemoteAddr, Weak<TProxySender>>,
     bin: Arc<Mutex<Vec<RemoteAddr>>>,
+    bind: fn(&SocketAddr) -> io::Result<AsyncUdpSocket>,
+    capacity: usize,
 }
 
 impl TProxySenderCache {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(capacity: usize, mode: ReplyMode) -> Self {
         Self {
             senders: Default::default(),
             bin: Default::default(),
+            bind: match mode {
+                ReplyMode::Transparent => AsyncUdpSocket::bind_nonlocal,
+                // Not bound on the remote, the kernel picks our own address
+                ReplyMode::Gateway => AsyncUdpSocket::bind_any,
+            },
+            capacity,
+        }
+    }
+
+    /// Senders bound on local addresses, for tests without CAP_NET_ADMIN.
+    #[cfg(test)]
+    pub(crate) fn new_local(capacity: usize) -> Self {
+        Self {
+            bind: AsyncUdpSocket::bind_local,
+            ..Self::new(capacity, ReplyMode::Transparent)
         }
     }
 
+    /// Remove all dropped entries, return the number of live senders.
+    pub(crate) fn sweep(&mut self) -> usize {
+        self.bin.lock().clear();
+        self.senders.retain(|_, sender| sender.strong_count() > 0);
+        self.senders.len()
+    }
+
     pub(crate) fn get_or_create(&mut self, remote: RemoteAddr) -> io::Result<Arc<TProxySender>> {
         // Try to clear up dropped entries
         if let Some(mut bin) = self.bin.try_lock() {
@@ -71,9 +110,15 @@ impl TProxySenderCache {
                 }
             }
         }
+        if self.senders.len() >= self.capacity
+            && !self.senders.contains_key(&remote)
+            && self.sweep() >= self.capacity
+        {
+            io_error!("Too many TProxy senders");
+        }
 
         let create_sender = || -> Result<_, io::Error> {
-            let sock = AsyncUdpSocket::bind_nonlocal(&remote.0)?;
+            let sock = (self.bind)(&remote.0)?;
             let inner = WeakGuard::new(remote, sock, self.bin.clone());
             Ok(Arc::new(TProxySender { inner }))
         };
@@ -95,3 +140,46 @@ impl TProxySenderCache {
         }
     }
 }
+
+#[tokio::test]
+async fn test_sender_cache_capacity() {
+    let remotes: Vec<RemoteAddr> = (0..3)
+        .map(|_| std::net::UdpSocket::bind("127.0.0.1:0").unwrap())
+        .map(|sock| sock.local_addr().unwrap().into())
+        .collect();
+    let mut cache = TProxySenderCache::new_local(2);
+    let first = cache.get_or_create(remotes[0]).unwrap();
+    assert_eq!(first.remote(), Some(remotes[0]));
+    let _second = cache.get_or_create(remotes[1]).unwrap();
+    assert!(cache.get_or_create(remotes[2]).is_err());
+    // Existing one still available
+    assert!(Arc::ptr_eq(
+        &first,
+        &cache.get_or_create(remotes[0]).unwrap()
+    ));
+    // Dead entry get swept to make room
+    drop(first);
+    assert!(cache.get_or_create(remotes[2]).is_ok());
+    assert_eq!(cache.sweep(), 1);
+}
+
+#[tokio::test]
+async fn test_gateway_sender() {
+    use crate::app::net::MsgArrayWriteBuffer;
+    use bytes::Bytes;
+
+    let client = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let remote: RemoteAddr = "192.0.2.1:443".parse::<SocketAddr>().unwrap().into();
+    let mut cache = TProxySenderCache::new(1, ReplyMode::Gateway);
+    let sender = cache.get_or_create(remote).unwrap();
+    let mut buf = MsgArrayWriteBuffer::with_capacity(1);
+    buf.push(
+        [Bytes::from_static(b"hi")],
+        Some(client.local_addr().unwrap()),
+    );
+    sender.as_ref().as_ref().batch_send(&mut buf).await.unwrap();
+    let mut pkt = [0; 8];
+    let (len, from) = client.recv_from(&mut pkt).await.unwrap();
+    assert_eq!(&pkt[..len], b"hi");
+    assert_ne!(from, remote.0);
+}
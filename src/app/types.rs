@@ -21,11 +21,12 @@ impl From<SocketAddr> for RemoteAddr {
 
 pub(crate) type UdpPackets = (ClientAddr, RemoteAddr, Box<[Bytes]>);
 
-fn canonicalize_socket_addr(addr: SocketAddr) -> SocketAddr {
+/// Convert IPv4-mapped IPv6 address to IPv4 one.
+pub(crate) fn canonicalize_socket_addr(addr: SocketAddr) -> SocketAddr {
     match addr {
         SocketAddr::V4(_) => addr,
         SocketAddr::V6(addr6) => {
-            if let Some(ip4) = addr6.ip().to_ipv4() {
+            if let Some(ip4) = addr6.ip().to_ipv4_mapped() {
                 (ip4, addr6.port()).into()
             } else {
                 addr
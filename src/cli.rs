@@ -1,19 +1,25 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{self, Read},
     net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
     path::{Path, PathBuf},
+    str::FromStr,
     time::Duration,
 };
 
-use clap::Parser;
-use serde::Deserialize;
+use clap::{Parser, ValueEnum};
+use derivative::Derivative;
+use serde::{Deserialize, Deserializer};
 use tracing::metadata::LevelFilter;
 
 use crate::app::InnerProto;
 
-#[derive(Parser, Debug)]
+/// `tokio::sync::Semaphore::MAX_PERMITS`, not public in tokio 1.20
+const MAX_SEMAPHORE_PERMITS: usize = usize::MAX >> 3;
+
+#[derive(Parser, Derivative)]
+#[derivative(Debug)]
 #[clap(author, version, about, long_about = None)]
 pub(crate) struct CliArgs {
     /// Address to bind on for the incoming UDP sessions
@@ -22,7 +28,8 @@ pub(crate) struct CliArgs {
     pub(crate) host: IpAddr,
 
     /// Port number to bind on for the incoming UDP sessions
-    #[clap(short = 'p', long, required = true)]
+    #[clap(short = 'p', long, required_unless_present = "bench-decode")]
+    #[clap(default_value_t = 0, hide_default_value = true)]
     pub(crate) port: u16,
 
     /// TOML config file with the list of upstream proxy servers.
@@ -47,6 +54,157 @@ pub(crate) struct CliArgs {
     #[clap(long)]
     pub(crate) remote_dns: bool,
 
+    /// Obtain domain name from QUIC initial packet (if exists), resolve it
+    /// locally and pass the resolved address to SOCKSv5 server. Names not
+    /// cached yet are resolved in background, their conns meanwhile go by
+    /// the original destination.
+    #[clap(long, conflicts_with = "remote-dns")]
+    pub(crate) local_dns: bool,
+
+    /// Accept QUIC initial packets with the fixed bit cleared, as greased
+    /// by clients supporting RFC 9287.
+    #[clap(long)]
+    pub(crate) tolerate_greased_bit: bool,
+
+    /// Max time to cache the results of local DNS resolution
+    #[clap(long, default_value = "60s")]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) local_dns_cache_ttl: Duration,
+
+    /// Forward zero-length UDP datagrams instead of dropping them
+    #[clap(long)]
+    pub(crate) allow_empty_udp: bool,
+
+    /// Use connected UDP sockets toward upstreams, so that kernel filters
+    /// replies by peer. Set to false if the upstream relays replies from
+    /// another port (e.g. behind NAT), then only their IP gets checked.
+    #[clap(long, default_value_t = true, action = clap::ArgAction::Set)]
+    pub(crate) socks_udp_connected: bool,
+
+    /// Share such number of UDP sockets per upstream among its sessions,
+    /// assigned round-robin, instead of one socket per session. Replies
+    /// are told apart by the address in their SOCKSv5 header, so sessions
+    /// to names, or to a target already on every pooled socket, still get
+    /// their own. Connected per --socks-udp-connected, otherwise replies
+    /// from the upstream's IP are taken from any port.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) socks_socket_pool: Option<u16>,
+
+    /// Network namespace, by name (under /var/run/netns) or path, to create
+    /// sockets toward upstreams in, including TCP control connections. The
+    /// TProxy socket & ones replying clients stay in the current one.
+    /// CAP_SYS_ADMIN required.
+    #[clap(long)]
+    pub(crate) netns: Option<String>,
+
+    /// Split datagrams larger than it (in bytes, excluding SOCKSv5 header)
+    /// into SOCKSv5 UDP fragments. Many servers don't support fragments.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) socks_frag_size: Option<u16>,
+
+    /// Max bytes held by all sessions for reassembling fragmented SOCKSv5
+    /// UDP replies. Fragments beyond it are dropped along with the rest of
+    /// their datagram.
+    #[clap(long, default_value_t = 16 << 20)]
+    pub(crate) max_reassembly_mem: usize,
+
+    /// How to check the address in SOCKSv5 UDP replies against the target:
+    /// "off" ignores it, "log" counts mismatches and warns on the first of
+    /// each session, "drop" discards mismatched replies
+    #[clap(long, value_enum, default_value_t = ReplyAddrCheck::Off)]
+    pub(crate) validate_socks_reply_addr: ReplyAddrCheck,
+
+    /// Log at info level the address upstreams resolved each name to (on
+    /// the first reply), for `--remote-dns`. Logged at debug level otherwise.
+    #[clap(long)]
+    pub(crate) log_resolved_addr: bool,
+
+    /// Log at info level when a conn moves to another upstream, e.g. on
+    /// failover or draining. Logged at debug level otherwise.
+    #[clap(long)]
+    pub(crate) log_migrations: bool,
+
+    /// Log at info level when no server name can be found in the first
+    /// packet of a conn with --remote-dns or --local-dns, e.g. not a QUIC
+    /// Initial or without SNI, so the conn is routed by IP. Logged at debug
+    /// level otherwise.
+    #[clap(long)]
+    pub(crate) log_no_sni: bool,
+
+    /// Read PROXY protocol v2 header prepended to each intercepted datagram
+    /// (by another proxy in front), and take client & remote addresses
+    /// from it. Datagrams without a valid header are dropped. Replies are
+    /// still sent to the client directly.
+    #[clap(long, requires = "proxy-protocol-from")]
+    pub(crate) ingress_proxy_protocol: bool,
+
+    /// Accept PROXY protocol headers only from peers in these networks
+    /// (e.g. 10.0.0.0/8, ::1/128) with --ingress-proxy-protocol.
+    /// Datagrams from other peers are dropped, since their header could
+    /// spoof any client address.
+    #[clap(long, multiple_values = true)]
+    pub(crate) proxy_protocol_from: Vec<IpNet>,
+
+    /// Close QUIC conns early if the remote seems to have sent a stateless
+    /// reset (heuristic, may close healthy conns on CID changes)
+    #[clap(long)]
+    pub(crate) reset_teardown: bool,
+
+    /// Evict conns older than it even if still active, checked every 30s.
+    /// Next packets of the conn start a new session, maybe to other upstream.
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) max_conn_lifetime: Option<Duration>,
+
+    /// Send replies of all conns from one task every such interval (e.g.
+    /// 1ms) instead of one task per conn, so that replies to the same
+    /// remote go out in one sendmmsg call. Fewer syscalls under many busy
+    /// conns, at the cost of up to the interval of extra latency.
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) reply_batch_interval: Option<Duration>,
+
+    /// Decode first packets of new conns for --remote-dns/--local-dns on
+    /// such number of worker threads instead of the forwarding task, so a
+    /// flood of new conns doesn't hold up established ones. New conns are
+    /// dropped while the queue of workers is full.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) decode_workers: Option<u16>,
+
+    /// Max time to keep upstreams removed on reload while their conns are
+    /// migrated away, checked every 30s. Conns left after it are migrated
+    /// at once regardless of `--rebalance-max-migrations`.
+    #[clap(long, default_value = "5m")]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) drain_timeout: Duration,
+
+    /// Answer debug queries on which upstream would be selected, intercepted
+    /// to this port of any address instead of being forwarded. Query with
+    /// UDP payload "QUPROXY?<remote-addr> [sni]", padded with spaces to fit
+    /// the JSON reply, or it's dropped.
+    #[clap(long)]
+    pub(crate) debug_query_port: Option<u16>,
+
+    /// Port number of HTTP listener, serving `/healthz` (liveness),
+    /// `/ready` (first probe cycle done & any upstream usable), `/metrics`
+    /// (Prometheus), `/servers` (upstream status in JSON), `/explain` (why a
+    /// flow goes to which upstream), and admin API
+    #[clap(long)]
+    pub(crate) http_port: Option<u16>,
+
+    /// Bearer token required by admin API of the HTTP listener (drain &
+    /// undrain upstreams, `/diagnostics`). Without it, admin API answers
+    /// loopback peers only.
+    #[clap(long)]
+    #[derivative(Debug(format_with = "fmt_redacted"))]
+    pub(crate) admin_token: Option<String>,
+
+    /// Serve `/metrics` in OpenMetrics format to scrapers accepting it, with
+    /// exemplars carrying the `probe_id` logged by the latest successful
+    /// probe (at debug level)
+    #[clap(long)]
+    pub(crate) metrics_exemplars: bool,
+
     /// Disable availability check
     #[clap(long)]
     pub(crate) no_check: bool,
@@ -56,6 +214,49 @@ pub(crate) struct CliArgs {
     #[clap(parse(try_from_str = parse_duration::parse))]
     pub(crate) check_interval: Duration,
 
+    /// File to keep ping histories of upstreams across restarts, loaded on
+    /// start (if not older than an hour, nor from the future) and saved on
+    /// SIGTERM/SIGINT. Those of socks5_tcp upstreams are restored on their
+    /// first negotiation.
+    #[clap(long)]
+    pub(crate) state_file: Option<PathBuf>,
+
+    /// Probe all upstreams once before forwarding any packet, so that the
+    /// first connections go to a healthy one. Ignored with `--no-check`.
+    #[clap(long)]
+    pub(crate) probe_on_start: bool,
+
+    /// Max time to wait for `--probe-on-start`
+    #[clap(long, default_value = "10s")]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) probe_on_start_timeout: Duration,
+
+    /// Skip probing upstreams that received replies in the last few seconds,
+    /// taking them as healthy. Their ping history is left as is.
+    #[clap(long)]
+    pub(crate) passive_check: bool,
+
+    /// Mark an upstream down after this many consecutive timed-out probes.
+    /// Hard errors (e.g. bind/send failures) mark it down immediately.
+    #[clap(long, default_value_t = 1)]
+    pub(crate) down_after_timeouts: usize,
+
+    /// Also mark an upstream down on a timed-out probe if its packet loss
+    /// over the ping history reaches this percentage
+    #[clap(long)]
+    pub(crate) down_loss_percent: Option<u8>,
+
+    /// Upstreams with average RTT exceeding it are excluded from selection,
+    /// until it drops below 80% of that. Per-upstream `max_rtt` overrides.
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) max_rtt: Option<Duration>,
+
+    /// Method of availability check: "dns" queries a DNS server, "quic"
+    /// expects version negotiation from a QUIC server
+    #[clap(long, value_enum, default_value_t = CheckMethod::Dns)]
+    pub(crate) check_method: CheckMethod,
+
     /// Address of a DNS server to do availability check (IPv4)
     #[clap(long, default_value = "1.1.1.1:53")]
     pub(crate) check_dns_server_v4: SocketAddrV4,
@@ -64,11 +265,92 @@ pub(crate) struct CliArgs {
     #[clap(long, default_value = "[2606:4700:4700::1111]:53")]
     pub(crate) check_dns_server_v6: SocketAddrV6,
 
+    /// How DNS check queries are padded to 500 bytes: "experimental" with
+    /// random bytes in a local-use EDNS option (65001), "standard" with the
+    /// EDNS Padding option (RFC 7830) for strict resolvers, or "none" to
+    /// send them unpadded
+    #[clap(long, value_enum, default_value_t = DnsPadding::Experimental)]
+    pub(crate) dns_padding: DnsPadding,
+
+    /// Set SO_MARK on sockets of availability checks, to route or limit
+    /// them apart from data traffic. CAP_NET_ADMIN required.
+    #[clap(long)]
+    pub(crate) probe_fwmark: Option<u32>,
+
+    /// Set DSCP (0 to 63) on packets of availability checks
+    #[clap(long, value_parser = clap::value_parser!(u8).range(..64))]
+    pub(crate) probe_dscp: Option<u8>,
+
+    /// Address of a QUIC server to do availability check (IPv4)
+    #[clap(long, default_value = "1.1.1.1:443")]
+    pub(crate) check_quic_server_v4: SocketAddrV4,
+
+    /// Address of a QUIC server to do availability check (IPv6)
+    #[clap(long, default_value = "[2606:4700:4700::1111]:443")]
+    pub(crate) check_quic_server_v6: SocketAddrV6,
+
+    /// Min time to wait for the first UDP reply on a fresh SOCKSv5 session.
+    /// Some servers (e.g. those hold the first UDP reply until the TCP
+    /// control connection get acknowledged) reply late at first; set this
+    /// to avoid misjudging them as down.
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) first_reply_grace: Option<Duration>,
+
+    /// Max time to wait for replies of a health check probe, and to send
+    /// its probes, no matter how slow the server was before. Takes priority
+    /// over --first-reply-grace.
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) max_probe_wait: Option<Duration>,
+
+    /// Warn if a new upstream session of a conn gets no reply within it,
+    /// naming the upstream and the remote (SNI if known)
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) first_reply_timeout: Option<Duration>,
+
+    /// Also migrate the conn to another upstream on its next packet if
+    /// `--first-reply-timeout` exceeded, without waiting for health check
+    #[clap(long, requires = "first-reply-timeout")]
+    pub(crate) migrate_on_no_reply: bool,
+
+    /// Re-send the first Initial packet of a new conn once, via the same
+    /// upstream session, if no reply within it (e.g. 300ms), in case it
+    /// got lost beyond the upstream. Should be well below the ~1s before
+    /// clients retransmit it themselves.
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) initial_resend_after: Option<Duration>,
+
+    /// Path MTU toward clients (e.g. 1280), replies that don't fit in it
+    /// with IP & UDP headers are dropped & counted instead of fragmented
+    #[clap(long, value_parser = clap::value_parser!(u16).range(576..))]
+    pub(crate) client_mtu: Option<u16>,
+
     /// Period of time to check & reinitiate SOCKSv5 TCP connections
     #[clap(long, default_value = "20s")]
     #[clap(parse(try_from_str = parse_duration::parse))]
     pub(crate) socks5_tcp_check_interval: Duration,
 
+    /// Send TCP keepalive probes on SOCKSv5 TCP connections idle for this
+    /// long, so that NATs & firewalls don't drop them along with the UDP
+    /// association. Disabled if not set.
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) socks5_tcp_keepalive: Option<Duration>,
+
+    /// Period of time between TCP keepalive probes without response, see
+    /// `--socks5-tcp-keepalive`
+    #[clap(long, default_value = "10s")]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) socks5_tcp_keepalive_interval: Duration,
+
+    /// Max number of SOCKSv5 TCP connections being set up at the same time,
+    /// to pace reconnecting after an outage
+    #[clap(long, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    pub(crate) refer_reconnect_concurrency: u16,
+
     /// Level of logging verbosity [possible values: off, error, warn, info,
     /// debug, trace]
     #[clap(long)]
@@ -80,15 +362,316 @@ pub(crate) struct CliArgs {
     #[clap(parse(try_from_str = parse_duration::parse))]
     pub(crate) udp_session_timeout: Duration,
 
+    /// Migrate connections older than this to the best server if they are
+    /// not on it, e.g. to rebalance after a server recovered
+    #[clap(long)]
+    #[clap(parse(try_from_str = parse_duration::parse))]
+    pub(crate) rebalance_after: Option<Duration>,
+
+    /// Max number of rebalance migrations per check interval
+    #[clap(long, default_value_t = 8)]
+    pub(crate) rebalance_max_migrations: usize,
+
+    /// Weight of RTT jitter (standard deviation) added to the delay when
+    /// scoring upstreams, e.g. 1.0 to deprioritize jittery ones for
+    /// interactive traffic. Zero to ignore jitter.
+    #[clap(long, default_value_t = 0.0)]
+    pub(crate) jitter_weight: f32,
+
+    /// How to estimate delay from ping history when scoring upstreams:
+    /// mean of all, or EWMA reacting faster to recent changes
+    #[clap(long, value_enum, default_value_t = DelayEstimator::Mean)]
+    pub(crate) delay_estimator: DelayEstimator,
+
+    /// Weight of the latest reply for `--delay-estimator ewma`, in (0, 1]
+    #[clap(long, default_value_t = 0.2, value_parser = parse_ewma_alpha)]
+    pub(crate) ewma_alpha: f32,
+
+    /// How to select upstream for new connections: "best" picks the one
+    /// with best score, "consistent-hash" maps the same remote to the same
+    /// upstream (rendezvous hashing with bounded loads)
+    #[clap(long, value_enum, default_value_t = SelectMode::Best)]
+    pub(crate) select_mode: SelectMode,
+
+    /// How to pick among upstreams of equal score on each selection with
+    /// --select-mode best: "none" keeps the previous order, "sessions"
+    /// takes the one with fewest active sessions, "rotate" takes them in
+    /// turn
+    #[clap(long, value_enum, default_value_t = TieBreak::None)]
+    pub(crate) tie_break: TieBreak,
+
+    /// How to identify a QUIC conn: "full" uses client & remote address,
+    /// "client-ip" ignores client port so that a client behind rebinding
+    /// NAT keeps its upstream session (replies follow its latest port).
+    /// The latter merges different clients sharing one IP & talking to the
+    /// same remote, only use it if they are all QUIC clients, which
+    /// tolerate packets of others' conns.
+    #[clap(long, value_enum, default_value_t = ConnKey::Full)]
+    pub(crate) conn_key: ConnKey,
+
+    /// Answer new conns of QUIC versions other than v1 & v2 with a Version
+    /// Negotiation packet offering v1 & v2, sent as from the remote, instead
+    /// of forwarding a handshake that can't be inspected
+    #[clap(long)]
+    pub(crate) force_quic_v1: bool,
+
+    /// Only proxy new QUIC conns offering one of these ALPN protocols (e.g.
+    /// "h3"), others & ones without a known ALPN are dropped. Decodes the
+    /// Initial packet even without --remote-dns/--local-dns.
+    #[clap(long, multiple_values = true)]
+    pub(crate) allow_alpn: Vec<String>,
+
+    /// Drop new QUIC conns offering any of these ALPN protocols, e.g. "doq"
+    /// against DNS-over-QUIC
+    #[clap(long, multiple_values = true)]
+    pub(crate) deny_alpn: Vec<String>,
+
+    /// Keep the conn of a client that moved to another port (e.g. NAT
+    /// rebinding), by matching the DCID of its short-header packets with
+    /// CIDs the remote chose, instead of opening a new one. Only moves
+    /// within the same client IP are followed. No effect with
+    /// `--conn-key client-ip`, which ignores client ports anyway.
+    #[clap(long)]
+    pub(crate) follow_client_port: bool,
+
+    /// Prefer the upstream last selected for a client IP on its new conns,
+    /// as long as it's healthy & capable, so that flows of a client don't
+    /// spread over upstreams. Remembered for `--udp-session-timeout`, up to
+    /// `--udp-max-sessions` clients.
+    #[clap(long)]
+    pub(crate) sticky_client: bool,
+
+    /// Load factor of consistent hashing, upstreams with more than this
+    /// times their share of sessions by weight are skipped. Min 1.0.
+    #[clap(long, default_value_t = 1.25)]
+    pub(crate) hash_load_factor: f64,
+
+    /// Mixed into consistent hashing. Instances sharing the same salt map a
+    /// remote to the same upstream, use different ones to spread them apart.
+    #[clap(long)]
+    #[derivative(Debug(format_with = "fmt_redacted"))]
+    pub(crate) hash_salt: Option<String>,
+
+    /// Max packets per second forwarded to upstreams, excess are dropped.
+    /// QUIC's anti-amplification limit already helps, this is a
+    /// defense-in-depth against spoofed clients.
+    #[clap(long)]
+    pub(crate) max_egress_pps: Option<u64>,
+
+    /// Max bytes per second forwarded to upstreams, excess are dropped
+    #[clap(long)]
+    pub(crate) max_egress_bps: Option<u64>,
+
+    /// Split `--max-egress-pps` evenly among client IPs active within
+    /// `--udp-session-timeout`. Clients beyond their share only get the
+    /// spare half of the global rate, so they can't starve the others.
+    #[clap(long, requires = "max-egress-pps")]
+    pub(crate) egress_fair_share: bool,
+
+    /// Max packets per second forwarded to upstreams per client IP
+    #[clap(long)]
+    pub(crate) max_client_egress_pps: Option<u64>,
+
+    /// Max bytes per second forwarded to upstreams per client IP
+    #[clap(long)]
+    pub(crate) max_client_egress_bps: Option<u64>,
+
+    /// Warn if new conns exceed this ratio of packets from clients over
+    /// 10s (e.g. 0.5), which suggests a routing loop or a scan
+    #[clap(long)]
+    pub(crate) loop_warn_conn_ratio: Option<f64>,
+
+    /// Warn if a client IP opens conns to more than this many remotes
+    /// over 10s, which suggests a routing loop or a scan
+    #[clap(long)]
+    pub(crate) loop_warn_remotes_per_client: Option<usize>,
+
     /// Max number of tracked UDP sessions
     #[clap(long, default_value_t = 512)]
     pub(crate) udp_max_sessions: usize,
+
+    /// Max number of sockets for sending replies to clients, one for each
+    /// remote address. Should be larger than `--udp-max-sessions`.
+    #[clap(long, default_value_t = 1024)]
+    pub(crate) max_tproxy_senders: usize,
+
+    /// How to send replies to clients: "transparent" spoofs the remote as
+    /// the source (IP_TRANSPARENT), "gateway" sends from quproxy's own
+    /// address on a random port, for where spoofing isn't possible. Clients
+    /// must be set up to accept replies from the gateway in that mode, e.g.
+    /// by their own NAT or tunnel.
+    #[clap(long, value_enum, default_value_t = ReplyMode::Transparent)]
+    pub(crate) reply_mode: ReplyMode,
+
+    /// Max number of live tasks forwarding replies to clients, one for each
+    /// session. New conns are refused when exhausted.
+    #[clap(long, default_value_t = 2048)]
+    #[clap(value_parser = clap::builder::RangedU64ValueParser::<usize>::new()
+        .range(1..=MAX_SEMAPHORE_PERMITS as u64))]
+    pub(crate) max_reply_tasks: usize,
+
+    /// Benchmark QUIC initial packet decoding then exit
+    #[clap(long)]
+    pub(crate) bench_decode: bool,
+
+    /// Number of iterations for --bench-decode
+    #[clap(long, default_value_t = 10000, value_parser = clap::value_parser!(u32).range(1..))]
+    pub(crate) iterations: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CheckMethod {
+    Dns,
+    Quic,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DnsPadding {
+    Experimental,
+    Standard,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum DelayEstimator {
+    Mean,
+    Ewma,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SelectMode {
+    Best,
+    ConsistentHash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum TieBreak {
+    None,
+    Sessions,
+    Rotate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ReplyMode {
+    Transparent,
+    Gateway,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ReplyAddrCheck {
+    Off,
+    Log,
+    Drop,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ConnKey {
+    Full,
+    ClientIp,
+}
+
+/// IP network in CIDR notation, e.g. `192.0.2.0/24`. A bare address is
+/// taken as a single host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct IpNet {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|err| format!("{}", err))?
+            .to_canonical();
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            "" => max_len,
+            len => len.parse().map_err(|err| format!("{}", err))?,
+        };
+        if prefix_len > max_len {
+            return Err(format!("prefix length over {}", max_len));
+        }
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Hide secrets from debug output, which goes into logs & diagnostics.
+fn fmt_redacted<T>(value: &Option<T>, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match value {
+        Some(_) => f.write_str("Some(<redacted>)"),
+        None => f.write_str("None"),
+    }
+}
+
+fn parse_ewma_alpha(s: &str) -> Result<f32, String> {
+    let alpha: f32 = s.parse().map_err(|err| format!("{}", err))?;
+    if alpha > 0.0 && alpha <= 1.0 {
+        Ok(alpha)
+    } else {
+        Err("not in (0, 1]".into())
+    }
+}
+
+impl CliArgs {
+    /// Whether to decode Initial packets of new conns, for their SNI or ALPN
+    pub(crate) fn decode_initial(&self) -> bool {
+        self.remote_dns
+            || self.local_dns
+            || !self.allow_alpn.is_empty()
+            || !self.deny_alpn.is_empty()
+    }
+
+    /// Whether a new conn offering `alpn` passes `--allow-alpn` &
+    /// `--deny-alpn`.
+    pub(crate) fn alpn_allowed(&self, alpn: &[String]) -> bool {
+        let offered = |list: &[String]| alpn.iter().any(|proto| list.contains(proto));
+        (self.allow_alpn.is_empty() || offered(&self.allow_alpn)) && !offered(&self.deny_alpn)
+    }
+
+    /// IPv4 & IPv6 targets of availability check per `check_method`
+    pub(crate) fn check_targets(&self) -> (SocketAddrV4, SocketAddrV6) {
+        match self.check_method {
+            CheckMethod::Dns => (self.check_dns_server_v4, self.check_dns_server_v6),
+            CheckMethod::Quic => (self.check_quic_server_v4, self.check_quic_server_v6),
+        }
+    }
 }
 
 #[derive(Deserialize, Default)]
 pub(crate) struct ConfigFile {
     #[serde(serialize_with = "toml::ser::tables_last")]
     pub(crate) upstreams: HashMap<String, Upstream>,
+    /// Domain name (SNI) => upstream name, subdomains included
+    #[serde(default)]
+    pub(crate) pins: HashMap<String, String>,
+    /// Domain names (SNI, subdomains included) whose conns are sent via two
+    /// upstreams at once, for reliability at double the cost
+    #[serde(default)]
+    pub(crate) duplicate: HashSet<String>,
 }
 
 #[derive(Deserialize, Default, PartialEq, Eq, Clone, Copy)]
@@ -106,24 +689,142 @@ fn bool_true() -> bool {
     true
 }
 
+fn weight_one() -> u32 {
+    1
+}
+
 #[derive(Deserialize, PartialEq, Eq)]
 pub(crate) struct Upstream {
     #[serde(alias = "proto")]
     #[serde(default)]
     pub(crate) protocol: UpstreamProtocol,
     #[serde(alias = "addr")]
-    pub(crate) address: SocketAddr,
+    pub(crate) address: UpstreamAddr,
     #[serde(default = "bool_true")]
     pub(crate) enabled: bool,
     #[serde(default)]
     #[serde(alias = "inner_protocol")]
     pub(crate) inner_proto: InnerProto,
+    /// Max bytes per second sending to the upstream
+    #[serde(default)]
+    pub(crate) tx_limit: Option<u64>,
+    /// Max bytes per second receiving from the upstream, servers approaching
+    /// it are deprioritized
+    #[serde(default)]
+    pub(crate) rx_limit: Option<u64>,
+    /// Exclude the upstream if its average RTT exceeds it, e.g. "500ms"
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub(crate) max_rtt: Option<Duration>,
+    /// Name of the pool it belongs to, shown in logs & status
+    #[serde(default)]
+    pub(crate) group: Option<String>,
+    /// DNS server to check the upstream against in place of
+    /// `--check-dns-server-v4`, e.g. a resolver near the upstream
+    #[serde(default)]
+    pub(crate) check_dns_v4: Option<SocketAddrV4>,
+    /// DNS server to check the upstream against in place of
+    /// `--check-dns-server-v6`
+    #[serde(default)]
+    pub(crate) check_dns_v6: Option<SocketAddrV6>,
+    /// Local address to send from, e.g. a loopback alias, in place of the
+    /// one picked by the kernel
+    #[serde(default)]
+    pub(crate) bind_from: Option<IpAddr>,
+    /// Username & password for RFC 1929 authentication, for "socks5_tcp"
+    #[serde(default)]
+    pub(crate) username: Option<String>,
+    #[serde(default)]
+    pub(crate) password: Option<String>,
+    /// Relative share of conns it takes among usable upstreams, divided by
+    /// its score in best mode, or of keys in consistent-hash mode; 0 to
+    /// take conns only if all others have weight 0 too
+    #[serde(default = "weight_one")]
+    pub(crate) weight: u32,
+}
+
+/// Address of an upstream in the list file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum UpstreamAddr {
+    Ip(SocketAddr),
+    /// "host:port", resolved by quproxy
+    Host(String),
+}
+
+impl<'de> Deserialize<'de> for UpstreamAddr {
+    /// IP-like ones that fail to parse are rejected, instead of being taken
+    /// as hostnames that never resolve.
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let text = String::deserialize(deserializer)?;
+        if let Ok(addr) = text.parse() {
+            return Ok(Self::Ip(addr));
+        }
+        let host = match text.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host,
+            _ => return Err(serde::de::Error::custom("expect host:port")),
+        };
+        let ip_like = host.starts_with('[')
+            || host.contains(':')
+            || host.chars().all(|c| c.is_ascii_digit() || c == '.');
+        if ip_like {
+            let msg = format!("invalid IP address {}", text);
+            return Err(serde::de::Error::custom(msg));
+        }
+        Ok(Self::Host(text))
+    }
+}
+
+impl std::fmt::Display for UpstreamAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Ip(addr) => write!(f, "{}", addr),
+            Self::Host(host) => f.write_str(host),
+        }
+    }
+}
+
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let text = String::deserialize(deserializer)?;
+    parse_duration::parse(&text)
+        .map(Some)
+        .map_err(serde::de::Error::custom)
 }
 
 impl ConfigFile {
+    /// Read & parse config file, with its path included in error message.
     pub(crate) fn from_path<T: AsRef<Path>>(path: T) -> io::Result<Self> {
+        let path = path.as_ref();
+        let with_path = |kind, err: &dyn std::fmt::Display| {
+            io::Error::new(kind, format!("{}: {}", path.display(), err))
+        };
         let mut buf = String::new();
-        File::open(path)?.read_to_string(&mut buf)?;
-        Ok(toml::de::from_str(&buf)?)
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut buf))
+            .map_err(|err| with_path(err.kind(), &err))?;
+        toml::de::from_str(&buf).map_err(|err| with_path(io::ErrorKind::InvalidData, &err))
     }
 }
+
+#[test]
+fn test_ip_net() {
+    let net: IpNet = "192.0.2.0/24".parse().unwrap();
+    assert!(net.contains([192, 0, 2, 255].into()));
+    assert!(net.contains("::ffff:192.0.2.1".parse().unwrap()));
+    assert!(!net.contains([192, 0, 3, 1].into()));
+    assert!(!net.contains("2001:db8::1".parse().unwrap()));
+    let host: IpNet = "2001:db8::1".parse().unwrap();
+    assert!(host.contains("2001:db8::1".parse().unwrap()));
+    assert!(!host.contains("2001:db8::2".parse().unwrap()));
+    let any: IpNet = "::/0".parse().unwrap();
+    assert!(any.contains("2001:db8::2".parse().unwrap()));
+    assert!("192.0.2.0/33".parse::<IpNet>().is_err());
+
+    // Headers must not be trusted from anywhere by default
+    let args = ["quproxy", "-p", "0", "--ingress-proxy-protocol"];
+    assert!(CliArgs::try_parse_from(args).is_err());
+}
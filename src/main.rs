@@ -1,9 +1,14 @@
 use clap::Parser;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 use tracing_subscriber::prelude::*;
 
 mod app;
 mod cli;
 
+/// EX_CONFIG from sysexits.h
+const EXIT_CONFIG_ERROR: i32 = 78;
+
 #[tokio::main]
 async fn main() {
     let args = cli::CliArgs::parse();
@@ -11,18 +16,86 @@ async fn main() {
         .with(args.log_level)
         .with(tracing_subscriber::fmt::layer())
         .init();
-    let context = app::AppContext::from_cli_args(args);
+    if args.bench_decode {
+        app::bench_decode(args.iterations);
+        return;
+    }
+    let context = match app::AppContext::from_cli_args(args) {
+        Ok(context) => context,
+        Err(err) => {
+            error!("Failed to load config: {}", err);
+            std::process::exit(EXIT_CONFIG_ERROR);
+        }
+    };
+    if let Some(netns) = &context.cli_args.netns {
+        if let Err(err) = app::enter_netns(netns) {
+            error!("Failed to enter network namespace: {}", err);
+            std::process::exit(EXIT_CONFIG_ERROR);
+        }
+    }
+    tokio::spawn(reload_on_hangup(context.clone()));
+    tokio::spawn(summary_on_user2(context.clone()));
 
     tokio::spawn(app::SocksReferService::new(&context).launch());
     if !context.cli_args.no_check {
-        tokio::spawn(app::CheckingService::new(&context).launch());
+        let checking = app::CheckingService::new(&context);
+        if context.cli_args.probe_on_start {
+            info!("Probing upstreams before serving");
+            checking
+                .ping_all_once(context.cli_args.probe_on_start_timeout)
+                .await;
+        }
+        tokio::spawn(checking.launch());
     }
 
+    // Preflight, for clear messages instead of failures on binding sockets
+    app::check_kernel_support(&(context.cli_args.host, context.cli_args.port).into());
     let tproxy_receiver =
         app::TProxyReceiver::new(&context).expect("Failed to launch TProxy receiver");
     let receiver = tproxy_receiver.incoming_packets();
+    let (admin_tx, admin_rx) = tokio::sync::mpsc::channel(8);
+    // Start after the tproxy socket is bound, for `/healthz` to be meaningful
+    if let Some(port) = context.cli_args.http_port {
+        let service = app::HttpService::bind(&context, port, admin_tx)
+            .await
+            .expect("Failed to launch HTTP service");
+        tokio::spawn(service.launch());
+    }
+
+    let forward = app::SocksForwardService::new(&context, admin_rx)
+        .expect("Failed to launch SOCKS forward service");
+    tokio::select! {
+        _ = forward.serve(receiver) => (),
+        _ = shutdown_signal() => info!("Shutting down"),
+    }
+    context.save_state();
+}
 
-    app::SocksForwardService::new(&context)
-        .serve(receiver)
-        .await;
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen on SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("Failed to listen on SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => (),
+        _ = interrupt.recv() => (),
+    }
+}
+
+async fn reload_on_hangup(context: app::AppContext) {
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to listen on SIGHUP");
+    while hangup.recv().await.is_some() {
+        info!("SIGHUP received, reloading upstreams");
+        // Hostnames of upstreams get resolved, blocking
+        if let Err(err) = tokio::task::block_in_place(|| context.reload_upstreams()) {
+            warn!("Failed to reload, keep previous config: {}", err);
+        }
+    }
+}
+
+async fn summary_on_user2(context: app::AppContext) {
+    let mut user2 = signal(SignalKind::user_defined2()).expect("Failed to listen on SIGUSR2");
+    while user2.recv().await.is_some() {
+        if let Err(err) = context.write_summary(&mut std::io::stderr().lock()) {
+            warn!("Failed to write summary: {}", err);
+        }
+    }
 }